@@ -0,0 +1,261 @@
+use mongodb::error::{ErrorKind, WriteFailure, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR};
+use rdkafka::error::{KafkaError as RDKafkaError, RDKafkaErrorCode};
+
+/// Whether an operation that failed with an error is worth trying again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// A temporary failure, such as a lost connection or a leader election, that is expected to
+    /// clear on its own.
+    Transient,
+    /// Retrying cannot help, e.g. a payload that does not deserialize. These go to the dead letter
+    /// topic straight away.
+    Permanent,
+    /// The downstream system asked us to slow down. Worth retrying, but with a longer backoff.
+    Throttled,
+}
+
+impl ErrorClass {
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, ErrorClass::Permanent)
+    }
+}
+
+/// Implemented by errors that can tell whether they are worth retrying.
+pub trait Classify {
+    fn class(&self) -> ErrorClass;
+
+    fn is_retryable(&self) -> bool {
+        self.class().is_retryable()
+    }
+}
+
+impl Classify for RDKafkaError {
+    fn class(&self) -> ErrorClass {
+        let Some(code) = self.rdkafka_error_code() else {
+            return match self {
+                RDKafkaError::ClientConfig(..)
+                | RDKafkaError::ClientCreation(_)
+                | RDKafkaError::Subscription(_)
+                | RDKafkaError::Nul(_) => ErrorClass::Permanent,
+                // Anything else is retried, calling it permanent would dead letter messages that
+                // may well go through on the next attempt.
+                _ => ErrorClass::Transient,
+            };
+        };
+
+        match code {
+            RDKafkaErrorCode::QueueFull | RDKafkaErrorCode::ThrottlingQuotaExceeded => {
+                ErrorClass::Throttled
+            }
+
+            RDKafkaErrorCode::MessageSizeTooLarge
+            | RDKafkaErrorCode::InvalidMessage
+            | RDKafkaErrorCode::InvalidMessageSize
+            | RDKafkaErrorCode::PolicyViolation
+            | RDKafkaErrorCode::BadMessage
+            | RDKafkaErrorCode::InvalidRecord
+            | RDKafkaErrorCode::UnknownTopic
+            | RDKafkaErrorCode::UnknownPartition
+            | RDKafkaErrorCode::TopicAuthorizationFailed
+            | RDKafkaErrorCode::GroupAuthorizationFailed
+            | RDKafkaErrorCode::ClusterAuthorizationFailed
+            | RDKafkaErrorCode::SaslAuthenticationFailed
+            | RDKafkaErrorCode::UnsupportedVersion
+            | RDKafkaErrorCode::UnsupportedCompressionType
+            | RDKafkaErrorCode::InvalidArgument
+            | RDKafkaErrorCode::InvalidConfig
+            | RDKafkaErrorCode::Fatal => ErrorClass::Permanent,
+
+            _ => ErrorClass::Transient,
+        }
+    }
+}
+
+// Server error codes that mean "try again later" rather than "this will never work".
+// https://github.com/mongodb/mongo/blob/master/src/mongo/base/error_codes.yml
+const MONGO_TRANSIENT_CODES: [i32; 12] = [
+    6,     // HostUnreachable
+    7,     // HostNotFound
+    89,    // NetworkTimeout
+    91,    // ShutdownInProgress
+    112,   // WriteConflict
+    189,   // PrimarySteppedDown
+    9001,  // SocketException
+    10107, // NotWritablePrimary
+    11600, // InterruptedAtShutdown
+    11602, // InterruptedDueToReplStateChange
+    13435, // NotPrimaryNoSecondaryOk
+    13436, // NotPrimaryOrSecondary
+];
+const MONGO_THROTTLED_CODES: [i32; 4] = [
+    50,    // MaxTimeMSExpired
+    262,   // ExceededTimeLimit
+    462,   // IngressRequestRateLimitExceeded
+    16500, // RequestRateTooLarge
+];
+
+fn classify_mongo_code(code: i32) -> ErrorClass {
+    if MONGO_THROTTLED_CODES.contains(&code) {
+        ErrorClass::Throttled
+    } else if MONGO_TRANSIENT_CODES.contains(&code) {
+        ErrorClass::Transient
+    } else {
+        ErrorClass::Permanent
+    }
+}
+
+impl Classify for mongodb::error::Error {
+    fn class(&self) -> ErrorClass {
+        if self.contains_label(RETRYABLE_WRITE_ERROR)
+            || self.contains_label(TRANSIENT_TRANSACTION_ERROR)
+        {
+            return ErrorClass::Transient;
+        }
+
+        match self.kind.as_ref() {
+            ErrorKind::Io(_)
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::DnsResolve { .. } => ErrorClass::Transient,
+            ErrorKind::Command(error) => classify_mongo_code(error.code),
+            ErrorKind::Write(WriteFailure::WriteError(error)) => classify_mongo_code(error.code),
+            ErrorKind::Write(WriteFailure::WriteConcernError(_)) => ErrorClass::Transient,
+            ErrorKind::InsertMany(error) => match &error.write_errors {
+                Some(write_errors) => write_errors
+                    .iter()
+                    .map(|write_error| classify_mongo_code(write_error.code))
+                    .find(|class| *class != ErrorClass::Permanent)
+                    .unwrap_or(ErrorClass::Permanent),
+                None => ErrorClass::Transient,
+            },
+            ErrorKind::InvalidArgument { .. }
+            | ErrorKind::Authentication { .. }
+            | ErrorKind::BsonDeserialization(_)
+            | ErrorKind::BsonSerialization(_)
+            | ErrorKind::InvalidTlsConfig { .. }
+            | ErrorKind::IncompatibleServer { .. }
+            | ErrorKind::SessionsNotSupported => ErrorClass::Permanent,
+            // As for Kafka, an error not known to be permanent is worth another attempt.
+            _ => ErrorClass::Transient,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::KafkaError;
+    use mongodb::bson::{self, doc, Document};
+    use mongodb::error::{CommandError, Error as MongoError, WriteConcernError, WriteError};
+
+    fn produce_error(code: RDKafkaErrorCode) -> RDKafkaError {
+        RDKafkaError::MessageProduction(code)
+    }
+
+    fn command_error(code: i32) -> MongoError {
+        let error: CommandError =
+            bson::from_document(doc! { "code": code, "codeName": "", "errmsg": "" }).unwrap();
+        ErrorKind::Command(error).into()
+    }
+
+    fn write_error(code: i32) -> MongoError {
+        let error: WriteError = bson::from_document(doc! { "code": code, "errmsg": "" }).unwrap();
+        ErrorKind::Write(WriteFailure::WriteError(error)).into()
+    }
+
+    #[test]
+    fn kafka_error_codes() {
+        assert_eq!(
+            produce_error(RDKafkaErrorCode::QueueFull).class(),
+            ErrorClass::Throttled
+        );
+        assert_eq!(
+            produce_error(RDKafkaErrorCode::MessageSizeTooLarge).class(),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            produce_error(RDKafkaErrorCode::UnknownTopic).class(),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            produce_error(RDKafkaErrorCode::BrokerTransportFailure).class(),
+            ErrorClass::Transient
+        );
+        assert_eq!(
+            produce_error(RDKafkaErrorCode::LeaderNotAvailable).class(),
+            ErrorClass::Transient
+        );
+    }
+
+    #[test]
+    fn kafka_errors_without_a_code() {
+        assert_eq!(
+            RDKafkaError::ClientCreation("bad config".into()).class(),
+            ErrorClass::Permanent
+        );
+        assert_eq!(RDKafkaError::Canceled.class(), ErrorClass::Transient);
+    }
+
+    #[test]
+    fn mongo_error_codes() {
+        assert_eq!(command_error(112).class(), ErrorClass::Transient);
+        assert_eq!(command_error(189).class(), ErrorClass::Transient);
+        assert_eq!(command_error(16500).class(), ErrorClass::Throttled);
+        assert_eq!(command_error(13).class(), ErrorClass::Permanent);
+        assert_eq!(write_error(11000).class(), ErrorClass::Permanent);
+        assert_eq!(write_error(112).class(), ErrorClass::Transient);
+    }
+
+    #[test]
+    fn mongo_transient_transaction_label() {
+        // The driver only takes labels from the server, write concern errors carry theirs.
+        let error: WriteConcernError = bson::from_document(doc! {
+            "code": 64,
+            "errmsg": "waiting for replication timed out",
+            "errorLabels": [TRANSIENT_TRANSACTION_ERROR],
+        })
+        .unwrap();
+        let error: MongoError = ErrorKind::Write(WriteFailure::WriteConcernError(error)).into();
+
+        assert!(error.contains_label(TRANSIENT_TRANSACTION_ERROR));
+        assert_eq!(error.class(), ErrorClass::Transient);
+    }
+
+    #[test]
+    fn mongo_error_kinds() {
+        let io: MongoError = std::io::Error::from(std::io::ErrorKind::ConnectionReset).into();
+        assert_eq!(io.class(), ErrorClass::Transient);
+
+        let deserialization = bson::from_document::<i32>(Document::new()).unwrap_err();
+        let deserialization: MongoError = ErrorKind::from(deserialization).into();
+        assert_eq!(deserialization.class(), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn retries_exhausted_is_classified_by_its_source() {
+        let exhausted = |source: KafkaError| KafkaError::RetriesExhausted {
+            attempts: 3,
+            source: Box::new(source),
+        };
+
+        assert_eq!(
+            exhausted(KafkaError::MessageSend(produce_error(
+                RDKafkaErrorCode::QueueFull
+            )))
+            .class(),
+            ErrorClass::Throttled
+        );
+        assert_eq!(
+            exhausted(KafkaError::Timeout("downstream".into())).class(),
+            ErrorClass::Transient
+        );
+        assert_eq!(
+            exhausted(KafkaError::Rejected("bad".into())).class(),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            exhausted(KafkaError::Storage(command_error(112))).class(),
+            ErrorClass::Transient
+        );
+    }
+}
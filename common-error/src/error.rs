@@ -1,17 +1,317 @@
+use crate::classification::{Classify, ErrorClass};
+use rdkafka::error::KafkaError as RDKafkaError;
+use serde::Serialize;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum KafkaError {
     #[error("Failed to create Kafka Client: {0}")]
-    ClientCreation(String),
+    ClientCreation(#[source] RDKafkaError),
     #[error("Failed to send message: {0}")]
-    MessageSend(String),
+    MessageSend(#[source] RDKafkaError),
     #[error("Message delivery failed: {0}")]
-    MessageDelivery(String),
+    MessageDelivery(#[source] RDKafkaError),
     #[error("Failed to Deserialize message: {0}")]
     Deserialization(#[from] serde_json::Error),
     #[error("Connection timeout: {0}")]
     Timeout(String),
+    #[error("Invalid Kafka configuration: {0}")]
+    Configuration(String),
+    #[error("Storage operation failed: {0}")]
+    Storage(#[from] mongodb::error::Error),
+    #[error("Message rejected: {0}")]
+    Rejected(String),
+    #[error("Downstream system is throttling: {0}")]
+    Throttled(String),
+    #[error("Circuit breaker is open: {0}")]
+    CircuitOpen(String),
+    #[error("Gave up after {attempts} attempts: {source}")]
+    RetriesExhausted {
+        attempts: u32,
+        #[source]
+        source: Box<KafkaError>,
+    },
+}
+
+impl KafkaError {
+    /// Whether the failed operation is worth retrying, and how.
+    ///
+    /// Handlers can return `Rejected` for messages they know will never succeed and `Throttled`
+    /// when a downstream system pushes back, everything else is classified from its source.
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            KafkaError::ClientCreation(e) | KafkaError::MessageSend(e) => e.class(),
+            KafkaError::MessageDelivery(e) => e.class(),
+            KafkaError::Deserialization(_) => ErrorClass::Permanent,
+            KafkaError::Timeout(_) => ErrorClass::Transient,
+            KafkaError::Configuration(_) => ErrorClass::Permanent,
+            KafkaError::Storage(e) => e.class(),
+            KafkaError::Rejected(_) => ErrorClass::Permanent,
+            KafkaError::Throttled(_) => ErrorClass::Throttled,
+            KafkaError::CircuitOpen(_) => ErrorClass::Transient,
+            KafkaError::RetriesExhausted { source, .. } => source.class(),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.class().is_retryable()
+    }
+}
+
+impl Classify for KafkaError {
+    fn class(&self) -> ErrorClass {
+        KafkaError::class(self)
+    }
 }
 
 pub type KafkaResult<T> = Result<T, KafkaError>;
+
+/// Errors shared by the warehouse services, wrapping the lower level storage and messaging errors.
+///
+/// Every variant has a stable `code` and an HTTP mapping, so the same error can be logged,
+/// classified for retries and returned from an API as `application/problem+json`.
+#[derive(Debug, Error)]
+pub enum WarehouseError {
+    #[error("Storage operation failed: {0}")]
+    Storage(#[from] mongodb::error::Error),
+    #[error("Messaging failed: {0}")]
+    Messaging(#[from] KafkaError),
+    #[error("Validation failed: {0}")]
+    Validation(String),
+    #[error("Serialization failed: {0}")]
+    Serialization(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Invalid configuration: {0}")]
+    Configuration(String),
+    #[error("{resource} {id} not found")]
+    NotFound { resource: &'static str, id: String },
+    /// The request clashes with the current state, e.g. a record that already exists.
+    #[error("Conflict: {0}")]
+    Conflict(String),
+}
+
+impl WarehouseError {
+    pub fn not_found(resource: &'static str, id: impl ToString) -> Self {
+        WarehouseError::NotFound {
+            resource,
+            id: id.to_string(),
+        }
+    }
+
+    /// Stable identifier for the kind of failure, safe for clients and dashboards to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            WarehouseError::Storage(_) => "STORAGE_ERROR",
+            WarehouseError::Messaging(_) => "MESSAGING_ERROR",
+            WarehouseError::Validation(_) => "VALIDATION_FAILED",
+            WarehouseError::Serialization(_) => "SERIALIZATION_ERROR",
+            WarehouseError::Configuration(_) => "CONFIGURATION_ERROR",
+            WarehouseError::NotFound { .. } => "NOT_FOUND",
+            WarehouseError::Conflict(_) => "CONFLICT",
+        }
+    }
+
+    pub fn http_status(&self) -> u16 {
+        match self {
+            WarehouseError::Storage(_) | WarehouseError::Messaging(_) if self.is_retryable() => 503,
+            WarehouseError::Storage(_) => 500,
+            WarehouseError::Messaging(_) => 502,
+            WarehouseError::Validation(_) => 422,
+            WarehouseError::Serialization(_) => 400,
+            WarehouseError::Configuration(_) => 500,
+            WarehouseError::NotFound { .. } => 404,
+            WarehouseError::Conflict(_) => 409,
+        }
+    }
+
+    pub fn to_problem(&self) -> ProblemDetails {
+        let status = self.http_status();
+        let title = match self {
+            WarehouseError::Storage(_) => "Storage unavailable",
+            WarehouseError::Messaging(_) => "Messaging unavailable",
+            WarehouseError::Validation(_) => "Validation failed",
+            WarehouseError::Serialization(_) => "Malformed payload",
+            WarehouseError::Configuration(_) => "Service misconfigured",
+            WarehouseError::NotFound { .. } => "Not found",
+            WarehouseError::Conflict(_) => "Conflict",
+        };
+
+        // Internal failures keep their details in the logs rather than in the response.
+        let detail = match self {
+            WarehouseError::Validation(_)
+            | WarehouseError::Serialization(_)
+            | WarehouseError::NotFound { .. }
+            | WarehouseError::Conflict(_) => self.to_string(),
+            _ => title.to_string(),
+        };
+
+        ProblemDetails {
+            problem_type: format!(
+                "urn:warehouse:problem:{}",
+                self.code().to_lowercase().replace('_', "-")
+            ),
+            title: title.to_string(),
+            status,
+            detail,
+            code: self.code().to_string(),
+        }
+    }
+}
+
+impl Classify for WarehouseError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            WarehouseError::Storage(e) => e.class(),
+            WarehouseError::Messaging(e) => e.class(),
+            _ => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl From<serde_json::Error> for WarehouseError {
+    fn from(e: serde_json::Error) -> Self {
+        WarehouseError::Serialization(Box::new(e))
+    }
+}
+
+impl From<csv::Error> for WarehouseError {
+    fn from(e: csv::Error) -> Self {
+        WarehouseError::Serialization(Box::new(e))
+    }
+}
+
+impl From<mongodb::bson::ser::Error> for WarehouseError {
+    fn from(e: mongodb::bson::ser::Error) -> Self {
+        WarehouseError::Serialization(Box::new(e))
+    }
+}
+
+impl From<mongodb::bson::de::Error> for WarehouseError {
+    fn from(e: mongodb::bson::de::Error) -> Self {
+        WarehouseError::Serialization(Box::new(e))
+    }
+}
+
+pub const PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+
+/// An RFC 9457 problem details body.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+}
+
+pub type WarehouseResult<T> = Result<T, WarehouseError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::{self, doc};
+    use mongodb::error::{CommandError, ErrorKind};
+    use rdkafka::error::RDKafkaErrorCode;
+
+    fn storage_error(code: i32, message: &str) -> WarehouseError {
+        let error: CommandError =
+            bson::from_document(doc! { "code": code, "codeName": "", "errmsg": message }).unwrap();
+        WarehouseError::Storage(ErrorKind::Command(error).into())
+    }
+
+    fn messaging_error(code: RDKafkaErrorCode) -> WarehouseError {
+        WarehouseError::Messaging(KafkaError::MessageSend(RDKafkaError::MessageProduction(
+            code,
+        )))
+    }
+
+    fn serialization_error() -> WarehouseError {
+        serde_json::from_str::<u32>("{").unwrap_err().into()
+    }
+
+    #[test]
+    fn codes_and_statuses_are_stable() {
+        let cases = [
+            (storage_error(112, "write conflict"), "STORAGE_ERROR", 503),
+            (storage_error(11000, "duplicate key"), "STORAGE_ERROR", 500),
+            (
+                messaging_error(RDKafkaErrorCode::BrokerTransportFailure),
+                "MESSAGING_ERROR",
+                503,
+            ),
+            (
+                messaging_error(RDKafkaErrorCode::MessageSizeTooLarge),
+                "MESSAGING_ERROR",
+                502,
+            ),
+            (
+                WarehouseError::Validation("quantity must be at least 1".into()),
+                "VALIDATION_FAILED",
+                422,
+            ),
+            (serialization_error(), "SERIALIZATION_ERROR", 400),
+            (
+                WarehouseError::Configuration("MONGO_URI is not set".into()),
+                "CONFIGURATION_ERROR",
+                500,
+            ),
+            (
+                WarehouseError::not_found("ASN for shipment", 42),
+                "NOT_FOUND",
+                404,
+            ),
+            (
+                WarehouseError::Conflict("shipment 42 is already closed".into()),
+                "CONFLICT",
+                409,
+            ),
+        ];
+
+        for (error, code, status) in cases {
+            assert_eq!(error.code(), code, "{}", error);
+            assert_eq!(error.http_status(), status, "{}", error);
+
+            let problem = error.to_problem();
+            assert_eq!(problem.code, code);
+            assert_eq!(problem.status, status);
+            assert_eq!(
+                problem.problem_type,
+                format!(
+                    "urn:warehouse:problem:{}",
+                    code.to_lowercase().replace('_', "-")
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn client_errors_explain_themselves() {
+        let problem = WarehouseError::not_found("ASN for shipment", 42).to_problem();
+        assert_eq!(problem.title, "Not found");
+        assert_eq!(problem.detail, "ASN for shipment 42 not found");
+
+        let problem = WarehouseError::Validation("quantity must be at least 1".into()).to_problem();
+        assert_eq!(
+            problem.detail,
+            "Validation failed: quantity must be at least 1"
+        );
+    }
+
+    #[test]
+    fn internal_errors_keep_their_details_out_of_the_problem() {
+        let cases = [
+            storage_error(11000, "E11000 duplicate key in warehouse.inbound_lines"),
+            messaging_error(RDKafkaErrorCode::MessageSizeTooLarge),
+            WarehouseError::Configuration("mongodb://admin:secret@db".into()),
+        ];
+
+        for error in cases {
+            let problem = error.to_problem();
+            assert_eq!(problem.detail, problem.title, "{}", error);
+            let body = serde_json::to_string(&problem).unwrap();
+            for detail in ["E11000", "inbound_lines", "secret", "Broker"] {
+                assert!(!body.contains(detail), "{} leaks {:?}", body, detail);
+            }
+        }
+    }
+}
@@ -1 +1,2 @@
+pub mod classification;
 pub mod error;
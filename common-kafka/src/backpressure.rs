@@ -0,0 +1,562 @@
+use crate::consumer::TopicPartition;
+use crate::message::ConsumedMessage;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Limits for how much work the consumer takes on before it stops fetching.
+///
+/// `max_in_flight` counts every message handed to the consumer that has not been committed yet,
+/// whether it is being handled or queued behind an earlier message of the same partition.
+/// A partition whose handler keeps failing is paused for `initial_pause`, doubling up to
+/// `max_pause` while it keeps failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackpressureConfig {
+    pub max_in_flight: usize,
+    pub initial_pause: Duration,
+    pub max_pause: Duration,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 100,
+            initial_pause: Duration::from_secs(1),
+            max_pause: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What the consumer loop has to do after a flow control state change.
+#[derive(Debug)]
+pub enum FlowAction {
+    Dispatch(Dispatch),
+    Pause(Vec<TopicPartition>),
+    Resume(Vec<TopicPartition>),
+    PauseAssigned,
+    ResumeAssigned { except: Vec<TopicPartition> },
+}
+
+/// A message that should be handed to the handler now.
+///
+/// The generation identifies the partition assignment it was dispatched under, so completions for
+/// partitions that were revoked in the meantime can be recognised and dropped.
+#[derive(Debug)]
+pub struct Dispatch {
+    pub generation: u64,
+    pub message: ConsumedMessage,
+}
+
+/// How a dispatched message finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    /// Handled, or handed off to a retry topic, and safe to commit.
+    Succeeded,
+    /// The handler failed and the message has to be handled again after a pause.
+    Failed,
+    /// The message is not due yet, hold the partition for the given time and hand it out again.
+    Deferred(Duration),
+}
+
+/// A partition held back in Kafka until `resume_at`. `backoff` is the pause applied after the last
+/// failure, zero if the partition is only waiting for a deferred message.
+#[derive(Debug)]
+struct Held {
+    resume_at: Instant,
+    backoff: Duration,
+}
+
+#[derive(Debug)]
+struct PartitionState {
+    generation: u64,
+    queue: VecDeque<ConsumedMessage>,
+    /// Offsets of the dispatched messages in order, with the message and its outcome once it
+    /// finished.
+    dispatched: VecDeque<(i64, Option<(ConsumedMessage, Completion)>)>,
+    held: Option<Held>,
+    /// Set when a dispatched message did not succeed, the messages dispatched after it go back to
+    /// the queue as they finish.
+    returning: bool,
+}
+
+impl PartitionState {
+    fn dispatch(&mut self, window: usize, actions: &mut Vec<FlowAction>) {
+        while self.dispatched.len() < window {
+            let Some(message) = self.queue.pop_front() else {
+                break;
+            };
+            self.dispatched.push_back((message.offset, None));
+            actions.push(FlowAction::Dispatch(Dispatch {
+                generation: self.generation,
+                message,
+            }));
+        }
+    }
+
+    fn requeue(&mut self, message: ConsumedMessage) {
+        let at = self
+            .queue
+            .partition_point(|queued| queued.offset < message.offset);
+        self.queue.insert(at, message);
+    }
+}
+
+/// The outcome of `FlowControl::complete`.
+#[derive(Debug)]
+pub struct Completed {
+    /// Offset of the last message of the partition that is now safe to commit, if it moved.
+    pub commit: Option<i64>,
+    pub actions: Vec<FlowAction>,
+}
+
+/// Per-partition ordering and pause bookkeeping for `EventConsumer`.
+///
+/// Only one message per partition is handled at a time by default, later messages of the same
+/// partition wait in a local queue. This does not talk to Kafka itself, it returns the pause,
+/// resume and dispatch actions for the caller to apply, so the consumer can keep polling while
+/// partitions are paused and never exceeds `max.poll.interval.ms`.
+#[derive(Debug)]
+pub struct FlowControl {
+    config: BackpressureConfig,
+    window: usize,
+    partitions: HashMap<TopicPartition, PartitionState>,
+    buffered: usize,
+    saturated: bool,
+    next_generation: u64,
+}
+
+impl FlowControl {
+    pub fn new(config: BackpressureConfig) -> Self {
+        Self {
+            config,
+            window: 1,
+            partitions: HashMap::new(),
+            buffered: 0,
+            saturated: false,
+            next_generation: 0,
+        }
+    }
+
+    /// Lets up to `window` messages of a partition be dispatched at once, e.g. to fill batches.
+    ///
+    /// Offsets are still only committed in order: if a message does not succeed, it and every
+    /// message of its partition dispatched after it are handed out again, even those that
+    /// succeeded.
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.buffered
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.saturated
+    }
+
+    pub fn held_partitions(&self) -> Vec<TopicPartition> {
+        self.partitions
+            .iter()
+            .filter(|(_, state)| state.held.is_some())
+            .map(|(partition, _)| partition.clone())
+            .collect()
+    }
+
+    /// Takes ownership of a freshly consumed message.
+    pub fn accept(&mut self, message: ConsumedMessage) -> Vec<FlowAction> {
+        let mut actions = Vec::new();
+        let partition = message.topic_partition();
+
+        let next_generation = &mut self.next_generation;
+        let state = self.partitions.entry(partition.clone()).or_insert_with(|| {
+            *next_generation += 1;
+            PartitionState {
+                generation: *next_generation,
+                queue: VecDeque::new(),
+                dispatched: VecDeque::new(),
+                held: None,
+                returning: false,
+            }
+        });
+
+        state.queue.push_back(message);
+        self.buffered += 1;
+
+        if state.held.is_none() && !state.returning {
+            state.dispatch(self.window, &mut actions);
+        }
+
+        if self.saturated {
+            // Partitions assigned while saturated start out fetching, pause them as they show up.
+            actions.push(FlowAction::Pause(vec![partition]));
+        } else if self.buffered >= self.config.max_in_flight {
+            tracing::warn!(
+                "In-flight limit of {} reached, pausing assigned partitions",
+                self.config.max_in_flight
+            );
+            self.saturated = true;
+            actions.push(FlowAction::PauseAssigned);
+        }
+
+        actions
+    }
+
+    /// Records the outcome of a dispatched message.
+    ///
+    /// Returns `None` if the partition was revoked since the message was dispatched, in which case
+    /// its offset must not be committed.
+    pub fn complete(
+        &mut self,
+        generation: u64,
+        message: ConsumedMessage,
+        completion: Completion,
+    ) -> Option<Completed> {
+        let partition = message.topic_partition();
+        let state = self.partitions.get_mut(&partition)?;
+        if state.generation != generation {
+            return None;
+        }
+        let (_, outcome) = state
+            .dispatched
+            .iter_mut()
+            .find(|(offset, _)| *offset == message.offset)?;
+        *outcome = Some((message, completion));
+
+        let mut actions = Vec::new();
+        let mut commit = None;
+
+        // Outcomes are applied in offset order, a message that finished early waits for the ones
+        // dispatched before it.
+        while let Some((_, Some(_))) = state.dispatched.front() {
+            let Some((_, Some((message, completion)))) = state.dispatched.pop_front() else {
+                unreachable!("front was checked to be finished");
+            };
+
+            if state.returning {
+                state.requeue(message);
+                continue;
+            }
+
+            match completion {
+                Completion::Succeeded => {
+                    self.buffered -= 1;
+                    commit = Some(message.offset);
+
+                    if let Some(held) = state.held.take() {
+                        if !held.backoff.is_zero() {
+                            tracing::info!(
+                                "Partition {}[{}] recovered, resuming",
+                                partition.topic,
+                                partition.partition
+                            );
+                        }
+                        if !self.saturated {
+                            actions.push(FlowAction::Resume(vec![partition.clone()]));
+                        }
+                    }
+                }
+                Completion::Failed => {
+                    let backoff = match &state.held {
+                        Some(held) if !held.backoff.is_zero() => {
+                            (held.backoff * 2).min(self.config.max_pause)
+                        }
+                        _ => self.config.initial_pause,
+                    };
+                    tracing::warn!(
+                        "Handler failing for {}[{}], pausing for {:?}",
+                        partition.topic,
+                        partition.partition,
+                        backoff
+                    );
+
+                    state.requeue(message);
+                    state.returning = true;
+                    state.held = Some(Held {
+                        resume_at: Instant::now() + backoff,
+                        backoff,
+                    });
+                    actions.push(FlowAction::Pause(vec![partition.clone()]));
+                }
+                Completion::Deferred(delay) => {
+                    tracing::debug!(
+                        "Message in {}[{}] not due for {:?}, pausing partition",
+                        partition.topic,
+                        partition.partition,
+                        delay
+                    );
+
+                    let backoff = state
+                        .held
+                        .as_ref()
+                        .map(|held| held.backoff)
+                        .unwrap_or_default();
+                    state.requeue(message);
+                    state.returning = true;
+                    state.held = Some(Held {
+                        resume_at: Instant::now() + delay,
+                        backoff,
+                    });
+                    actions.push(FlowAction::Pause(vec![partition.clone()]));
+                }
+            }
+        }
+
+        if state.dispatched.is_empty() {
+            state.returning = false;
+        }
+        if state.held.is_none() && !state.returning {
+            state.dispatch(self.window, &mut actions);
+        }
+
+        if self.saturated && self.buffered <= self.config.max_in_flight / 2 {
+            tracing::info!("In-flight messages drained, resuming assigned partitions");
+            self.saturated = false;
+            actions.push(FlowAction::ResumeAssigned {
+                except: self.held_partitions(),
+            });
+        }
+
+        Some(Completed { commit, actions })
+    }
+
+    /// Re-dispatches the head message of every held partition whose pause has elapsed. The
+    /// partition stays paused in Kafka until that message succeeds.
+    pub fn due(&mut self, now: Instant) -> Vec<FlowAction> {
+        let mut actions = Vec::new();
+
+        for state in self.partitions.values_mut() {
+            let ready = matches!(&state.held, Some(held) if held.resume_at <= now);
+            if ready && state.dispatched.is_empty() {
+                if let Some(message) = state.queue.pop_front() {
+                    state.dispatched.push_back((message.offset, None));
+                    actions.push(FlowAction::Dispatch(Dispatch {
+                        generation: state.generation,
+                        message,
+                    }));
+                }
+            }
+        }
+
+        actions
+    }
+
+    /// Drops everything held for revoked partitions, their uncommitted messages will be consumed
+    /// again by whichever member they are assigned to next.
+    pub fn revoke(&mut self, partitions: &[TopicPartition]) -> Vec<FlowAction> {
+        for partition in partitions {
+            if let Some(state) = self.partitions.remove(partition) {
+                self.buffered -= state.queue.len() + state.dispatched.len();
+            }
+        }
+
+        if self.saturated && self.buffered <= self.config.max_in_flight / 2 {
+            self.saturated = false;
+            return vec![FlowAction::ResumeAssigned {
+                except: self.held_partitions(),
+            }];
+        }
+
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: BackpressureConfig = BackpressureConfig {
+        max_in_flight: 100,
+        initial_pause: Duration::from_secs(1),
+        max_pause: Duration::from_secs(60),
+    };
+
+    fn message(offset: i64) -> ConsumedMessage {
+        ConsumedMessage {
+            topic: "INBOUND".to_string(),
+            partition: 0,
+            offset,
+            key: Vec::new(),
+            payload: Vec::new(),
+            timestamp: None,
+            headers: Vec::new(),
+        }
+    }
+
+    fn partition() -> TopicPartition {
+        message(0).topic_partition()
+    }
+
+    /// The offsets of the dispatch actions, and the generation they were dispatched under.
+    fn dispatched(actions: &[FlowAction]) -> (Vec<i64>, u64) {
+        let mut generation = 0;
+        let offsets = actions
+            .iter()
+            .filter_map(|action| match action {
+                FlowAction::Dispatch(dispatch) => {
+                    generation = dispatch.generation;
+                    Some(dispatch.message.offset)
+                }
+                _ => None,
+            })
+            .collect();
+        (offsets, generation)
+    }
+
+    fn accept_all(flow: &mut FlowControl, offsets: impl IntoIterator<Item = i64>) -> Vec<i64> {
+        offsets
+            .into_iter()
+            .flat_map(|offset| dispatched(&flow.accept(message(offset))).0)
+            .collect()
+    }
+
+    fn complete(flow: &mut FlowControl, offset: i64, completion: Completion) -> Completed {
+        flow.complete(1, message(offset), completion).unwrap()
+    }
+
+    #[test]
+    fn dispatches_one_message_per_partition_by_default() {
+        let mut flow = FlowControl::new(CONFIG);
+        assert_eq!(accept_all(&mut flow, 0..3), vec![0]);
+
+        let completed = complete(&mut flow, 0, Completion::Succeeded);
+        assert_eq!(completed.commit, Some(0));
+        assert_eq!(dispatched(&completed.actions).0, vec![1]);
+        assert_eq!(flow.in_flight(), 2);
+    }
+
+    #[test]
+    fn out_of_order_completions_commit_only_the_contiguous_prefix() {
+        let mut flow = FlowControl::new(CONFIG).with_window(3);
+        assert_eq!(accept_all(&mut flow, 0..3), vec![0, 1, 2]);
+
+        assert_eq!(complete(&mut flow, 2, Completion::Succeeded).commit, None);
+        assert_eq!(complete(&mut flow, 1, Completion::Succeeded).commit, None);
+        assert_eq!(
+            complete(&mut flow, 0, Completion::Succeeded).commit,
+            Some(2)
+        );
+        assert_eq!(flow.in_flight(), 0);
+    }
+
+    #[test]
+    fn failure_mid_window_requeues_later_successes_in_offset_order() {
+        let mut flow = FlowControl::new(CONFIG).with_window(3);
+        assert_eq!(accept_all(&mut flow, 0..4), vec![0, 1, 2]);
+
+        complete(&mut flow, 2, Completion::Succeeded);
+        let completed = complete(&mut flow, 0, Completion::Succeeded);
+        assert_eq!(completed.commit, Some(0));
+        assert_eq!(dispatched(&completed.actions).0, vec![3]);
+
+        let completed = complete(&mut flow, 1, Completion::Failed);
+        assert_eq!(completed.commit, None);
+        assert!(completed.actions.iter().any(
+            |action| matches!(action, FlowAction::Pause(partitions) if partitions == &[partition()])
+        ));
+        assert_eq!(flow.held_partitions(), vec![partition()]);
+
+        // The message dispatched after the failure is returned as well once it finishes.
+        let completed = complete(&mut flow, 3, Completion::Succeeded);
+        assert_eq!(completed.commit, None);
+        assert!(dispatched(&completed.actions).0.is_empty());
+        assert_eq!(flow.in_flight(), 3);
+
+        // Only the failed message is retried while the partition is held...
+        let retry = flow.due(Instant::now() + CONFIG.initial_pause);
+        assert_eq!(dispatched(&retry).0, vec![1]);
+
+        // ...and once it succeeds the rest follow in offset order.
+        let completed = complete(&mut flow, 1, Completion::Succeeded);
+        assert_eq!(completed.commit, Some(1));
+        assert!(completed
+            .actions
+            .iter()
+            .any(|action| matches!(action, FlowAction::Resume(_))));
+        assert_eq!(dispatched(&completed.actions).0, vec![2, 3]);
+        assert!(flow.held_partitions().is_empty());
+    }
+
+    #[test]
+    fn held_partition_is_not_due_before_its_pause_elapsed() {
+        let mut flow = FlowControl::new(CONFIG);
+        accept_all(&mut flow, 0..2);
+        complete(&mut flow, 0, Completion::Failed);
+
+        assert!(flow.due(Instant::now()).is_empty());
+        assert!(flow
+            .accept(message(2))
+            .iter()
+            .all(|action| !matches!(action, FlowAction::Dispatch(_))));
+        assert_eq!(
+            dispatched(&flow.due(Instant::now() + CONFIG.initial_pause)).0,
+            vec![0]
+        );
+
+        // A second failure doubles the pause.
+        complete(&mut flow, 0, Completion::Failed);
+        assert!(flow.due(Instant::now() + CONFIG.initial_pause).is_empty());
+        assert_eq!(
+            dispatched(&flow.due(Instant::now() + 2 * CONFIG.initial_pause)).0,
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn deferred_message_is_handed_out_again_when_due() {
+        let mut flow = FlowControl::new(CONFIG);
+        accept_all(&mut flow, 0..1);
+
+        let delay = Duration::from_secs(30);
+        let completed = complete(&mut flow, 0, Completion::Deferred(delay));
+        assert_eq!(completed.commit, None);
+        assert!(flow.due(Instant::now() + delay / 2).is_empty());
+        assert_eq!(dispatched(&flow.due(Instant::now() + delay)).0, vec![0]);
+    }
+
+    #[test]
+    fn revoke_forgets_queued_and_dispatched_messages() {
+        let mut flow = FlowControl::new(CONFIG).with_window(2);
+        accept_all(&mut flow, 0..3);
+        assert_eq!(flow.in_flight(), 3);
+
+        flow.revoke(&[partition()]);
+        assert_eq!(flow.in_flight(), 0);
+
+        // A completion from before the revoke must not be committed, even once the partition is
+        // assigned again.
+        let (offsets, generation) = dispatched(&flow.accept(message(0)));
+        assert_eq!(offsets, vec![0]);
+        assert_ne!(generation, 1);
+        assert!(flow
+            .complete(1, message(0), Completion::Succeeded)
+            .is_none());
+        assert!(flow
+            .complete(generation, message(0), Completion::Succeeded)
+            .is_some());
+    }
+
+    #[test]
+    fn pauses_everything_at_the_in_flight_limit_and_resumes_at_half() {
+        let mut flow = FlowControl::new(BackpressureConfig {
+            max_in_flight: 4,
+            ..CONFIG
+        });
+        let actions: Vec<FlowAction> = (0..4)
+            .flat_map(|offset| flow.accept(message(offset)))
+            .collect();
+        assert!(actions
+            .iter()
+            .any(|action| matches!(action, FlowAction::PauseAssigned)));
+        assert!(flow.is_saturated());
+
+        complete(&mut flow, 0, Completion::Succeeded);
+        assert!(flow.is_saturated());
+        let completed = complete(&mut flow, 1, Completion::Succeeded);
+        assert!(completed
+            .actions
+            .iter()
+            .any(|action| matches!(action, FlowAction::ResumeAssigned { .. })));
+        assert!(!flow.is_saturated());
+    }
+}
@@ -0,0 +1,281 @@
+use crate::consumer::{MessageHandler, RebalanceEvent};
+use crate::message::ConsumedMessage;
+use async_trait::async_trait;
+use common_error::error::{KafkaError, KafkaResult};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{sleep_until, Instant};
+
+/// How messages are grouped into batches: a batch is handed to the handler once it holds
+/// `max_messages`, or `max_wait` after its first message arrived.
+///
+/// A batch never holds more messages than the consumer's `BackpressureConfig::max_in_flight`, so
+/// raise that along with `max_messages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+    pub max_messages: usize,
+    pub max_wait: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 100,
+            max_wait: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Handles messages a batch at a time, e.g. to write them with one bulk insert.
+///
+/// Messages of a partition are in offset order within a batch. Each message gets its own
+/// outcome, failed messages are retried and routed to retry or dead letter topics on their own,
+/// just like with a `MessageHandler`. Offsets are committed in order, so when a message is
+/// retried later, the messages of its partition after it are handed out again as well and
+/// handlers should be idempotent.
+#[async_trait]
+pub trait BatchMessageHandler: Send + Sync {
+    /// Returns one outcome per message, in the order of `messages`.
+    async fn handle_batch(&self, messages: &[ConsumedMessage]) -> Vec<KafkaResult<()>>;
+
+    /// See `MessageHandler::pre_rebalance`.
+    fn pre_rebalance(&self, _event: &RebalanceEvent) {}
+
+    /// See `MessageHandler::post_rebalance`.
+    fn post_rebalance(&self, _event: &RebalanceEvent) {}
+}
+
+type Waiter = oneshot::Sender<KafkaResult<()>>;
+
+#[derive(Default)]
+struct Pending {
+    messages: Vec<(ConsumedMessage, Waiter)>,
+    started: Option<Instant>,
+}
+
+impl Pending {
+    fn due_at(&self, config: &BatchConfig) -> Option<Instant> {
+        if self.messages.len() >= config.max_messages {
+            return Some(Instant::now());
+        }
+        self.started.map(|started| started + config.max_wait)
+    }
+}
+
+/// Adapts a `BatchMessageHandler` to the per-message path of the consumers, so ordering, retries
+/// and commits work the same for both.
+///
+/// Every dispatched message joins the open batch and waits for its outcome. Whichever message is
+/// waiting when the batch is due hands it to the handler, one batch at a time.
+pub(crate) struct Batcher {
+    handler: Box<dyn BatchMessageHandler>,
+    config: BatchConfig,
+    pending: Mutex<Pending>,
+    handling: tokio::sync::Mutex<()>,
+}
+
+impl Batcher {
+    pub(crate) fn new(handler: Box<dyn BatchMessageHandler>, config: BatchConfig) -> Self {
+        Self {
+            handler,
+            config: BatchConfig {
+                max_messages: config.max_messages.max(1),
+                ..config
+            },
+            pending: Mutex::new(Pending::default()),
+            handling: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    fn join(&self, message: &ConsumedMessage) -> oneshot::Receiver<KafkaResult<()>> {
+        let (waiter, outcome) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        pending.started.get_or_insert_with(Instant::now);
+        pending.messages.push((message.clone(), waiter));
+        outcome
+    }
+
+    async fn batch_due(&self) {
+        let due_at = self.pending.lock().unwrap().due_at(&self.config);
+        match due_at {
+            Some(due_at) if due_at <= Instant::now() => {}
+            Some(due_at) => sleep_until(due_at).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn flush(&self) {
+        let _handling = self.handling.lock().await;
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending
+                .due_at(&self.config)
+                .is_none_or(|due_at| due_at > Instant::now())
+            {
+                return;
+            }
+            let taken = pending.messages.len().min(self.config.max_messages);
+            let batch: Vec<_> = pending.messages.drain(..taken).collect();
+            // Messages that did not fit start the next batch.
+            if pending.messages.is_empty() {
+                pending.started = None;
+            }
+            batch
+        };
+
+        let (messages, waiters): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        let mut outcomes = self.handler.handle_batch(&messages).await.into_iter();
+        for waiter in waiters {
+            let outcome = outcomes.next().unwrap_or_else(|| {
+                Err(KafkaError::Rejected(
+                    "batch handler returned no outcome for the message".into(),
+                ))
+            });
+            let _ = waiter.send(outcome);
+        }
+    }
+}
+
+#[async_trait]
+impl MessageHandler for Batcher {
+    async fn handle(&self, _key: &[u8], _payload: &[u8]) -> KafkaResult<()> {
+        Err(KafkaError::Configuration(
+            "batch handlers only handle whole messages".into(),
+        ))
+    }
+
+    async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+        let mut outcome = self.join(message);
+        loop {
+            tokio::select! {
+                biased;
+                outcome = &mut outcome => {
+                    return outcome.unwrap_or_else(|_| {
+                        Err(KafkaError::Timeout("batch was dropped before it was handled".into()))
+                    });
+                }
+                _ = self.batch_due() => self.flush().await,
+            }
+        }
+    }
+
+    fn pre_rebalance(&self, event: &RebalanceEvent) {
+        self.handler.pre_rebalance(event);
+    }
+
+    fn post_rebalance(&self, event: &RebalanceEvent) {
+        self.handler.post_rebalance(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// The offsets of a handled batch and when it was handed over.
+    type Handled = (Vec<i64>, Instant);
+
+    /// Records the batches it gets and returns `outcomes` outcomes for each, all successes unless
+    /// fewer are asked for.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        batches: Arc<Mutex<Vec<Handled>>>,
+        outcomes: Option<usize>,
+    }
+
+    #[async_trait]
+    impl BatchMessageHandler for Recorder {
+        async fn handle_batch(&self, messages: &[ConsumedMessage]) -> Vec<KafkaResult<()>> {
+            let offsets = messages.iter().map(|message| message.offset).collect();
+            self.batches.lock().unwrap().push((offsets, Instant::now()));
+            let count = self.outcomes.unwrap_or(messages.len());
+            (0..count).map(|_| Ok(())).collect()
+        }
+    }
+
+    fn message(offset: i64) -> ConsumedMessage {
+        ConsumedMessage {
+            topic: "INBOUND".to_string(),
+            partition: 0,
+            offset,
+            key: Vec::new(),
+            payload: Vec::new(),
+            timestamp: None,
+            headers: Vec::new(),
+        }
+    }
+
+    async fn handle_all(batcher: &Batcher, offsets: std::ops::Range<i64>) -> Vec<KafkaResult<()>> {
+        let messages: Vec<_> = offsets.map(message).collect();
+        futures::future::join_all(
+            messages
+                .iter()
+                .map(|message| batcher.handle_message(message)),
+        )
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flushes_once_the_batch_is_full() {
+        let recorder = Recorder::default();
+        let batcher = Batcher::new(
+            Box::new(recorder.clone()),
+            BatchConfig {
+                max_messages: 3,
+                max_wait: Duration::from_secs(3600),
+            },
+        );
+        let started = Instant::now();
+
+        let outcomes = handle_all(&batcher, 0..5).await;
+
+        assert!(outcomes.iter().all(Result::is_ok));
+        let batches = recorder.batches.lock().unwrap();
+        assert_eq!(batches[0], (vec![0, 1, 2], started));
+        // The rest makes up a batch of its own, handed over when its wait is up.
+        assert_eq!(batches[1].0, vec![3, 4]);
+        assert_eq!(batches.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flushes_a_partial_batch_after_max_wait() {
+        let recorder = Recorder::default();
+        let max_wait = Duration::from_millis(200);
+        let batcher = Batcher::new(
+            Box::new(recorder.clone()),
+            BatchConfig {
+                max_messages: 10,
+                max_wait,
+            },
+        );
+        let started = Instant::now();
+
+        let outcomes = handle_all(&batcher, 0..2).await;
+
+        assert!(outcomes.iter().all(Result::is_ok));
+        let batches = recorder.batches.lock().unwrap();
+        assert_eq!(batches.as_slice(), &[(vec![0, 1], started + max_wait)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_without_an_outcome_fail() {
+        let recorder = Recorder {
+            outcomes: Some(1),
+            ..Recorder::default()
+        };
+        let batcher = Batcher::new(
+            Box::new(recorder),
+            BatchConfig {
+                max_messages: 2,
+                max_wait: Duration::from_secs(1),
+            },
+        );
+
+        let outcomes = handle_all(&batcher, 0..2).await;
+
+        assert!(outcomes[0].is_ok());
+        assert!(matches!(outcomes[1], Err(KafkaError::Rejected(_))));
+    }
+}
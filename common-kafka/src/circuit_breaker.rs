@@ -0,0 +1,365 @@
+use common_error::error::{KafkaError, KafkaResult};
+use opentelemetry::metrics::{Counter, Gauge};
+use opentelemetry::{global, KeyValue};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When the breaker opens and how it recovers.
+///
+/// It opens after `failure_threshold` consecutive retryable failures and rejects calls for
+/// `open_duration`. After that up to `half_open_probes` calls are let through at a time, the
+/// first one to succeed closes the breaker and a failure opens it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub open_duration: Duration,
+    pub half_open_probes: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+            half_open_probes: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+
+    fn gauge_value(&self) -> u64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        }
+    }
+}
+
+/// A snapshot of the breaker for health checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitHealth {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// Time left until an open breaker lets probes through.
+    pub retry_in: Option<Duration>,
+}
+
+impl CircuitHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.state == CircuitState::Closed
+    }
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probes_in_flight: u32,
+}
+
+struct BreakerMetrics {
+    state: Gauge<u64>,
+    transitions: Counter<u64>,
+    rejected: Counter<u64>,
+}
+
+/// Stops calls to a broker that keeps failing, so callers fail fast instead of each waiting for the
+/// full delivery timeout.
+///
+/// Only retryable errors count as failures, a permanent error such as an oversized message says
+/// nothing about the broker's health.
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    inner: Mutex<BreakerState>,
+    metrics: BreakerMetrics,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: impl Into<String>, config: CircuitBreakerConfig) -> Self {
+        let meter = global::meter("common-kafka");
+        let metrics = BreakerMetrics {
+            state: meter
+                .u64_gauge("circuit_breaker.state")
+                .with_description("0 closed, 1 half open, 2 open")
+                .build(),
+            transitions: meter
+                .u64_counter("circuit_breaker.transitions")
+                .with_description("State changes, by the state entered")
+                .build(),
+            rejected: meter
+                .u64_counter("circuit_breaker.rejected")
+                .with_description("Calls short-circuited while the breaker was open")
+                .build(),
+        };
+
+        let breaker = Self {
+            name: name.into(),
+            config,
+            inner: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probes_in_flight: 0,
+            }),
+            metrics,
+        };
+        breaker
+            .metrics
+            .state
+            .record(CircuitState::Closed.gauge_value(), &breaker.attributes());
+        breaker
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    pub fn health(&self) -> CircuitHealth {
+        let inner = self.inner.lock().unwrap();
+        let retry_in = match (inner.state, inner.opened_at) {
+            (CircuitState::Open, Some(opened_at)) => Some(
+                self.config
+                    .open_duration
+                    .saturating_sub(opened_at.elapsed()),
+            ),
+            _ => None,
+        };
+
+        CircuitHealth {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            retry_in,
+        }
+    }
+
+    /// Whether a call made now would be let through, without reserving a probe.
+    pub fn allows_calls(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => self.open_elapsed(&inner),
+            CircuitState::HalfOpen => inner.probes_in_flight < self.config.half_open_probes,
+        }
+    }
+
+    /// Runs `operation` unless the breaker is open, recording its outcome.
+    ///
+    /// A call dropped before `operation` finishes, e.g. by a timeout around it, counts as a
+    /// failure, otherwise a half-open probe would stay reserved forever.
+    pub async fn call<T, F>(&self, operation: F) -> KafkaResult<T>
+    where
+        F: Future<Output = KafkaResult<T>>,
+    {
+        self.acquire()?;
+        let mut pending = PendingCall {
+            breaker: self,
+            finished: false,
+        };
+
+        let result = operation.await;
+        pending.finished = true;
+        self.record(&result);
+        result
+    }
+
+    /// Reserves a call, failing with `KafkaError::CircuitOpen` if the breaker is open. Every
+    /// successful acquire has to be followed by a `record` of the call's outcome.
+    pub(crate) fn acquire(&self) -> KafkaResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.state == CircuitState::Open && self.open_elapsed(&inner) {
+            self.transition(&mut inner, CircuitState::HalfOpen);
+        }
+
+        match inner.state {
+            CircuitState::Closed => Ok(()),
+            CircuitState::HalfOpen if inner.probes_in_flight < self.config.half_open_probes => {
+                inner.probes_in_flight += 1;
+                Ok(())
+            }
+            _ => {
+                self.metrics.rejected.add(1, &self.attributes());
+                Err(KafkaError::CircuitOpen(format!(
+                    "{} after {} consecutive failures",
+                    self.name, inner.consecutive_failures
+                )))
+            }
+        }
+    }
+
+    pub(crate) fn record<T>(&self, result: &KafkaResult<T>) {
+        match result {
+            Ok(_) => self.on_success(),
+            Err(e) if e.is_retryable() => self.on_failure(),
+            Err(_) => self.release_probe(),
+        }
+    }
+
+    fn on_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+        if inner.state != CircuitState::Closed {
+            tracing::info!("Circuit breaker {} closed", self.name);
+            self.transition(&mut inner, CircuitState::Closed);
+        }
+    }
+
+    fn on_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+
+        let trip = match inner.state {
+            CircuitState::Closed => inner.consecutive_failures >= self.config.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if trip {
+            tracing::warn!(
+                "Circuit breaker {} opened after {} consecutive failures, retrying in {:?}",
+                self.name,
+                inner.consecutive_failures,
+                self.config.open_duration
+            );
+            inner.opened_at = Some(Instant::now());
+            self.transition(&mut inner, CircuitState::Open);
+        }
+    }
+
+    /// Gives back a call that was acquired, without recording an outcome, e.g. one that was never
+    /// made.
+    pub(crate) fn release_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+    }
+
+    fn open_elapsed(&self, inner: &BreakerState) -> bool {
+        inner
+            .opened_at
+            .is_none_or(|opened_at| opened_at.elapsed() >= self.config.open_duration)
+    }
+
+    fn transition(&self, inner: &mut BreakerState, state: CircuitState) {
+        inner.state = state;
+        if state != CircuitState::HalfOpen {
+            inner.probes_in_flight = 0;
+        }
+
+        let mut attributes = self.attributes();
+        self.metrics.state.record(state.gauge_value(), &attributes);
+        attributes.push(KeyValue::new("state", state.as_str()));
+        self.metrics.transitions.add(1, &attributes);
+    }
+
+    fn attributes(&self) -> Vec<KeyValue> {
+        vec![KeyValue::new("breaker", self.name.clone())]
+    }
+}
+
+/// Records a failure for a call that was acquired but never finished.
+struct PendingCall<'a> {
+    breaker: &'a CircuitBreaker,
+    finished: bool,
+}
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::warn!(
+                "Call through circuit breaker {} was cancelled",
+                self.breaker.name
+            );
+            self.breaker.on_failure();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            CircuitBreakerConfig {
+                failure_threshold: 2,
+                open_duration: Duration::from_secs(10),
+                half_open_probes: 1,
+            },
+        )
+    }
+
+    async fn fail(breaker: &CircuitBreaker) -> KafkaResult<()> {
+        breaker
+            .call(async { Err::<(), _>(KafkaError::Timeout("no broker".into())) })
+            .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_threshold_and_closes_on_successful_probe() {
+        let breaker = breaker();
+        fail(&breaker).await.unwrap_err();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        fail(&breaker).await.unwrap_err();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let rejected = breaker.call(async { Ok(()) }).await;
+        assert!(matches!(rejected, Err(KafkaError::CircuitOpen(_))));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        breaker.call(async { Ok(()) }).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_errors_do_not_count() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker
+                .call(async { Err::<(), _>(KafkaError::Rejected("bad".into())) })
+                .await
+                .unwrap_err();
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_probe_is_released() {
+        let breaker = breaker();
+        fail(&breaker).await.unwrap_err();
+        fail(&breaker).await.unwrap_err();
+        tokio::time::advance(Duration::from_secs(10)).await;
+
+        let probe = breaker.call(std::future::pending::<KafkaResult<()>>());
+        let timed_out = tokio::time::timeout(Duration::from_secs(1), probe).await;
+        assert!(timed_out.is_err());
+
+        // The cancelled probe counts as a failure and opens the breaker again, rather than
+        // holding the only probe slot forever.
+        assert_eq!(breaker.state(), CircuitState::Open);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        breaker.call(async { Ok(()) }).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
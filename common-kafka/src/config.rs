@@ -1,9 +1,329 @@
+use crate::backpressure::BackpressureConfig;
+use crate::retry::RetryPolicy;
+use crate::retry_topics::RetryStrategy;
+use common_error::error::{KafkaError, KafkaResult};
+use rdkafka::ClientConfig;
+use std::collections::HashMap;
+
 pub trait KafkaConfigTrait {
     fn brokers(&self) -> &str;
     fn topic(&self) -> &str;
     fn group_id(&self) -> &str;
     fn timeout_ms(&self) -> u64;
-    fn max_retries(&self) -> u32;
+    fn retry_policy(&self) -> RetryPolicy;
+    fn profile(&self) -> TuningProfile;
+    fn overrides(&self) -> &HashMap<String, String>;
+    fn assignment_strategy(&self) -> AssignmentStrategy;
+    fn backpressure(&self) -> BackpressureConfig;
+    fn retry_strategy(&self) -> RetryStrategy;
+}
+
+/// Partition assignment strategy used by the consumer group.
+///
+/// `CooperativeSticky` moves only the partitions that change owner during a rebalance, instead of
+/// revoking every partition from every member first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AssignmentStrategy {
+    #[default]
+    Range,
+    RoundRobin,
+    CooperativeSticky,
+}
+
+impl AssignmentStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AssignmentStrategy::Range => "range",
+            AssignmentStrategy::RoundRobin => "roundrobin",
+            AssignmentStrategy::CooperativeSticky => "cooperative-sticky",
+        }
+    }
+}
+
+/// Named sets of librdkafka properties, applied before any per-config overrides.
+///
+/// `Balanced` matches the settings the producer and consumer have always used, `LowLatency` trades
+/// batching for per-message latency and `HighThroughput` does the opposite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TuningProfile {
+    #[default]
+    Balanced,
+    LowLatency,
+    HighThroughput,
+}
+
+impl TuningProfile {
+    pub fn producer_properties(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            TuningProfile::Balanced => &[
+                ("compression.type", "snappy"),
+                ("retry.backoff.ms", "500"),
+                ("request.required.acks", "all"),
+                ("queue.buffering.max.messages", "100000"),
+                ("queue.buffering.max.kbytes", "1048576"),
+                ("batch.size", "16384"),
+                ("linger.ms", "5"),
+            ],
+            TuningProfile::LowLatency => &[
+                ("compression.type", "none"),
+                ("retry.backoff.ms", "100"),
+                ("request.required.acks", "all"),
+                ("queue.buffering.max.messages", "100000"),
+                ("batch.size", "16384"),
+                ("linger.ms", "0"),
+            ],
+            TuningProfile::HighThroughput => &[
+                ("compression.type", "lz4"),
+                ("retry.backoff.ms", "500"),
+                ("request.required.acks", "all"),
+                ("queue.buffering.max.messages", "1000000"),
+                ("queue.buffering.max.kbytes", "2097152"),
+                ("batch.size", "1000000"),
+                ("linger.ms", "50"),
+            ],
+        }
+    }
+
+    pub fn consumer_properties(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            TuningProfile::Balanced => &[
+                ("session.timeout.ms", "6000"),
+                ("max.poll.interval.ms", "300000"),
+            ],
+            TuningProfile::LowLatency => &[
+                ("session.timeout.ms", "6000"),
+                ("max.poll.interval.ms", "300000"),
+                ("fetch.wait.max.ms", "10"),
+                ("fetch.min.bytes", "1"),
+            ],
+            TuningProfile::HighThroughput => &[
+                ("session.timeout.ms", "45000"),
+                ("max.poll.interval.ms", "300000"),
+                ("fetch.wait.max.ms", "500"),
+                ("fetch.min.bytes", "65536"),
+                ("queued.max.messages.kbytes", "262144"),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientRole {
+    Producer,
+    Consumer,
+}
+
+/// Builds the librdkafka configuration for an `EventProducer`.
+///
+/// Properties are layered as profile, then the settings the producer relies on, then the
+/// config's overrides, and the result is validated before any client is created.
+pub fn producer_client_config<T: KafkaConfigTrait>(config: &T) -> KafkaResult<ClientConfig> {
+    let mut client_config = ClientConfig::new();
+
+    for (key, value) in config.profile().producer_properties() {
+        client_config.set(*key, *value);
+    }
+
+    client_config
+        .set("bootstrap.servers", config.brokers())
+        .set("message.timeout.ms", config.timeout_ms().to_string());
+
+    apply_overrides(&mut client_config, config.overrides());
+    validate(&client_config, ClientRole::Producer)?;
+
+    Ok(client_config)
+}
+
+/// Builds the librdkafka configuration for an `EventConsumer`.
+///
+/// Offsets are always committed by the consumer itself, so overriding `enable.auto.commit`
+/// is rejected rather than silently ignored.
+pub fn consumer_client_config<T: KafkaConfigTrait>(config: &T) -> KafkaResult<ClientConfig> {
+    let mut client_config = ClientConfig::new();
+
+    for (key, value) in config.profile().consumer_properties() {
+        client_config.set(*key, *value);
+    }
+
+    client_config
+        .set("bootstrap.servers", config.brokers())
+        .set("group.id", config.group_id())
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest")
+        .set(
+            "partition.assignment.strategy",
+            config.assignment_strategy().as_str(),
+        );
+
+    apply_overrides(&mut client_config, config.overrides());
+    validate(&client_config, ClientRole::Consumer)?;
+
+    Ok(client_config)
+}
+
+fn apply_overrides(client_config: &mut ClientConfig, overrides: &HashMap<String, String>) {
+    for (key, value) in overrides {
+        client_config.set(key, value);
+    }
+}
+
+fn validate(client_config: &ClientConfig, role: ClientRole) -> KafkaResult<()> {
+    if client_config
+        .get("bootstrap.servers")
+        .is_none_or(str::is_empty)
+    {
+        return Err(KafkaError::Configuration(
+            "bootstrap.servers must not be empty".into(),
+        ));
+    }
+
+    match role {
+        ClientRole::Producer => validate_producer(client_config),
+        ClientRole::Consumer => validate_consumer(client_config),
+    }
+}
+
+fn validate_producer(client_config: &ClientConfig) -> KafkaResult<()> {
+    let get = |key: &str| client_config.get(key);
+
+    if let Some(level) = get("compression.level") {
+        let level: i32 = parse_number("compression.level", level)?;
+        let codec = get("compression.type")
+            .or_else(|| get("compression.codec"))
+            .unwrap_or("none");
+
+        let range = match codec {
+            "gzip" => -1..=9,
+            "lz4" => -1..=12,
+            "zstd" => -1..=22,
+            "snappy" | "none" => -1..=0,
+            other => {
+                return Err(KafkaError::Configuration(format!(
+                    "unknown compression.type '{}'",
+                    other
+                )))
+            }
+        };
+
+        if !range.contains(&level) {
+            return Err(KafkaError::Configuration(format!(
+                "compression.level {} is not valid for compression.type '{}' (allowed {}..={})",
+                level,
+                codec,
+                range.start(),
+                range.end()
+            )));
+        }
+    }
+
+    if get("enable.idempotence") == Some("true") {
+        let acks = get("request.required.acks")
+            .or_else(|| get("acks"))
+            .unwrap_or("all");
+        if acks != "all" && acks != "-1" {
+            return Err(KafkaError::Configuration(format!(
+                "enable.idempotence requires acks=all, got acks={}",
+                acks
+            )));
+        }
+
+        if let Some(in_flight) =
+            get("max.in.flight.requests.per.connection").or_else(|| get("max.in.flight"))
+        {
+            let in_flight: u32 = parse_number("max.in.flight.requests.per.connection", in_flight)?;
+            if in_flight > 5 {
+                return Err(KafkaError::Configuration(format!(
+                    "enable.idempotence requires max.in.flight.requests.per.connection <= 5, got {}",
+                    in_flight
+                )));
+            }
+        }
+    }
+
+    if let Some(batch_size) = get("batch.size") {
+        let batch_size: u64 = parse_number("batch.size", batch_size)?;
+        let max_bytes: u64 = match get("message.max.bytes") {
+            Some(value) => parse_number("message.max.bytes", value)?,
+            None => 1_000_000,
+        };
+        if batch_size > max_bytes {
+            return Err(KafkaError::Configuration(format!(
+                "batch.size {} exceeds message.max.bytes {}",
+                batch_size, max_bytes
+            )));
+        }
+    }
+
+    let linger = get("linger.ms").or_else(|| get("queue.buffering.max.ms"));
+    if let (Some(linger), Some(timeout)) = (linger, get("message.timeout.ms")) {
+        let linger: f64 = parse_number("linger.ms", linger)?;
+        let timeout: u64 = parse_number("message.timeout.ms", timeout)?;
+        if timeout != 0 && linger >= timeout as f64 {
+            return Err(KafkaError::Configuration(format!(
+                "linger.ms {} must be lower than message.timeout.ms {}",
+                linger, timeout
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_consumer(client_config: &ClientConfig) -> KafkaResult<()> {
+    let get = |key: &str| client_config.get(key);
+
+    if get("enable.auto.commit") != Some("false") {
+        return Err(KafkaError::Configuration(
+            "enable.auto.commit cannot be enabled, EventConsumer commits offsets itself".into(),
+        ));
+    }
+
+    if let Some(strategies) = get("partition.assignment.strategy") {
+        let strategies: Vec<&str> = strategies.split(',').map(str::trim).collect();
+        if strategies.contains(&"cooperative-sticky") && strategies.len() > 1 {
+            return Err(KafkaError::Configuration(format!(
+                "cooperative-sticky cannot be combined with eager assignment strategies, got '{}'",
+                strategies.join(",")
+            )));
+        }
+    }
+
+    let session: Option<u64> = get("session.timeout.ms")
+        .map(|value| parse_number("session.timeout.ms", value))
+        .transpose()?;
+    let poll: Option<u64> = get("max.poll.interval.ms")
+        .map(|value| parse_number("max.poll.interval.ms", value))
+        .transpose()?;
+    let heartbeat: Option<u64> = get("heartbeat.interval.ms")
+        .map(|value| parse_number("heartbeat.interval.ms", value))
+        .transpose()?;
+
+    if let (Some(session), Some(poll)) = (session, poll) {
+        if poll < session {
+            return Err(KafkaError::Configuration(format!(
+                "max.poll.interval.ms {} must be at least session.timeout.ms {}",
+                poll, session
+            )));
+        }
+    }
+
+    if let (Some(heartbeat), Some(session)) = (heartbeat, session) {
+        if heartbeat >= session {
+            return Err(KafkaError::Configuration(format!(
+                "heartbeat.interval.ms {} must be lower than session.timeout.ms {}",
+                heartbeat, session
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_number<N: std::str::FromStr>(key: &str, value: &str) -> KafkaResult<N> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| KafkaError::Configuration(format!("{} must be numeric, got '{}'", key, value)))
 }
 
 #[derive(Debug, Clone)]
@@ -12,7 +332,12 @@ pub struct InboundConfig {
     pub topic: String,
     pub group_id: String,
     pub timeout_ms: u64,
-    pub max_retries: u32,
+    pub retry_policy: RetryPolicy,
+    pub profile: TuningProfile,
+    pub overrides: HashMap<String, String>,
+    pub assignment_strategy: AssignmentStrategy,
+    pub backpressure: BackpressureConfig,
+    pub retry_strategy: RetryStrategy,
 }
 
 impl KafkaConfigTrait for InboundConfig {
@@ -28,8 +353,23 @@ impl KafkaConfigTrait for InboundConfig {
     fn timeout_ms(&self) -> u64 {
         self.timeout_ms
     }
-    fn max_retries(&self) -> u32 {
-        self.max_retries
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+    fn profile(&self) -> TuningProfile {
+        self.profile
+    }
+    fn overrides(&self) -> &HashMap<String, String> {
+        &self.overrides
+    }
+    fn assignment_strategy(&self) -> AssignmentStrategy {
+        self.assignment_strategy
+    }
+    fn backpressure(&self) -> BackpressureConfig {
+        self.backpressure
+    }
+    fn retry_strategy(&self) -> RetryStrategy {
+        self.retry_strategy.clone()
     }
 }
 
@@ -39,7 +379,12 @@ pub struct FulfillmentConfig {
     pub topic: String,
     pub group_id: String,
     pub timeout_ms: u64,
-    pub max_retries: u32,
+    pub retry_policy: RetryPolicy,
+    pub profile: TuningProfile,
+    pub overrides: HashMap<String, String>,
+    pub assignment_strategy: AssignmentStrategy,
+    pub backpressure: BackpressureConfig,
+    pub retry_strategy: RetryStrategy,
 }
 
 impl KafkaConfigTrait for FulfillmentConfig {
@@ -55,8 +400,23 @@ impl KafkaConfigTrait for FulfillmentConfig {
     fn timeout_ms(&self) -> u64 {
         self.timeout_ms
     }
-    fn max_retries(&self) -> u32 {
-        self.max_retries
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+    fn profile(&self) -> TuningProfile {
+        self.profile
+    }
+    fn overrides(&self) -> &HashMap<String, String> {
+        &self.overrides
+    }
+    fn assignment_strategy(&self) -> AssignmentStrategy {
+        self.assignment_strategy
+    }
+    fn backpressure(&self) -> BackpressureConfig {
+        self.backpressure
+    }
+    fn retry_strategy(&self) -> RetryStrategy {
+        self.retry_strategy.clone()
     }
 }
 
@@ -67,9 +427,50 @@ impl InboundConfig {
             topic: "INBOUND".to_string(),
             group_id: "INBOUND_GROUP".to_string(),
             timeout_ms: 5000,
-            max_retries: 5,
+            retry_policy: RetryPolicy::default(),
+            profile: TuningProfile::default(),
+            overrides: HashMap::new(),
+            assignment_strategy: AssignmentStrategy::default(),
+            backpressure: BackpressureConfig::default(),
+            retry_strategy: RetryStrategy::default(),
         }
     }
+
+    pub fn with_profile(mut self, profile: TuningProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn with_override(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.overrides.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_assignment_strategy(mut self, strategy: AssignmentStrategy) -> Self {
+        self.assignment_strategy = strategy;
+        self
+    }
+
+    pub fn with_backpressure(mut self, backpressure: BackpressureConfig) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    pub fn with_retry_strategy(mut self, retry_strategy: RetryStrategy) -> Self {
+        self.retry_strategy = retry_strategy;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+}
+
+impl Default for InboundConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FulfillmentConfig {
@@ -79,7 +480,189 @@ impl FulfillmentConfig {
             topic: "FULFILLMENT".to_string(),
             group_id: "FULFILLMENT_GROUP".to_string(),
             timeout_ms: 5000,
-            max_retries: 5,
+            retry_policy: RetryPolicy::default(),
+            profile: TuningProfile::default(),
+            overrides: HashMap::new(),
+            assignment_strategy: AssignmentStrategy::default(),
+            backpressure: BackpressureConfig::default(),
+            retry_strategy: RetryStrategy::default(),
         }
     }
+
+    pub fn with_profile(mut self, profile: TuningProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn with_override(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.overrides.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_assignment_strategy(mut self, strategy: AssignmentStrategy) -> Self {
+        self.assignment_strategy = strategy;
+        self
+    }
+
+    pub fn with_backpressure(mut self, backpressure: BackpressureConfig) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    pub fn with_retry_strategy(mut self, retry_strategy: RetryStrategy) -> Self {
+        self.retry_strategy = retry_strategy;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+}
+
+impl Default for FulfillmentConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn producer_error(config: InboundConfig) -> String {
+        match producer_client_config(&config) {
+            Err(KafkaError::Configuration(message)) => message,
+            other => panic!(
+                "expected a configuration error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    fn consumer_error(config: InboundConfig) -> String {
+        match consumer_client_config(&config) {
+            Err(KafkaError::Configuration(message)) => message,
+            other => panic!(
+                "expected a configuration error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+
+    #[test]
+    fn profiles_pass_validation() {
+        for profile in [
+            TuningProfile::Balanced,
+            TuningProfile::LowLatency,
+            TuningProfile::HighThroughput,
+        ] {
+            let config = InboundConfig::new().with_profile(profile);
+            assert!(producer_client_config(&config).is_ok(), "{:?}", profile);
+            assert!(consumer_client_config(&config).is_ok(), "{:?}", profile);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_brokers() {
+        let config = InboundConfig {
+            brokers: String::new(),
+            ..InboundConfig::new()
+        };
+        assert!(producer_error(config.clone()).contains("bootstrap.servers"));
+        assert!(consumer_error(config).contains("bootstrap.servers"));
+    }
+
+    #[test]
+    fn rejects_compression_level_out_of_range_for_codec() {
+        let config = InboundConfig::new()
+            .with_override("compression.type", "gzip")
+            .with_override("compression.level", "12");
+        assert!(producer_error(config).contains("compression.level 12"));
+
+        let config = InboundConfig::new()
+            .with_override("compression.type", "zstd")
+            .with_override("compression.level", "12");
+        assert!(producer_client_config(&config).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_compression_type() {
+        let config = InboundConfig::new()
+            .with_override("compression.type", "brotli")
+            .with_override("compression.level", "1");
+        assert!(producer_error(config).contains("unknown compression.type"));
+    }
+
+    #[test]
+    fn rejects_non_numeric_values() {
+        let config = InboundConfig::new().with_override("compression.level", "high");
+        assert!(producer_error(config).contains("must be numeric"));
+
+        let config = InboundConfig::new().with_override("session.timeout.ms", "soon");
+        assert!(consumer_error(config).contains("must be numeric"));
+    }
+
+    #[test]
+    fn rejects_idempotence_without_acks_all() {
+        let config = InboundConfig::new()
+            .with_override("enable.idempotence", "true")
+            .with_override("request.required.acks", "1");
+        assert!(producer_error(config).contains("requires acks=all"));
+    }
+
+    #[test]
+    fn rejects_idempotence_with_too_many_in_flight() {
+        let config = InboundConfig::new()
+            .with_override("enable.idempotence", "true")
+            .with_override("max.in.flight.requests.per.connection", "6");
+        assert!(producer_error(config).contains("<= 5"));
+    }
+
+    #[test]
+    fn rejects_batch_larger_than_max_message() {
+        let config = InboundConfig::new()
+            .with_override("batch.size", "2000000")
+            .with_override("message.max.bytes", "1000000");
+        assert!(producer_error(config).contains("exceeds message.max.bytes"));
+    }
+
+    #[test]
+    fn rejects_linger_not_below_message_timeout() {
+        let config = InboundConfig::new().with_override("linger.ms", "5000");
+        assert!(producer_error(config).contains("must be lower than message.timeout.ms"));
+    }
+
+    #[test]
+    fn rejects_auto_commit() {
+        let config = InboundConfig::new().with_override("enable.auto.commit", "true");
+        assert!(consumer_error(config).contains("enable.auto.commit"));
+    }
+
+    #[test]
+    fn rejects_cooperative_sticky_mixed_with_eager_strategies() {
+        let config = InboundConfig::new()
+            .with_override("partition.assignment.strategy", "range,cooperative-sticky");
+        assert!(consumer_error(config).contains("cooperative-sticky cannot be combined"));
+
+        let config =
+            InboundConfig::new().with_assignment_strategy(AssignmentStrategy::CooperativeSticky);
+        assert!(consumer_client_config(&config).is_ok());
+    }
+
+    #[test]
+    fn rejects_poll_interval_below_session_timeout() {
+        let config = InboundConfig::new()
+            .with_override("session.timeout.ms", "60000")
+            .with_override("max.poll.interval.ms", "30000");
+        assert!(consumer_error(config).contains("max.poll.interval.ms"));
+    }
+
+    #[test]
+    fn rejects_heartbeat_not_below_session_timeout() {
+        let config = InboundConfig::new()
+            .with_override("session.timeout.ms", "10000")
+            .with_override("heartbeat.interval.ms", "10000");
+        assert!(consumer_error(config).contains("heartbeat.interval.ms"));
+    }
 }
@@ -1,21 +1,175 @@
-use crate::config::KafkaConfigTrait;
+use crate::backpressure::{BackpressureConfig, Completion, FlowAction, FlowControl};
+use crate::batch::{BatchConfig, BatchMessageHandler, Batcher};
+use crate::config::{consumer_client_config, KafkaConfigTrait};
+use crate::message::ConsumedMessage;
+use crate::producer::{EventProducer, MessageProducer};
+use crate::retry::RetryPolicy;
+use crate::retry_topics::{
+    not_before_delay, send_to_dead_letter, RetryStrategy, RetryTopicsConfig,
+};
 use async_trait::async_trait;
 use common_error::error::{KafkaError, KafkaResult};
+use futures::stream::FuturesUnordered;
 use futures::StreamExt;
-use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
-use rdkafka::message::Message;
-use rdkafka::ClientConfig;
+use rdkafka::consumer::{
+    BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer,
+};
+use rdkafka::{ClientContext, Offset, TopicPartitionList};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::time::{interval, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TopicPartition {
+    pub topic: String,
+    pub partition: i32,
+}
+
+/// A partition assignment change, passed to the handler before and after librdkafka applies it.
+///
+/// With the cooperative-sticky strategy these only list the partitions that actually moved.
+#[derive(Debug, Clone)]
+pub enum RebalanceEvent {
+    Assign(Vec<TopicPartition>),
+    Revoke(Vec<TopicPartition>),
+    Error(String),
+}
 
 #[async_trait]
 pub trait MessageHandler: Send + Sync {
     async fn handle(&self, key: &[u8], payload: &[u8]) -> KafkaResult<()>;
+
+    /// Handles a whole consumed message. Override this instead of relying on `handle` when the
+    /// headers or position of the message matter, `handle` is only called from here.
+    async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+        self.handle(&message.key, &message.payload).await
+    }
+
+    /// Called before a rebalance is applied. On revoke this is the last chance to flush any state
+    /// kept for the partitions, offsets of processed messages are committed straight after.
+    ///
+    /// Runs on the consumer's poll, so it should return quickly.
+    fn pre_rebalance(&self, _event: &RebalanceEvent) {}
+
+    /// Called after a rebalance is applied, e.g. to warm caches for newly assigned partitions.
+    fn post_rebalance(&self, _event: &RebalanceEvent) {}
+}
+
+/// Consumer context that forwards rebalances to the handler and commits the offsets of processed
+/// messages for partitions that are being revoked, so the next owner does not process them again.
+pub struct EventConsumerContext {
+    handler: Arc<dyn MessageHandler>,
+    processed: Mutex<HashMap<TopicPartition, i64>>,
+    revoked: Mutex<Vec<TopicPartition>>,
+}
+
+impl EventConsumerContext {
+    fn new(handler: Arc<dyn MessageHandler>) -> Self {
+        Self {
+            handler,
+            processed: Mutex::new(HashMap::new()),
+            revoked: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn take_revoked(&self) -> Vec<TopicPartition> {
+        std::mem::take(&mut *self.revoked.lock().unwrap())
+    }
+
+    fn record_processed(&self, topic: &str, partition: i32, offset: i64) {
+        let mut processed = self.processed.lock().unwrap();
+        processed.insert(
+            TopicPartition {
+                topic: topic.to_string(),
+                partition,
+            },
+            offset + 1,
+        );
+    }
+
+    fn commit_revoked(&self, consumer: &BaseConsumer<Self>, revoked: &[TopicPartition]) {
+        let mut offsets = TopicPartitionList::new();
+        {
+            let mut processed = self.processed.lock().unwrap();
+            for partition in revoked {
+                if let Some(offset) = processed.remove(partition) {
+                    if let Err(e) = offsets.add_partition_offset(
+                        &partition.topic,
+                        partition.partition,
+                        Offset::Offset(offset),
+                    ) {
+                        tracing::error!("Failed to add offset for revoked partition: {}", e);
+                    }
+                }
+            }
+        }
+
+        if offsets.count() == 0 {
+            return;
+        }
+
+        if let Err(e) = consumer.commit(&offsets, CommitMode::Sync) {
+            tracing::error!("Failed to commit offsets for revoked partitions: {}", e);
+        }
+    }
+}
+
+impl ClientContext for EventConsumerContext {}
+
+impl ConsumerContext for EventConsumerContext {
+    fn pre_rebalance(&self, base_consumer: &BaseConsumer<Self>, rebalance: &Rebalance<'_>) {
+        let event = to_rebalance_event(rebalance);
+        tracing::info!("Rebalance starting: {:?}", event);
+
+        self.handler.pre_rebalance(&event);
+
+        if let RebalanceEvent::Revoke(partitions) = &event {
+            self.commit_revoked(base_consumer, partitions);
+            self.revoked
+                .lock()
+                .unwrap()
+                .extend(partitions.iter().cloned());
+        }
+    }
+
+    fn post_rebalance(&self, _base_consumer: &BaseConsumer<Self>, rebalance: &Rebalance<'_>) {
+        let event = to_rebalance_event(rebalance);
+        self.handler.post_rebalance(&event);
+        tracing::info!("Rebalance finished: {:?}", event);
+    }
+}
+
+fn to_rebalance_event(rebalance: &Rebalance<'_>) -> RebalanceEvent {
+    let partitions = |list: &TopicPartitionList| {
+        list.elements()
+            .iter()
+            .map(|element| TopicPartition {
+                topic: element.topic().to_string(),
+                partition: element.partition(),
+            })
+            .collect()
+    };
+
+    match rebalance {
+        Rebalance::Assign(list) => RebalanceEvent::Assign(partitions(list)),
+        Rebalance::Revoke(list) => RebalanceEvent::Revoke(partitions(list)),
+        Rebalance::Error(e) => RebalanceEvent::Error(e.to_string()),
+    }
+}
+
+/// Something that consumes messages and hands them to a `MessageHandler` until it is stopped.
+#[async_trait]
+pub trait MessageConsumer: Send + Sync {
+    async fn start(&self) -> KafkaResult<()>;
 }
 
 pub struct EventConsumer {
-    consumer: StreamConsumer,
-    handler: Box<dyn MessageHandler>,
-    max_retries: u32,
+    consumer: StreamConsumer<EventConsumerContext>,
+    backpressure: BackpressureConfig,
+    /// How many messages of a partition may be handled at once.
+    window: usize,
+    processor: MessageProcessor,
 }
 
 impl EventConsumer {
@@ -23,73 +177,333 @@ impl EventConsumer {
         config: T,
         handler: Box<dyn MessageHandler>,
     ) -> KafkaResult<Self> {
-        let consumer: StreamConsumer = ClientConfig::new()
-            .set("bootstrap.servers", config.brokers())
-            .set("group.id", config.group_id())
-            .set("enable.auto.commit", "false")
-            .set("auto.offset.reset", "earliest")
-            .set("session.timeout.ms", "6000")
-            .set("max.poll.interval.ms", "300000")
-            .create()
-            .map_err(|e| KafkaError::ClientCreation(e.to_string()))?;
+        Self::build(&config, handler, &[config.topic()], config.group_id())
+    }
+
+    /// Creates a consumer that hands messages to `handler` in batches, see `BatchMessageHandler`.
+    pub fn batched<T: KafkaConfigTrait>(
+        config: T,
+        handler: Box<dyn BatchMessageHandler>,
+        batch: BatchConfig,
+    ) -> KafkaResult<Self> {
+        let handler = Box::new(Batcher::new(handler, batch));
+        let mut consumer = Self::build(&config, handler, &[config.topic()], config.group_id())?;
+        consumer.window = batch.max_messages;
+        Ok(consumer)
+    }
+
+    /// Creates the consumer for the retry topics of a config using `RetryStrategy::RetryTopics`.
+    ///
+    /// It joins its own `<group>.retry` group, waits until each retried message is due by pausing
+    /// its partition, and passes messages that fail again on to the next tier or the dead letter
+    /// topic.
+    pub fn retry_consumer<T: KafkaConfigTrait>(
+        config: T,
+        handler: Box<dyn MessageHandler>,
+    ) -> KafkaResult<Self> {
+        let RetryStrategy::RetryTopics(retry_topics) = config.retry_strategy() else {
+            return Err(KafkaError::Configuration(
+                "a retry consumer requires the RetryTopics retry strategy".into(),
+            ));
+        };
+
+        let group_id = format!("{}.retry", config.group_id());
+        Self::build(&config, handler, &retry_topics.retry_topics(), &group_id)
+    }
+
+    /// Creates the consumer of a `Requester`'s reply topic, in a `<group>.<reply topic>` group of
+    /// its own. Pass it `Requester::reply_handler`.
+    pub fn reply_consumer<T: KafkaConfigTrait>(
+        config: T,
+        reply_topic: &str,
+        handler: Box<dyn MessageHandler>,
+    ) -> KafkaResult<Self> {
+        let group_id = format!("{}.{}", config.group_id(), reply_topic);
+        Self::build(&config, handler, &[reply_topic], &group_id)
+    }
+
+    fn build<T: KafkaConfigTrait>(
+        config: &T,
+        handler: Box<dyn MessageHandler>,
+        topics: &[&str],
+        group_id: &str,
+    ) -> KafkaResult<Self> {
+        let handler: Arc<dyn MessageHandler> = Arc::from(handler);
+
+        let consumer: StreamConsumer<EventConsumerContext> = consumer_client_config(config)?
+            .set("group.id", group_id)
+            .create_with_context(EventConsumerContext::new(handler.clone()))
+            .map_err(KafkaError::ClientCreation)?;
 
         consumer
-            .subscribe(&[config.topic()])
-            .map_err(|e| KafkaError::ClientCreation(e.to_string()))?;
+            .subscribe(topics)
+            .map_err(KafkaError::ClientCreation)?;
+
+        let failures = FailureRoute::new(config.retry_strategy(), || {
+            let producer: Arc<dyn MessageProducer> = Arc::new(EventProducer::from_config(config)?);
+            KafkaResult::Ok(producer)
+        })?;
 
         Ok(EventConsumer {
             consumer,
-            handler,
-            max_retries: config.max_retries(),
+            backpressure: config.backpressure(),
+            window: 1,
+            processor: MessageProcessor {
+                handler,
+                retry_policy: config.retry_policy(),
+                failures,
+            },
         })
     }
 
+    /// Consumes until the stream ends.
+    ///
+    /// Messages of different partitions are handled concurrently, up to the in-flight limit, and
+    /// in order within a partition. A partition whose handler fails is paused rather than skipped,
+    /// and the stream keeps being polled throughout so pausing never costs the group membership.
     pub async fn start(&self) -> KafkaResult<()> {
         let mut message_stream = self.consumer.stream();
+        let mut flow = FlowControl::new(self.backpressure).with_window(self.window);
+        let mut in_flight = FuturesUnordered::new();
+        let mut resume_check = interval(Duration::from_millis(250));
+
+        loop {
+            let revoked = self.consumer.context().take_revoked();
+            if !revoked.is_empty() {
+                let actions = flow.revoke(&revoked);
+                self.apply(actions, &mut in_flight)?;
+            }
 
-        while let Some(message_result) = message_stream.next().await {
-            match message_result {
-                Ok(message) => {
-                    let key = message.key().unwrap_or_default();
-                    let payload = message.payload().unwrap_or_default();
-
-                    match self.process_with_retry(key, payload).await {
-                        Ok(_) => {
-                            self.consumer
-                                .commit_message(&message, CommitMode::Async)
-                                .map_err(|e| KafkaError::MessageDelivery(e.to_string()))?;
+            tokio::select! {
+                message_result = message_stream.next() => {
+                    match message_result {
+                        Some(Ok(message)) => {
+                            let actions = flow.accept(ConsumedMessage::from(&message));
+                            self.apply(actions, &mut in_flight)?;
                         }
-                        Err(e) => {
-                            tracing::error!("Failed to process message: {}", e);
-                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        // Polling again straight away is what lets librdkafka recover, and
+                        // handlers of other partitions keep completing meanwhile.
+                        Some(Err(e)) => tracing::error!("Error receiving message: {}", e),
+                        None => break,
+                    }
+                }
+                Some((generation, message, completion)) = in_flight.next() => {
+                    let (topic, partition) = (message.topic.clone(), message.partition);
+                    if let Some(completed) = flow.complete(generation, message, completion) {
+                        if let Some(offset) = completed.commit {
+                            self.commit(&topic, partition, offset)?;
                         }
+                        self.apply(completed.actions, &mut in_flight)?;
                     }
                 }
-                Err(e) => {
-                    tracing::error!("Error receiving message: {}", e);
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                _ = resume_check.tick() => {
+                    let actions = flow.due(Instant::now());
+                    self.apply(actions, &mut in_flight)?;
                 }
             }
         }
         Ok(())
     }
 
-    async fn process_with_retry(&self, key: &[u8], payload: &[u8]) -> KafkaResult<()> {
-        let mut retries = 0;
-        let mut backoff = Duration::from_millis(100);
+    fn apply<'a>(
+        &'a self,
+        actions: Vec<FlowAction>,
+        in_flight: &mut FuturesUnordered<HandlerFuture<'a>>,
+    ) -> KafkaResult<()> {
+        for action in actions {
+            match action {
+                FlowAction::Dispatch(dispatch) => {
+                    in_flight.push(Box::pin(async move {
+                        let completion = self.processor.process(&dispatch.message).await;
+                        (dispatch.generation, dispatch.message, completion)
+                    }));
+                }
+                FlowAction::Pause(partitions) => {
+                    self.consumer
+                        .pause(&to_partition_list(&partitions))
+                        .map_err(KafkaError::MessageDelivery)?;
+                }
+                FlowAction::Resume(partitions) => {
+                    self.consumer
+                        .resume(&to_partition_list(&partitions))
+                        .map_err(KafkaError::MessageDelivery)?;
+                }
+                FlowAction::PauseAssigned => {
+                    let assignment = self
+                        .consumer
+                        .assignment()
+                        .map_err(KafkaError::MessageDelivery)?;
+                    self.consumer
+                        .pause(&assignment)
+                        .map_err(KafkaError::MessageDelivery)?;
+                }
+                FlowAction::ResumeAssigned { except } => {
+                    let assignment = self
+                        .consumer
+                        .assignment()
+                        .map_err(KafkaError::MessageDelivery)?;
+                    let partitions: Vec<TopicPartition> = assignment
+                        .elements()
+                        .iter()
+                        .map(|element| TopicPartition {
+                            topic: element.topic().to_string(),
+                            partition: element.partition(),
+                        })
+                        .filter(|partition| !except.contains(partition))
+                        .collect();
+                    self.consumer
+                        .resume(&to_partition_list(&partitions))
+                        .map_err(KafkaError::MessageDelivery)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(&self, topic: &str, partition: i32, offset: i64) -> KafkaResult<()> {
+        self.consumer
+            .context()
+            .record_processed(topic, partition, offset);
 
-        while retries < self.max_retries {
-            match self.handler.handle(key, payload).await {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    tracing::warn!("Retry {} failed: {}", retries, e);
-                    retries += 1;
-                    tokio::time::sleep(backoff).await;
-                    backoff *= 2;
+        let mut offsets = TopicPartitionList::new();
+        offsets
+            .add_partition_offset(topic, partition, Offset::Offset(offset + 1))
+            .map_err(KafkaError::MessageDelivery)?;
+
+        self.consumer
+            .commit(&offsets, CommitMode::Async)
+            .map_err(KafkaError::MessageDelivery)
+    }
+}
+
+#[async_trait]
+impl MessageConsumer for EventConsumer {
+    async fn start(&self) -> KafkaResult<()> {
+        EventConsumer::start(self).await
+    }
+}
+
+/// Where failed messages go: a retry strategy together with the producer it republishes through.
+pub(crate) enum FailureRoute {
+    Blocking {
+        dead_letter: Option<(String, Arc<dyn MessageProducer>)>,
+    },
+    RetryTopics {
+        retry_topics: RetryTopicsConfig,
+        producer: Arc<dyn MessageProducer>,
+    },
+}
+
+impl FailureRoute {
+    /// Creates the producer only for strategies that republish anything.
+    pub(crate) fn new<E>(
+        strategy: RetryStrategy,
+        producer: impl FnOnce() -> Result<Arc<dyn MessageProducer>, E>,
+    ) -> Result<Self, E> {
+        Ok(match strategy {
+            RetryStrategy::Blocking { dead_letter_topic } => FailureRoute::Blocking {
+                dead_letter: match dead_letter_topic {
+                    Some(topic) => Some((topic, producer()?)),
+                    None => None,
+                },
+            },
+            RetryStrategy::RetryTopics(retry_topics) => FailureRoute::RetryTopics {
+                retry_topics,
+                producer: producer()?,
+            },
+        })
+    }
+}
+
+/// Handles a single message according to the retry strategy, shared by `EventConsumer` and the
+/// in-memory consumer.
+pub(crate) struct MessageProcessor {
+    pub(crate) handler: Arc<dyn MessageHandler>,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) failures: FailureRoute,
+}
+
+impl MessageProcessor {
+    pub(crate) async fn process(&self, message: &ConsumedMessage) -> Completion {
+        match &self.failures {
+            FailureRoute::RetryTopics {
+                retry_topics,
+                producer,
+            } => {
+                if let Some(delay) = not_before_delay(message) {
+                    return Completion::Deferred(delay);
+                }
+
+                let Err(e) = self.handler.handle_message(message).await else {
+                    return Completion::Succeeded;
+                };
+
+                match retry_topics
+                    .route_failure(producer.as_ref(), message, &e)
+                    .await
+                {
+                    Ok(()) => Completion::Succeeded,
+                    Err(route_error) => {
+                        tracing::error!("Failed to route message to retry topic: {}", route_error);
+                        Completion::Failed
+                    }
+                }
+            }
+            FailureRoute::Blocking { dead_letter } => {
+                let Err(e) = self.process_with_retry(message).await else {
+                    return Completion::Succeeded;
+                };
+
+                if e.is_retryable() {
+                    tracing::error!("Failed to process message: {}", e);
+                    return Completion::Failed;
+                }
+
+                match dead_letter {
+                    Some((topic, producer)) => {
+                        match send_to_dead_letter(producer.as_ref(), topic, message, &e).await {
+                            Ok(()) => Completion::Succeeded,
+                            Err(dlq_error) => {
+                                tracing::error!(
+                                    "Failed to send message to dead letter topic: {}",
+                                    dlq_error
+                                );
+                                Completion::Failed
+                            }
+                        }
+                    }
+                    // Without a dead letter topic there is nowhere to keep the message, so the
+                    // partition stays held on it until someone intervenes rather than losing it.
+                    None => {
+                        tracing::error!(
+                            "Message {}[{}]@{} can never be processed and there is no dead letter topic, holding the partition: {}",
+                            message.topic,
+                            message.partition,
+                            message.offset,
+                            e
+                        );
+                        Completion::Failed
+                    }
                 }
             }
         }
+    }
+
+    async fn process_with_retry(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+        self.retry_policy
+            .retry(|| self.handler.handle_message(message))
+            .await
+    }
+}
+
+type HandlerFuture<'a> = std::pin::Pin<
+    Box<dyn std::future::Future<Output = (u64, ConsumedMessage, Completion)> + Send + 'a>,
+>;
 
-        Err(KafkaError::MessageDelivery("Max retries exceeded".into()))
+fn to_partition_list(partitions: &[TopicPartition]) -> TopicPartitionList {
+    let mut list = TopicPartitionList::new();
+    for partition in partitions {
+        list.add_partition(&partition.topic, partition.partition);
     }
+    list
 }
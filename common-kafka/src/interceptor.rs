@@ -0,0 +1,251 @@
+use crate::producer::DeliveryReport;
+use common_error::error::KafkaResult;
+use opentelemetry::metrics::Counter;
+use opentelemetry::{global, KeyValue};
+use std::sync::Arc;
+
+/// A record on its way to the broker, as interceptors see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutgoingRecord {
+    pub topic: String,
+    pub key: Vec<u8>,
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl OutgoingRecord {
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Sets a header, replacing any existing value.
+    pub fn set_header(&mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) {
+        let (name, value) = (name.into(), value.into());
+        match self.headers.iter_mut().find(|(key, _)| *key == name) {
+            Some((_, existing)) => *existing = value,
+            None => self.headers.push((name, value)),
+        }
+    }
+
+    pub(crate) fn header_refs(&self) -> Vec<(&str, &[u8])> {
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+            .collect()
+    }
+}
+
+/// Hooks into every send of a producer, e.g. to add headers, validate or count records.
+pub trait ProducerInterceptor: Send + Sync {
+    /// Called before a record is sent, in the order the interceptors were added. May change the
+    /// record, or fail to reject the send.
+    fn on_send(&self, _record: &mut OutgoingRecord) -> KafkaResult<()> {
+        Ok(())
+    }
+
+    /// Called with the outcome of every send that got past `on_send`.
+    fn on_acknowledgement(&self, _record: &OutgoingRecord, _result: &KafkaResult<DeliveryReport>) {}
+}
+
+/// The interceptors of a producer, shared by its clones.
+#[derive(Clone, Default)]
+pub(crate) struct Interceptors(Vec<Arc<dyn ProducerInterceptor>>);
+
+impl Interceptors {
+    pub(crate) fn push(&mut self, interceptor: impl ProducerInterceptor + 'static) {
+        self.0.push(Arc::new(interceptor));
+    }
+
+    /// Runs `on_send` of every interceptor and returns the record to send in place of the original,
+    /// or `None` without copying anything if there are no interceptors.
+    pub(crate) fn before_send(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<Option<OutgoingRecord>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+
+        let mut record = OutgoingRecord {
+            topic: topic.to_string(),
+            key: key.to_vec(),
+            payload: payload.to_vec(),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_vec()))
+                .collect(),
+        };
+        for interceptor in &self.0 {
+            interceptor.on_send(&mut record)?;
+        }
+
+        Ok(Some(record))
+    }
+
+    pub(crate) fn after_send(&self, record: &OutgoingRecord, result: &KafkaResult<DeliveryReport>) {
+        for interceptor in &self.0 {
+            interceptor.on_acknowledgement(record, result);
+        }
+    }
+}
+
+/// Adds the same headers to every record, e.g. the name of the service that produced it. Headers
+/// the record already carries are left as they are.
+#[derive(Debug, Clone, Default)]
+pub struct HeadersInterceptor {
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl HeadersInterceptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+impl ProducerInterceptor for HeadersInterceptor {
+    fn on_send(&self, record: &mut OutgoingRecord) -> KafkaResult<()> {
+        for (name, value) in &self.headers {
+            if record.header(name).is_none() {
+                record.headers.push((name.clone(), value.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts sends by topic and outcome.
+pub struct MetricsInterceptor {
+    sends: Counter<u64>,
+}
+
+impl MetricsInterceptor {
+    pub fn new() -> Self {
+        let meter = global::meter("common-kafka");
+        Self {
+            sends: meter
+                .u64_counter("producer.sends")
+                .with_description("Records sent, by topic and outcome")
+                .build(),
+        }
+    }
+}
+
+impl Default for MetricsInterceptor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProducerInterceptor for MetricsInterceptor {
+    fn on_acknowledgement(&self, record: &OutgoingRecord, result: &KafkaResult<DeliveryReport>) {
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        self.sends.add(
+            1,
+            &[
+                KeyValue::new("topic", record.topic.clone()),
+                KeyValue::new("outcome", outcome),
+            ],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InboundConfig;
+    use crate::memory::{InMemoryBroker, InMemoryProducer};
+    use crate::producer::MessageProducer;
+    use common_error::error::KafkaError;
+    use std::sync::Mutex;
+
+    /// The key of an acknowledged record and whether it was delivered.
+    type Acknowledged = (Vec<u8>, bool);
+
+    /// Rejects records without a key and records the outcome of every send.
+    #[derive(Clone, Default)]
+    struct RequireKey {
+        acknowledged: Arc<Mutex<Vec<Acknowledged>>>,
+    }
+
+    impl ProducerInterceptor for RequireKey {
+        fn on_send(&self, record: &mut OutgoingRecord) -> KafkaResult<()> {
+            if record.key.is_empty() {
+                return Err(KafkaError::Rejected("record has no key".into()));
+            }
+            Ok(())
+        }
+
+        fn on_acknowledgement(
+            &self,
+            record: &OutgoingRecord,
+            result: &KafkaResult<DeliveryReport>,
+        ) {
+            self.acknowledged
+                .lock()
+                .unwrap()
+                .push((record.key.clone(), result.is_ok()));
+        }
+    }
+
+    fn producer(broker: &InMemoryBroker, interceptor: RequireKey) -> InMemoryProducer {
+        InMemoryProducer::new(broker, InboundConfig::new())
+            .with_interceptor(HeadersInterceptor::new().with_header("x-source", "test"))
+            .with_interceptor(interceptor)
+    }
+
+    #[tokio::test]
+    async fn on_send_error_rejects_the_send() {
+        let broker = InMemoryBroker::new();
+        let interceptor = RequireKey::default();
+        let producer = producer(&broker, interceptor.clone());
+
+        let result = producer.send_event(b"", b"{}").await;
+
+        assert!(matches!(result, Err(KafkaError::Rejected(_))));
+        assert!(broker.messages("INBOUND").is_empty());
+        assert!(interceptor.acknowledged.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn on_acknowledgement_sees_the_outcome() {
+        let broker = InMemoryBroker::new();
+        let interceptor = RequireKey::default();
+        let producer = producer(&broker, interceptor.clone());
+
+        producer.send_event(b"a", b"{}").await.unwrap();
+        broker.set_unavailable(true);
+        producer.send_event(b"b", b"{}").await.unwrap_err();
+
+        assert_eq!(
+            *interceptor.acknowledged.lock().unwrap(),
+            [(b"a".to_vec(), true), (b"b".to_vec(), false)]
+        );
+    }
+
+    #[tokio::test]
+    async fn headers_are_added_without_replacing_existing_ones() {
+        let broker = InMemoryBroker::new();
+        let producer = producer(&broker, RequireKey::default());
+
+        producer.send_to("INBOUND", b"a", b"{}", &[]).await.unwrap();
+        producer
+            .send_to("INBOUND", b"b", b"{}", &[("x-source", b"relay".as_slice())])
+            .await
+            .unwrap();
+
+        let messages = broker.messages("INBOUND");
+        assert_eq!(messages[0].header("x-source"), Some(b"test".as_slice()));
+        assert_eq!(messages[1].header("x-source"), Some(b"relay".as_slice()));
+    }
+}
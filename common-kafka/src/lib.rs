@@ -1,10 +1,34 @@
+pub mod backpressure;
+pub mod batch;
+pub mod circuit_breaker;
 pub mod config;
 pub mod consumer;
+pub mod interceptor;
+pub mod lookup;
+pub mod memory;
+pub mod message;
+pub mod middleware;
+pub mod partitioner;
 pub mod producer;
-
-use config::{FulfillmentConfig, InboundConfig};
-pub use consumer::{EventConsumer, MessageHandler};
-pub use producer::EventProducer;
+pub mod request_reply;
+pub mod retry;
+pub mod retry_topics;
+
+pub use backpressure::BackpressureConfig;
+pub use batch::{BatchConfig, BatchMessageHandler};
+pub use circuit_breaker::{CircuitBreakerConfig, CircuitState};
+pub use consumer::{
+    EventConsumer, MessageConsumer, MessageHandler, RebalanceEvent, TopicPartition,
+};
+pub use interceptor::{OutgoingRecord, ProducerInterceptor};
+pub use lookup::DeliveryLookup;
+pub use message::ConsumedMessage;
+pub use middleware::{HandlerBuilder, Middleware, Next};
+pub use partitioner::Partitioner;
+pub use producer::{DeliveryReport, EventProducer, MessageProducer};
+pub use request_reply::{RequestHandler, Requester, Responder};
+pub use retry::RetryPolicy;
+pub use retry_topics::{RetryStrategy, RetryTopicsConfig};
 
 use common_error::error::KafkaResult;
 
@@ -16,11 +40,10 @@ use async_trait::async_trait;
 ///
 /// Using a box isn't completely necessary here, but if we are going to be storing large data later on or using
 /// a polymorphic hierarchy
-
 pub struct MessagePrinter {}
 
 impl MessagePrinter {
-    fn new() -> Box<Self> {
+    pub fn new() -> Box<Self> {
         Box::new(MessagePrinter {})
     }
 }
@@ -38,27 +61,3 @@ impl MessageHandler for MessagePrinter {
         Ok(())
     }
 }
-
-/// This is the main function that processes the Kafka Messages concurrently.
-#[tokio::main]
-async fn main() -> KafkaResult<()> {
-    // Initialise the tracing library for structured logging.
-
-    tracing_subscriber::fmt::init();
-
-    // inbound_config: initiates a new config for the inbound consumer.
-    // fulfillment_config: initiates a new config for the fulfillment consumer.
-
-    let inbound_config = InboundConfig::new();
-    let fulfillment_config = FulfillmentConfig::new();
-
-    // inbound_consumer: creates a new consumer for the Inbound Pipeline
-    // fulfillment_consumer: creates a new consumer for the Fulfillment Pipeline.
-    let inbound_consumer = EventConsumer::new(inbound_config, MessagePrinter::new())?;
-    let fulfillment_consumer = EventConsumer::new(fulfillment_config, MessagePrinter::new())?;
-
-    // try_join! executes multiple async tasks and waits for all of them to complete, if any ask returns an error, it stops and propagates the error to the caller
-    tokio::try_join!(inbound_consumer.start(), fulfillment_consumer.start())?;
-
-    Ok(())
-}
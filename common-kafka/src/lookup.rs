@@ -0,0 +1,165 @@
+use crate::memory::InMemoryProducer;
+use crate::message::ConsumedMessage;
+use crate::producer::{DeliveryReport, EventProducer};
+use async_trait::async_trait;
+use common_error::error::{KafkaError, KafkaResult};
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::KafkaError as RDKafkaError;
+use rdkafka::{ClientConfig, Offset, TopicPartitionList};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Finds messages that were already published, so a producer that crashed before recording a send
+/// can tell whether the message made it to the broker instead of sending it twice.
+#[async_trait]
+pub trait DeliveryLookup: Send + Sync {
+    /// The first message in `topic` with a timestamp of at least `since` (in milliseconds) that
+    /// carries the header `name` with `value`.
+    async fn find_published(
+        &self,
+        topic: &str,
+        since: i64,
+        name: &str,
+        value: &[u8],
+    ) -> KafkaResult<Option<DeliveryReport>>;
+}
+
+#[async_trait]
+impl DeliveryLookup for EventProducer {
+    /// Reads every partition from the offset for `since` up to its high watermark, so this is only
+    /// cheap for recent timestamps.
+    async fn find_published(
+        &self,
+        topic: &str,
+        since: i64,
+        name: &str,
+        value: &[u8],
+    ) -> KafkaResult<Option<DeliveryReport>> {
+        let config = self.lookup_config().clone();
+        let timeout = self.timeout();
+        let (topic, name, value) = (topic.to_string(), name.to_string(), value.to_vec());
+
+        tokio::task::spawn_blocking(move || {
+            scan_partitions(&config, &topic, since, &name, &value, timeout)
+        })
+        .await
+        .map_err(|err| KafkaError::Timeout(format!("lookup task failed: {}", err)))?
+    }
+}
+
+fn scan_partitions(
+    config: &ClientConfig,
+    topic: &str,
+    since: i64,
+    name: &str,
+    value: &[u8],
+    timeout: Duration,
+) -> KafkaResult<Option<DeliveryReport>> {
+    let consumer: BaseConsumer = config.create().map_err(KafkaError::ClientCreation)?;
+
+    let metadata = consumer
+        .fetch_metadata(Some(topic), timeout)
+        .map_err(KafkaError::MessageDelivery)?;
+    let mut by_time = TopicPartitionList::new();
+    for partition in metadata
+        .topics()
+        .iter()
+        .filter(|metadata| metadata.name() == topic)
+        .flat_map(|metadata| metadata.partitions())
+    {
+        by_time
+            .add_partition_offset(topic, partition.id(), Offset::Offset(since))
+            .map_err(KafkaError::MessageDelivery)?;
+    }
+
+    let start = consumer
+        .offsets_for_times(by_time, timeout)
+        .map_err(KafkaError::MessageDelivery)?;
+
+    // Partition -> high watermark, for every partition with messages since the timestamp.
+    let mut remaining = HashMap::new();
+    let mut assignment = TopicPartitionList::new();
+    for element in start.elements() {
+        let Offset::Offset(offset) = element.offset() else {
+            continue;
+        };
+        let (_, high) = consumer
+            .fetch_watermarks(topic, element.partition(), timeout)
+            .map_err(KafkaError::MessageDelivery)?;
+        if offset < high {
+            assignment
+                .add_partition_offset(topic, element.partition(), Offset::Offset(offset))
+                .map_err(KafkaError::MessageDelivery)?;
+            remaining.insert(element.partition(), high);
+        }
+    }
+
+    if remaining.is_empty() {
+        return Ok(None);
+    }
+    consumer
+        .assign(&assignment)
+        .map_err(KafkaError::MessageDelivery)?;
+
+    let deadline = Instant::now() + timeout * remaining.len() as u32;
+    while !remaining.is_empty() {
+        if Instant::now() > deadline {
+            return Err(KafkaError::Timeout(format!(
+                "reading {} back did not finish within {:?}",
+                topic,
+                timeout * remaining.len() as u32
+            )));
+        }
+
+        let message = match consumer.poll(Duration::from_millis(100)) {
+            None => continue,
+            Some(Err(RDKafkaError::PartitionEOF(partition))) => {
+                remaining.remove(&partition);
+                continue;
+            }
+            Some(Err(e)) => return Err(KafkaError::MessageDelivery(e)),
+            Some(Ok(message)) => ConsumedMessage::from(&message),
+        };
+
+        if message.header(name) == Some(value) {
+            return Ok(Some(DeliveryReport {
+                topic: message.topic,
+                partition: message.partition,
+                offset: message.offset,
+                timestamp: message.timestamp.unwrap_or(since),
+            }));
+        }
+        if remaining
+            .get(&message.partition)
+            .is_some_and(|high| message.offset + 1 >= *high)
+        {
+            remaining.remove(&message.partition);
+        }
+    }
+
+    Ok(None)
+}
+
+#[async_trait]
+impl DeliveryLookup for InMemoryProducer {
+    async fn find_published(
+        &self,
+        topic: &str,
+        since: i64,
+        name: &str,
+        value: &[u8],
+    ) -> KafkaResult<Option<DeliveryReport>> {
+        Ok(self
+            .broker()
+            .messages(topic)
+            .into_iter()
+            .filter(|message| message.timestamp.unwrap_or_default() >= since)
+            .find(|message| message.header(name) == Some(value))
+            .map(|message| DeliveryReport {
+                topic: message.topic,
+                partition: message.partition,
+                offset: message.offset,
+                timestamp: message.timestamp.unwrap_or(since),
+            }))
+    }
+}
@@ -0,0 +1,38 @@
+use common_error::error::KafkaResult;
+use common_kafka::config::{FulfillmentConfig, InboundConfig};
+use common_kafka::middleware::{MetricsMiddleware, TracingMiddleware};
+use common_kafka::{EventConsumer, HandlerBuilder, MessagePrinter};
+
+/// This is the main function that processes the Kafka Messages concurrently.
+#[tokio::main]
+async fn main() -> KafkaResult<()> {
+    // Initialise the tracing library for structured logging.
+
+    tracing_subscriber::fmt::init();
+
+    // inbound_config: initiates a new config for the inbound consumer.
+    // fulfillment_config: initiates a new config for the fulfillment consumer.
+
+    let inbound_config = InboundConfig::new();
+    let fulfillment_config = FulfillmentConfig::new();
+
+    // inbound_consumer: creates a new consumer for the Inbound Pipeline
+    // fulfillment_consumer: creates a new consumer for the Fulfillment Pipeline.
+    // middleware: wraps the handlers of both pipelines in the same tracing and metrics, the printer
+    // already logs every message.
+    let middleware = HandlerBuilder::new()
+        .layer(TracingMiddleware)
+        .layer(MetricsMiddleware::new());
+
+    let inbound_consumer =
+        EventConsumer::new(inbound_config, middleware.handler(MessagePrinter::new()))?;
+    let fulfillment_consumer = EventConsumer::new(
+        fulfillment_config,
+        middleware.handler(MessagePrinter::new()),
+    )?;
+
+    // try_join! executes multiple async tasks and waits for all of them to complete, if any ask returns an error, it stops and propagates the error to the caller
+    tokio::try_join!(inbound_consumer.start(), fulfillment_consumer.start())?;
+
+    Ok(())
+}
@@ -0,0 +1,1092 @@
+//! An in-process stand-in for Kafka, for testing handlers, producers and consumers without a
+//! broker.
+//!
+//! Topics have partitions with offsets, consumers join groups that split the partitions between
+//! their members, and offsets are committed per group once a message succeeds, so uncommitted
+//! messages are delivered again to the next member that owns their partition. Retries, dead
+//! letter topics and backpressure go through the same code as `EventConsumer`.
+
+use crate::backpressure::{BackpressureConfig, Completion, FlowAction, FlowControl};
+use crate::batch::{BatchConfig, BatchMessageHandler, Batcher};
+use crate::config::KafkaConfigTrait;
+use crate::consumer::{
+    FailureRoute, MessageConsumer, MessageHandler, MessageProcessor, RebalanceEvent, TopicPartition,
+};
+use crate::interceptor::{Interceptors, ProducerInterceptor};
+use crate::message::ConsumedMessage;
+use crate::partitioner::{fnv1a, Partitioner};
+use crate::producer::{DeliveryReport, MessageProducer};
+use crate::retry_topics::RetryStrategy;
+use async_trait::async_trait;
+use chrono::Utc;
+use common_error::error::{KafkaError, KafkaResult};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use rdkafka::error::{KafkaError as RDKafkaError, RDKafkaErrorCode};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{interval, Instant};
+
+#[derive(Debug, Default)]
+struct GroupState {
+    members: Vec<u64>,
+    committed: HashMap<TopicPartition, i64>,
+    /// Bumped whenever members join or leave, so members know to fetch their new assignment.
+    generation: u64,
+}
+
+#[derive(Debug, Default)]
+struct BrokerState {
+    topics: HashMap<String, Vec<Vec<ConsumedMessage>>>,
+    groups: HashMap<String, GroupState>,
+    default_partitions: i32,
+    unavailable: bool,
+    closed: bool,
+}
+
+impl BrokerState {
+    fn partitions(&mut self, topic: &str) -> &mut Vec<Vec<ConsumedMessage>> {
+        let default_partitions = self.default_partitions.max(1) as usize;
+        self.topics
+            .entry(topic.to_string())
+            .or_insert_with(|| vec![Vec::new(); default_partitions])
+    }
+
+    /// Partitions of `topics` owned by `member`, spread round robin over the members in the order
+    /// they joined.
+    fn assignment(
+        &mut self,
+        group_id: &str,
+        member: u64,
+        topics: &[String],
+    ) -> Vec<TopicPartition> {
+        let mut partitions: Vec<TopicPartition> = topics
+            .iter()
+            .flat_map(|topic| {
+                let count = self.partitions(topic).len() as i32;
+                (0..count).map(move |partition| TopicPartition {
+                    topic: topic.clone(),
+                    partition,
+                })
+            })
+            .collect();
+        partitions.sort_by(|a, b| (&a.topic, a.partition).cmp(&(&b.topic, b.partition)));
+
+        let Some(group) = self.groups.get(group_id) else {
+            return Vec::new();
+        };
+        let Some(index) = group.members.iter().position(|id| *id == member) else {
+            return Vec::new();
+        };
+        let members = group.members.len();
+
+        partitions
+            .into_iter()
+            .enumerate()
+            .filter(|(position, _)| position % members == index)
+            .map(|(_, partition)| partition)
+            .collect()
+    }
+}
+
+/// A set of in-memory topics shared by the producers and consumers created from it.
+///
+/// Topics are created on first use with the default partition count, one unless changed with
+/// `with_default_partitions`.
+#[derive(Clone)]
+pub struct InMemoryBroker {
+    state: Arc<Mutex<BrokerState>>,
+    notify: Arc<Notify>,
+    next_member: Arc<AtomicU64>,
+}
+
+impl Default for InMemoryBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryBroker {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(BrokerState {
+                default_partitions: 1,
+                ..BrokerState::default()
+            })),
+            notify: Arc::new(Notify::new()),
+            next_member: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn with_default_partitions(self, partitions: i32) -> Self {
+        self.state.lock().unwrap().default_partitions = partitions;
+        self
+    }
+
+    /// Creates a topic, or grows an existing one to `partitions`. Groups consuming it pick up the
+    /// new partitions on their next rebalance.
+    pub fn create_topic(&self, topic: &str, partitions: i32) {
+        let mut state = self.state.lock().unwrap();
+        let existing = state.partitions(topic);
+        if existing.len() < partitions as usize {
+            existing.resize(partitions as usize, Vec::new());
+        }
+        for group in state.groups.values_mut() {
+            group.generation += 1;
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+
+    /// Every message in a topic, partition by partition in offset order.
+    pub fn messages(&self, topic: &str) -> Vec<ConsumedMessage> {
+        let state = self.state.lock().unwrap();
+        state
+            .topics
+            .get(topic)
+            .map(|partitions| partitions.iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The next offset a group will read from a partition, `None` if it never committed one.
+    pub fn committed_offset(&self, group_id: &str, topic: &str, partition: i32) -> Option<i64> {
+        let state = self.state.lock().unwrap();
+        state.groups.get(group_id).and_then(|group| {
+            group
+                .committed
+                .get(&TopicPartition {
+                    topic: topic.to_string(),
+                    partition,
+                })
+                .copied()
+        })
+    }
+
+    /// While unavailable, every send fails with a transient transport error, as if the broker
+    /// were down.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.state.lock().unwrap().unavailable = unavailable;
+    }
+
+    /// Ends every consumer's `start` once its in-flight messages are done, like a closed stream.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+    }
+
+    fn append(
+        &self,
+        topic: &str,
+        partition: Option<i32>,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport> {
+        let mut state = self.state.lock().unwrap();
+        if state.unavailable {
+            return Err(KafkaError::MessageSend(RDKafkaError::MessageProduction(
+                RDKafkaErrorCode::BrokerTransportFailure,
+            )));
+        }
+
+        let partitions = state.partitions(topic);
+        let count = partitions.len() as i32;
+        let partition = partition.unwrap_or_else(|| (fnv1a(key) % count as u64) as i32);
+        let log = partitions
+            .get_mut(partition as usize)
+            .ok_or(KafkaError::MessageSend(RDKafkaError::MessageProduction(
+                RDKafkaErrorCode::UnknownPartition,
+            )))?;
+
+        let offset = log.len() as i64;
+        let timestamp = Utc::now().timestamp_millis();
+        log.push(ConsumedMessage {
+            topic: topic.to_string(),
+            partition,
+            offset,
+            key: key.to_vec(),
+            payload: payload.to_vec(),
+            timestamp: Some(timestamp),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_vec()))
+                .collect(),
+        });
+        drop(state);
+        self.notify.notify_waiters();
+
+        Ok(DeliveryReport {
+            topic: topic.to_string(),
+            partition,
+            offset,
+            timestamp,
+        })
+    }
+
+    fn partition_count(&self, topic: &str) -> i32 {
+        self.state.lock().unwrap().partitions(topic).len() as i32
+    }
+
+    fn join(&self, group_id: &str) -> u64 {
+        let member = self.next_member.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        let group = state.groups.entry(group_id.to_string()).or_default();
+        group.members.push(member);
+        group.generation += 1;
+        drop(state);
+        self.notify.notify_waiters();
+        member
+    }
+
+    fn leave(&self, group_id: &str, member: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(group) = state.groups.get_mut(group_id) {
+            group.members.retain(|id| *id != member);
+            group.generation += 1;
+        }
+        drop(state);
+        self.notify.notify_waiters();
+    }
+}
+
+/// Publishes to an `InMemoryBroker`. Without a partitioner, records are spread by a hash of their
+/// key.
+#[derive(Clone)]
+pub struct InMemoryProducer {
+    broker: InMemoryBroker,
+    topic: String,
+    partitioner: Option<Arc<dyn Partitioner>>,
+    interceptors: Interceptors,
+}
+
+impl InMemoryProducer {
+    pub fn new<T: KafkaConfigTrait>(broker: &InMemoryBroker, config: T) -> Self {
+        Self {
+            broker: broker.clone(),
+            topic: config.topic().to_string(),
+            partitioner: None,
+            interceptors: Interceptors::default(),
+        }
+    }
+
+    pub fn with_partitioner(mut self, partitioner: impl Partitioner + 'static) -> Self {
+        self.partitioner = Some(Arc::new(partitioner));
+        self
+    }
+
+    pub fn with_interceptor(mut self, interceptor: impl ProducerInterceptor + 'static) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    pub fn broker(&self) -> &InMemoryBroker {
+        &self.broker
+    }
+
+    fn append(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport> {
+        let partition = self.partitioner.as_ref().and_then(|partitioner| {
+            partitioner.partition(key, payload, self.broker.partition_count(topic))
+        });
+        self.broker.append(topic, partition, key, payload, headers)
+    }
+}
+
+#[async_trait]
+impl MessageProducer for InMemoryProducer {
+    fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    async fn send_to(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport> {
+        let Some(record) = self
+            .interceptors
+            .before_send(topic, key, payload, headers)?
+        else {
+            return self.append(topic, key, payload, headers);
+        };
+
+        let result = self.append(
+            &record.topic,
+            &record.key,
+            &record.payload,
+            &record.header_refs(),
+        );
+        self.interceptors.after_send(&record, &result);
+        result
+    }
+
+    fn accepts_sends(&self) -> bool {
+        !self.broker.state.lock().unwrap().unavailable
+    }
+}
+
+/// Consumes from an `InMemoryBroker` as a member of a consumer group, with the same ordering,
+/// retry and backpressure behaviour as `EventConsumer`.
+///
+/// Leaves its group when dropped, handing its partitions to the remaining members.
+pub struct InMemoryConsumer {
+    broker: InMemoryBroker,
+    group_id: String,
+    member: u64,
+    topics: Vec<String>,
+    backpressure: BackpressureConfig,
+    window: usize,
+    processor: MessageProcessor,
+}
+
+impl InMemoryConsumer {
+    pub fn new<T: KafkaConfigTrait>(
+        broker: &InMemoryBroker,
+        config: T,
+        handler: Box<dyn MessageHandler>,
+    ) -> Self {
+        let topics = vec![config.topic().to_string()];
+        Self::build(
+            broker,
+            &config,
+            handler,
+            topics,
+            config.group_id().to_string(),
+        )
+    }
+
+    /// The in-memory counterpart of `EventConsumer::batched`.
+    pub fn batched<T: KafkaConfigTrait>(
+        broker: &InMemoryBroker,
+        config: T,
+        handler: Box<dyn BatchMessageHandler>,
+        batch: BatchConfig,
+    ) -> Self {
+        let mut consumer = Self::new(broker, config, Box::new(Batcher::new(handler, batch)));
+        consumer.window = batch.max_messages;
+        consumer
+    }
+
+    /// The in-memory counterpart of `EventConsumer::retry_consumer`.
+    pub fn retry_consumer<T: KafkaConfigTrait>(
+        broker: &InMemoryBroker,
+        config: T,
+        handler: Box<dyn MessageHandler>,
+    ) -> KafkaResult<Self> {
+        let RetryStrategy::RetryTopics(retry_topics) = config.retry_strategy() else {
+            return Err(KafkaError::Configuration(
+                "a retry consumer requires the RetryTopics retry strategy".into(),
+            ));
+        };
+
+        let topics = retry_topics
+            .retry_topics()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let group_id = format!("{}.retry", config.group_id());
+        Ok(Self::build(broker, &config, handler, topics, group_id))
+    }
+
+    /// The in-memory counterpart of `EventConsumer::reply_consumer`.
+    pub fn reply_consumer<T: KafkaConfigTrait>(
+        broker: &InMemoryBroker,
+        config: T,
+        reply_topic: &str,
+        handler: Box<dyn MessageHandler>,
+    ) -> Self {
+        let group_id = format!("{}.{}", config.group_id(), reply_topic);
+        Self::build(
+            broker,
+            &config,
+            handler,
+            vec![reply_topic.to_string()],
+            group_id,
+        )
+    }
+
+    fn build<T: KafkaConfigTrait>(
+        broker: &InMemoryBroker,
+        config: &T,
+        handler: Box<dyn MessageHandler>,
+        topics: Vec<String>,
+        group_id: String,
+    ) -> Self {
+        let Ok(failures) = FailureRoute::new(config.retry_strategy(), || {
+            Ok::<_, Infallible>(Arc::new(InMemoryProducer {
+                broker: broker.clone(),
+                topic: config.topic().to_string(),
+                partitioner: None,
+                interceptors: Interceptors::default(),
+            }))
+        });
+
+        Self {
+            broker: broker.clone(),
+            member: broker.join(&group_id),
+            group_id,
+            topics,
+            backpressure: config.backpressure(),
+            window: 1,
+            processor: MessageProcessor {
+                handler: Arc::from(handler),
+                retry_policy: config.retry_policy(),
+                failures,
+            },
+        }
+    }
+
+    /// Consumes until there is nothing left to do: every assigned partition is read to the end and
+    /// nothing is in flight or held back for a retry. With retry topics this waits out their
+    /// delays, which follow the wall clock like their not-before headers, so tests give their
+    /// tiers short delays rather than pausing Tokio's time.
+    pub async fn run_until_idle(&self) -> KafkaResult<()> {
+        self.run(true).await
+    }
+
+    async fn run(&self, until_idle: bool) -> KafkaResult<()> {
+        let mut session = Session {
+            flow: FlowControl::new(self.backpressure).with_window(self.window),
+            generation: None,
+            assigned: Vec::new(),
+            positions: HashMap::new(),
+            paused: HashSet::new(),
+        };
+        let mut in_flight = FuturesUnordered::new();
+        let mut resume_check = interval(Duration::from_millis(250));
+
+        loop {
+            // Registered before looking at the broker so no append or rebalance is missed.
+            let notified = self.broker.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let actions = self.rebalance(&mut session);
+            self.apply(&mut session, actions, &mut in_flight);
+            let fetched = self.fetch(&mut session, &mut in_flight);
+
+            if in_flight.is_empty() && !fetched {
+                let closed = self.broker.state.lock().unwrap().closed;
+                let idle = session.flow.in_flight() == 0 && self.at_end(&session);
+                if closed || (until_idle && idle) {
+                    break;
+                }
+            }
+
+            tokio::select! {
+                Some((generation, message, completion)) = in_flight.next() => {
+                    let partition = message.topic_partition();
+                    if let Some(completed) = session.flow.complete(generation, message, completion) {
+                        if let Some(offset) = completed.commit {
+                            self.commit(partition, offset);
+                        }
+                        self.apply(&mut session, completed.actions, &mut in_flight);
+                    }
+                }
+                _ = &mut notified, if !fetched => {}
+                _ = resume_check.tick() => {
+                    let actions = session.flow.due(Instant::now());
+                    self.apply(&mut session, actions, &mut in_flight);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Picks up assignment changes of the group, revoking and assigning partitions like a
+    /// rebalance of a real consumer.
+    fn rebalance(&self, session: &mut Session) -> Vec<FlowAction> {
+        let (generation, assignment) = {
+            let mut state = self.broker.state.lock().unwrap();
+            let generation = state
+                .groups
+                .get(&self.group_id)
+                .map(|group| group.generation);
+            if generation == session.generation {
+                return Vec::new();
+            }
+            let assignment = state.assignment(&self.group_id, self.member, &self.topics);
+            (generation, assignment)
+        };
+        session.generation = generation;
+
+        let revoked: Vec<TopicPartition> = session
+            .assigned
+            .iter()
+            .filter(|partition| !assignment.contains(partition))
+            .cloned()
+            .collect();
+        let added: Vec<TopicPartition> = assignment
+            .iter()
+            .filter(|partition| !session.assigned.contains(partition))
+            .cloned()
+            .collect();
+
+        let handler = &self.processor.handler;
+        let mut actions = Vec::new();
+        if !revoked.is_empty() {
+            let event = RebalanceEvent::Revoke(revoked.clone());
+            handler.pre_rebalance(&event);
+            actions = session.flow.revoke(&revoked);
+            for partition in &revoked {
+                session.positions.remove(partition);
+                session.paused.remove(partition);
+            }
+            handler.post_rebalance(&event);
+        }
+        if !added.is_empty() {
+            let event = RebalanceEvent::Assign(added.clone());
+            handler.pre_rebalance(&event);
+            let state = self.broker.state.lock().unwrap();
+            for partition in &added {
+                let committed = state
+                    .groups
+                    .get(&self.group_id)
+                    .and_then(|group| group.committed.get(partition).copied())
+                    .unwrap_or(0);
+                session.positions.insert(partition.clone(), committed);
+            }
+            drop(state);
+            handler.post_rebalance(&event);
+        }
+
+        session.assigned = assignment;
+        actions
+    }
+
+    /// Hands every fetchable message to flow control. Returns whether anything was fetched.
+    fn fetch<'a>(
+        &'a self,
+        session: &mut Session,
+        in_flight: &mut FuturesUnordered<HandlerFuture<'a>>,
+    ) -> bool {
+        let mut fetched = false;
+
+        for partition in session.assigned.clone() {
+            loop {
+                if session.paused.contains(&partition) {
+                    break;
+                }
+                let position = session.positions[&partition];
+                let message = {
+                    let state = self.broker.state.lock().unwrap();
+                    state
+                        .topics
+                        .get(&partition.topic)
+                        .and_then(|partitions| partitions.get(partition.partition as usize))
+                        .and_then(|log| log.get(position as usize))
+                        .cloned()
+                };
+                let Some(message) = message else {
+                    break;
+                };
+
+                session.positions.insert(partition.clone(), position + 1);
+                fetched = true;
+                let actions = session.flow.accept(message);
+                self.apply(session, actions, in_flight);
+            }
+        }
+
+        fetched
+    }
+
+    fn apply<'a>(
+        &'a self,
+        session: &mut Session,
+        actions: Vec<FlowAction>,
+        in_flight: &mut FuturesUnordered<HandlerFuture<'a>>,
+    ) {
+        for action in actions {
+            match action {
+                FlowAction::Dispatch(dispatch) => {
+                    in_flight.push(Box::pin(async move {
+                        let completion = self.processor.process(&dispatch.message).await;
+                        (dispatch.generation, dispatch.message, completion)
+                    }));
+                }
+                FlowAction::Pause(partitions) => session.paused.extend(partitions),
+                FlowAction::Resume(partitions) => {
+                    for partition in &partitions {
+                        session.paused.remove(partition);
+                    }
+                }
+                FlowAction::PauseAssigned => session.paused.extend(session.assigned.clone()),
+                FlowAction::ResumeAssigned { except } => {
+                    session
+                        .paused
+                        .retain(|partition| except.contains(partition));
+                }
+            }
+        }
+    }
+
+    fn commit(&self, partition: TopicPartition, offset: i64) {
+        let mut state = self.broker.state.lock().unwrap();
+        if let Some(group) = state.groups.get_mut(&self.group_id) {
+            group.committed.insert(partition, offset + 1);
+        }
+    }
+
+    fn at_end(&self, session: &Session) -> bool {
+        let state = self.broker.state.lock().unwrap();
+        session.assigned.iter().all(|partition| {
+            let end = state
+                .topics
+                .get(&partition.topic)
+                .and_then(|partitions| partitions.get(partition.partition as usize))
+                .map_or(0, |log| log.len() as i64);
+            session.positions.get(partition).copied().unwrap_or(0) >= end
+        })
+    }
+}
+
+#[async_trait]
+impl MessageConsumer for InMemoryConsumer {
+    /// Consumes until the broker is closed.
+    async fn start(&self) -> KafkaResult<()> {
+        self.run(false).await
+    }
+}
+
+impl Drop for InMemoryConsumer {
+    fn drop(&mut self) {
+        self.broker.leave(&self.group_id, self.member);
+    }
+}
+
+struct Session {
+    flow: FlowControl,
+    generation: Option<u64>,
+    assigned: Vec<TopicPartition>,
+    positions: HashMap<TopicPartition, i64>,
+    paused: HashSet<TopicPartition>,
+}
+
+type HandlerFuture<'a> = std::pin::Pin<
+    Box<dyn std::future::Future<Output = (u64, ConsumedMessage, Completion)> + Send + 'a>,
+>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InboundConfig;
+    use crate::retry::RetryPolicy;
+    use crate::retry_topics::{
+        RetryTier, RetryTopicsConfig, ERROR_HEADER, NOT_BEFORE_HEADER, ORIGINAL_OFFSET_HEADER,
+        ORIGINAL_TOPIC_HEADER, RETRY_ATTEMPT_HEADER,
+    };
+
+    /// Records the messages it handles and fails them with the errors queued for their offset.
+    #[derive(Clone, Default)]
+    struct Scripted {
+        handled: Arc<Mutex<Vec<(i32, i64)>>>,
+        failures: Arc<Mutex<HashMap<i64, Vec<KafkaError>>>>,
+    }
+
+    impl Scripted {
+        fn failing(self, offset: i64, errors: impl IntoIterator<Item = KafkaError>) -> Self {
+            self.failures
+                .lock()
+                .unwrap()
+                .insert(offset, errors.into_iter().collect());
+            self
+        }
+
+        fn handled(&self) -> Vec<(i32, i64)> {
+            self.handled.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl MessageHandler for Scripted {
+        async fn handle(&self, _key: &[u8], _payload: &[u8]) -> KafkaResult<()> {
+            unreachable!("handle_message is overridden")
+        }
+
+        async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+            self.handled
+                .lock()
+                .unwrap()
+                .push((message.partition, message.offset));
+            let error = self
+                .failures
+                .lock()
+                .unwrap()
+                .get_mut(&message.offset)
+                .and_then(Vec::pop);
+            error.map_or(Ok(()), Err)
+        }
+    }
+
+    fn config() -> InboundConfig {
+        InboundConfig::new()
+            .with_retry_policy(RetryPolicy::fixed(Duration::from_millis(10)).with_max_attempts(2))
+    }
+
+    fn timeout() -> KafkaError {
+        KafkaError::Timeout("downstream".into())
+    }
+
+    async fn publish(broker: &InMemoryBroker, keys: &[&str]) {
+        let producer = InMemoryProducer::new(broker, config());
+        for key in keys {
+            producer.send_event(key.as_bytes(), b"{}").await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commits_handled_messages() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a", "b", "c"]).await;
+
+        let handler = Scripted::default();
+        let consumer = InMemoryConsumer::new(&broker, config(), Box::new(handler.clone()));
+        consumer.run_until_idle().await.unwrap();
+
+        assert_eq!(handler.handled(), vec![(0, 0), (0, 1), (0, 2)]);
+        assert_eq!(
+            broker.committed_offset("INBOUND_GROUP", "INBOUND", 0),
+            Some(3)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_failures_in_place() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a", "b"]).await;
+
+        let handler = Scripted::default().failing(0, [timeout()]);
+        let consumer = InMemoryConsumer::new(&broker, config(), Box::new(handler.clone()));
+        consumer.run_until_idle().await.unwrap();
+
+        assert_eq!(handler.handled(), vec![(0, 0), (0, 0), (0, 1)]);
+        assert_eq!(
+            broker.committed_offset("INBOUND_GROUP", "INBOUND", 0),
+            Some(2)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_permanent_failures_to_the_dead_letter_topic() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a", "b"]).await;
+
+        let config = config().with_retry_strategy(RetryStrategy::Blocking {
+            dead_letter_topic: Some("INBOUND.DLQ".to_string()),
+        });
+        let handler = Scripted::default().failing(0, [KafkaError::Rejected("bad".into())]);
+        let consumer = InMemoryConsumer::new(&broker, config, Box::new(handler.clone()));
+        consumer.run_until_idle().await.unwrap();
+
+        // A permanent failure is not retried.
+        assert_eq!(handler.handled(), vec![(0, 0), (0, 1)]);
+        let dead_lettered = broker.messages("INBOUND.DLQ");
+        assert_eq!(dead_lettered.len(), 1);
+        assert_eq!(dead_lettered[0].key, b"a");
+        assert_eq!(
+            broker.committed_offset("INBOUND_GROUP", "INBOUND", 0),
+            Some(2)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_permanent_failures_without_a_dead_letter_topic() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a", "b"]).await;
+
+        let handler =
+            Scripted::default().failing(1, (0..100).map(|_| KafkaError::Rejected("bad".into())));
+        let consumer = InMemoryConsumer::new(&broker, config(), Box::new(handler.clone()));
+        let idle = tokio::time::timeout(Duration::from_secs(10), consumer.run_until_idle()).await;
+
+        assert!(
+            idle.is_err(),
+            "the failing message must hold up the partition"
+        );
+        assert_eq!(
+            broker.committed_offset("INBOUND_GROUP", "INBOUND", 0),
+            Some(1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn uncommitted_messages_go_to_the_next_member() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a", "b", "c"]).await;
+
+        let failing = Scripted::default().failing(1, (0..100).map(|_| timeout()));
+        let first = InMemoryConsumer::new(&broker, config(), Box::new(failing.clone()));
+        let idle = tokio::time::timeout(Duration::from_secs(10), first.run_until_idle()).await;
+        assert!(idle.is_err());
+        drop(first);
+
+        let handler = Scripted::default();
+        let second = InMemoryConsumer::new(&broker, config(), Box::new(handler.clone()));
+        second.run_until_idle().await.unwrap();
+
+        // Offset 0 was committed by the first member, the rest is delivered again.
+        assert_eq!(handler.handled(), vec![(0, 1), (0, 2)]);
+        assert_eq!(
+            broker.committed_offset("INBOUND_GROUP", "INBOUND", 0),
+            Some(3)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rebalance_hands_over_partitions_at_their_committed_offset() {
+        let broker = InMemoryBroker::new().with_default_partitions(2);
+        let keys = ["a", "b", "c", "d", "e", "f"];
+        publish(&broker, &keys).await;
+        let on = |partition| {
+            broker
+                .messages("INBOUND")
+                .iter()
+                .filter(|message| message.partition == partition)
+                .count()
+        };
+        assert!(on(0) > 0 && on(1) > 0, "keys should cover both partitions");
+
+        let first = Scripted::default();
+        let second = Scripted::default();
+        let first_consumer = InMemoryConsumer::new(&broker, config(), Box::new(first.clone()));
+        let second_consumer = InMemoryConsumer::new(&broker, config(), Box::new(second.clone()));
+        first_consumer.run_until_idle().await.unwrap();
+        second_consumer.run_until_idle().await.unwrap();
+
+        assert!(first.handled().iter().all(|(partition, _)| *partition == 0));
+        assert!(second
+            .handled()
+            .iter()
+            .all(|(partition, _)| *partition == 1));
+        assert_eq!(first.handled().len(), on(0));
+        assert_eq!(second.handled().len(), on(1));
+
+        // The first member takes over partition 1 where the second one committed, so nothing is
+        // handled twice.
+        drop(second_consumer);
+        publish(&broker, &keys).await;
+        first_consumer.run_until_idle().await.unwrap();
+
+        assert_eq!(
+            first.handled().len() + second.handled().len(),
+            2 * keys.len()
+        );
+        assert_eq!(
+            broker.committed_offset("INBOUND_GROUP", "INBOUND", 1),
+            Some(on(1) as i64)
+        );
+    }
+
+    /// Fails every message with a copy of `error`, recording each with the time it was handled.
+    #[derive(Clone)]
+    struct AlwaysFailing {
+        error: fn() -> KafkaError,
+        handled: Arc<Mutex<Vec<(ConsumedMessage, i64)>>>,
+    }
+
+    impl AlwaysFailing {
+        fn new(error: fn() -> KafkaError) -> Self {
+            Self {
+                error,
+                handled: Arc::default(),
+            }
+        }
+
+        fn handled(&self) -> Vec<(ConsumedMessage, i64)> {
+            self.handled.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl MessageHandler for AlwaysFailing {
+        async fn handle(&self, _key: &[u8], _payload: &[u8]) -> KafkaResult<()> {
+            unreachable!("handle_message is overridden")
+        }
+
+        async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+            let now = Utc::now().timestamp_millis();
+            self.handled.lock().unwrap().push((message.clone(), now));
+            Err((self.error)())
+        }
+    }
+
+    /// Short tiers, so the retry topics run on the wall clock their not-before headers use.
+    fn retry_topics_config() -> InboundConfig {
+        let tier = |topic: &str, millis| RetryTier {
+            topic: topic.to_string(),
+            delay: Duration::from_millis(millis),
+        };
+        config().with_retry_strategy(RetryStrategy::RetryTopics(RetryTopicsConfig {
+            tiers: vec![
+                tier("INBOUND.retry.1", 20),
+                tier("INBOUND.retry.2", 40),
+                tier("INBOUND.retry.3", 60),
+            ],
+            dead_letter_topic: "INBOUND.dlq".to_string(),
+        }))
+    }
+
+    fn header<'a>(message: &'a ConsumedMessage, name: &str) -> Option<&'a str> {
+        message
+            .header(name)
+            .map(|value| std::str::from_utf8(value).unwrap())
+    }
+
+    async fn publish_with_headers(broker: &InMemoryBroker) {
+        InMemoryProducer::new(broker, config())
+            .send_to(
+                "INBOUND",
+                b"a",
+                b"{}",
+                &[("x-outbox-id", b"42"), ("x-event-type", b"stock_received")],
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn run_with_retry_topics(broker: &InMemoryBroker, handler: &AlwaysFailing) {
+        let consumer =
+            InMemoryConsumer::new(broker, retry_topics_config(), Box::new(handler.clone()));
+        consumer.run_until_idle().await.unwrap();
+        let retry_consumer = InMemoryConsumer::retry_consumer(
+            broker,
+            retry_topics_config(),
+            Box::new(handler.clone()),
+        )
+        .unwrap();
+        retry_consumer.run_until_idle().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_topics_walk_the_tiers_then_dead_letter() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a"]).await;
+
+        let handler = AlwaysFailing::new(timeout);
+        run_with_retry_topics(&broker, &handler).await;
+
+        let topics: Vec<String> = handler
+            .handled()
+            .iter()
+            .map(|(message, _)| message.topic.clone())
+            .collect();
+        assert_eq!(
+            topics,
+            vec![
+                "INBOUND",
+                "INBOUND.retry.1",
+                "INBOUND.retry.2",
+                "INBOUND.retry.3"
+            ]
+        );
+        for (topic, attempt) in [
+            ("INBOUND.retry.1", "1"),
+            ("INBOUND.retry.2", "2"),
+            ("INBOUND.retry.3", "3"),
+            ("INBOUND.dlq", "4"),
+        ] {
+            let messages = broker.messages(topic);
+            assert_eq!(messages.len(), 1, "{}", topic);
+            assert_eq!(header(&messages[0], RETRY_ATTEMPT_HEADER), Some(attempt));
+            assert_eq!(header(&messages[0], ORIGINAL_TOPIC_HEADER), Some("INBOUND"));
+            assert_eq!(header(&messages[0], ORIGINAL_OFFSET_HEADER), Some("0"));
+        }
+        // Only retry topics wait, the dead letter topic keeps the message as it is.
+        assert_eq!(
+            header(&broker.messages("INBOUND.dlq")[0], NOT_BEFORE_HEADER),
+            None
+        );
+        assert_eq!(
+            broker.committed_offset("INBOUND_GROUP", "INBOUND", 0),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn retried_messages_wait_for_their_not_before_time() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a"]).await;
+
+        let handler = AlwaysFailing::new(timeout);
+        run_with_retry_topics(&broker, &handler).await;
+
+        let retried: Vec<(ConsumedMessage, i64)> = handler.handled().into_iter().skip(1).collect();
+        assert_eq!(retried.len(), 3);
+        for (message, handled_at) in retried {
+            let not_before: i64 = header(&message, NOT_BEFORE_HEADER)
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(
+                handled_at >= not_before,
+                "{} was handled {}ms early",
+                message.topic,
+                not_before - handled_at
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_skip_the_retry_topics() {
+        let broker = InMemoryBroker::new();
+        publish(&broker, &["a"]).await;
+
+        let handler = AlwaysFailing::new(|| KafkaError::Rejected("bad".into()));
+        run_with_retry_topics(&broker, &handler).await;
+
+        assert_eq!(handler.handled().len(), 1);
+        assert!(broker.messages("INBOUND.retry.1").is_empty());
+        let dead_lettered = broker.messages("INBOUND.dlq");
+        assert_eq!(dead_lettered.len(), 1);
+        assert_eq!(header(&dead_lettered[0], RETRY_ATTEMPT_HEADER), Some("1"));
+        assert_eq!(
+            header(&dead_lettered[0], ERROR_HEADER),
+            Some("Message rejected: bad")
+        );
+    }
+
+    #[tokio::test]
+    async fn republished_messages_keep_their_headers() {
+        let broker = InMemoryBroker::new();
+        publish_with_headers(&broker).await;
+
+        let handler = AlwaysFailing::new(timeout);
+        run_with_retry_topics(&broker, &handler).await;
+
+        for topic in [
+            "INBOUND.retry.1",
+            "INBOUND.retry.2",
+            "INBOUND.retry.3",
+            "INBOUND.dlq",
+        ] {
+            let message = &broker.messages(topic)[0];
+            assert_eq!(header(message, "x-outbox-id"), Some("42"), "{}", topic);
+            assert_eq!(
+                header(message, "x-event-type"),
+                Some("stock_received"),
+                "{}",
+                topic
+            );
+            // Retry headers are replaced rather than piled up.
+            let attempts = message
+                .headers
+                .iter()
+                .filter(|(key, _)| key == RETRY_ATTEMPT_HEADER)
+                .count();
+            assert_eq!(attempts, 1, "{}", topic);
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Middleware for `MessageHandler`s, so cross-cutting concerns such as logging, metrics,
+//! deduplication and validation are written once and stacked around any handler.
+//!
+//! ```no_run
+//! # use common_kafka::config::InboundConfig;
+//! # use common_kafka::middleware::{DedupMiddleware, MetricsMiddleware, TracingMiddleware};
+//! # use common_kafka::{EventConsumer, HandlerBuilder, MessagePrinter};
+//! # fn main() -> common_error::error::KafkaResult<()> {
+//! let handler = HandlerBuilder::new()
+//!     .layer(TracingMiddleware)
+//!     .layer(MetricsMiddleware::new())
+//!     .layer(DedupMiddleware::new("x-outbox-id", 10_000))
+//!     .handler(MessagePrinter::new());
+//! let consumer = EventConsumer::new(InboundConfig::new(), handler)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::consumer::{MessageHandler, RebalanceEvent};
+use crate::message::ConsumedMessage;
+use async_trait::async_trait;
+use common_error::classification::ErrorClass;
+use common_error::error::{KafkaError, KafkaResult};
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::{global, KeyValue};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::Instrument;
+
+/// Runs around a handler. Calling `next.run` hands the message on to the next middleware, or to
+/// the handler itself, not calling it skips the message.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(&self, message: &ConsumedMessage, next: Next<'_>) -> KafkaResult<()>;
+}
+
+/// The rest of the stack below a middleware.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    handler: &'a dyn MessageHandler,
+}
+
+impl Next<'_> {
+    pub async fn run(self, message: &ConsumedMessage) -> KafkaResult<()> {
+        self.handler.handle_message(message).await
+    }
+}
+
+/// Stacks middleware around handlers. The first middleware added is the outermost one: it sees
+/// every message first and its outcome last.
+///
+/// The builder can be cloned and reused for any number of handlers, the middleware is shared
+/// between them.
+#[derive(Clone, Default)]
+pub struct HandlerBuilder {
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl HandlerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn layer(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn handler(&self, handler: Box<dyn MessageHandler>) -> Box<dyn MessageHandler> {
+        self.middleware
+            .iter()
+            .rev()
+            .fold(handler, |inner, middleware| {
+                Box::new(Layered {
+                    middleware: middleware.clone(),
+                    inner,
+                })
+            })
+    }
+}
+
+struct Layered {
+    middleware: Arc<dyn Middleware>,
+    inner: Box<dyn MessageHandler>,
+}
+
+#[async_trait]
+impl MessageHandler for Layered {
+    async fn handle(&self, key: &[u8], payload: &[u8]) -> KafkaResult<()> {
+        self.inner.handle(key, payload).await
+    }
+
+    async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+        let next = Next {
+            handler: self.inner.as_ref(),
+        };
+        self.middleware.handle(message, next).await
+    }
+
+    fn pre_rebalance(&self, event: &RebalanceEvent) {
+        self.inner.pre_rebalance(event);
+    }
+
+    fn post_rebalance(&self, event: &RebalanceEvent) {
+        self.inner.post_rebalance(event);
+    }
+}
+
+/// Logs every message and how handling it went.
+#[derive(Debug, Clone, Default)]
+pub struct LoggingMiddleware;
+
+#[async_trait]
+impl Middleware for LoggingMiddleware {
+    async fn handle(&self, message: &ConsumedMessage, next: Next<'_>) -> KafkaResult<()> {
+        tracing::info!(
+            "Handling {}[{}]@{} with key {}",
+            message.topic,
+            message.partition,
+            message.offset,
+            String::from_utf8_lossy(&message.key)
+        );
+
+        let started = Instant::now();
+        let result = next.run(message).await;
+        match &result {
+            Ok(()) => tracing::info!(
+                "Handled {}[{}]@{} in {:?}",
+                message.topic,
+                message.partition,
+                message.offset,
+                started.elapsed()
+            ),
+            Err(e) => tracing::warn!(
+                "Failed to handle {}[{}]@{}: {}",
+                message.topic,
+                message.partition,
+                message.offset,
+                e
+            ),
+        }
+        result
+    }
+}
+
+/// Runs the rest of the stack in a `kafka.consume` span carrying the message's position.
+#[derive(Debug, Clone, Default)]
+pub struct TracingMiddleware;
+
+#[async_trait]
+impl Middleware for TracingMiddleware {
+    async fn handle(&self, message: &ConsumedMessage, next: Next<'_>) -> KafkaResult<()> {
+        let span = tracing::info_span!(
+            "kafka.consume",
+            topic = %message.topic,
+            partition = message.partition,
+            offset = message.offset,
+        );
+        next.run(message).instrument(span).await
+    }
+}
+
+/// Counts handled messages by topic and outcome and records how long handling took.
+pub struct MetricsMiddleware {
+    messages: Counter<u64>,
+    duration: Histogram<f64>,
+}
+
+impl MetricsMiddleware {
+    pub fn new() -> Self {
+        let meter = global::meter("common-kafka");
+        Self {
+            messages: meter
+                .u64_counter("consumer.messages")
+                .with_description("Handled messages, by topic and outcome")
+                .build(),
+            duration: meter
+                .f64_histogram("consumer.handle.duration")
+                .with_description("Time spent handling a message")
+                .with_unit("s")
+                .build(),
+        }
+    }
+}
+
+impl Default for MetricsMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Middleware for MetricsMiddleware {
+    async fn handle(&self, message: &ConsumedMessage, next: Next<'_>) -> KafkaResult<()> {
+        let started = Instant::now();
+        let result = next.run(message).await;
+
+        let outcome = match &result {
+            Ok(()) => "ok",
+            Err(e) => match e.class() {
+                ErrorClass::Transient => "transient",
+                ErrorClass::Permanent => "permanent",
+                ErrorClass::Throttled => "throttled",
+            },
+        };
+        let attributes = [
+            KeyValue::new("topic", message.topic.clone()),
+            KeyValue::new("outcome", outcome),
+        ];
+        self.messages.add(1, &attributes);
+        self.duration
+            .record(started.elapsed().as_secs_f64(), &attributes);
+
+        result
+    }
+}
+
+/// Skips messages whose value of a header, such as the outbox id, was already handled.
+///
+/// Remembers the last `capacity` values handled by this consumer only, so it catches the
+/// redeliveries of a rebalance or a relay restart, not duplicates handled by other members.
+pub struct DedupMiddleware {
+    header: String,
+    capacity: usize,
+    seen: Mutex<Seen>,
+}
+
+/// Handled header values, and the order they were handled in to forget the oldest.
+#[derive(Default)]
+struct Seen {
+    values: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl DedupMiddleware {
+    pub fn new(header: impl Into<String>, capacity: usize) -> Self {
+        Self {
+            header: header.into(),
+            capacity: capacity.max(1),
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    fn remember(&self, value: &[u8]) {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.values.insert(value.to_vec()) {
+            seen.order.push_back(value.to_vec());
+        }
+        while seen.order.len() > self.capacity {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.values.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for DedupMiddleware {
+    async fn handle(&self, message: &ConsumedMessage, next: Next<'_>) -> KafkaResult<()> {
+        let Some(value) = message.header(&self.header) else {
+            return next.run(message).await;
+        };
+
+        if self.seen.lock().unwrap().values.contains(value) {
+            tracing::info!(
+                "Skipping duplicate {}[{}]@{} with {} {}",
+                message.topic,
+                message.partition,
+                message.offset,
+                self.header,
+                String::from_utf8_lossy(value)
+            );
+            return Ok(());
+        }
+
+        // Only remembered once handled, a failed message has to be handled again.
+        next.run(message).await?;
+        self.remember(value);
+        Ok(())
+    }
+}
+
+/// Rejects messages that fail a check before they reach the handler. Rejected messages are not
+/// retried and go to the dead letter topic, if there is one.
+pub struct ValidationMiddleware<F> {
+    validate: F,
+}
+
+impl<F> ValidationMiddleware<F>
+where
+    F: Fn(&ConsumedMessage) -> Result<(), String> + Send + Sync,
+{
+    pub fn new(validate: F) -> Self {
+        Self { validate }
+    }
+}
+
+#[async_trait]
+impl<F> Middleware for ValidationMiddleware<F>
+where
+    F: Fn(&ConsumedMessage) -> Result<(), String> + Send + Sync,
+{
+    async fn handle(&self, message: &ConsumedMessage, next: Next<'_>) -> KafkaResult<()> {
+        (self.validate)(message).map_err(KafkaError::Rejected)?;
+        next.run(message).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Logs when a message passes it on the way in and on the way out.
+    struct Named {
+        name: &'static str,
+        log: Log,
+    }
+
+    #[async_trait]
+    impl Middleware for Named {
+        async fn handle(&self, message: &ConsumedMessage, next: Next<'_>) -> KafkaResult<()> {
+            self.log.lock().unwrap().push(format!("{} in", self.name));
+            let result = next.run(message).await;
+            self.log.lock().unwrap().push(format!("{} out", self.name));
+            result
+        }
+    }
+
+    /// Logs every message it handles and fails the ones with the payload "fail".
+    struct Handler {
+        log: Log,
+    }
+
+    #[async_trait]
+    impl MessageHandler for Handler {
+        async fn handle(&self, _key: &[u8], payload: &[u8]) -> KafkaResult<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("handled {}", String::from_utf8_lossy(payload)));
+            match payload {
+                b"fail" => Err(KafkaError::Timeout("downstream".into())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn message(id: &str, payload: &str) -> ConsumedMessage {
+        ConsumedMessage {
+            topic: "INBOUND".to_string(),
+            partition: 0,
+            offset: 0,
+            key: Vec::new(),
+            payload: payload.as_bytes().to_vec(),
+            timestamp: None,
+            headers: vec![("x-outbox-id".to_string(), id.as_bytes().to_vec())],
+        }
+    }
+
+    fn handled(log: &Log) -> Vec<String> {
+        log.lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.starts_with("handled"))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn first_layer_is_outermost() {
+        let log = Log::default();
+        let handler = HandlerBuilder::new()
+            .layer(Named {
+                name: "outer",
+                log: log.clone(),
+            })
+            .layer(Named {
+                name: "inner",
+                log: log.clone(),
+            })
+            .handler(Box::new(Handler { log: log.clone() }));
+
+        handler.handle_message(&message("1", "a")).await.unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "outer in",
+                "inner in",
+                "handled a",
+                "inner out",
+                "outer out"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dedup_skips_handled_values_but_not_failed_ones() {
+        let log = Log::default();
+        let handler = HandlerBuilder::new()
+            .layer(DedupMiddleware::new("x-outbox-id", 10))
+            .handler(Box::new(Handler { log: log.clone() }));
+
+        handler.handle_message(&message("1", "a")).await.unwrap();
+        handler.handle_message(&message("1", "a")).await.unwrap();
+        handler
+            .handle_message(&message("2", "fail"))
+            .await
+            .unwrap_err();
+        handler
+            .handle_message(&message("2", "fail"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(handled(&log), ["handled a", "handled fail", "handled fail"]);
+    }
+
+    #[tokio::test]
+    async fn dedup_forgets_the_oldest_values_beyond_capacity() {
+        let log = Log::default();
+        let handler = HandlerBuilder::new()
+            .layer(DedupMiddleware::new("x-outbox-id", 2))
+            .handler(Box::new(Handler { log: log.clone() }));
+
+        for id in ["1", "2", "3", "1", "3"] {
+            handler.handle_message(&message(id, id)).await.unwrap();
+        }
+
+        // 1 was forgotten when 3 came in, 3 is still remembered.
+        assert_eq!(
+            handled(&log),
+            ["handled 1", "handled 2", "handled 3", "handled 1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn validation_rejects_before_the_handler() {
+        let log = Log::default();
+        let handler = HandlerBuilder::new()
+            .layer(ValidationMiddleware::new(
+                |message: &ConsumedMessage| match message.payload.is_empty() {
+                    true => Err("empty payload".to_string()),
+                    false => Ok(()),
+                },
+            ))
+            .handler(Box::new(Handler { log: log.clone() }));
+
+        let result = handler.handle_message(&message("1", "")).await;
+
+        assert!(matches!(result, Err(KafkaError::Rejected(_))));
+        assert!(handled(&log).is_empty());
+    }
+}
@@ -0,0 +1,364 @@
+use common_error::error::{KafkaError, KafkaResult};
+use rdkafka::producer::{FutureProducer, Producer};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Picks the partition a record is produced to.
+///
+/// Returning `None` leaves the choice to librdkafka, which hashes the record key.
+pub trait Partitioner: Send + Sync {
+    fn partition(&self, key: &[u8], payload: &[u8], partition_count: i32) -> Option<i32>;
+}
+
+/// Keeps every event for a shipment on the same partition, so consumers see them in order.
+///
+/// The shipment is read from the payload's `shipment_id` field, records without one fall back to
+/// the default partitioning.
+#[derive(Debug, Clone, Default)]
+pub struct ShipmentPartitioner;
+
+impl Partitioner for ShipmentPartitioner {
+    fn partition(&self, _key: &[u8], payload: &[u8], partition_count: i32) -> Option<i32> {
+        let shipment_id = payload_field(payload, "shipment_id")?;
+        Some(modulo(fnv1a(shipment_id.as_bytes()), partition_count))
+    }
+}
+
+/// Keeps every event for a dock on the same partition, using the payload's `warehouse_id` and
+/// `dock_id` fields. Dock ids are only unique within a warehouse, so both are hashed.
+#[derive(Debug, Clone, Default)]
+pub struct DockPartitioner;
+
+impl Partitioner for DockPartitioner {
+    fn partition(&self, _key: &[u8], payload: &[u8], partition_count: i32) -> Option<i32> {
+        let warehouse_id = payload_field(payload, "warehouse_id")?;
+        let dock_id = payload_field(payload, "dock_id")?;
+        let dock = format!("{}/{}", warehouse_id, dock_id);
+        Some(modulo(fnv1a(dock.as_bytes()), partition_count))
+    }
+}
+
+/// Sends every record to the same partition.
+///
+/// A partition the topic does not have is clamped to its last partition, or the first for a
+/// negative one, rather than failing every send.
+#[derive(Debug, Clone)]
+pub struct ExplicitPartitioner(pub i32);
+
+impl Partitioner for ExplicitPartitioner {
+    fn partition(&self, _key: &[u8], _payload: &[u8], partition_count: i32) -> Option<i32> {
+        Some(self.0.clamp(0, partition_count.max(1) - 1))
+    }
+}
+
+/// Jump consistent hashing of the record key, or of a payload field if one is given.
+///
+/// Unlike a plain modulo, adding partitions only moves the keys that land on the new partitions,
+/// every other key keeps its partition.
+#[derive(Debug, Clone, Default)]
+pub struct ConsistentHashPartitioner {
+    field: Option<String>,
+}
+
+impl ConsistentHashPartitioner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn by_field(field: impl Into<String>) -> Self {
+        Self {
+            field: Some(field.into()),
+        }
+    }
+}
+
+impl Partitioner for ConsistentHashPartitioner {
+    fn partition(&self, key: &[u8], payload: &[u8], partition_count: i32) -> Option<i32> {
+        let hash = match &self.field {
+            Some(field) => fnv1a(payload_field(payload, field)?.as_bytes()),
+            None => fnv1a(key),
+        };
+        Some(jump_hash(hash, partition_count))
+    }
+}
+
+/// A JSON payload field as a string, whether it holds a string or a number.
+fn payload_field(payload: &[u8], field: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    match value.get(field)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// FNV-1a, stable across builds and platforms unlike the std hasher.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn modulo(hash: u64, partition_count: i32) -> i32 {
+    (hash % partition_count.max(1) as u64) as i32
+}
+
+/// Lamping and Veach's jump consistent hash.
+fn jump_hash(mut key: u64, buckets: i32) -> i32 {
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+
+    while next < i64::from(buckets.max(1)) {
+        bucket = next;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+
+    bucket as i32
+}
+
+const PARTITION_COUNT_TTL: Duration = Duration::from_secs(60);
+
+/// Partition counts per topic, read from the cluster metadata and refreshed once a minute so
+/// partitions added to a topic are picked up.
+#[derive(Clone, Default)]
+pub(crate) struct PartitionCounts {
+    counts: Arc<Mutex<HashMap<String, (i32, Instant)>>>,
+    /// Topics whose count is being fetched in the background.
+    refreshing: Arc<Mutex<HashSet<String>>>,
+}
+
+impl PartitionCounts {
+    pub(crate) async fn get(
+        &self,
+        producer: &FutureProducer,
+        topic: &str,
+        timeout: Duration,
+    ) -> KafkaResult<i32> {
+        if let Some(count) = self.cached(topic) {
+            return Ok(count);
+        }
+
+        let producer = producer.clone();
+        let owned_topic = topic.to_string();
+        let count = tokio::task::spawn_blocking(move || {
+            fetch_partition_count(&producer, &owned_topic, timeout)
+        })
+        .await
+        .map_err(|err| KafkaError::Timeout(format!("metadata task failed: {}", err)))??;
+
+        self.store(topic, count);
+        Ok(count)
+    }
+
+    /// The last count fetched for `topic`, even if it is due for a refresh, without waiting for
+    /// the cluster. A missing or expired count is fetched in the background, so this has to be
+    /// called from within a Tokio runtime.
+    pub(crate) fn get_or_refresh(
+        &self,
+        producer: &FutureProducer,
+        topic: &str,
+        timeout: Duration,
+    ) -> Option<i32> {
+        let stored = self.counts.lock().unwrap().get(topic).copied();
+        let expired =
+            stored.is_none_or(|(_, fetched_at)| fetched_at.elapsed() >= PARTITION_COUNT_TTL);
+        if expired && self.refreshing.lock().unwrap().insert(topic.to_string()) {
+            let counts = self.clone();
+            let producer = producer.clone();
+            let topic = topic.to_string();
+            tokio::task::spawn_blocking(move || {
+                match fetch_partition_count(&producer, &topic, timeout) {
+                    Ok(count) => counts.store(&topic, count),
+                    Err(e) => {
+                        tracing::warn!("Failed to fetch the partition count of {}: {}", topic, e)
+                    }
+                }
+                counts.refreshing.lock().unwrap().remove(&topic);
+            });
+        }
+        stored.map(|(count, _)| count)
+    }
+
+    fn cached(&self, topic: &str) -> Option<i32> {
+        let counts = self.counts.lock().unwrap();
+        counts
+            .get(topic)
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < PARTITION_COUNT_TTL)
+            .map(|(count, _)| *count)
+    }
+
+    fn store(&self, topic: &str, count: i32) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.insert(topic.to_string(), (count, Instant::now()));
+    }
+}
+
+fn fetch_partition_count(
+    producer: &FutureProducer,
+    topic: &str,
+    timeout: Duration,
+) -> KafkaResult<i32> {
+    let metadata = producer
+        .client()
+        .fetch_metadata(Some(topic), timeout)
+        .map_err(KafkaError::MessageSend)?;
+
+    metadata
+        .topics()
+        .iter()
+        .find(|metadata| metadata.name() == topic)
+        .map(|metadata| metadata.partitions().len() as i32)
+        .filter(|count| *count > 0)
+        // Possibly a topic that is still being created, worth another try.
+        .ok_or_else(|| KafkaError::Timeout(format!("no partition metadata for topic {}", topic)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> impl Iterator<Item = u64> {
+        (0..10_000u64).map(|key| fnv1a(format!("shipment-{}", key).as_bytes()))
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn jump_hash_only_moves_keys_onto_added_partitions() {
+        for buckets in 1..32 {
+            for key in keys() {
+                let before = jump_hash(key, buckets);
+                let after = jump_hash(key, buckets + 1);
+                assert!(
+                    after == before || after == buckets,
+                    "key {} moved from {} to {} going to {} partitions",
+                    key,
+                    before,
+                    after,
+                    buckets + 1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn jump_hash_spreads_keys_over_every_partition() {
+        let mut counts = [0usize; 8];
+        for key in keys() {
+            counts[jump_hash(key, 8) as usize] += 1;
+        }
+        // 1250 each if perfectly even.
+        assert!(
+            counts.iter().all(|count| (1000..1500).contains(count)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn jump_hash_handles_degenerate_partition_counts() {
+        for key in keys().take(100) {
+            assert_eq!(jump_hash(key, 1), 0);
+            assert_eq!(jump_hash(key, 0), 0);
+            assert_eq!(jump_hash(key, -3), 0);
+        }
+    }
+
+    #[test]
+    fn payload_fields_are_read_as_strings_or_numbers() {
+        assert_eq!(
+            payload_field(br#"{"shipment_id": 42}"#, "shipment_id"),
+            Some("42".to_string())
+        );
+        assert_eq!(
+            payload_field(br#"{"shipment_id": "42"}"#, "shipment_id"),
+            Some("42".to_string())
+        );
+        assert_eq!(
+            payload_field(br#"{"shipment_id": true}"#, "shipment_id"),
+            None
+        );
+        assert_eq!(
+            payload_field(br#"{"shipment_id": null}"#, "shipment_id"),
+            None
+        );
+        assert_eq!(payload_field(br#"{"other": 42}"#, "shipment_id"), None);
+        assert_eq!(payload_field(b"not json", "shipment_id"), None);
+    }
+
+    #[test]
+    fn shipment_partitioner_keys_by_shipment() {
+        let partitioner = ShipmentPartitioner;
+        let numeric = partitioner.partition(b"a", br#"{"shipment_id": 8746788}"#, 12);
+        let string = partitioner.partition(b"b", br#"{"shipment_id": "8746788"}"#, 12);
+
+        assert_eq!(numeric, Some(modulo(fnv1a(b"8746788"), 12)));
+        assert_eq!(numeric, string);
+        assert_eq!(
+            partitioner.partition(b"a", br#"{"product_id": 1}"#, 12),
+            None
+        );
+    }
+
+    #[test]
+    fn dock_partitioner_needs_warehouse_and_dock() {
+        let partitioner = DockPartitioner;
+        assert_eq!(
+            partitioner.partition(b"", br#"{"warehouse_id": "AMS1", "dock_id": 3}"#, 12),
+            Some(modulo(fnv1a(b"AMS1/3"), 12))
+        );
+        assert_eq!(partitioner.partition(b"", br#"{"dock_id": 3}"#, 12), None);
+        assert_eq!(
+            partitioner.partition(b"", br#"{"warehouse_id": "AMS1"}"#, 12),
+            None
+        );
+    }
+
+    #[test]
+    fn consistent_hash_partitioner_uses_the_key_or_a_field() {
+        let by_key = ConsistentHashPartitioner::new();
+        assert_eq!(
+            by_key.partition(b"8746788", b"{}", 12),
+            Some(jump_hash(fnv1a(b"8746788"), 12))
+        );
+
+        let by_field = ConsistentHashPartitioner::by_field("shipment_id");
+        assert_eq!(
+            by_field.partition(b"ignored", br#"{"shipment_id": 8746788}"#, 12),
+            by_key.partition(b"8746788", b"{}", 12)
+        );
+        assert_eq!(by_field.partition(b"8746788", b"{}", 12), None);
+    }
+
+    #[test]
+    fn partitions_stay_within_the_topic() {
+        let payload = br#"{"shipment_id": 1, "warehouse_id": "AMS1", "dock_id": 3}"#;
+        let partitioners: [Box<dyn Partitioner>; 4] = [
+            Box::new(ShipmentPartitioner),
+            Box::new(DockPartitioner),
+            Box::new(ConsistentHashPartitioner::by_field("shipment_id")),
+            Box::new(ExplicitPartitioner(5)),
+        ];
+        for count in 1..=12 {
+            for partitioner in &partitioners {
+                let partition = partitioner.partition(b"key", payload, count).unwrap();
+                assert!((0..count).contains(&partition));
+            }
+        }
+    }
+
+    #[test]
+    fn explicit_partitioner_clamps_to_the_topic() {
+        assert_eq!(ExplicitPartitioner(2).partition(b"", b"", 6), Some(2));
+        assert_eq!(ExplicitPartitioner(6).partition(b"", b"", 6), Some(5));
+        assert_eq!(ExplicitPartitioner(-1).partition(b"", b"", 6), Some(0));
+    }
+}
@@ -1,53 +1,396 @@
-use crate::config::KafkaConfigTrait;
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitHealth};
+use crate::config::{producer_client_config, KafkaConfigTrait};
+use crate::interceptor::{Interceptors, OutgoingRecord, ProducerInterceptor};
+use crate::partitioner::{PartitionCounts, Partitioner};
+use async_trait::async_trait;
+use chrono::Utc;
 use common_error::error::{KafkaError, KafkaResult};
-use rdkafka::config::ClientConfig;
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord, Producer};
+use rdkafka::ClientConfig;
+use std::sync::Arc;
 use std::time::Duration;
 
+/// Where the broker stored a message.
+///
+/// `timestamp` is the create time the producer set on the record, in milliseconds. Topics using
+/// `LogAppendTime` store the broker's own time instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub timestamp: i64,
+}
+
+/// Something that publishes messages, implemented by `EventProducer` and the in-memory producer
+/// so code that only sends can be tested without a broker.
+#[async_trait]
+pub trait MessageProducer: Send + Sync {
+    /// The topic `send_event` publishes to.
+    fn topic(&self) -> &str;
+
+    async fn send_to(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport>;
+
+    async fn send_event(&self, key: &[u8], payload: &[u8]) -> KafkaResult<DeliveryReport> {
+        self.send_to(self.topic(), key, payload, &[]).await
+    }
+
+    /// Whether a send made now would be attempted rather than rejected straight away.
+    fn accepts_sends(&self) -> bool {
+        true
+    }
+}
+
 #[derive(Clone)]
 pub struct EventProducer {
     producer: FutureProducer,
     topic: String,
     timeout: Duration,
+    breaker: Option<Arc<CircuitBreaker>>,
+    partitioner: Option<Arc<dyn Partitioner>>,
+    partition_counts: PartitionCounts,
+    lookup_config: ClientConfig,
+    interceptors: Interceptors,
 }
 
 impl EventProducer {
     pub fn new<T: KafkaConfigTrait>(config: T) -> KafkaResult<Self> {
-        let producer: FutureProducer = ClientConfig::new()
-            .set("bootstrap.servers", config.brokers())
-            .set("message.timeout.ms", config.timeout_ms().to_string())
-            .set("compression.type", "snappy")
-            .set("compression.level", "6")
-            .set("retry.backoff.ms", "500")
-            .set("request.required.acks", "all")
-            .set("queue.buffering.max.messages", "100000")
-            .set("queue.buffering.max.kbytes", "1048576")
-            .set("batch.size", "16384")
-            .set("linger.ms", "5")
+        Self::from_config(&config)
+    }
+
+    pub(crate) fn from_config<T: KafkaConfigTrait>(config: &T) -> KafkaResult<Self> {
+        let producer: FutureProducer = producer_client_config(config)?
             .create()
-            .map_err(|e| KafkaError::ClientCreation(e.to_string()))?;
+            .map_err(KafkaError::ClientCreation)?;
+
+        // Reading back published messages needs a consumer on the same cluster, with the same
+        // credentials but none of the producer tuning.
+        let mut lookup_config = ClientConfig::new();
+        lookup_config
+            .set("bootstrap.servers", config.brokers())
+            .set("group.id", format!("{}.lookup", config.group_id()))
+            .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "true");
+        for (key, value) in config.overrides() {
+            if ["security.", "sasl.", "ssl."]
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+            {
+                lookup_config.set(key, value);
+            }
+        }
 
         Ok(EventProducer {
             producer,
             topic: config.topic().to_string(),
             timeout: Duration::from_secs(config.timeout_ms() / 1000),
+            breaker: None,
+            partitioner: None,
+            partition_counts: PartitionCounts::default(),
+            lookup_config,
+            interceptors: Interceptors::default(),
         })
     }
 
-    pub async fn send_event<K, V>(&self, key: K, payload: V) -> KafkaResult<()>
+    /// Guards every send with a circuit breaker, shared by all clones of this producer. While it is
+    /// open, sends fail straight away with `KafkaError::CircuitOpen`.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        let name = format!("producer.{}", self.topic);
+        self.breaker = Some(Arc::new(CircuitBreaker::new(name, config)));
+        self
+    }
+
+    /// Chooses partitions with `partitioner` instead of librdkafka's hash of the record key.
+    pub fn with_partitioner(mut self, partitioner: impl Partitioner + 'static) -> Self {
+        self.partitioner = Some(Arc::new(partitioner));
+        self
+    }
+
+    /// Adds an interceptor that sees every record before it is sent and its outcome after. They run
+    /// in the order they were added.
+    pub fn with_interceptor(mut self, interceptor: impl ProducerInterceptor + 'static) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    pub(crate) fn lookup_config(&self) -> &ClientConfig {
+        &self.lookup_config
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The circuit breaker's state, `None` if the producer has none.
+    pub fn health(&self) -> Option<CircuitHealth> {
+        self.breaker.as_ref().map(|breaker| breaker.health())
+    }
+
+    /// Whether a send made now would reach the broker rather than being short-circuited.
+    pub fn accepts_sends(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .is_none_or(|breaker| breaker.allows_calls())
+    }
+
+    pub async fn send_event<K, V>(&self, key: K, payload: V) -> KafkaResult<DeliveryReport>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
-        let record = FutureRecord::to(&self.topic)
-            .payload(payload.as_ref())
-            .key(key.as_ref());
+        self.send_to(&self.topic, key, payload, &[]).await
+    }
 
-        self.producer
-            .send(record, self.timeout)
-            .await
-            .map_err(|(err, _)| KafkaError::MessageSend(err.to_string()))?;
+    /// Sends to an arbitrary topic rather than the configured one, e.g. retry and dead letter
+    /// topics.
+    pub async fn send_to<K, V>(
+        &self,
+        topic: &str,
+        key: K,
+        payload: V,
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let (key, payload) = (key.as_ref(), payload.as_ref());
+        let Some(record) = self
+            .interceptors
+            .before_send(topic, key, payload, headers)?
+        else {
+            return self.send_record(topic, key, payload, headers).await;
+        };
+
+        let result = self
+            .send_record(
+                &record.topic,
+                &record.key,
+                &record.payload,
+                &record.header_refs(),
+            )
+            .await;
+        self.interceptors.after_send(&record, &result);
+        result
+    }
+
+    async fn send_record(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport> {
+        // The partition count is fetched inside the breaker, an open breaker fails fast rather
+        // than waiting for metadata from a broker that is down.
+        let send = async {
+            let partition = match &self.partitioner {
+                Some(partitioner) => {
+                    let count = self
+                        .partition_counts
+                        .get(&self.producer, topic, self.timeout)
+                        .await?;
+                    partitioner.partition(key, payload, count)
+                }
+                None => None,
+            };
+
+            let timestamp = Utc::now().timestamp_millis();
+            let record = build_record(topic, key, payload, headers, partition, timestamp);
+            let (partition, offset) = self
+                .producer
+                .send(record, self.timeout)
+                .await
+                .map_err(|(err, _)| KafkaError::MessageSend(err))?;
+
+            Ok(DeliveryReport {
+                topic: topic.to_string(),
+                partition,
+                offset,
+                timestamp,
+            })
+        };
+
+        match &self.breaker {
+            Some(breaker) => breaker.call(send).await,
+            None => send.await,
+        }
+    }
+
+    /// Queues a message without waiting for it to be delivered. `on_delivery` is called with the
+    /// outcome from a background task, so this has to be called from within a Tokio runtime.
+    ///
+    /// With a partitioner, the topic's partition count is read from a cache that is refreshed in the
+    /// background, so this never waits for the cluster. The first message to a topic whose count
+    /// was never fetched fails with a transient `KafkaError::Timeout`. Fails straight away as well
+    /// if the local queue is full or the circuit breaker is open. Messages still queued at shutdown
+    /// are only delivered if `flush` is called.
+    pub fn enqueue<K, V, F>(&self, key: K, payload: V, on_delivery: F) -> KafkaResult<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        F: FnOnce(KafkaResult<DeliveryReport>) + Send + 'static,
+    {
+        let (key, payload) = (key.as_ref(), payload.as_ref());
+        let intercepted = self
+            .interceptors
+            .before_send(&self.topic, key, payload, &[])?;
+        let header_refs = intercepted.as_ref().map(OutgoingRecord::header_refs);
+        let (topic, key, payload, headers) = match (&intercepted, &header_refs) {
+            (Some(record), Some(headers)) => (
+                record.topic.as_str(),
+                record.key.as_slice(),
+                record.payload.as_slice(),
+                headers.as_slice(),
+            ),
+            _ => (self.topic.as_str(), key, payload, &[][..]),
+        };
+
+        // Before looking at the partition count, so an open breaker fails fast.
+        if let Some(breaker) = &self.breaker {
+            breaker.acquire()?;
+        }
+        let partition = match &self.partitioner {
+            Some(partitioner) => {
+                match self
+                    .partition_counts
+                    .get_or_refresh(&self.producer, topic, self.timeout)
+                {
+                    Some(count) => partitioner.partition(key, payload, count),
+                    None => {
+                        // Nothing was sent, so the call tells the breaker nothing about the broker.
+                        if let Some(breaker) = &self.breaker {
+                            breaker.release_probe();
+                        }
+                        let result: KafkaResult<DeliveryReport> = Err(KafkaError::Timeout(
+                            format!("partition count of topic {} is still being fetched", topic),
+                        ));
+                        if let Some(record) = &intercepted {
+                            self.interceptors.after_send(record, &result);
+                        }
+                        return result.map(|_| ());
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let timestamp = Utc::now().timestamp_millis();
+        let record = build_record(topic, key, payload, headers, partition, timestamp);
+        let delivery: DeliveryFuture = match self.producer.send_result(record) {
+            Ok(delivery) => delivery,
+            Err((err, _)) => {
+                let result: KafkaResult<DeliveryReport> = Err(KafkaError::MessageSend(err));
+                if let Some(breaker) = &self.breaker {
+                    breaker.record(&result);
+                }
+                if let Some(record) = &intercepted {
+                    self.interceptors.after_send(record, &result);
+                }
+                return result.map(|_| ());
+            }
+        };
+
+        let topic = topic.to_string();
+        let breaker = self.breaker.clone();
+        let interceptors = self.interceptors.clone();
+        tokio::spawn(async move {
+            let result = match delivery.await {
+                Ok(Ok((partition, offset))) => Ok(DeliveryReport {
+                    topic,
+                    partition,
+                    offset,
+                    timestamp,
+                }),
+                Ok(Err((err, _))) => Err(KafkaError::MessageDelivery(err)),
+                Err(_) => Err(KafkaError::MessageDelivery(
+                    rdkafka::error::KafkaError::Canceled,
+                )),
+            };
+
+            if let Some(breaker) = &breaker {
+                breaker.record(&result);
+            }
+            if let Some(record) = &intercepted {
+                interceptors.after_send(record, &result);
+            }
+            on_delivery(result);
+        });
 
         Ok(())
     }
+
+    /// Waits until every queued message has been delivered or failed, for shutdown.
+    pub async fn flush(&self, timeout: Duration) -> KafkaResult<()> {
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || producer.flush(timeout))
+            .await
+            .map_err(|err| KafkaError::Timeout(format!("flush task failed: {}", err)))?
+            .map_err(|err| {
+                KafkaError::Timeout(format!(
+                    "messages still queued after {:?}: {}",
+                    timeout, err
+                ))
+            })
+    }
+}
+
+#[async_trait]
+impl MessageProducer for EventProducer {
+    fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    async fn send_to(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport> {
+        EventProducer::send_to(self, topic, key, payload, headers).await
+    }
+
+    fn accepts_sends(&self) -> bool {
+        EventProducer::accepts_sends(self)
+    }
+}
+
+fn build_record<'a>(
+    topic: &'a str,
+    key: &'a [u8],
+    payload: &'a [u8],
+    headers: &[(&str, &[u8])],
+    partition: Option<i32>,
+    timestamp: i64,
+) -> FutureRecord<'a, [u8], [u8]> {
+    let mut record = FutureRecord::to(topic)
+        .payload(payload)
+        .key(key)
+        .timestamp(timestamp);
+
+    if let Some(partition) = partition {
+        record = record.partition(partition);
+    }
+
+    if !headers.is_empty() {
+        let headers = headers
+            .iter()
+            .fold(OwnedHeaders::new(), |owned, (key, value)| {
+                owned.insert(Header {
+                    key,
+                    value: Some(*value),
+                })
+            });
+        record = record.headers(headers);
+    }
+
+    record
 }
@@ -0,0 +1,372 @@
+//! Request-reply over Kafka, for interactions that need an answer such as "is this SKU allowed at
+//! this dock?".
+//!
+//! A `Requester` publishes a request carrying a correlation id and the topic to reply to, and
+//! waits for the reply with that correlation id to arrive on its reply consumer. A `Responder`
+//! consumes requests, passes them to a `RequestHandler` and publishes what it returns to the
+//! requested reply topic.
+//!
+//! Every requester instance needs a reply topic of its own, otherwise its replies may be read by
+//! another instance's reply consumer and time out.
+
+use crate::consumer::MessageHandler;
+use crate::message::ConsumedMessage;
+use crate::producer::MessageProducer;
+use async_trait::async_trait;
+use common_error::error::{KafkaError, KafkaResult};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+pub const REPLY_TO_HEADER: &str = "x-reply-to";
+/// Set on replies to requests the responder rejected, with the error as its value.
+pub const REPLY_ERROR_HEADER: &str = "x-reply-error";
+
+type PendingReplies = Arc<Mutex<HashMap<String, oneshot::Sender<ConsumedMessage>>>>;
+
+/// Sends requests and waits for their replies.
+///
+/// Replies are read by a reply consumer created with `EventConsumer::reply_consumer` (or its
+/// in-memory counterpart) around `reply_handler`, which has to be running for `request` to get
+/// any.
+pub struct Requester {
+    producer: Arc<dyn MessageProducer>,
+    reply_topic: String,
+    timeout: Duration,
+    pending: PendingReplies,
+}
+
+impl Requester {
+    pub fn new(
+        producer: Arc<dyn MessageProducer>,
+        reply_topic: impl Into<String>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            producer,
+            reply_topic: reply_topic.into(),
+            timeout,
+            pending: PendingReplies::default(),
+        }
+    }
+
+    pub fn reply_topic(&self) -> &str {
+        &self.reply_topic
+    }
+
+    /// The handler for the reply consumer, completing the requests waiting for each reply.
+    pub fn reply_handler(&self) -> Box<dyn MessageHandler> {
+        Box::new(ReplyRouter {
+            pending: self.pending.clone(),
+        })
+    }
+
+    /// Sends a request to `topic` and returns the reply payload.
+    ///
+    /// Fails with `KafkaError::Timeout` if no reply arrives in time and with
+    /// `KafkaError::Rejected` if the responder rejected the request.
+    pub async fn request(&self, topic: &str, key: &[u8], payload: &[u8]) -> KafkaResult<Vec<u8>> {
+        let correlation_id = format!("{:032x}", rand::random::<u128>());
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(correlation_id.clone(), sender);
+        // Removes the entry however this returns, including when the caller drops the future.
+        let _waiting = Waiting {
+            pending: &self.pending,
+            correlation_id: &correlation_id,
+        };
+
+        let headers = [
+            (CORRELATION_ID_HEADER, correlation_id.as_bytes()),
+            (REPLY_TO_HEADER, self.reply_topic.as_bytes()),
+        ];
+        self.producer.send_to(topic, key, payload, &headers).await?;
+
+        let reply = match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) | Err(_) => {
+                return Err(KafkaError::Timeout(format!(
+                    "no reply to request {} on {} within {:?}",
+                    correlation_id, self.reply_topic, self.timeout
+                )))
+            }
+        };
+
+        match reply.header(REPLY_ERROR_HEADER) {
+            Some(error) => Err(KafkaError::Rejected(
+                String::from_utf8_lossy(error).into_owned(),
+            )),
+            None => Ok(reply.payload),
+        }
+    }
+}
+
+struct Waiting<'a> {
+    pending: &'a PendingReplies,
+    correlation_id: &'a str,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(self.correlation_id);
+    }
+}
+
+/// Hands replies to the request waiting for them. Replies nobody waits for any more, because the
+/// request timed out or the requester restarted, are dropped.
+struct ReplyRouter {
+    pending: PendingReplies,
+}
+
+#[async_trait]
+impl MessageHandler for ReplyRouter {
+    async fn handle(&self, _key: &[u8], _payload: &[u8]) -> KafkaResult<()> {
+        Err(KafkaError::Rejected(
+            "reply without a correlation id".into(),
+        ))
+    }
+
+    async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+        let Some(correlation_id) = message.header(CORRELATION_ID_HEADER) else {
+            tracing::warn!(
+                "Dropping reply {}[{}]@{} without a correlation id",
+                message.topic,
+                message.partition,
+                message.offset
+            );
+            return Ok(());
+        };
+        let correlation_id = String::from_utf8_lossy(correlation_id);
+
+        match self.pending.lock().unwrap().remove(correlation_id.as_ref()) {
+            Some(sender) => {
+                let _ = sender.send(message.clone());
+            }
+            None => tracing::debug!("Dropping late reply to request {}", correlation_id),
+        }
+        Ok(())
+    }
+}
+
+/// Answers requests, the responding side of a `Requester`.
+#[async_trait]
+pub trait RequestHandler: Send + Sync {
+    /// Returns the reply payload. `KafkaError::Rejected` and other permanent errors are sent back
+    /// to the requester, anything else is retried like any handler error.
+    async fn reply(&self, key: &[u8], payload: &[u8]) -> KafkaResult<Vec<u8>>;
+}
+
+/// A `MessageHandler` that answers the requests it consumes with a `RequestHandler` and publishes
+/// the replies to the topic each request asks for.
+pub struct Responder<H> {
+    handler: H,
+    producer: Arc<dyn MessageProducer>,
+}
+
+impl<H: RequestHandler> Responder<H> {
+    pub fn new(handler: H, producer: Arc<dyn MessageProducer>) -> Self {
+        Self { handler, producer }
+    }
+}
+
+#[async_trait]
+impl<H: RequestHandler> MessageHandler for Responder<H> {
+    async fn handle(&self, _key: &[u8], _payload: &[u8]) -> KafkaResult<()> {
+        Err(KafkaError::Rejected(
+            "request without correlation id and reply topic".into(),
+        ))
+    }
+
+    async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+        let (Some(correlation_id), Some(reply_to)) = (
+            message.header(CORRELATION_ID_HEADER),
+            message.header(REPLY_TO_HEADER),
+        ) else {
+            tracing::warn!(
+                "Rejecting request {}[{}]@{} without correlation id and reply topic",
+                message.topic,
+                message.partition,
+                message.offset
+            );
+            return self.handle(&message.key, &message.payload).await;
+        };
+        let reply_to = std::str::from_utf8(reply_to)
+            .map_err(|_| KafkaError::Rejected("reply topic is not valid UTF-8".into()))?;
+
+        let (payload, error) = match self.handler.reply(&message.key, &message.payload).await {
+            Ok(payload) => (payload, None),
+            Err(e) if !e.is_retryable() => (Vec::new(), Some(e.to_string())),
+            Err(e) => return Err(e),
+        };
+
+        let mut headers = vec![(CORRELATION_ID_HEADER, correlation_id)];
+        if let Some(error) = &error {
+            headers.push((REPLY_ERROR_HEADER, error.as_bytes()));
+        }
+        self.producer
+            .send_to(reply_to, &message.key, &payload, &headers)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::InboundConfig;
+    use crate::consumer::MessageConsumer;
+    use crate::memory::{InMemoryBroker, InMemoryConsumer, InMemoryProducer};
+
+    const REQUESTS: &str = "DOCK_CHECK";
+    const REPLIES: &str = "DOCK_CHECK.replies.1";
+
+    /// Allows every SKU except "blocked", and fails transiently for "flaky".
+    struct DockCheck;
+
+    #[async_trait]
+    impl RequestHandler for DockCheck {
+        async fn reply(&self, _key: &[u8], payload: &[u8]) -> KafkaResult<Vec<u8>> {
+            match payload {
+                b"blocked" => Err(KafkaError::Rejected("SKU not allowed at dock 4".into())),
+                b"flaky" => Err(KafkaError::Timeout("catalog".into())),
+                _ => Ok(b"allowed".to_vec()),
+            }
+        }
+    }
+
+    fn config() -> InboundConfig {
+        InboundConfig {
+            topic: REQUESTS.to_string(),
+            ..InboundConfig::new()
+        }
+    }
+
+    fn requester(broker: &InMemoryBroker, timeout: Duration) -> Requester {
+        let producer = Arc::new(InMemoryProducer::new(broker, config()));
+        Requester::new(producer, REPLIES, timeout)
+    }
+
+    /// Starts a responder and the requester's reply consumer on the broker.
+    fn serve(broker: &InMemoryBroker, requester: &Requester) {
+        let producer = Arc::new(InMemoryProducer::new(broker, config()));
+        let responder = InMemoryConsumer::new(
+            broker,
+            config(),
+            Box::new(Responder::new(DockCheck, producer)),
+        );
+        let replies =
+            InMemoryConsumer::reply_consumer(broker, config(), REPLIES, requester.reply_handler());
+        tokio::spawn(async move { responder.start().await });
+        tokio::spawn(async move { replies.start().await });
+    }
+
+    fn request_message(headers: Vec<(String, Vec<u8>)>) -> ConsumedMessage {
+        ConsumedMessage {
+            topic: REQUESTS.to_string(),
+            partition: 0,
+            offset: 0,
+            key: b"sku-1".to_vec(),
+            payload: b"sku-1".to_vec(),
+            timestamp: None,
+            headers,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn request_gets_the_reply() {
+        let broker = InMemoryBroker::new();
+        let requester = requester(&broker, Duration::from_secs(5));
+        serve(&broker, &requester);
+
+        let reply = requester.request(REQUESTS, b"dock-4", b"sku-1").await;
+
+        assert_eq!(reply.unwrap(), b"allowed");
+        assert!(requester.pending.lock().unwrap().is_empty());
+        broker.close();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_request_comes_back_as_rejected() {
+        let broker = InMemoryBroker::new();
+        let requester = requester(&broker, Duration::from_secs(5));
+        serve(&broker, &requester);
+
+        let reply = requester.request(REQUESTS, b"dock-4", b"blocked").await;
+
+        match reply {
+            Err(KafkaError::Rejected(reason)) => assert!(reason.contains("not allowed")),
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        broker.close();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_removes_the_pending_request() {
+        let broker = InMemoryBroker::new();
+        let requester = requester(&broker, Duration::from_secs(1));
+
+        let reply = requester.request(REQUESTS, b"dock-4", b"sku-1").await;
+
+        assert!(matches!(reply, Err(KafkaError::Timeout(_))));
+        assert!(requester.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_reply_is_dropped() {
+        let broker = InMemoryBroker::new();
+        let requester = requester(&broker, Duration::from_secs(1));
+        requester
+            .request(REQUESTS, b"dock-4", b"sku-1")
+            .await
+            .unwrap_err();
+
+        // The responder answers only after the requester gave up.
+        let request = broker.messages(REQUESTS).remove(0);
+        let producer = Arc::new(InMemoryProducer::new(&broker, config()));
+        Responder::new(DockCheck, producer)
+            .handle_message(&request)
+            .await
+            .unwrap();
+        let reply = broker.messages(REPLIES).remove(0);
+
+        let router = requester.reply_handler();
+        assert!(router.handle_message(&reply).await.is_ok());
+        assert!(requester.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn request_without_headers_is_rejected() {
+        let broker = InMemoryBroker::new();
+        let producer = Arc::new(InMemoryProducer::new(&broker, config()));
+        let responder = Responder::new(DockCheck, producer);
+
+        let missing_reply_to =
+            request_message(vec![(CORRELATION_ID_HEADER.to_string(), b"abc".to_vec())]);
+        for message in [request_message(Vec::new()), missing_reply_to] {
+            let result = responder.handle_message(&message).await;
+            assert!(matches!(result, Err(KafkaError::Rejected(_))));
+        }
+        assert!(broker.messages(REPLIES).is_empty());
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried_rather_than_replied() {
+        let broker = InMemoryBroker::new();
+        let producer = Arc::new(InMemoryProducer::new(&broker, config()));
+        let responder = Responder::new(DockCheck, producer);
+
+        let mut message = request_message(vec![
+            (CORRELATION_ID_HEADER.to_string(), b"abc".to_vec()),
+            (REPLY_TO_HEADER.to_string(), REPLIES.as_bytes().to_vec()),
+        ]);
+        message.payload = b"flaky".to_vec();
+
+        let result = responder.handle_message(&message).await;
+        assert!(matches!(result, Err(KafkaError::Timeout(_))));
+        assert!(broker.messages(REPLIES).is_empty());
+    }
+}
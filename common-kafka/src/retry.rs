@@ -0,0 +1,331 @@
+use common_error::classification::ErrorClass;
+use common_error::error::{KafkaError, KafkaResult};
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How the wait between attempts grows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// The same delay before every retry.
+    Fixed(Duration),
+    /// `initial`, doubling after every retry up to `max`.
+    Exponential { initial: Duration, max: Duration },
+    /// A random delay between zero and the exponential delay. Spreads out retries from many
+    /// clients that failed at the same moment, e.g. relays restarting together after an outage.
+    ExponentialJitter { initial: Duration, max: Duration },
+}
+
+/// What to do with an error of a given class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClassRule {
+    /// Give up straight away.
+    Stop,
+    /// Retry, with the backoff delay multiplied by `factor`.
+    Retry { factor: u32 },
+}
+
+/// A retry policy shared by the consumer, the producer and the outbox relay.
+///
+/// `max_attempts` counts the first attempt too, so `1` never retries. With `max_elapsed` set,
+/// no retry is started that would begin after that much time has passed since the first attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub backoff: Backoff,
+    pub max_attempts: u32,
+    pub max_elapsed: Option<Duration>,
+    pub transient: ClassRule,
+    pub throttled: ClassRule,
+    pub permanent: ClassRule,
+}
+
+impl Default for RetryPolicy {
+    /// The backoff the consumer has always used: 100ms doubling, five attempts, and four times
+    /// the delay when the downstream is throttling.
+    fn default() -> Self {
+        Self::exponential(Duration::from_millis(100), Duration::from_secs(30)).with_max_attempts(5)
+    }
+}
+
+impl RetryPolicy {
+    fn with_backoff(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            max_attempts: 5,
+            max_elapsed: None,
+            transient: ClassRule::Retry { factor: 1 },
+            throttled: ClassRule::Retry { factor: 4 },
+            permanent: ClassRule::Stop,
+        }
+    }
+
+    pub fn fixed(delay: Duration) -> Self {
+        Self::with_backoff(Backoff::Fixed(delay))
+    }
+
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Self::with_backoff(Backoff::Exponential { initial, max })
+    }
+
+    pub fn exponential_jitter(initial: Duration, max: Duration) -> Self {
+        Self::with_backoff(Backoff::ExponentialJitter { initial, max })
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    pub fn with_max_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.max_elapsed = Some(max_elapsed);
+        self
+    }
+
+    pub fn with_rule(mut self, class: ErrorClass, rule: ClassRule) -> Self {
+        match class {
+            ErrorClass::Transient => self.transient = rule,
+            ErrorClass::Throttled => self.throttled = rule,
+            ErrorClass::Permanent => self.permanent = rule,
+        }
+        self
+    }
+
+    pub fn rule(&self, class: ErrorClass) -> ClassRule {
+        match class {
+            ErrorClass::Transient => self.transient,
+            ErrorClass::Throttled => self.throttled,
+            ErrorClass::Permanent => self.permanent,
+        }
+    }
+
+    /// The backoff before retry number `retry`, counting from zero, ignoring error classes.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponential = |initial: Duration, max: Duration| {
+            initial
+                .checked_mul(2u32.saturating_pow(retry))
+                .unwrap_or(max)
+                .min(max)
+        };
+
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => exponential(initial, max),
+            Backoff::ExponentialJitter { initial, max } => {
+                let ceiling = exponential(initial, max);
+                Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64))
+            }
+        }
+    }
+
+    /// How long to wait before the next attempt after `attempts` attempts failed, the last one with
+    /// an error of class `class`. `None` if the policy says to give up.
+    pub fn next_delay(
+        &self,
+        attempts: u32,
+        elapsed: Duration,
+        class: ErrorClass,
+    ) -> Option<Duration> {
+        let ClassRule::Retry { factor } = self.rule(class) else {
+            return None;
+        };
+        if attempts >= self.max_attempts {
+            return None;
+        }
+
+        let delay = self
+            .delay(attempts.saturating_sub(1))
+            .saturating_mul(factor);
+        match self.max_elapsed {
+            Some(max_elapsed) if elapsed + delay > max_elapsed => None,
+            _ => Some(delay),
+        }
+    }
+
+    /// Runs `operation` until it succeeds or the policy gives up.
+    ///
+    /// Errors the policy stops on straight away are returned as they are. Running out of attempts
+    /// or time returns `KafkaError::RetriesExhausted` wrapping the last error.
+    pub async fn retry<T, F, Fut>(&self, mut operation: F) -> KafkaResult<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = KafkaResult<T>>,
+    {
+        let started = Instant::now();
+        let mut attempts = 0;
+
+        loop {
+            let e = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            attempts += 1;
+
+            let class = e.class();
+            if self.rule(class) == ClassRule::Stop {
+                return Err(e);
+            }
+            let Some(delay) = self.next_delay(attempts, started.elapsed(), class) else {
+                return Err(KafkaError::RetriesExhausted {
+                    attempts,
+                    source: Box::new(e),
+                });
+            };
+
+            tracing::warn!(
+                "Attempt {} failed, retrying in {:?}: {}",
+                attempts,
+                delay,
+                e
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::error::{KafkaError as RDKafkaError, RDKafkaErrorCode};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn timeout() -> KafkaError {
+        KafkaError::Timeout("downstream".into())
+    }
+
+    fn queue_full() -> KafkaError {
+        KafkaError::MessageSend(RDKafkaError::MessageProduction(RDKafkaErrorCode::QueueFull))
+    }
+
+    /// Runs `policy` on an operation failing with `errors` in turn, then succeeding. Returns the
+    /// result and how many attempts were made.
+    async fn run(
+        policy: RetryPolicy,
+        errors: impl Fn(u32) -> Option<KafkaError>,
+    ) -> (KafkaResult<()>, u32) {
+        let attempts = AtomicU32::new(0);
+        let result = policy
+            .retry(|| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                let error = errors(attempt);
+                async move { error.map_or(Ok(()), Err) }
+            })
+            .await;
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_attempts_counts_the_first_attempt() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(100)).with_max_attempts(3);
+        let (result, attempts) = run(policy, |_| Some(timeout())).await;
+
+        assert_eq!(attempts, 3);
+        assert!(matches!(
+            result,
+            Err(KafkaError::RetriesExhausted { attempts: 3, .. })
+        ));
+
+        let (result, attempts) = run(policy.with_max_attempts(1), |_| Some(timeout())).await;
+        assert_eq!(attempts, 1);
+        assert!(matches!(
+            result,
+            Err(KafkaError::RetriesExhausted { attempts: 1, .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn succeeds_once_the_operation_does() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(100)).with_max_attempts(5);
+        let started = Instant::now();
+        let (result, attempts) = run(policy, |attempt| (attempt < 2).then(timeout)).await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+        assert_eq!(started.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_elapsed_cuts_retries_short() {
+        let policy = RetryPolicy::fixed(Duration::from_secs(1))
+            .with_max_attempts(10)
+            .with_max_elapsed(Duration::from_millis(2500));
+        let started = Instant::now();
+        let (result, attempts) = run(policy, |_| Some(timeout())).await;
+
+        // A third retry would start 3s in, past the limit.
+        assert_eq!(attempts, 3);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert!(matches!(
+            result,
+            Err(KafkaError::RetriesExhausted { attempts: 3, .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stop_rule_returns_the_error_as_it_is() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(100));
+        let (result, attempts) = run(policy, |_| Some(KafkaError::Rejected("bad".into()))).await;
+        assert_eq!(attempts, 1);
+        assert!(matches!(result, Err(KafkaError::Rejected(_))));
+
+        let policy = policy.with_rule(ErrorClass::Transient, ClassRule::Stop);
+        let (result, attempts) = run(policy, |_| Some(timeout())).await;
+        assert_eq!(attempts, 1);
+        assert!(matches!(result, Err(KafkaError::Timeout(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_errors_back_off_longer() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(100));
+        assert_eq!(
+            policy.next_delay(1, Duration::ZERO, ErrorClass::Throttled),
+            Some(Duration::from_millis(400))
+        );
+        assert_eq!(
+            policy.next_delay(1, Duration::ZERO, ErrorClass::Transient),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(
+            policy.next_delay(1, Duration::ZERO, ErrorClass::Permanent),
+            None
+        );
+
+        let started = Instant::now();
+        let (result, attempts) = run(policy, |attempt| (attempt < 2).then(queue_full)).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 3);
+        assert_eq!(started.elapsed(), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_its_cap() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<u128> = (0..6)
+            .map(|retry| policy.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+
+        // Doublings that overflow a Duration end up at the cap too.
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+        let policy = RetryPolicy::exponential(Duration::from_secs(u64::MAX / 2), Duration::MAX);
+        assert_eq!(policy.delay(2), Duration::MAX);
+    }
+
+    #[test]
+    fn jitter_stays_below_the_exponential_delay() {
+        let policy =
+            RetryPolicy::exponential_jitter(Duration::from_millis(100), Duration::from_secs(1));
+        for retry in 0..6 {
+            let ceiling = Duration::from_millis(100)
+                .saturating_mul(1 << retry)
+                .min(Duration::from_secs(1));
+            let delays: Vec<Duration> = (0..200).map(|_| policy.delay(retry)).collect();
+            assert!(delays.iter().all(|delay| *delay <= ceiling), "{:?}", delays);
+            assert!(
+                delays.iter().any(|delay| *delay < ceiling / 2),
+                "{:?}",
+                delays
+            );
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::message::ConsumedMessage;
+use crate::producer::MessageProducer;
+use chrono::Utc;
+use common_error::error::{KafkaError, KafkaResult};
+use std::time::Duration;
+
+pub const RETRY_ATTEMPT_HEADER: &str = "x-retry-attempt";
+pub const NOT_BEFORE_HEADER: &str = "x-not-before";
+pub const ORIGINAL_TOPIC_HEADER: &str = "x-original-topic";
+pub const ORIGINAL_PARTITION_HEADER: &str = "x-original-partition";
+pub const ORIGINAL_OFFSET_HEADER: &str = "x-original-offset";
+pub const ERROR_HEADER: &str = "x-exception-message";
+
+/// The headers set on every republished message, replacing any the message carried already.
+const RETRY_HEADERS: [&str; 6] = [
+    RETRY_ATTEMPT_HEADER,
+    NOT_BEFORE_HEADER,
+    ORIGINAL_TOPIC_HEADER,
+    ORIGINAL_PARTITION_HEADER,
+    ORIGINAL_OFFSET_HEADER,
+    ERROR_HEADER,
+];
+
+/// How `EventConsumer` retries a message whose handler failed.
+///
+/// Either way, errors classified as permanent are never retried and go to the dead letter topic.
+#[derive(Debug, Clone)]
+pub enum RetryStrategy {
+    /// Retries in-process with exponential backoff, holding up the partition until it succeeds.
+    /// Without a dead letter topic, a permanently failing message holds up its partition too, it
+    /// is never committed past.
+    Blocking { dead_letter_topic: Option<String> },
+    /// Republishes the message to a chain of delayed retry topics and finally a dead letter topic,
+    /// so the main topic keeps flowing.
+    RetryTopics(RetryTopicsConfig),
+}
+
+impl Default for RetryStrategy {
+    fn default() -> Self {
+        RetryStrategy::Blocking {
+            dead_letter_topic: None,
+        }
+    }
+}
+
+/// A delayed retry topic. Messages are not handled again until `delay` after they were
+/// republished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryTier {
+    pub topic: String,
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryTopicsConfig {
+    pub tiers: Vec<RetryTier>,
+    pub dead_letter_topic: String,
+}
+
+impl RetryTopicsConfig {
+    /// The 10s, 1m and 10m tiers our other services use, named `<topic>.retry.<delay>` with a
+    /// `<topic>.dlq` dead letter topic.
+    pub fn for_topic(topic: &str) -> Self {
+        let tier = |suffix: &str, delay: Duration| RetryTier {
+            topic: format!("{}.retry.{}", topic, suffix),
+            delay,
+        };
+
+        Self {
+            tiers: vec![
+                tier("10s", Duration::from_secs(10)),
+                tier("1m", Duration::from_secs(60)),
+                tier("10m", Duration::from_secs(600)),
+            ],
+            dead_letter_topic: format!("{}.dlq", topic),
+        }
+    }
+
+    pub fn retry_topics(&self) -> Vec<&str> {
+        self.tiers.iter().map(|tier| tier.topic.as_str()).collect()
+    }
+
+    /// Publishes a failed message to the next retry tier, or to the dead letter topic once every
+    /// tier has been tried or if the error is permanent. The original coordinates travel in
+    /// headers so the message can be traced back from any tier.
+    pub async fn route_failure(
+        &self,
+        producer: &dyn MessageProducer,
+        message: &ConsumedMessage,
+        error: &KafkaError,
+    ) -> KafkaResult<()> {
+        let attempt = retry_attempt(message);
+        let tier = self
+            .tiers
+            .get(attempt as usize)
+            .filter(|_| error.is_retryable());
+
+        let Some(tier) = tier else {
+            return send_to_dead_letter(producer, &self.dead_letter_topic, message, error).await;
+        };
+
+        tracing::warn!(
+            "Sending message from {}[{}]@{} to retry topic {}: {}",
+            message.topic,
+            message.partition,
+            message.offset,
+            tier.topic,
+            error
+        );
+
+        let not_before = Utc::now().timestamp_millis() + tier.delay.as_millis() as i64;
+        send_with_retry_headers(
+            producer,
+            &tier.topic,
+            message,
+            error,
+            Some(not_before.to_string()),
+        )
+        .await
+    }
+}
+
+/// Publishes a message that will not be retried any more to a dead letter topic, with the same
+/// headers as the retry topics.
+pub async fn send_to_dead_letter(
+    producer: &dyn MessageProducer,
+    topic: &str,
+    message: &ConsumedMessage,
+    error: &KafkaError,
+) -> KafkaResult<()> {
+    tracing::error!(
+        "Giving up on message from {}[{}]@{}, sending to {}: {}",
+        message.topic,
+        message.partition,
+        message.offset,
+        topic,
+        error
+    );
+
+    send_with_retry_headers(producer, topic, message, error, None).await
+}
+
+async fn send_with_retry_headers(
+    producer: &dyn MessageProducer,
+    topic: &str,
+    message: &ConsumedMessage,
+    error: &KafkaError,
+    not_before: Option<String>,
+) -> KafkaResult<()> {
+    let attempt = retry_attempt(message);
+    let error = error.to_string();
+
+    let original_topic =
+        header_string(message, ORIGINAL_TOPIC_HEADER).unwrap_or_else(|| message.topic.clone());
+    let original_partition = header_string(message, ORIGINAL_PARTITION_HEADER)
+        .unwrap_or_else(|| message.partition.to_string());
+    let original_offset = header_string(message, ORIGINAL_OFFSET_HEADER)
+        .unwrap_or_else(|| message.offset.to_string());
+
+    let next_attempt = (attempt + 1).to_string();
+    // Headers the message already carried, e.g. ids consumers deduplicate or reply by, travel
+    // with it.
+    let mut headers: Vec<(&str, &[u8])> = message
+        .headers
+        .iter()
+        .filter(|(key, _)| !RETRY_HEADERS.contains(&key.as_str()))
+        .map(|(key, value)| (key.as_str(), value.as_slice()))
+        .collect();
+    headers.extend([
+        (RETRY_ATTEMPT_HEADER, next_attempt.as_bytes()),
+        (ORIGINAL_TOPIC_HEADER, original_topic.as_bytes()),
+        (ORIGINAL_PARTITION_HEADER, original_partition.as_bytes()),
+        (ORIGINAL_OFFSET_HEADER, original_offset.as_bytes()),
+        (ERROR_HEADER, error.as_bytes()),
+    ]);
+    if let Some(not_before) = &not_before {
+        headers.push((NOT_BEFORE_HEADER, not_before.as_bytes()));
+    }
+
+    producer
+        .send_to(topic, &message.key, &message.payload, &headers)
+        .await?;
+    Ok(())
+}
+
+fn header_string(message: &ConsumedMessage, name: &str) -> Option<String> {
+    message
+        .header(name)
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// How many times the message has already been routed through the retry topics.
+pub fn retry_attempt(message: &ConsumedMessage) -> u32 {
+    header_string(message, RETRY_ATTEMPT_HEADER)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// How long until a retried message is due, `None` if it can be handled now.
+pub fn not_before_delay(message: &ConsumedMessage) -> Option<Duration> {
+    let not_before: i64 = header_string(message, NOT_BEFORE_HEADER)?.parse().ok()?;
+    let remaining = not_before - Utc::now().timestamp_millis();
+
+    (remaining > 0).then(|| Duration::from_millis(remaining as u64))
+}
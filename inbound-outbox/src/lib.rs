@@ -1,114 +1,194 @@
 use chrono::{DateTime, Utc};
-use common_kafka::EventProducer;
+use common_error::error::{WarehouseError, WarehouseResult};
+use common_kafka::DeliveryReport;
 use futures_util::TryStreamExt;
-use mongodb::{bson::doc, options::ClientOptions, Client, Collection};
+use mongodb::bson::{doc, oid::ObjectId, DateTime as BsonDateTime};
+use mongodb::change_stream::{event::ChangeStreamEvent, ChangeStream};
+use mongodb::options::{ClientOptions, ReturnDocument};
+use mongodb::results::InsertOneResult;
+use mongodb::{Client, ClientSession, Collection};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::time::{interval, Duration};
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub mod relay;
+
+/// The event type of stock booked in, which is what entries written before entries had a type
+/// were.
+pub const STOCK_RECEIVED: &str = "stock_received";
+
+fn stock_received() -> String {
+    STOCK_RECEIVED.to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OutboxEntry {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    pub id: Option<mongodb::bson::oid::ObjectId>,
+    pub id: Option<ObjectId>,
     pub user_id: u64,
+    /// What the payload describes, relayed as a header so consumers can tell events apart.
+    #[serde(default = "stock_received")]
+    pub event_type: String,
     pub payload: String,
     pub status: String,
     pub created_at: DateTime<Utc>,
+    /// Where the entry was published, recorded once it is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kafka_partition: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kafka_offset: Option<i64>,
+    /// When a relay claimed the entry for sending, cleared again if the send fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claimed_at: Option<BsonDateTime>,
 }
+
+impl OutboxEntry {
+    fn pending(user_id: u64, event_type: &str, payload: String) -> Self {
+        Self {
+            id: None,
+            user_id,
+            event_type: event_type.to_string(),
+            payload,
+            status: "pending".to_string(),
+            created_at: Utc::now(),
+            kafka_partition: None,
+            kafka_offset: None,
+            claimed_at: None,
+        }
+    }
+}
+
+fn inserted_id(result: &InsertOneResult) -> WarehouseResult<ObjectId> {
+    result
+        .inserted_id
+        .as_object_id()
+        .ok_or_else(|| WarehouseError::Serialization("outbox entry _id is not an ObjectId".into()))
+}
+
 #[derive(Clone)]
 pub struct Outbox {
     collection: Collection<OutboxEntry>,
 }
 
 impl Outbox {
-    pub async fn new(
-        uri: &str,
-        db_name: &str,
-        collection_name: &str,
-    ) -> mongodb::error::Result<Self> {
+    pub async fn new(uri: &str, db_name: &str, collection_name: &str) -> WarehouseResult<Self> {
         let options = ClientOptions::parse(uri).await?;
         let client = Client::with_options(options)?;
+        Ok(Self::from_client(&client, db_name, collection_name))
+    }
+
+    /// An outbox on an existing client, e.g. one that also writes the records the entries are
+    /// about.
+    pub fn from_client(client: &Client, db_name: &str, collection_name: &str) -> Self {
         let collection = client
             .database(db_name)
             .collection::<OutboxEntry>(collection_name);
-        Ok(Self { collection })
+        Self { collection }
+    }
+
+    /// Adds a `STOCK_RECEIVED` entry.
+    pub async fn add_entry(&self, user_id: u64, payload: String) -> WarehouseResult<ObjectId> {
+        let result = self
+            .collection
+            .insert_one(OutboxEntry::pending(user_id, STOCK_RECEIVED, payload))
+            .await?;
+        inserted_id(&result)
     }
 
-    pub async fn add_entry(
+    /// Adds an entry as part of the session's transaction, so it is only relayed if the writes it
+    /// was added with are committed.
+    pub async fn add_entry_with_session(
         &self,
+        session: &mut ClientSession,
         user_id: u64,
+        event_type: &str,
         payload: String,
-    ) -> mongodb::error::Result<mongodb::bson::oid::ObjectId> {
-        let entry = OutboxEntry {
-            id: None,
-            user_id,
-            payload,
-            status: "pending".to_string(),
-            created_at: Utc::now(),
-        };
-
-        let result = self.collection.insert_one(entry).await?;
-        Ok(result.inserted_id.as_object_id().unwrap())
+    ) -> WarehouseResult<ObjectId> {
+        let result = self
+            .collection
+            .insert_one(OutboxEntry::pending(user_id, event_type, payload))
+            .session(session)
+            .await?;
+        inserted_id(&result)
     }
 
-    pub async fn mark_sent(&self, id: mongodb::bson::oid::ObjectId) -> mongodb::error::Result<()> {
+    pub async fn mark_sent(&self, id: ObjectId, delivery: &DeliveryReport) -> WarehouseResult<()> {
         self.collection
-            .update_one(doc! { "_id": id}, doc! { "$set": { "status": "sent "}})
+            .update_one(
+                doc! { "_id": id},
+                doc! { "$set": {
+                    "status": "sent",
+                    "kafka_partition": delivery.partition,
+                    "kafka_offset": delivery.offset,
+                }},
+            )
             .await?;
         Ok(())
     }
 }
 
 impl Outbox {
-    pub async fn fetch_pending_entries(&self) -> mongodb::error::Result<Vec<OutboxEntry>> {
+    pub async fn fetch_pending_entries(&self) -> WarehouseResult<Vec<OutboxEntry>> {
         let cursor = self.collection.find(doc! { "status": "pending"}).await?;
 
-        cursor.try_collect().await
+        Ok(cursor.try_collect().await?)
     }
-}
 
-pub async fn retry_pending_entries(outbox: Arc<Outbox>, kafka_producer: Arc<EventProducer>) {
-    let mut retry_interval = interval(Duration::from_secs(60));
+    pub async fn get(&self, id: ObjectId) -> WarehouseResult<OutboxEntry> {
+        self.collection
+            .find_one(doc! { "_id": id})
+            .await?
+            .ok_or_else(|| WarehouseError::not_found("outbox entry", id))
+    }
 
-    loop {
-        retry_interval.tick().await;
+    /// Moves a pending entry to processing so no other relay sends it. Returns `None` if it was
+    /// already claimed.
+    pub async fn claim(&self, id: ObjectId) -> WarehouseResult<Option<OutboxEntry>> {
+        Ok(self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": id, "status": "pending"},
+                doc! { "$set": { "status": "processing", "claimed_at": BsonDateTime::now()}},
+            )
+            .return_document(ReturnDocument::After)
+            .await?)
+    }
 
-        let pending_entries = match outbox.fetch_pending_entries().await {
-            Ok(entries) => entries,
-            Err(err) => {
-                eprintln!("Failed to fetch pending entries: {:?}", err);
-                continue;
-            }
-        };
+    /// Hands a claimed entry back to the pending state, e.g. after its send failed.
+    pub async fn release(&self, id: ObjectId) -> WarehouseResult<()> {
+        self.collection
+            .update_one(
+                doc! { "_id": id, "status": "processing"},
+                doc! {
+                    "$set": { "status": "pending"},
+                    "$unset": { "claimed_at": ""},
+                },
+            )
+            .await?;
+        Ok(())
+    }
 
-        if pending_entries.is_empty() {
-            println!("No pending entries to retry");
-            continue;
-        }
+    /// Entries claimed longer than `timeout` ago that were never marked sent, most likely because
+    /// the relay that claimed them stopped.
+    pub async fn fetch_stale_claims(&self, timeout: Duration) -> WarehouseResult<Vec<OutboxEntry>> {
+        let cutoff = BsonDateTime::from_millis(
+            BsonDateTime::now().timestamp_millis() - timeout.as_millis() as i64,
+        );
+        let cursor = self
+            .collection
+            .find(doc! { "status": "processing", "claimed_at": { "$lt": cutoff}})
+            .await?;
 
-        for entry in pending_entries {
-            println!("Retrying entry with ID: {:?}", entry.id);
-
-            match kafka_producer
-                .send_event(entry.user_id.to_string(), &entry.payload)
-                .await
-            {
-                Ok(_) => {
-                    if let Some(entry_id) = entry.id {
-                        if let Err(err) = outbox.mark_sent(entry_id).await {
-                            eprintln!(
-                                "Failed to update status for entry ID {:?}: {:?}",
-                                entry_id, err
-                            );
-                        } else {
-                            println!("Successfully marked entry as sent: {:?}", entry_id);
-                        }
-                    }
-                }
-                Err(err) => {
-                    eprintln!("Failed to send entry to Kafka: {:?}", err);
-                }
-            }
-        }
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Newly inserted entries, as they are written. Requires MongoDB to run as a replica set.
+    pub async fn watch_inserts(
+        &self,
+    ) -> WarehouseResult<ChangeStream<ChangeStreamEvent<OutboxEntry>>> {
+        Ok(self
+            .collection
+            .watch()
+            .pipeline([doc! { "$match": { "operationType": "insert"}}])
+            .await?)
     }
 }
@@ -0,0 +1,524 @@
+use crate::{Outbox, OutboxEntry};
+use async_trait::async_trait;
+use common_error::error::{KafkaError, KafkaResult, WarehouseResult};
+use common_kafka::{DeliveryLookup, DeliveryReport, MessageProducer, RetryPolicy};
+use futures_util::StreamExt;
+use mongodb::bson::oid::ObjectId;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// Header carrying the outbox entry id on every relayed message, so consumers can deduplicate and
+/// a recovering relay can find messages that were sent but never marked.
+pub const OUTBOX_ID_HEADER: &str = "x-outbox-id";
+
+/// Header carrying the entry's event type.
+pub const EVENT_TYPE_HEADER: &str = "x-event-type";
+
+/// How far before its claim a message is looked for, to allow for clock skew between relays.
+const CLAIM_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelayConfig {
+    /// Retries of a single send.
+    pub send_policy: RetryPolicy,
+    /// Spacing of the sweeps for entries the change stream missed, growing while sweeps fail.
+    pub sweep_policy: RetryPolicy,
+    /// How long an entry may stay claimed before it is assumed its relay stopped. Has to be longer
+    /// than a send can take with all its retries.
+    pub claim_timeout: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            // Jittered so relays restarting together after an outage do not retry in lockstep.
+            send_policy: RetryPolicy::exponential_jitter(
+                Duration::from_secs(2),
+                Duration::from_secs(30),
+            )
+            .with_max_attempts(4),
+            sweep_policy: RetryPolicy::fixed(Duration::from_secs(10)),
+            claim_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The outbox operations the relay needs besides watching for inserts, implemented by `Outbox`.
+/// Lets the claim and recovery logic run against an in-memory store in tests.
+#[async_trait]
+pub trait RelayStore: Send + Sync {
+    async fn fetch_pending_entries(&self) -> WarehouseResult<Vec<OutboxEntry>>;
+    async fn claim(&self, id: ObjectId) -> WarehouseResult<Option<OutboxEntry>>;
+    async fn release(&self, id: ObjectId) -> WarehouseResult<()>;
+    async fn mark_sent(&self, id: ObjectId, delivery: &DeliveryReport) -> WarehouseResult<()>;
+    async fn fetch_stale_claims(&self, timeout: Duration) -> WarehouseResult<Vec<OutboxEntry>>;
+}
+
+#[async_trait]
+impl RelayStore for Outbox {
+    async fn fetch_pending_entries(&self) -> WarehouseResult<Vec<OutboxEntry>> {
+        Outbox::fetch_pending_entries(self).await
+    }
+
+    async fn claim(&self, id: ObjectId) -> WarehouseResult<Option<OutboxEntry>> {
+        Outbox::claim(self, id).await
+    }
+
+    async fn release(&self, id: ObjectId) -> WarehouseResult<()> {
+        Outbox::release(self, id).await
+    }
+
+    async fn mark_sent(&self, id: ObjectId, delivery: &DeliveryReport) -> WarehouseResult<()> {
+        Outbox::mark_sent(self, id, delivery).await
+    }
+
+    async fn fetch_stale_claims(&self, timeout: Duration) -> WarehouseResult<Vec<OutboxEntry>> {
+        Outbox::fetch_stale_claims(self, timeout).await
+    }
+}
+
+/// What a sweep did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SweepSummary {
+    pub recovered: usize,
+    pub sent: usize,
+    pub failed: usize,
+}
+
+/// Publishes outbox entries to Kafka and marks them sent.
+///
+/// An entry is claimed before it is sent and marked sent afterwards. If a relay stops in between,
+/// the claim goes stale and the next sweep looks the entry up on the topic: if it was published
+/// it is marked sent with the original delivery, otherwise it is released and sent again. Every
+/// entry is published once, even across crashes.
+pub struct OutboxRelay<P, S = Outbox> {
+    outbox: S,
+    producer: Arc<P>,
+    config: RelayConfig,
+}
+
+impl<P: MessageProducer + DeliveryLookup> OutboxRelay<P> {
+    /// Relays entries as they are inserted, sweeping for the ones the change stream missed on
+    /// start and then per `sweep_policy`. Runs until the change stream ends.
+    pub async fn run(&self) -> WarehouseResult<()> {
+        let mut changes = self.outbox.watch_inserts().await?;
+        let next_sweep = sleep(Duration::ZERO);
+        tokio::pin!(next_sweep);
+        let mut failed_sweeps = 0;
+
+        loop {
+            tokio::select! {
+                change = changes.next() => {
+                    let entry = match change {
+                        Some(Ok(event)) => event.full_document,
+                        Some(Err(err)) => return Err(err.into()),
+                        None => return Ok(()),
+                    };
+                    let Some(id) = entry.and_then(|entry| entry.id) else {
+                        continue;
+                    };
+                    // Left pending for the next sweep while the broker is down.
+                    if !self.producer.accepts_sends() {
+                        continue;
+                    }
+                    if let Err(err) = self.relay_entry(id).await {
+                        tracing::error!("Failed to relay entry {}: {}", id, err);
+                    }
+                }
+                _ = &mut next_sweep => {
+                    failed_sweeps = match self.sweep().await {
+                        Ok(summary) if summary.failed == 0 => 0,
+                        Ok(_) => failed_sweeps + 1,
+                        Err(err) => {
+                            tracing::error!("Outbox sweep failed: {}", err);
+                            failed_sweeps + 1
+                        }
+                    };
+                    let delay = self.config.sweep_policy.delay(failed_sweeps);
+                    next_sweep.as_mut().reset(Instant::now() + delay);
+                }
+            }
+        }
+    }
+}
+
+impl<P: MessageProducer + DeliveryLookup, S: RelayStore> OutboxRelay<P, S> {
+    pub fn new(outbox: S, producer: Arc<P>, config: RelayConfig) -> Self {
+        Self {
+            outbox,
+            producer,
+            config,
+        }
+    }
+
+    /// Recovers stale claims, then relays every pending entry.
+    pub async fn sweep(&self) -> WarehouseResult<SweepSummary> {
+        let mut summary = SweepSummary {
+            recovered: self.recover_stale_claims().await?,
+            ..SweepSummary::default()
+        };
+
+        if !self.producer.accepts_sends() {
+            tracing::warn!("Kafka unavailable, skipping pending entries");
+            summary.failed = self.outbox.fetch_pending_entries().await?.len();
+            return Ok(summary);
+        }
+
+        for entry in self.outbox.fetch_pending_entries().await? {
+            let Some(id) = entry.id else {
+                continue;
+            };
+            match self.relay_entry(id).await {
+                Ok(true) => summary.sent += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    tracing::error!("Failed to relay entry {}: {}", id, err);
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Claims, publishes and marks a single entry. Returns `false` if another relay claimed it
+    /// first.
+    pub async fn relay_entry(&self, id: ObjectId) -> WarehouseResult<bool> {
+        let Some(entry) = self.outbox.claim(id).await? else {
+            return Ok(false);
+        };
+
+        let delivery = match self.publish(&entry).await {
+            Ok(delivery) => delivery,
+            Err(err) => {
+                self.outbox.release(id).await?;
+                return Err(err.into());
+            }
+        };
+
+        self.outbox.mark_sent(id, &delivery).await?;
+        tracing::info!(
+            "Relayed entry {} to {}[{}]@{}",
+            id,
+            delivery.topic,
+            delivery.partition,
+            delivery.offset
+        );
+        Ok(true)
+    }
+
+    /// Publishes an entry without touching its status.
+    pub async fn publish(&self, entry: &OutboxEntry) -> KafkaResult<DeliveryReport> {
+        let id = entry
+            .id
+            .ok_or_else(|| KafkaError::Rejected("outbox entry has no id".into()))?
+            .to_hex();
+        let key = entry.user_id.to_string();
+        let headers = [
+            (OUTBOX_ID_HEADER, id.as_bytes()),
+            (EVENT_TYPE_HEADER, entry.event_type.as_bytes()),
+        ];
+
+        self.config
+            .send_policy
+            .retry(|| {
+                self.producer.send_to(
+                    self.producer.topic(),
+                    key.as_bytes(),
+                    entry.payload.as_bytes(),
+                    &headers,
+                )
+            })
+            .await
+    }
+
+    /// Resolves claims older than `claim_timeout`: entries found on the topic are marked sent,
+    /// the rest go back to pending. Returns how many claims were resolved.
+    pub async fn recover_stale_claims(&self) -> WarehouseResult<usize> {
+        let stale = self
+            .outbox
+            .fetch_stale_claims(self.config.claim_timeout)
+            .await?;
+        let mut recovered = 0;
+
+        for entry in stale {
+            let (Some(id), Some(claimed_at)) = (entry.id, entry.claimed_at) else {
+                continue;
+            };
+            let since = claimed_at.timestamp_millis() - CLAIM_CLOCK_SKEW.as_millis() as i64;
+
+            let found = self
+                .producer
+                .find_published(
+                    self.producer.topic(),
+                    since,
+                    OUTBOX_ID_HEADER,
+                    id.to_hex().as_bytes(),
+                )
+                .await;
+
+            match found {
+                Ok(Some(delivery)) => {
+                    tracing::warn!("Entry {} was sent before its relay stopped", id);
+                    self.outbox.mark_sent(id, &delivery).await?;
+                }
+                Ok(None) => {
+                    tracing::warn!("Entry {} was never sent, releasing it", id);
+                    self.outbox.release(id).await?;
+                }
+                Err(err) => {
+                    // Releasing without knowing could publish the entry twice, try again later.
+                    tracing::error!("Could not check whether entry {} was sent: {}", id, err);
+                    continue;
+                }
+            }
+            recovered += 1;
+        }
+
+        Ok(recovered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_kafka::config::InboundConfig;
+    use common_kafka::memory::{InMemoryBroker, InMemoryProducer};
+    use mongodb::bson::DateTime as BsonDateTime;
+    use std::sync::Mutex;
+
+    /// An outbox kept in memory, with the status transitions of `Outbox`.
+    #[derive(Default)]
+    struct FakeOutbox {
+        entries: Mutex<Vec<OutboxEntry>>,
+    }
+
+    impl FakeOutbox {
+        fn add(&self, user_id: u64, payload: &str) -> ObjectId {
+            let id = ObjectId::new();
+            let mut entry = OutboxEntry::pending(user_id, crate::STOCK_RECEIVED, payload.into());
+            entry.id = Some(id);
+            self.entries.lock().unwrap().push(entry);
+            id
+        }
+
+        /// Puts an entry in the state a relay that stopped after claiming it leaves behind.
+        fn abandon_claim(&self, id: ObjectId, ago: Duration) {
+            self.update(id, |entry| {
+                entry.status = "processing".to_string();
+                entry.claimed_at = Some(BsonDateTime::from_millis(
+                    BsonDateTime::now().timestamp_millis() - ago.as_millis() as i64,
+                ));
+            });
+        }
+
+        fn status(&self, id: ObjectId) -> (String, Option<i64>) {
+            let entries = self.entries.lock().unwrap();
+            let entry = entries.iter().find(|entry| entry.id == Some(id)).unwrap();
+            (entry.status.clone(), entry.kafka_offset)
+        }
+
+        fn update(&self, id: ObjectId, update: impl FnOnce(&mut OutboxEntry)) {
+            let mut entries = self.entries.lock().unwrap();
+            if let Some(entry) = entries.iter_mut().find(|entry| entry.id == Some(id)) {
+                update(entry);
+            }
+        }
+
+        fn with_status(&self, status: &str) -> Vec<OutboxEntry> {
+            self.entries
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|entry| entry.status == status)
+                .cloned()
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl RelayStore for FakeOutbox {
+        async fn fetch_pending_entries(&self) -> WarehouseResult<Vec<OutboxEntry>> {
+            Ok(self.with_status("pending"))
+        }
+
+        async fn claim(&self, id: ObjectId) -> WarehouseResult<Option<OutboxEntry>> {
+            let mut entries = self.entries.lock().unwrap();
+            Ok(entries
+                .iter_mut()
+                .find(|entry| entry.id == Some(id) && entry.status == "pending")
+                .map(|entry| {
+                    entry.status = "processing".to_string();
+                    entry.claimed_at = Some(BsonDateTime::now());
+                    entry.clone()
+                }))
+        }
+
+        async fn release(&self, id: ObjectId) -> WarehouseResult<()> {
+            self.update(id, |entry| {
+                if entry.status == "processing" {
+                    entry.status = "pending".to_string();
+                    entry.claimed_at = None;
+                }
+            });
+            Ok(())
+        }
+
+        async fn mark_sent(&self, id: ObjectId, delivery: &DeliveryReport) -> WarehouseResult<()> {
+            self.update(id, |entry| {
+                entry.status = "sent".to_string();
+                entry.kafka_partition = Some(delivery.partition);
+                entry.kafka_offset = Some(delivery.offset);
+            });
+            Ok(())
+        }
+
+        async fn fetch_stale_claims(&self, timeout: Duration) -> WarehouseResult<Vec<OutboxEntry>> {
+            let cutoff = BsonDateTime::now().timestamp_millis() - timeout.as_millis() as i64;
+            Ok(self
+                .with_status("processing")
+                .into_iter()
+                .filter(|entry| {
+                    entry
+                        .claimed_at
+                        .is_some_and(|claimed_at| claimed_at.timestamp_millis() < cutoff)
+                })
+                .collect())
+        }
+    }
+
+    fn relay(broker: &InMemoryBroker) -> OutboxRelay<InMemoryProducer, FakeOutbox> {
+        let producer = Arc::new(InMemoryProducer::new(broker, InboundConfig::new()));
+        let config = RelayConfig {
+            send_policy: RetryPolicy::fixed(Duration::from_millis(1)).with_max_attempts(2),
+            ..RelayConfig::default()
+        };
+        OutboxRelay::new(FakeOutbox::default(), producer, config)
+    }
+
+    #[tokio::test]
+    async fn sweep_publishes_pending_entries_and_marks_them_sent() {
+        let broker = InMemoryBroker::new();
+        let relay = relay(&broker);
+        let outbox = &relay.outbox;
+        let first = outbox.add(7, "{\"n\":1}");
+        let second = outbox.add(7, "{\"n\":2}");
+
+        let summary = relay.sweep().await.unwrap();
+
+        assert_eq!(
+            summary,
+            SweepSummary {
+                recovered: 0,
+                sent: 2,
+                failed: 0
+            }
+        );
+        assert_eq!(outbox.status(first), ("sent".to_string(), Some(0)));
+        assert_eq!(outbox.status(second), ("sent".to_string(), Some(1)));
+
+        let published = broker.messages("INBOUND");
+        assert_eq!(published[0].key, b"7");
+        assert_eq!(published[0].payload, b"{\"n\":1}");
+        assert_eq!(
+            published[0].header(OUTBOX_ID_HEADER),
+            Some(first.to_hex().as_bytes())
+        );
+        assert_eq!(
+            published[0].header(EVENT_TYPE_HEADER),
+            Some(crate::STOCK_RECEIVED.as_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_send_releases_the_entry() {
+        let broker = InMemoryBroker::new();
+        let relay = relay(&broker);
+        let outbox = &relay.outbox;
+        let id = outbox.add(7, "{}");
+
+        broker.set_unavailable(true);
+        assert!(relay.relay_entry(id).await.is_err());
+        assert_eq!(outbox.status(id), ("pending".to_string(), None));
+        assert!(broker.messages("INBOUND").is_empty());
+
+        broker.set_unavailable(false);
+        assert!(relay.relay_entry(id).await.unwrap());
+        assert_eq!(outbox.status(id), ("sent".to_string(), Some(0)));
+    }
+
+    #[tokio::test]
+    async fn entry_claimed_elsewhere_is_left_alone() {
+        let broker = InMemoryBroker::new();
+        let relay = relay(&broker);
+        let outbox = &relay.outbox;
+        let id = outbox.add(7, "{}");
+        outbox.claim(id).await.unwrap();
+
+        assert!(!relay.relay_entry(id).await.unwrap());
+        assert!(broker.messages("INBOUND").is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_claim_that_was_published_is_marked_sent_without_resending() {
+        let broker = InMemoryBroker::new();
+        let relay = relay(&broker);
+        let outbox = &relay.outbox;
+        let id = outbox.add(7, "{}");
+
+        // The relay published the entry and stopped before marking it sent.
+        let entry = outbox.claim(id).await.unwrap().unwrap();
+        relay.publish(&entry).await.unwrap();
+        outbox.abandon_claim(id, Duration::from_secs(600));
+
+        let summary = relay.sweep().await.unwrap();
+
+        assert_eq!(summary.recovered, 1);
+        assert_eq!(summary.sent, 0);
+        assert_eq!(outbox.status(id), ("sent".to_string(), Some(0)));
+        assert_eq!(broker.messages("INBOUND").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_claim_that_was_never_published_is_sent_again() {
+        let broker = InMemoryBroker::new();
+        let relay = relay(&broker);
+        let outbox = &relay.outbox;
+        let id = outbox.add(7, "{}");
+        outbox.abandon_claim(id, Duration::from_secs(600));
+
+        let summary = relay.sweep().await.unwrap();
+
+        assert_eq!(summary.recovered, 1);
+        assert_eq!(summary.sent, 1);
+        assert_eq!(outbox.status(id), ("sent".to_string(), Some(0)));
+        assert_eq!(broker.messages("INBOUND").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn recent_claim_is_not_recovered() {
+        let broker = InMemoryBroker::new();
+        let relay = relay(&broker);
+        let outbox = &relay.outbox;
+        let id = outbox.add(7, "{}");
+        outbox.abandon_claim(id, Duration::from_secs(10));
+
+        assert_eq!(relay.sweep().await.unwrap(), SweepSummary::default());
+        assert_eq!(outbox.status(id), ("processing".to_string(), None));
+    }
+
+    #[tokio::test]
+    async fn sweep_leaves_entries_pending_while_broker_is_unavailable() {
+        let broker = InMemoryBroker::new();
+        let relay = relay(&broker);
+        let outbox = &relay.outbox;
+        let id = outbox.add(7, "{}");
+        broker.set_unavailable(true);
+
+        let summary = relay.sweep().await.unwrap();
+
+        assert_eq!(summary.failed, 1);
+        assert_eq!(outbox.status(id), ("pending".to_string(), None));
+    }
+}
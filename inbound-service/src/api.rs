@@ -0,0 +1,487 @@
+//! The HTTP API the handheld scanners call to book stock in.
+//!
+//! - `POST /scans` records one scan and returns the received line. Resubmitting a scan with the
+//!   same `idempotency_key` returns the line it was first recorded as, with `200 OK` rather than
+//!   `201 Created`.
+//! - `POST /scans/batch` records `{"scans": [...]}` all together or not at all, `200 OK` when every
+//!   scan was recorded before.
+//! - `GET /shipments/{shipment_id}/lines` lists the lines received against a shipment.
+//! - `POST /asns` stores the Advance Shipping Notice of a shipment. Submitting the same ASN again
+//!   returns the stored one with `200 OK`.
+//! - `GET /shipments/{shipment_id}/asn` returns the ASN with what was received of each product.
+//! - `POST /shipments/{shipment_id}/close` with `{"operator_id"}` closes the shipment and
+//!   publishes its over, short and unexpected item discrepancies.
+//! - `GET /shipments/{shipment_id}/quarantine` lists the stock quarantined on receipt.
+//! - `POST /quarantine/{quarantine_id}/release` and `.../scrap` with `{"reason_code",
+//!   "operator_id"}` and optionally `"quantity"` release or scrap quarantined stock, all that is
+//!   still held without a quantity.
+//!
+//! Errors are returned as `application/problem+json`.
+
+use crate::model::{
+    Asn, AsnStatus, Condition, Discrepancy, Disposition, DispositionAction, IdempotencyKey, NewAsn,
+    QuarantinedStock, ReceivedLine, Scan,
+};
+use crate::receiving::{tally, ProductTally};
+use crate::store::ReceivingStore;
+use crate::uom::Uom;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{FromRequest, Path, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, NaiveDate, Utc};
+use common_error::error::{WarehouseError, PROBLEM_JSON_CONTENT_TYPE};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+/// The most scans a single batch may hold, to keep its transaction short.
+pub const MAX_BATCH_SCANS: usize = 500;
+
+pub fn router(store: ReceivingStore) -> Router {
+    Router::new()
+        .route("/scans", post(submit_scan))
+        .route("/scans/batch", post(submit_batch))
+        .route("/shipments/{shipment_id}/lines", get(shipment_lines))
+        .route("/asns", post(create_asn))
+        .route("/shipments/{shipment_id}/asn", get(shipment_asn))
+        .route("/shipments/{shipment_id}/close", post(close_shipment))
+        .route(
+            "/shipments/{shipment_id}/quarantine",
+            get(shipment_quarantine),
+        )
+        .route(
+            "/quarantine/{quarantine_id}/release",
+            post(release_quarantined),
+        )
+        .route("/quarantine/{quarantine_id}/scrap", post(scrap_quarantined))
+        .with_state(store)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScanBatch {
+    pub scans: Vec<Scan>,
+}
+
+/// A received line as the API returns it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LineView {
+    pub id: String,
+    pub shipment_id: u64,
+    pub product_id: u64,
+    /// In eaches.
+    pub quantity: u32,
+    pub received_uom: Uom,
+    pub received_quantity: u32,
+    pub operator_id: u64,
+    #[serde(default)]
+    pub condition: Condition,
+    pub outbox_id: String,
+    pub received_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<IdempotencyKey>,
+}
+
+impl From<ReceivedLine> for LineView {
+    fn from(line: ReceivedLine) -> Self {
+        Self {
+            id: line.id.to_hex(),
+            shipment_id: line.shipment_id,
+            product_id: line.product_id,
+            quantity: line.quantity,
+            received_uom: line.received_uom,
+            received_quantity: line.received_quantity.unwrap_or(line.quantity),
+            operator_id: line.operator_id,
+            condition: line.condition,
+            outbox_id: line.outbox_id.to_hex(),
+            received_at: line.received_at,
+            lot: line.lot,
+            expiry: line.expiry,
+            serial: line.serial,
+            idempotency_key: line.idempotency_key,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloseShipment {
+    pub operator_id: u64,
+}
+
+/// Quarantined stock as the API returns it.
+#[derive(Debug, Serialize)]
+pub struct QuarantineView {
+    pub id: String,
+    pub line_id: String,
+    pub shipment_id: u64,
+    pub product_id: u64,
+    pub condition: Condition,
+    pub quantity: u32,
+    pub held: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    pub quarantined_at: DateTime<Utc>,
+    pub dispositions: Vec<Disposition>,
+}
+
+impl From<QuarantinedStock> for QuarantineView {
+    fn from(stock: QuarantinedStock) -> Self {
+        Self {
+            id: stock.id.to_hex(),
+            line_id: stock.line_id.to_hex(),
+            shipment_id: stock.shipment_id,
+            product_id: stock.product_id,
+            condition: stock.condition,
+            quantity: stock.quantity,
+            held: stock.held,
+            lot: stock.lot,
+            expiry: stock.expiry,
+            serial: stock.serial,
+            quarantined_at: stock.quarantined_at,
+            dispositions: stock.dispositions,
+        }
+    }
+}
+
+/// A request to release or scrap quarantined stock.
+#[derive(Debug, Deserialize)]
+pub struct DisposeStock {
+    /// In eaches, all that is still held if left out.
+    #[serde(default)]
+    pub quantity: Option<u32>,
+    pub reason_code: String,
+    pub operator_id: u64,
+}
+
+/// An ASN as the API returns it, with what was received of each product so far.
+#[derive(Debug, Serialize)]
+pub struct AsnView {
+    pub shipment_id: u64,
+    pub supplier: String,
+    pub expected_arrival: DateTime<Utc>,
+    pub status: AsnStatus,
+    pub created_at: DateTime<Utc>,
+    pub last_received_at: Option<DateTime<Utc>>,
+    pub closed_at: Option<DateTime<Utc>>,
+    pub products: Vec<ProductTally>,
+}
+
+impl AsnView {
+    fn new(asn: Asn, lines: &[ReceivedLine]) -> Self {
+        Self {
+            products: tally(&asn, lines),
+            shipment_id: asn.shipment_id,
+            supplier: asn.supplier,
+            expected_arrival: asn.expected_arrival,
+            status: asn.status,
+            created_at: asn.created_at,
+            last_received_at: asn.last_received_at,
+            closed_at: asn.closed_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClosedShipment {
+    pub asn: AsnView,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+async fn submit_scan(
+    State(store): State<ReceivingStore>,
+    ApiJson(scan): ApiJson<Scan>,
+) -> Result<(StatusCode, Json<LineView>), ApiError> {
+    validate(&scan).map_err(WarehouseError::Validation)?;
+    let (line, recorded) = store.record_or_replay_scan(&scan).await?;
+    Ok((created_or_replayed(recorded), Json(line.into())))
+}
+
+async fn submit_batch(
+    State(store): State<ReceivingStore>,
+    ApiJson(batch): ApiJson<ScanBatch>,
+) -> Result<(StatusCode, Json<Vec<LineView>>), ApiError> {
+    if batch.scans.is_empty() {
+        return Err(WarehouseError::Validation("batch holds no scans".into()).into());
+    }
+    if batch.scans.len() > MAX_BATCH_SCANS {
+        return Err(WarehouseError::Validation(format!(
+            "batch holds {} scans, at most {} are allowed",
+            batch.scans.len(),
+            MAX_BATCH_SCANS
+        ))
+        .into());
+    }
+    for (index, scan) in batch.scans.iter().enumerate() {
+        validate(scan).map_err(|e| WarehouseError::Validation(format!("scan {}: {}", index, e)))?;
+    }
+
+    let lines = store.record_or_replay_scans(&batch.scans).await?;
+    let recorded = lines.iter().any(|(_, recorded)| *recorded);
+    Ok((
+        created_or_replayed(recorded),
+        Json(lines.into_iter().map(|(line, _)| line.into()).collect()),
+    ))
+}
+
+/// `201 Created` when anything was recorded, `200 OK` when all of it was a replay.
+fn created_or_replayed(recorded: bool) -> StatusCode {
+    if recorded {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    }
+}
+
+async fn shipment_lines(
+    State(store): State<ReceivingStore>,
+    Path(shipment_id): Path<u64>,
+) -> Result<Json<Vec<LineView>>, ApiError> {
+    let lines = store.lines_for_shipment(shipment_id).await?;
+    Ok(Json(lines.into_iter().map(LineView::from).collect()))
+}
+
+async fn create_asn(
+    State(store): State<ReceivingStore>,
+    ApiJson(asn): ApiJson<NewAsn>,
+) -> Result<(StatusCode, Json<AsnView>), ApiError> {
+    asn.validate().map_err(WarehouseError::Validation)?;
+    let (asn, created) = store.create_asn(asn).await?;
+    if created {
+        return Ok((StatusCode::CREATED, Json(AsnView::new(asn, &[]))));
+    }
+    let lines = store.lines_for_shipment(asn.shipment_id).await?;
+    Ok((StatusCode::OK, Json(AsnView::new(asn, &lines))))
+}
+
+async fn shipment_asn(
+    State(store): State<ReceivingStore>,
+    Path(shipment_id): Path<u64>,
+) -> Result<Json<AsnView>, ApiError> {
+    let asn = store.asn(shipment_id).await?;
+    let lines = store.lines_for_shipment(shipment_id).await?;
+    Ok(Json(AsnView::new(asn, &lines)))
+}
+
+async fn close_shipment(
+    State(store): State<ReceivingStore>,
+    Path(shipment_id): Path<u64>,
+    ApiJson(close): ApiJson<CloseShipment>,
+) -> Result<Json<ClosedShipment>, ApiError> {
+    let (asn, discrepancies) = store.close_shipment(shipment_id, close.operator_id).await?;
+    let lines = store.lines_for_shipment(shipment_id).await?;
+    Ok(Json(ClosedShipment {
+        asn: AsnView::new(asn, &lines),
+        discrepancies,
+    }))
+}
+
+async fn shipment_quarantine(
+    State(store): State<ReceivingStore>,
+    Path(shipment_id): Path<u64>,
+) -> Result<Json<Vec<QuarantineView>>, ApiError> {
+    let stock = store.quarantined_for_shipment(shipment_id).await?;
+    Ok(Json(stock.into_iter().map(QuarantineView::from).collect()))
+}
+
+async fn release_quarantined(
+    State(store): State<ReceivingStore>,
+    Path(quarantine_id): Path<String>,
+    ApiJson(request): ApiJson<DisposeStock>,
+) -> Result<Json<QuarantineView>, ApiError> {
+    dispose(&store, &quarantine_id, DispositionAction::Release, request).await
+}
+
+async fn scrap_quarantined(
+    State(store): State<ReceivingStore>,
+    Path(quarantine_id): Path<String>,
+    ApiJson(request): ApiJson<DisposeStock>,
+) -> Result<Json<QuarantineView>, ApiError> {
+    dispose(&store, &quarantine_id, DispositionAction::Scrap, request).await
+}
+
+async fn dispose(
+    store: &ReceivingStore,
+    quarantine_id: &str,
+    action: DispositionAction,
+    request: DisposeStock,
+) -> Result<Json<QuarantineView>, ApiError> {
+    let id = ObjectId::parse_str(quarantine_id)
+        .map_err(|_| WarehouseError::not_found("quarantined stock", quarantine_id))?;
+    if request.quantity == Some(0) {
+        return Err(WarehouseError::Validation("quantity must be at least 1".into()).into());
+    }
+    if request.reason_code.trim().is_empty() {
+        return Err(WarehouseError::Validation("reason code must not be empty".into()).into());
+    }
+
+    let stock = store
+        .dispose_quarantined(
+            id,
+            action,
+            request.quantity,
+            request.reason_code.trim(),
+            request.operator_id,
+        )
+        .await?;
+    Ok(Json(stock.into()))
+}
+
+fn validate(scan: &Scan) -> Result<(), String> {
+    if scan.quantity == 0 {
+        return Err("quantity must be at least 1".into());
+    }
+    if [&scan.lot, &scan.serial]
+        .into_iter()
+        .any(|field| field.as_ref().is_some_and(|value| value.trim().is_empty()))
+    {
+        return Err("lot and serial must not be empty when given".into());
+    }
+    if scan
+        .idempotency_key
+        .as_ref()
+        .is_some_and(|key| key.device_id.trim().is_empty())
+    {
+        return Err("idempotency key needs a device id".into());
+    }
+    Ok(())
+}
+
+/// `axum::Json`, rejecting malformed bodies with a problem response instead of plain text.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// A `WarehouseError` returned from a handler, responded to as problem details.
+#[derive(Debug)]
+pub struct ApiError(pub WarehouseError);
+
+impl From<WarehouseError> for ApiError {
+    fn from(error: WarehouseError) -> Self {
+        Self(error)
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(WarehouseError::Serialization(Box::new(rejection)))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let problem = self.0.to_problem();
+        if problem.status >= 500 {
+            tracing::error!("Request failed: {}", self.0);
+        }
+
+        let status =
+            StatusCode::from_u16(problem.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (
+            status,
+            [(header::CONTENT_TYPE, PROBLEM_JSON_CONTENT_TYPE)],
+            Json(problem),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::http::Request;
+    use mongodb::Client;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    /// A router whose store never connects, for requests that are turned away before reaching it.
+    async fn app() -> Router {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1").await.unwrap();
+        router(ReceivingStore::from_client(client, "inbound", "outbox"))
+    }
+
+    async fn post(uri: &str, body: impl Into<Body>) -> (StatusCode, Option<String>, Value) {
+        let request = Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.into())
+            .unwrap();
+        let response = app().await.oneshot(request).await.unwrap();
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn scan(quantity: u32) -> Value {
+        json!({ "shipment_id": 1, "product_id": 2, "quantity": quantity, "operator_id": 3 })
+    }
+
+    #[tokio::test]
+    async fn invalid_scans_are_unprocessable_problems() {
+        let (status, content_type, problem) = post("/scans", scan(0).to_string()).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(content_type.as_deref(), Some(PROBLEM_JSON_CONTENT_TYPE));
+        assert_eq!(problem["code"], "VALIDATION_FAILED");
+        assert_eq!(problem["status"], 422);
+        assert_eq!(
+            problem["detail"],
+            "Validation failed: quantity must be at least 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_a_bad_request() {
+        for body in ["{\"shipment_id\": 1,", "{\"shipment_id\": \"one\"}"] {
+            let (status, content_type, problem) = post("/scans", body).await;
+
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(content_type.as_deref(), Some(PROBLEM_JSON_CONTENT_TYPE));
+            assert_eq!(problem["code"], "SERIALIZATION_ERROR");
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_must_hold_between_one_and_the_maximum_scans() {
+        let (status, _, problem) = post("/scans/batch", json!({ "scans": [] }).to_string()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["detail"], "Validation failed: batch holds no scans");
+
+        let scans = vec![scan(1); MAX_BATCH_SCANS + 1];
+        let (status, _, problem) =
+            post("/scans/batch", json!({ "scans": scans }).to_string()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            problem["detail"],
+            "Validation failed: batch holds 501 scans, at most 500 are allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_name_the_invalid_scan() {
+        let scans = [scan(1), scan(0)];
+        let (status, _, problem) =
+            post("/scans/batch", json!({ "scans": scans }).to_string()).await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["code"], "VALIDATION_FAILED");
+        assert_eq!(
+            problem["detail"],
+            "Validation failed: scan 1: quantity must be at least 1"
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use clap::{Parser, ValueEnum};
+use common_error::error::{WarehouseError, WarehouseResult};
+use common_kafka::config::InboundConfig;
+use common_kafka::consumer::EventConsumer;
+use inbound_outbox::Outbox;
+use inbound_service::load::{
+    run, Latencies, LatencyTracker, LoadConfig, LoadReport, Pattern, Target, DEFAULT_OPERATORS,
+};
+use inbound_service::simulator::read_rows;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const DB_NAME: &str = "warehouse";
+const OUTBOX_COLLECTION: &str = "inbound_outbox";
+
+/// How long to wait for the Kafka consumer to be assigned partitions before writing.
+const ASSIGNMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Parser)]
+#[command(about = "Generates scan load against the inbound pipeline and reports how it held up")]
+struct Args {
+    #[arg(long, value_enum, default_value = "outbox")]
+    target: TargetKind,
+    #[arg(long, env = "MONGO_URI", default_value = "mongodb://localhost:27017")]
+    mongo_uri: String,
+    /// Base URL of the scan-ingest API, for `--target http`.
+    #[arg(long, env = "API_URL", default_value = "http://localhost:8080")]
+    api_url: String,
+    /// Rows picked from at random.
+    #[arg(long, default_value = "inbound-stock.csv")]
+    csv: PathBuf,
+    /// Operator ids the rows are booked in for, the original simulator's users by default.
+    #[arg(long, value_delimiter = ',')]
+    operators: Vec<u64>,
+    /// Writes per second.
+    #[arg(long, default_value_t = 10.0)]
+    rate: f64,
+    #[arg(long, value_enum, default_value = "steady")]
+    pattern: PatternKind,
+    /// Writes per burst, for `--pattern burst`.
+    #[arg(long, default_value_t = 50)]
+    burst_size: u32,
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+    /// Random by default.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Writes in flight at most.
+    #[arg(long, default_value_t = 64)]
+    concurrency: usize,
+    #[arg(long, env = "KAFKA_BROKERS", default_value = "localhost:9092")]
+    brokers: String,
+    /// Skips measuring the latency to Kafka.
+    #[arg(long)]
+    no_kafka: bool,
+    /// How long to wait for the last writes to reach Kafka.
+    #[arg(long, default_value_t = 30)]
+    drain_secs: u64,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TargetKind {
+    Outbox,
+    Http,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PatternKind {
+    Steady,
+    Poisson,
+    Burst,
+    Ramp,
+}
+
+#[tokio::main]
+async fn main() -> WarehouseResult<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    if !(args.rate > 0.0 && args.rate.is_finite()) {
+        return Err(WarehouseError::Configuration(
+            "--rate must be a positive number".to_string(),
+        ));
+    }
+    let rows = read_rows(&args.csv)?;
+    if rows.is_empty() {
+        return Err(WarehouseError::Configuration(format!(
+            "no rows in {}",
+            args.csv.display()
+        )));
+    }
+
+    let config = LoadConfig {
+        operators: if args.operators.is_empty() {
+            DEFAULT_OPERATORS.to_vec()
+        } else {
+            args.operators.clone()
+        },
+        rate: args.rate,
+        pattern: match args.pattern {
+            PatternKind::Steady => Pattern::Steady,
+            PatternKind::Poisson => Pattern::Poisson,
+            PatternKind::Burst => Pattern::Burst {
+                size: args.burst_size,
+            },
+            PatternKind::Ramp => Pattern::Ramp,
+        },
+        duration: Duration::from_secs(args.duration_secs),
+        seed: args.seed.unwrap_or_else(rand::random),
+        concurrency: args.concurrency,
+    };
+
+    let target = match args.target {
+        TargetKind::Outbox => {
+            Target::Outbox(Outbox::new(&args.mongo_uri, DB_NAME, OUTBOX_COLLECTION).await?)
+        }
+        TargetKind::Http => Target::Http {
+            client: reqwest::Client::new(),
+            base_url: args.api_url.clone(),
+        },
+    };
+
+    let tracker = if args.no_kafka {
+        None
+    } else {
+        Some(watch_kafka(&args.brokers).await?)
+    };
+
+    tracing::info!(
+        "Generating {:?} load of {} writes/s for {}s with seed {}",
+        config.pattern,
+        config.rate,
+        args.duration_secs,
+        config.seed
+    );
+    let report = run(
+        Arc::new(target),
+        &rows,
+        &config,
+        tracker,
+        Duration::from_secs(args.drain_secs),
+    )
+    .await;
+    print_report(&report, config.seed);
+    Ok(())
+}
+
+/// Starts consuming the inbound topic from its end, in a group of its own so the real consumers
+/// keep all their messages, and waits until it has partitions.
+async fn watch_kafka(brokers: &str) -> WarehouseResult<LatencyTracker> {
+    let config = InboundConfig {
+        brokers: brokers.to_string(),
+        group_id: format!("load-generator-{:016x}", rand::random::<u64>()),
+        ..InboundConfig::new()
+    }
+    .with_override("auto.offset.reset", "latest");
+
+    let tracker = LatencyTracker::new();
+    let consumer = EventConsumer::new(config, Box::new(tracker.clone()))?;
+    tokio::spawn(async move {
+        if let Err(e) = consumer.start().await {
+            tracing::error!("Kafka consumer stopped: {}", e);
+        }
+    });
+
+    let deadline = Instant::now() + ASSIGNMENT_TIMEOUT;
+    while !tracker.is_assigned() && Instant::now() < deadline {
+        sleep(Duration::from_millis(100)).await;
+    }
+    if !tracker.is_assigned() {
+        tracing::warn!("No partitions assigned yet, the first writes may be reported missing");
+    }
+    Ok(tracker)
+}
+
+fn print_report(report: &LoadReport, seed: u64) {
+    println!("seed:        {}", seed);
+    println!("elapsed:     {:.1}s", report.elapsed.as_secs_f64());
+    println!(
+        "writes:      {} attempted, {} succeeded, {} failed",
+        report.attempted,
+        report.written,
+        report.attempted - report.written
+    );
+    for (reason, count) in &report.failures {
+        println!("             {} x {}", count, reason);
+    }
+    println!("throughput:  {:.1} writes/s", report.throughput());
+    println!("write:       {}", latencies(&report.write_latency));
+    match &report.end_to_end {
+        Some(end_to_end) => {
+            println!("end-to-end:  {}", latencies(&end_to_end.latency));
+            println!("not seen:    {}", end_to_end.missing);
+        }
+        None => println!("end-to-end:  not measured"),
+    }
+}
+
+fn latencies(latencies: &Latencies) -> String {
+    if latencies.count == 0 {
+        return "no samples".to_string();
+    }
+    format!(
+        "p50 {:?}  p95 {:?}  p99 {:?}  max {:?}  ({} samples)",
+        latencies.p50, latencies.p95, latencies.p99, latencies.max, latencies.count
+    )
+}
@@ -0,0 +1,692 @@
+//! Parses X12 856 ship notices into ASNs.
+//!
+//! An interchange (`ISA`/`IEA`) holds functional groups (`GS`/`GE`) holding transaction sets
+//! (`ST`/`SE`), every 856 set becomes one `NewAsn`:
+//!
+//! - `BSN02`, the shipment identification, is the shipment id and has to be numeric.
+//! - The ship from party (`N1*SF`, or `N1*SU` without one) is the supplier.
+//! - The estimated delivery date (`DTM*017`, or the ship date `DTM*011` without one) is the
+//!   expected arrival.
+//! - Every item loop (`HL` with level code `I`) contributes its buyer's part number (`LIN` with
+//!   qualifier `BP`) as the product id and its units shipped (`SN1`, in `EA`, `CA` or `PL`) as
+//!   the quantity. Items of the same product and unit in different packs or orders are added up.
+//!
+//! Segments the ASN does not need are skipped. Segments that are needed but cannot be used, and
+//! transaction sets that do not make an ASN, are reported as rejected instead of failing the whole
+//! interchange.
+
+use crate::model::{AsnLine, NewAsn};
+use crate::uom::Uom;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use common_error::error::WarehouseError;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+/// Why an interchange could not be read at all.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum EdiError {
+    #[error("Not an X12 interchange: {0}")]
+    NotX12(String),
+    #[error("Malformed interchange envelope: {0}")]
+    Envelope(String),
+}
+
+impl From<EdiError> for WarehouseError {
+    fn from(e: EdiError) -> Self {
+        WarehouseError::Validation(e.to_string())
+    }
+}
+
+/// A segment that was not used, with its position in the interchange counting from 1.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RejectedSegment {
+    pub position: usize,
+    pub segment: String,
+    pub reason: String,
+}
+
+/// An 856 transaction set turned into an ASN.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShipNotice {
+    /// `ST02`, to find the set in the supplier's document.
+    pub control_number: String,
+    pub asn: NewAsn,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Interchange {
+    pub sender_id: String,
+    pub receiver_id: String,
+    pub control_number: String,
+    pub notices: Vec<ShipNotice>,
+    pub rejected: Vec<RejectedSegment>,
+}
+
+/// The interchange header is fixed length, its 4th character is the element separator and the one
+/// after `ISA16` the segment terminator.
+const ISA_LENGTH: usize = 106;
+
+struct Segment<'a> {
+    position: usize,
+    raw: &'a str,
+    elements: Vec<&'a str>,
+}
+
+impl<'a> Segment<'a> {
+    fn id(&self) -> &'a str {
+        self.elements[0]
+    }
+
+    /// The element at an X12 position, e.g. `element(2)` for `BSN02`, empty if absent.
+    fn element(&self, index: usize) -> &'a str {
+        self.elements
+            .get(index)
+            .map_or("", |element| element.trim())
+    }
+
+    fn reject(&self, reason: impl Into<String>) -> RejectedSegment {
+        RejectedSegment {
+            position: self.position,
+            segment: self.raw.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+pub fn parse_856(input: &str) -> Result<Interchange, EdiError> {
+    let input = input.trim_start();
+    if !input.starts_with("ISA") || input.len() < ISA_LENGTH || !input.is_char_boundary(ISA_LENGTH)
+    {
+        return Err(EdiError::NotX12(
+            "input does not start with a complete ISA segment".into(),
+        ));
+    }
+    let header = input.as_bytes();
+    let element_separator = header[3] as char;
+    let terminator = header[ISA_LENGTH - 1] as char;
+    if element_separator.is_ascii_alphanumeric() || terminator.is_ascii_alphanumeric() {
+        return Err(EdiError::NotX12(
+            "ISA segment does not declare usable delimiters".into(),
+        ));
+    }
+
+    let segments: Vec<Segment> = input
+        .split(terminator)
+        .map(str::trim)
+        .filter(|raw| !raw.is_empty())
+        .enumerate()
+        .map(|(index, raw)| Segment {
+            position: index + 1,
+            raw,
+            elements: raw.split(element_separator).collect(),
+        })
+        .collect();
+
+    let isa = &segments[0];
+    let control_number = isa.element(13).to_string();
+    let mut interchange = Interchange {
+        sender_id: isa.element(6).to_string(),
+        receiver_id: isa.element(8).to_string(),
+        control_number: control_number.clone(),
+        notices: Vec::new(),
+        rejected: Vec::new(),
+    };
+
+    let mut groups = 0;
+    let mut group: Option<(&Segment, usize)> = None;
+    let mut set: Option<usize> = None;
+    let mut closed = false;
+
+    for (index, segment) in segments.iter().enumerate().skip(1) {
+        if closed {
+            interchange
+                .rejected
+                .push(segment.reject("segment after the IEA trailer"));
+            continue;
+        }
+        match (segment.id(), set) {
+            ("SE", Some(start)) => {
+                set = None;
+                if let Some((_, sets)) = group.as_mut() {
+                    *sets += 1;
+                }
+                match transaction_set(&segments[start..=index]) {
+                    Ok((notice, rejected)) => {
+                        interchange.rejected.extend(rejected);
+                        interchange.notices.push(notice);
+                    }
+                    Err(rejected) => interchange.rejected.extend(rejected),
+                }
+            }
+            (_, Some(_)) => {}
+            ("GS", None) => {
+                if let Some((open, _)) = group {
+                    interchange
+                        .rejected
+                        .push(open.reject("functional group has no GE trailer"));
+                }
+                groups += 1;
+                group = Some((segment, 0));
+            }
+            ("ST", None) => {
+                if group.is_none() {
+                    interchange
+                        .rejected
+                        .push(segment.reject("transaction set outside of a functional group"));
+                }
+                set = Some(index);
+            }
+            ("GE", None) => match group.take() {
+                Some((gs, sets)) => {
+                    if segment.element(2) != gs.element(6) {
+                        interchange.rejected.push(segment.reject(format!(
+                            "control number does not match GS06 {}",
+                            gs.element(6)
+                        )));
+                    } else if segment.element(1) != sets.to_string() {
+                        interchange
+                            .rejected
+                            .push(segment.reject(format!("group holds {} transaction sets", sets)));
+                    }
+                }
+                None => interchange
+                    .rejected
+                    .push(segment.reject("GE without a functional group")),
+            },
+            ("IEA", None) => {
+                closed = true;
+                if segment.element(2) != control_number {
+                    interchange.rejected.push(segment.reject(format!(
+                        "control number does not match ISA13 {}",
+                        control_number
+                    )));
+                } else if segment.element(1) != groups.to_string() {
+                    interchange
+                        .rejected
+                        .push(segment.reject(format!("interchange holds {} groups", groups)));
+                }
+            }
+            _ => interchange
+                .rejected
+                .push(segment.reject("segment outside of a transaction set")),
+        }
+    }
+
+    if let Some(start) = set {
+        return Err(EdiError::Envelope(format!(
+            "transaction set starting at segment {} has no SE trailer",
+            segments[start].position
+        )));
+    }
+    if !closed {
+        return Err(EdiError::Envelope(
+            "interchange has no IEA trailer, the file may be truncated".into(),
+        ));
+    }
+    Ok(interchange)
+}
+
+/// An item loop while its segments are read.
+struct Item<'s, 'a> {
+    hl: &'s Segment<'a>,
+    product_id: Option<u64>,
+    quantity: Option<(u32, Uom)>,
+    rejected: bool,
+}
+
+/// Turns the segments from `ST` to `SE` into a ship notice and the segments it could not use, or
+/// into the reasons it makes no ASN.
+fn transaction_set(
+    segments: &[Segment],
+) -> Result<(ShipNotice, Vec<RejectedSegment>), Vec<RejectedSegment>> {
+    let st = &segments[0];
+    let se = &segments[segments.len() - 1];
+    if st.element(1) != "856" {
+        return Err(vec![st.reject(format!(
+            "transaction set {} is not a ship notice (856)",
+            st.element(1)
+        ))]);
+    }
+    if se.element(2) != st.element(2) {
+        return Err(vec![se.reject(format!(
+            "control number does not match ST02 {}",
+            st.element(2)
+        ))]);
+    }
+    if se.element(1) != segments.len().to_string() {
+        return Err(vec![se.reject(format!(
+            "transaction set holds {} segments, it may be truncated",
+            segments.len()
+        ))]);
+    }
+
+    let mut rejected = Vec::new();
+    let mut shipment_id = None;
+    let mut parties: HashMap<&str, &str> = HashMap::new();
+    let mut dates: HashMap<&str, DateTime<Utc>> = HashMap::new();
+    let mut levels: HashMap<&str, &str> = HashMap::new();
+    let mut quantities: BTreeMap<(u64, Uom), u32> = BTreeMap::new();
+    let mut item: Option<Item> = None;
+
+    for segment in &segments[1..segments.len() - 1] {
+        match segment.id() {
+            "BSN" => match segment.element(2).parse::<u64>() {
+                Ok(id) => shipment_id = Some(id),
+                Err(_) => rejected.push(segment.reject(format!(
+                    "shipment identification {:?} is not a numeric shipment id",
+                    segment.element(2)
+                ))),
+            },
+            "HL" => {
+                finish_item(item.take(), &mut quantities, &mut rejected);
+
+                let (id, parent, level) =
+                    (segment.element(1), segment.element(2), segment.element(3));
+                if !parent.is_empty() && !levels.contains_key(parent) {
+                    rejected.push(segment.reject(format!("parent HL {} is unknown", parent)));
+                    continue;
+                }
+                match level {
+                    "S" | "O" | "P" | "T" => {}
+                    "I" => {
+                        item = Some(Item {
+                            hl: segment,
+                            product_id: None,
+                            quantity: None,
+                            rejected: false,
+                        })
+                    }
+                    _ => {
+                        rejected.push(segment.reject(format!("unknown level code {:?}", level)));
+                        continue;
+                    }
+                }
+                levels.insert(id, level);
+            }
+            "N1" => {
+                parties
+                    .entry(segment.element(1))
+                    .or_insert(segment.element(2));
+            }
+            "DTM" => match edi_datetime(segment.element(2), segment.element(3)) {
+                Some(date) => {
+                    dates.entry(segment.element(1)).or_insert(date);
+                }
+                None => rejected.push(segment.reject("invalid date or time")),
+            },
+            "LIN" => {
+                let Some(item) = item.as_mut() else {
+                    rejected.push(segment.reject("LIN outside of an item loop"));
+                    continue;
+                };
+                // LIN02/03, LIN04/05, ... are pairs of qualifier and product id.
+                let buyers_part = segment
+                    .elements
+                    .get(2..)
+                    .unwrap_or_default()
+                    .chunks(2)
+                    .find(|pair| pair[0] == "BP" && pair.len() == 2)
+                    .map(|pair| pair[1].trim());
+                match buyers_part.map(str::parse::<u64>) {
+                    Some(Ok(product_id)) => item.product_id = Some(product_id),
+                    Some(Err(_)) => {
+                        item.rejected = true;
+                        rejected.push(segment.reject("buyer's part number is not a product id"));
+                    }
+                    None => {
+                        item.rejected = true;
+                        rejected.push(segment.reject("no buyer's part number (BP)"));
+                    }
+                }
+            }
+            "SN1" => {
+                let Some(item) = item.as_mut() else {
+                    rejected.push(segment.reject("SN1 outside of an item loop"));
+                    continue;
+                };
+                let unit = segment.element(3);
+                match (segment.element(2).parse::<u32>(), Uom::from_x12(unit)) {
+                    (Ok(quantity), Some(uom)) if quantity > 0 => {
+                        item.quantity = Some((quantity, uom))
+                    }
+                    (Ok(_), None) => {
+                        item.rejected = true;
+                        rejected.push(segment.reject(format!(
+                            "unit {:?} is not supported, quantities have to be in EA, CA or PL",
+                            unit
+                        )));
+                    }
+                    _ => {
+                        item.rejected = true;
+                        rejected.push(segment.reject("units shipped is not a positive number"));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    finish_item(item.take(), &mut quantities, &mut rejected);
+
+    let supplier = parties.get("SF").or_else(|| parties.get("SU"));
+    let expected_arrival = dates.get("017").or_else(|| dates.get("011"));
+    let asn = match (shipment_id, supplier, expected_arrival) {
+        (None, ..) => Err("no BSN segment with a numeric shipment id"),
+        (_, None, _) => Err("no ship from party (N1*SF or N1*SU)"),
+        (_, _, None) => Err("no delivery or ship date (DTM*017 or DTM*011)"),
+        _ if quantities.is_empty() => Err("no usable items"),
+        (Some(shipment_id), Some(supplier), Some(expected_arrival)) => Ok(NewAsn {
+            shipment_id,
+            supplier: supplier.to_string(),
+            expected_arrival: *expected_arrival,
+            lines: quantities
+                .into_iter()
+                .map(|((product_id, uom), quantity)| AsnLine {
+                    product_id,
+                    quantity,
+                    uom,
+                })
+                .collect(),
+        }),
+    };
+    let asn = match asn {
+        Ok(asn) => asn,
+        Err(missing) => {
+            rejected.push(st.reject(format!("transaction set makes no ASN: {}", missing)));
+            return Err(rejected);
+        }
+    };
+
+    Ok((
+        ShipNotice {
+            control_number: st.element(2).to_string(),
+            asn,
+        },
+        rejected,
+    ))
+}
+
+fn finish_item(
+    item: Option<Item>,
+    quantities: &mut BTreeMap<(u64, Uom), u32>,
+    rejected: &mut Vec<RejectedSegment>,
+) {
+    let Some(item) = item else {
+        return;
+    };
+    // The segment at fault of a rejected item was reported already.
+    if item.rejected {
+        return;
+    }
+    match (item.product_id, item.quantity) {
+        (Some(product_id), Some((quantity, uom))) => {
+            let total = quantities.entry((product_id, uom)).or_default();
+            *total = total.saturating_add(quantity);
+        }
+        (None, _) => rejected.push(item.hl.reject("item loop has no LIN segment")),
+        (_, None) => rejected.push(item.hl.reject("item loop has no SN1 segment")),
+    }
+}
+
+/// A `CCYYMMDD` date with an optional `HHMM` time, taken to be UTC.
+fn edi_datetime(date: &str, time: &str) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(date, "%Y%m%d").ok()?;
+    let time = match time {
+        "" => NaiveTime::MIN,
+        time => NaiveTime::parse_from_str(time.get(..4)?, "%H%M").ok()?,
+    };
+    Some(date.and_time(time).and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const ISA: &str = "ISA*00*          *00*          *ZZ*SENDERID       *ZZ*RECEIVERID     \
+                       *260101*1200*U*00401*000000001*0*P*>";
+
+    const ITEMS: [&str; 9] = [
+        "HL*2*1*I",
+        "LIN**BP*1001",
+        "SN1**10*EA",
+        "HL*3*1*I",
+        "LIN**BP*1002",
+        "SN1**2*CA",
+        "HL*4*1*I",
+        "LIN**BP*1001",
+        "SN1**5*EA",
+    ];
+
+    /// An interchange with one ship notice for shipment 12345 holding `items`, every count and
+    /// control number in its trailers matching.
+    fn interchange(items: &[&str]) -> String {
+        let mut set = vec![
+            "ST*856*0001",
+            "BSN*00*12345*20260101*1200",
+            "HL*1**S",
+            "DTM*017*20260105*0800",
+            "N1*SF*Acme Supply",
+        ];
+        set.extend_from_slice(items);
+        let se = format!("SE*{}*0001", set.len() + 1);
+
+        [ISA, "GS*SH*SENDER*RECEIVER*20260101*1200*1*X*004010"]
+            .into_iter()
+            .chain(set)
+            .chain([se.as_str(), "GE*1*1", "IEA*1*000000001"])
+            .map(|segment| format!("{}~\n", segment))
+            .collect()
+    }
+
+    fn rejected(interchange: &Interchange) -> Vec<(usize, &str)> {
+        interchange
+            .rejected
+            .iter()
+            .map(|rejected| (rejected.position, rejected.reason.as_str()))
+            .collect()
+    }
+
+    fn lines(notice: &ShipNotice) -> Vec<(u64, u32, Uom)> {
+        notice
+            .asn
+            .lines
+            .iter()
+            .map(|line| (line.product_id, line.quantity, line.uom))
+            .collect()
+    }
+
+    #[test]
+    fn well_formed_interchange_makes_an_asn() {
+        let interchange = parse_856(&interchange(&ITEMS)).unwrap();
+
+        assert_eq!(interchange.sender_id, "SENDERID");
+        assert_eq!(interchange.receiver_id, "RECEIVERID");
+        assert_eq!(interchange.control_number, "000000001");
+        assert!(
+            interchange.rejected.is_empty(),
+            "{:?}",
+            interchange.rejected
+        );
+        assert_eq!(interchange.notices.len(), 1);
+
+        let notice = &interchange.notices[0];
+        assert_eq!(notice.control_number, "0001");
+        assert_eq!(notice.asn.shipment_id, 12345);
+        assert_eq!(notice.asn.supplier, "Acme Supply");
+        assert_eq!(
+            notice.asn.expected_arrival,
+            Utc.with_ymd_and_hms(2026, 1, 5, 8, 0, 0).unwrap()
+        );
+        // Both items of product 1001 in eaches are added up.
+        assert_eq!(
+            lines(notice),
+            vec![(1001, 15, Uom::Each), (1002, 2, Uom::Case)]
+        );
+    }
+
+    #[test]
+    fn supplier_and_ship_date_fallbacks_are_used() {
+        let input = interchange(&ITEMS)
+            .replace("N1*SF*", "N1*SU*")
+            .replace("DTM*017*20260105*0800", "DTM*011*20260102");
+        let interchange = parse_856(&input).unwrap();
+
+        let asn = &interchange.notices[0].asn;
+        assert_eq!(asn.supplier, "Acme Supply");
+        assert_eq!(
+            asn.expected_arrival,
+            Utc.with_ymd_and_hms(2026, 1, 2, 0, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn segment_count_mismatch_rejects_the_set() {
+        let input = interchange(&ITEMS).replace("SE*15*0001", "SE*14*0001");
+        let interchange = parse_856(&input).unwrap();
+
+        assert!(interchange.notices.is_empty());
+        assert_eq!(
+            rejected(&interchange),
+            vec![(17, "transaction set holds 15 segments, it may be truncated")]
+        );
+    }
+
+    #[test]
+    fn set_control_number_mismatch_rejects_the_set() {
+        let input = interchange(&ITEMS).replace("SE*15*0001", "SE*15*0002");
+        let interchange = parse_856(&input).unwrap();
+
+        assert!(interchange.notices.is_empty());
+        assert_eq!(
+            rejected(&interchange),
+            vec![(17, "control number does not match ST02 0001")]
+        );
+    }
+
+    #[test]
+    fn group_and_interchange_count_mismatches_are_reported() {
+        let input = interchange(&ITEMS)
+            .replace("GE*1*1", "GE*2*1")
+            .replace("IEA*1*", "IEA*3*");
+        let interchange = parse_856(&input).unwrap();
+
+        // The counts are off, the ship notice itself is fine.
+        assert_eq!(interchange.notices.len(), 1);
+        assert_eq!(
+            rejected(&interchange),
+            vec![
+                (18, "group holds 1 transaction sets"),
+                (19, "interchange holds 1 groups"),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_control_number_mismatch_is_reported() {
+        let input = interchange(&ITEMS).replace("GE*1*1", "GE*1*2");
+        let interchange = parse_856(&input).unwrap();
+
+        assert_eq!(interchange.notices.len(), 1);
+        assert_eq!(
+            rejected(&interchange),
+            vec![(18, "control number does not match GS06 1")]
+        );
+    }
+
+    #[test]
+    fn missing_trailers_fail_the_interchange() {
+        let input = interchange(&ITEMS).replace("IEA*1*000000001~\n", "");
+        assert_eq!(
+            parse_856(&input).unwrap_err(),
+            EdiError::Envelope("interchange has no IEA trailer, the file may be truncated".into())
+        );
+
+        let input = interchange(&ITEMS).replace("SE*15*0001~\n", "");
+        assert_eq!(
+            parse_856(&input).unwrap_err(),
+            EdiError::Envelope("transaction set starting at segment 3 has no SE trailer".into())
+        );
+    }
+
+    #[test]
+    fn segments_after_the_trailer_are_rejected() {
+        let input = interchange(&ITEMS) + "ST*856*0002~\n";
+        let interchange = parse_856(&input).unwrap();
+
+        assert_eq!(interchange.notices.len(), 1);
+        assert_eq!(
+            rejected(&interchange),
+            vec![(20, "segment after the IEA trailer")]
+        );
+    }
+
+    #[test]
+    fn item_loops_without_lin_or_sn1_are_rejected() {
+        let interchange = parse_856(&interchange(&[
+            "HL*2*1*I",
+            "SN1**10*EA",
+            "HL*3*1*I",
+            "LIN**BP*1002",
+            "HL*4*1*I",
+            "LIN**BP*1003",
+            "SN1**4*PL",
+        ]))
+        .unwrap();
+
+        assert_eq!(lines(&interchange.notices[0]), vec![(1003, 4, Uom::Pallet)]);
+        assert_eq!(
+            rejected(&interchange),
+            vec![
+                (8, "item loop has no LIN segment"),
+                (10, "item loop has no SN1 segment"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_unit_rejects_the_item() {
+        let interchange = parse_856(&interchange(&[
+            "HL*2*1*I",
+            "LIN**BP*1001",
+            "SN1**3*BX",
+            "HL*3*1*I",
+            "LIN**BP*1002",
+            "SN1**2*CA",
+        ]))
+        .unwrap();
+
+        assert_eq!(lines(&interchange.notices[0]), vec![(1002, 2, Uom::Case)]);
+        assert_eq!(
+            rejected(&interchange),
+            vec![(
+                10,
+                "unit \"BX\" is not supported, quantities have to be in EA, CA or PL"
+            )]
+        );
+    }
+
+    #[test]
+    fn set_without_usable_items_makes_no_asn() {
+        let interchange =
+            parse_856(&interchange(&["HL*2*1*I", "LIN**VP*ABC-1", "SN1**3*EA"])).unwrap();
+
+        assert!(interchange.notices.is_empty());
+        assert_eq!(
+            rejected(&interchange),
+            vec![
+                (9, "no buyer's part number (BP)"),
+                (3, "transaction set makes no ASN: no usable items"),
+            ]
+        );
+    }
+
+    #[test]
+    fn other_input_is_not_x12() {
+        assert!(matches!(
+            parse_856("shipment_id,product_id\n1,2\n"),
+            Err(EdiError::NotX12(_))
+        ));
+        assert!(matches!(parse_856(&ISA[..50]), Err(EdiError::NotX12(_))));
+    }
+}
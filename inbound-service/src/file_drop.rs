@@ -0,0 +1,184 @@
+use crate::edi::{parse_856, RejectedSegment};
+use crate::store::ReceivingStore;
+use common_error::error::{WarehouseError, WarehouseResult};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::time::sleep;
+
+/// Extensions of the files picked up. Senders should write files under another name and rename
+/// them when complete, so half written files are never read.
+const EDI_EXTENSIONS: [&str; 3] = ["edi", "x12", "856"];
+
+#[derive(Debug, Clone)]
+pub struct FileDropConfig {
+    /// Where suppliers' ship notices are dropped. Ingested files are moved to its `processed`
+    /// subdirectory and unreadable ones to `rejected`, each with a `.report.json` next to it.
+    pub dir: PathBuf,
+    pub poll_interval: Duration,
+}
+
+/// An ASN a ship notice could not be stored as.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedNotice {
+    pub control_number: String,
+    pub shipment_id: u64,
+    pub reason: String,
+}
+
+/// What ingesting a file did, written next to it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IngestReport {
+    pub file: String,
+    pub interchange_control_number: Option<String>,
+    /// Shipments whose ASN was stored, or already was when the file is ingested again after
+    /// failing partway.
+    pub created: Vec<u64>,
+    pub failed: Vec<FailedNotice>,
+    pub rejected_segments: Vec<RejectedSegment>,
+    /// Why the file could not be read at all.
+    pub error: Option<String>,
+}
+
+/// Ingests X12 856 ship notices dropped into a local directory as ASNs.
+pub struct FileDrop {
+    store: ReceivingStore,
+    config: FileDropConfig,
+}
+
+impl FileDrop {
+    pub fn new(store: ReceivingStore, config: FileDropConfig) -> Self {
+        Self { store, config }
+    }
+
+    /// Ingests dropped files every `poll_interval`, until the directory cannot be read.
+    pub async fn run(&self) -> WarehouseResult<()> {
+        tracing::info!("Watching {} for ship notices", self.config.dir.display());
+        loop {
+            self.ingest_dropped().await?;
+            sleep(self.config.poll_interval).await;
+        }
+    }
+
+    /// Ingests every file currently in the directory and returns their reports. Files that fail
+    /// for a reason other than their content, e.g. MongoDB being unavailable, are logged and left
+    /// in place without a report.
+    pub async fn ingest_dropped(&self) -> WarehouseResult<Vec<IngestReport>> {
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(&self.config.dir)
+            .await
+            .map_err(|e| io_error(format!("cannot read {}", self.config.dir.display()), e))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| io_error(format!("cannot read {}", self.config.dir.display()), e))?
+        {
+            let path = entry.path();
+            let is_edi = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| EDI_EXTENSIONS.contains(&extension));
+            if is_edi && entry.file_type().await.is_ok_and(|kind| kind.is_file()) {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        let mut reports = Vec::new();
+        for path in files {
+            match self.ingest_file(&path).await {
+                Ok(report) => reports.push(report),
+                // The file is left where it is and tried again on the next poll.
+                Err(e) => tracing::error!(
+                    "Could not ingest {}, trying again on the next poll: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        Ok(reports)
+    }
+
+    async fn ingest_file(&self, path: &Path) -> WarehouseResult<IngestReport> {
+        let mut report = IngestReport {
+            file: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            ..IngestReport::default()
+        };
+
+        let bytes = fs::read(path)
+            .await
+            .map_err(|e| io_error(format!("cannot read {}", path.display()), e))?;
+        let interchange = match parse_856(&String::from_utf8_lossy(&bytes)) {
+            Ok(interchange) => interchange,
+            Err(e) => {
+                tracing::warn!("Rejecting {}: {}", path.display(), e);
+                report.error = Some(e.to_string());
+                self.file_away(path, "rejected", &report).await?;
+                return Ok(report);
+            }
+        };
+
+        report.interchange_control_number = Some(interchange.control_number);
+        report.rejected_segments = interchange.rejected;
+        for notice in interchange.notices {
+            let shipment_id = notice.asn.shipment_id;
+            let stored = match notice.asn.validate() {
+                Ok(()) => self.store.create_asn(notice.asn).await.map(|_| ()),
+                Err(e) => Err(WarehouseError::Validation(e)),
+            };
+            match stored {
+                Ok(()) => report.created.push(shipment_id),
+                // Anything else is worth trying again with the file left where it is.
+                Err(e @ (WarehouseError::Validation(_) | WarehouseError::Conflict(_))) => {
+                    report.failed.push(FailedNotice {
+                        control_number: notice.control_number,
+                        shipment_id,
+                        reason: e.to_string(),
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        tracing::info!(
+            "Ingested {}: {} ASNs created, {} failed, {} segments rejected",
+            path.display(),
+            report.created.len(),
+            report.failed.len(),
+            report.rejected_segments.len()
+        );
+        self.file_away(path, "processed", &report).await?;
+        Ok(report)
+    }
+
+    /// Moves a file into a subdirectory of the drop directory and writes its report next to it.
+    async fn file_away(
+        &self,
+        path: &Path,
+        subdir: &str,
+        report: &IngestReport,
+    ) -> WarehouseResult<()> {
+        let dir = self.config.dir.join(subdir);
+        fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| io_error(format!("cannot create {}", dir.display()), e))?;
+
+        let report_path = dir.join(format!("{}.report.json", report.file));
+        fs::write(&report_path, serde_json::to_vec_pretty(report)?)
+            .await
+            .map_err(|e| io_error(format!("cannot write {}", report_path.display()), e))?;
+
+        let target = dir.join(&report.file);
+        fs::rename(path, &target)
+            .await
+            .map_err(|e| io_error(format!("cannot move {}", path.display()), e))
+    }
+}
+
+fn io_error(what: String, e: std::io::Error) -> WarehouseError {
+    WarehouseError::Configuration(format!("{}: {}", what, e))
+}
@@ -0,0 +1,573 @@
+//! Imports supplier manifests in the `inbound-stock.csv` format.
+//!
+//! Rows are read one at a time and each is checked for a positive quantity and a product listed
+//! in the catalog that comes in the row's unit and whose lot, serial and shelf life rules it
+//! meets, and that its shipment, product, lot and serial were not already on an earlier line.
+//! Valid rows are written through the outbox in batches, every rejected row is written to a report
+//! with its line number and the reason.
+//!
+//! Every row is written with an idempotency key made of a hash of the file and the row's line, so
+//! importing the same file again, e.g. after an import stopped halfway, does not book its rows
+//! twice. A file that was edited in between is a different file, its rows are booked again.
+
+use crate::model::{CsvRow, IdempotencyKey, Product, ReceivedLine, Scan};
+use crate::store::ReceivingStore;
+use crate::uom::to_eaches;
+use async_trait::async_trait;
+use chrono::Utc;
+use common_error::classification::Classify;
+use common_error::error::WarehouseResult;
+use csv::{ErrorKind, ReaderBuilder, StringRecord, Writer};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// The store operations an import needs, implemented by `ReceivingStore`. Lets imports run
+/// against an in-memory catalog in tests.
+#[async_trait]
+pub trait ImportStore: Sync {
+    async fn products(&self, product_ids: &[u64]) -> WarehouseResult<HashMap<u64, Product>>;
+    async fn record_scans(&self, scans: &[Scan]) -> WarehouseResult<Vec<ReceivedLine>>;
+    async fn record_scan(&self, scan: &Scan) -> WarehouseResult<ReceivedLine>;
+}
+
+#[async_trait]
+impl ImportStore for ReceivingStore {
+    async fn products(&self, product_ids: &[u64]) -> WarehouseResult<HashMap<u64, Product>> {
+        ReceivingStore::products(self, product_ids).await
+    }
+
+    async fn record_scans(&self, scans: &[Scan]) -> WarehouseResult<Vec<ReceivedLine>> {
+        ReceivingStore::record_scans(self, scans).await
+    }
+
+    async fn record_scan(&self, scan: &Scan) -> WarehouseResult<ReceivedLine> {
+        ReceivingStore::record_scan(self, scan).await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportConfig {
+    /// Who the rows are booked in for.
+    pub operator_id: u64,
+    /// How many rows are checked against the catalog and written together, in one transaction.
+    pub batch_size: usize,
+    /// Validates every row and writes the report, but writes nothing to the database.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    pub rows: usize,
+    /// Rows that passed validation, the ones a dry run would have imported.
+    pub valid: usize,
+    /// Rows recorded, including ones an earlier import of the same file recorded already.
+    pub imported: usize,
+    pub rejected: usize,
+}
+
+/// A line of the report.
+#[derive(Serialize)]
+struct RejectedRow<'a> {
+    line: u64,
+    shipment_id: &'a str,
+    product_id: &'a str,
+    quantity: &'a str,
+    error: &'a str,
+}
+
+/// Shipment, product, lot and serial.
+type RowKey = (u64, u64, Option<String>, Option<String>);
+
+struct PendingRow {
+    line: u64,
+    record: StringRecord,
+    row: CsvRow,
+}
+
+/// Imports the CSV file at `input`, writing the rejected rows to a CSV report at `report`.
+///
+/// Stops on storage errors worth retrying, leaving the batches written so far in place. Importing
+/// the file again carries on from there.
+pub async fn import_csv<S: ImportStore>(
+    store: &S,
+    input: &Path,
+    report: &Path,
+    config: &ImportConfig,
+) -> WarehouseResult<ImportSummary> {
+    // Flexible, so rows with missing or extra fields are reported rather than ending the import.
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_path(input)?;
+    let headers = reader.headers()?.clone();
+    let mut import = Import {
+        store,
+        config,
+        device_id: format!("import:{}", file_hash(input)?),
+        columns: ["shipment_id", "product_id", "quantity"]
+            .map(|name| headers.iter().position(|header| header == name)),
+        headers,
+        report: Writer::from_path(report)?,
+        summary: ImportSummary::default(),
+        first_seen: HashMap::new(),
+        batch: Vec::new(),
+    };
+
+    let mut record = StringRecord::new();
+    loop {
+        match reader.read_record(&mut record) {
+            Ok(true) => import.add(&record).await?,
+            Ok(false) => break,
+            Err(e) if matches!(e.kind(), ErrorKind::Io(_)) => return Err(e.into()),
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line());
+                import.summary.rows += 1;
+                import.reject(
+                    line,
+                    &StringRecord::new(),
+                    &format!("unreadable row: {}", e),
+                )?;
+            }
+        }
+    }
+    import.flush().await?;
+    import.report.flush().map_err(csv::Error::from)?;
+
+    tracing::info!(
+        "Imported {}: {} rows, {} valid, {} imported, {} rejected{}",
+        input.display(),
+        import.summary.rows,
+        import.summary.valid,
+        import.summary.imported,
+        import.summary.rejected,
+        if config.dry_run { " (dry run)" } else { "" }
+    );
+    Ok(import.summary)
+}
+
+/// A hex SHA-256 of the file's contents.
+fn file_hash(path: &Path) -> WarehouseResult<String> {
+    let mut hasher = Sha256::new();
+    io::copy(
+        &mut File::open(path).map_err(csv::Error::from)?,
+        &mut hasher,
+    )
+    .map_err(csv::Error::from)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+struct Import<'a, S> {
+    store: &'a S,
+    config: &'a ImportConfig,
+    /// The device id of the rows' idempotency keys, their line is the sequence.
+    device_id: String,
+    headers: StringRecord,
+    /// Where the shipment id, product id and quantity are in a record.
+    columns: [Option<usize>; 3],
+    report: Writer<File>,
+    summary: ImportSummary,
+    /// The line each shipment, product, lot and serial was first seen on.
+    first_seen: HashMap<RowKey, u64>,
+    batch: Vec<PendingRow>,
+}
+
+impl<S: ImportStore> Import<'_, S> {
+    async fn add(&mut self, record: &StringRecord) -> WarehouseResult<()> {
+        let line = record.position().map_or(0, |position| position.line());
+        self.summary.rows += 1;
+
+        let row: CsvRow = match record.deserialize(Some(&self.headers)) {
+            Ok(row) => row,
+            Err(e) => return self.reject(line, record, &format!("malformed row: {}", e)),
+        };
+        if row.quantity == 0 {
+            return self.reject(line, record, "quantity must be at least 1");
+        }
+        let key = (
+            row.shipment_id,
+            row.product_id,
+            row.lot.clone(),
+            row.serial.clone(),
+        );
+        if let Some(first) = self.first_seen.get(&key) {
+            let error = format!("duplicate of line {}", first);
+            return self.reject(line, record, &error);
+        }
+        self.first_seen.insert(key, line);
+
+        self.batch.push(PendingRow {
+            line,
+            record: record.clone(),
+            row,
+        });
+        if self.batch.len() >= self.config.batch_size.max(1) {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> WarehouseResult<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+
+        let product_ids: Vec<u64> = batch
+            .iter()
+            .map(|pending| pending.row.product_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let products = self.store.products(&product_ids).await?;
+        let today = Utc::now().date_naive();
+
+        let mut valid = Vec::with_capacity(batch.len());
+        for pending in batch {
+            let row = &pending.row;
+            let checked = match products.get(&row.product_id) {
+                Some(product) => to_eaches(
+                    Some(product),
+                    row.product_id,
+                    row.quantity,
+                    row.uom.unwrap_or_default(),
+                )
+                .map_err(|e| e.to_string())
+                .and_then(|eaches| {
+                    product.check_receipt(
+                        eaches,
+                        row.lot.as_deref(),
+                        row.expiry,
+                        row.serial.as_deref(),
+                        today,
+                    )
+                }),
+                None => Err(format!("unknown product {}", row.product_id)),
+            };
+            match checked {
+                Ok(()) => valid.push(pending),
+                Err(error) => self.reject(pending.line, &pending.record, &error)?,
+            }
+        }
+        self.summary.valid += valid.len();
+        if self.config.dry_run || valid.is_empty() {
+            return Ok(());
+        }
+
+        let scans: Vec<Scan> = valid.iter().map(|pending| self.scan(pending)).collect();
+        match self.store.record_scans(&scans).await {
+            Ok(lines) => self.summary.imported += lines.len(),
+            Err(e) if e.is_retryable() => return Err(e),
+            // A row the store refuses, e.g. for a closed shipment, fails the whole batch. Write
+            // them one by one to find it.
+            Err(_) => {
+                for (pending, scan) in valid.iter().zip(&scans) {
+                    match self.store.record_scan(scan).await {
+                        Ok(_) => self.summary.imported += 1,
+                        Err(e) if e.is_retryable() => return Err(e),
+                        Err(e) => {
+                            self.summary.valid -= 1;
+                            self.reject(pending.line, &pending.record, &e.to_string())?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self, pending: &PendingRow) -> Scan {
+        let row = &pending.row;
+        Scan {
+            shipment_id: row.shipment_id,
+            product_id: row.product_id,
+            quantity: row.quantity,
+            uom: row.uom.unwrap_or_default(),
+            operator_id: self.config.operator_id,
+            condition: row.condition.unwrap_or_default(),
+            lot: row.lot.clone(),
+            expiry: row.expiry,
+            serial: row.serial.clone(),
+            idempotency_key: Some(IdempotencyKey {
+                device_id: self.device_id.clone(),
+                sequence: pending.line,
+            }),
+        }
+    }
+
+    fn reject(&mut self, line: u64, record: &StringRecord, error: &str) -> WarehouseResult<()> {
+        self.summary.rejected += 1;
+        let field =
+            |column: Option<usize>| column.and_then(|index| record.get(index)).unwrap_or("");
+        self.report.serialize(RejectedRow {
+            line,
+            shipment_id: field(self.columns[0]),
+            product_id: field(self.columns[1]),
+            quantity: field(self.columns[2]),
+            error,
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Condition;
+    use crate::uom::PackHierarchy;
+    use common_error::error::WarehouseError;
+    use mongodb::bson::oid::ObjectId;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    const HEADER: &str = "shipment_id,product_id,quantity,uom,condition,lot,expiry,serial\n";
+
+    /// Lists product 100, in eaches and inners of 6, and lot-controlled product 200, and records
+    /// scans once per idempotency key like `ReceivingStore`.
+    #[derive(Default)]
+    struct FakeStore {
+        /// Recorded lines by idempotency key.
+        recorded: Mutex<HashMap<IdempotencyKey, ReceivedLine>>,
+        /// Shipments whose scans are refused, like closed ones.
+        closed: HashSet<u64>,
+    }
+
+    fn product(product_id: u64, lot_controlled: bool) -> Product {
+        Product {
+            product_id,
+            name: format!("product {}", product_id),
+            lot_controlled,
+            serial_controlled: false,
+            min_shelf_life_days: None,
+            pack: PackHierarchy {
+                inner: Some(6),
+                ..PackHierarchy::default()
+            },
+        }
+    }
+
+    #[async_trait]
+    impl ImportStore for FakeStore {
+        async fn products(&self, product_ids: &[u64]) -> WarehouseResult<HashMap<u64, Product>> {
+            Ok([product(100, false), product(200, true)]
+                .into_iter()
+                .filter(|product| product_ids.contains(&product.product_id))
+                .map(|product| (product.product_id, product))
+                .collect())
+        }
+
+        async fn record_scans(&self, scans: &[Scan]) -> WarehouseResult<Vec<ReceivedLine>> {
+            if let Some(scan) = scans
+                .iter()
+                .find(|scan| self.closed.contains(&scan.shipment_id))
+            {
+                return Err(WarehouseError::Conflict(format!(
+                    "shipment {} is closed",
+                    scan.shipment_id
+                )));
+            }
+            let mut recorded = self.recorded.lock().unwrap();
+            Ok(scans
+                .iter()
+                .map(|scan| {
+                    let key = scan.idempotency_key.clone().unwrap();
+                    recorded.entry(key).or_insert_with(|| line(scan)).clone()
+                })
+                .collect())
+        }
+
+        async fn record_scan(&self, scan: &Scan) -> WarehouseResult<ReceivedLine> {
+            let mut lines = self.record_scans(std::slice::from_ref(scan)).await?;
+            Ok(lines.remove(0))
+        }
+    }
+
+    fn line(scan: &Scan) -> ReceivedLine {
+        ReceivedLine {
+            id: ObjectId::new(),
+            shipment_id: scan.shipment_id,
+            product_id: scan.product_id,
+            quantity: scan.quantity,
+            received_uom: scan.uom,
+            received_quantity: Some(scan.quantity),
+            operator_id: scan.operator_id,
+            condition: Condition::Good,
+            outbox_id: ObjectId::new(),
+            received_at: Utc::now(),
+            lot: scan.lot.clone(),
+            expiry: scan.expiry,
+            serial: scan.serial.clone(),
+            idempotency_key: scan.idempotency_key.clone(),
+        }
+    }
+
+    fn config(dry_run: bool) -> ImportConfig {
+        ImportConfig {
+            operator_id: 7,
+            batch_size: 2,
+            dry_run,
+        }
+    }
+
+    /// Writes `rows` to a file of their own and returns its path and the report's.
+    fn files(name: &str, rows: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("import-{}-{}.csv", std::process::id(), name));
+        std::fs::write(&input, format!("{}{}", HEADER, rows)).unwrap();
+        let report = dir.join(format!("import-{}-{}.errors.csv", std::process::id(), name));
+        (input, report)
+    }
+
+    /// The rejected lines of a report and their errors, by line.
+    fn rejections(report: &Path) -> Vec<(u64, String)> {
+        let mut rejections: Vec<(u64, String)> = csv::Reader::from_path(report)
+            .unwrap()
+            .deserialize::<(u64, String, String, String, String)>()
+            .map(|row| {
+                let (line, _, _, _, error) = row.unwrap();
+                (line, error)
+            })
+            .collect();
+        rejections.sort();
+        rejections
+    }
+
+    const MIXED: &str = "\
+1,100,5,,,,,
+1,100,0,,,,,
+1,999,1,,,,,
+1,200,1,,,,,
+3,100,2,case,,,,
+1,abc,1,,,,,
+1,100,5,,,,,
+1,200,3,,,L1,,
+1,200,3,inner,,L2,,
+";
+
+    #[tokio::test]
+    async fn invalid_and_duplicate_rows_are_reported() {
+        let store = FakeStore::default();
+        let (input, report) = files("mixed", MIXED);
+
+        let summary = import_csv(&store, &input, &report, &config(false))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            summary,
+            ImportSummary {
+                rows: 9,
+                valid: 3,
+                imported: 3,
+                rejected: 6,
+            }
+        );
+        let rejections = rejections(&report);
+        let lines: Vec<u64> = rejections.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6, 7, 8]);
+        assert_eq!(rejections[0].1, "quantity must be at least 1");
+        assert_eq!(rejections[1].1, "unknown product 999");
+        assert_eq!(rejections[2].1, "product 200 needs a lot");
+        assert_eq!(rejections[3].1, "Product 100 does not come in case packs");
+        assert!(
+            rejections[4].1.starts_with("malformed row"),
+            "{}",
+            rejections[4].1
+        );
+        // The same product again is a duplicate, in another lot it is not.
+        assert_eq!(rejections[5].1, "duplicate of line 2");
+        assert_eq!(store.recorded.lock().unwrap().len(), 3);
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(report).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_run_validates_without_writing() {
+        let store = FakeStore::default();
+        let (input, report) = files("dry-run", MIXED);
+
+        let summary = import_csv(&store, &input, &report, &config(true))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            summary,
+            ImportSummary {
+                rows: 9,
+                valid: 3,
+                imported: 0,
+                rejected: 6,
+            }
+        );
+        assert_eq!(rejections(&report).len(), 6);
+        assert!(store.recorded.lock().unwrap().is_empty());
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(report).unwrap();
+    }
+
+    #[tokio::test]
+    async fn importing_a_file_again_books_nothing_twice() {
+        let store = FakeStore::default();
+        let (input, report) = files("again", "1,100,5,,,,,\n2,100,6,,,,,\n1,200,3,,,L1,,\n");
+
+        for _ in 0..2 {
+            let summary = import_csv(&store, &input, &report, &config(false))
+                .await
+                .unwrap();
+            assert_eq!(summary.imported, 3);
+        }
+
+        let recorded = store.recorded.lock().unwrap();
+        let mut keys: Vec<&IdempotencyKey> = recorded.keys().collect();
+        keys.sort_by_key(|key| key.sequence);
+        let device_id = format!("import:{}", file_hash(&input).unwrap());
+        assert_eq!(
+            keys.iter()
+                .map(|key| (key.device_id.as_str(), key.sequence))
+                .collect::<Vec<_>>(),
+            vec![
+                (device_id.as_str(), 2),
+                (device_id.as_str(), 3),
+                (device_id.as_str(), 4)
+            ]
+        );
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(report).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_row_is_rejected_on_its_own() {
+        let store = FakeStore {
+            closed: HashSet::from([2]),
+            ..FakeStore::default()
+        };
+        let (input, report) = files("refused", "1,100,5,,,,,\n2,100,6,,,,,\n");
+
+        let summary = import_csv(&store, &input, &report, &config(false))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            summary,
+            ImportSummary {
+                rows: 2,
+                valid: 1,
+                imported: 1,
+                rejected: 1,
+            }
+        );
+        assert_eq!(
+            rejections(&report),
+            vec![(3, "Conflict: shipment 2 is closed".to_string())]
+        );
+
+        std::fs::remove_file(input).unwrap();
+        std::fs::remove_file(report).unwrap();
+    }
+}
@@ -0,0 +1,11 @@
+pub mod api;
+pub mod edi;
+pub mod file_drop;
+pub mod import;
+pub mod load;
+pub mod model;
+pub mod parser;
+pub mod receiving;
+pub mod simulator;
+pub mod store;
+pub mod uom;
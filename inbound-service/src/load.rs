@@ -0,0 +1,485 @@
+//! Generates scan load, straight into the outbox or through the HTTP API, and measures the
+//! throughput achieved and how long each scan takes to come out of Kafka.
+//!
+//! Runs are reproducible: the same seed picks the same rows, operators and, with the Poisson
+//! pattern, the same arrival times.
+
+use crate::api::LineView;
+use crate::model::{CsvRow, Scan};
+use crate::simulator::add_row;
+use async_trait::async_trait;
+use common_error::error::KafkaResult;
+use common_kafka::consumer::{MessageHandler, RebalanceEvent};
+use common_kafka::message::ConsumedMessage;
+use inbound_outbox::relay::OUTBOX_ID_HEADER;
+use inbound_outbox::Outbox;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, sleep_until, Instant};
+
+/// The users the original simulator booked stock in for.
+pub const DEFAULT_OPERATORS: [u64; 13] = [
+    16349, 17899, 17455, 20778, 13447, 20865, 17745, 18752, 16334, 16577, 17889, 13887, 12227,
+];
+
+/// How writes are spread over the run, always averaging the configured rate except for `Ramp`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pattern {
+    /// Evenly spaced writes.
+    Steady,
+    /// Writes at random moments, like independent scanners.
+    Poisson,
+    /// `size` writes at once, as often as keeps the average at the rate.
+    Burst { size: u32 },
+    /// The rate climbs from nothing to the configured rate over the run, to find where the
+    /// pipeline starts falling behind. Averages half the rate.
+    Ramp,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadConfig {
+    pub operators: Vec<u64>,
+    /// Writes per second.
+    pub rate: f64,
+    pub pattern: Pattern,
+    pub duration: Duration,
+    pub seed: u64,
+    /// Writes waiting for a response at most. When they all are, writes start late rather than
+    /// piling up, which shows as a lower achieved rate.
+    pub concurrency: usize,
+}
+
+/// Where scans are written.
+pub enum Target {
+    /// Outbox entries written straight to MongoDB, like the original simulator.
+    Outbox(Outbox),
+    /// `POST /scans` of a running inbound-service.
+    Http {
+        client: reqwest::Client,
+        base_url: String,
+    },
+}
+
+impl Target {
+    /// Writes a row and returns the id of its outbox entry.
+    async fn write(&self, operator_id: u64, row: &CsvRow) -> Result<String, String> {
+        match self {
+            Target::Outbox(outbox) => add_row(outbox, operator_id, row)
+                .await
+                .map(|id| id.to_hex())
+                .map_err(|e| e.to_string()),
+            Target::Http { client, base_url } => {
+                let scan = Scan {
+                    shipment_id: row.shipment_id,
+                    product_id: row.product_id,
+                    quantity: row.quantity,
+                    uom: row.uom.unwrap_or_default(),
+                    operator_id,
+                    condition: row.condition.unwrap_or_default(),
+                    lot: row.lot.clone(),
+                    expiry: row.expiry,
+                    serial: row.serial.clone(),
+                    idempotency_key: None,
+                };
+                let response = client
+                    .post(format!("{}/scans", base_url.trim_end_matches('/')))
+                    .json(&scan)
+                    .send()
+                    .await
+                    .map_err(|e| format!("request failed: {}", e))?;
+                if !response.status().is_success() {
+                    return Err(format!("HTTP {}", response.status()));
+                }
+                let line: LineView = response
+                    .json()
+                    .await
+                    .map_err(|e| format!("unreadable response: {}", e))?;
+                Ok(line.outbox_id)
+            }
+        }
+    }
+}
+
+/// The offsets of the writes from the start of the run.
+struct Schedule {
+    pattern: Pattern,
+    rate: f64,
+    duration: f64,
+    written: u64,
+    last: f64,
+}
+
+impl Schedule {
+    fn next(&mut self, rng: &mut StdRng) -> Option<Duration> {
+        let n = self.written as f64;
+        let at = match self.pattern {
+            Pattern::Steady => n / self.rate,
+            Pattern::Poisson => self.last - (1.0 - rng.gen::<f64>()).ln() / self.rate,
+            Pattern::Burst { size } => {
+                let size = f64::from(size.max(1));
+                (n / size).floor() * size / self.rate
+            }
+            // n writes are due once rate * t² / (2 * duration) reaches n.
+            Pattern::Ramp => (2.0 * self.duration * n / self.rate).sqrt(),
+        };
+        if at >= self.duration {
+            return None;
+        }
+
+        self.written += 1;
+        self.last = at;
+        Some(Duration::from_secs_f64(at))
+    }
+}
+
+/// Percentiles of a set of latencies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latencies {
+    pub count: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Latencies {
+    fn from(mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+        let percentile = |p: f64| {
+            let index = ((samples.len() as f64 * p).ceil() as usize).saturating_sub(1);
+            samples.get(index).copied().unwrap_or_default()
+        };
+        Self {
+            count: samples.len(),
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    pub elapsed: Duration,
+    pub attempted: usize,
+    pub written: usize,
+    /// Failed writes by reason.
+    pub failures: BTreeMap<String, usize>,
+    /// From the start of a write until it was acknowledged.
+    pub write_latency: Latencies,
+    /// From the start of a write until its message was consumed, if Kafka was watched.
+    pub end_to_end: Option<EndToEnd>,
+}
+
+impl LoadReport {
+    /// Acknowledged writes per second.
+    pub fn throughput(&self) -> f64 {
+        self.written as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EndToEnd {
+    pub latency: Latencies,
+    /// Written but not consumed by the end of the drain.
+    pub missing: usize,
+}
+
+/// Writes scans per the config, picking rows and operators at random, and waits for the
+/// written ones to come out of Kafka for at most `drain` if a tracker is given.
+pub async fn run(
+    target: Arc<Target>,
+    rows: &[CsvRow],
+    config: &LoadConfig,
+    tracker: Option<LatencyTracker>,
+    drain: Duration,
+) -> LoadReport {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut schedule = Schedule {
+        pattern: config.pattern,
+        rate: config.rate,
+        duration: config.duration.as_secs_f64(),
+        written: 0,
+        last: 0.0,
+    };
+    let permits = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let mut writes = JoinSet::new();
+    let mut report = LoadReport::default();
+    let mut write_latencies = Vec::new();
+
+    let started = Instant::now();
+    while let Some(at) = schedule.next(&mut rng) {
+        let (Some(row), Some(operator_id)) =
+            (rows.choose(&mut rng), config.operators.choose(&mut rng))
+        else {
+            break;
+        };
+        let row = CsvRow {
+            shipment_id: row.shipment_id,
+            product_id: row.product_id,
+            quantity: row.quantity,
+            uom: row.uom,
+            condition: row.condition,
+            lot: row.lot.clone(),
+            expiry: row.expiry,
+            serial: row.serial.clone(),
+        };
+        let operator_id = *operator_id;
+
+        sleep_until(started + at).await;
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            break;
+        };
+        let target = target.clone();
+        let tracker = tracker.clone();
+        writes.spawn(async move {
+            let began = Instant::now();
+            let result = target.write(operator_id, &row).await;
+            if let (Ok(outbox_id), Some(tracker)) = (&result, tracker) {
+                tracker.written(outbox_id, began);
+            }
+            drop(permit);
+            (began.elapsed(), result)
+        });
+        report.attempted += 1;
+
+        while let Some(done) = writes.try_join_next() {
+            record(&mut report, &mut write_latencies, done);
+        }
+    }
+    while let Some(done) = writes.join_next().await {
+        record(&mut report, &mut write_latencies, done);
+    }
+    report.elapsed = started.elapsed();
+    report.write_latency = Latencies::from(write_latencies);
+
+    if let Some(tracker) = tracker {
+        let deadline = Instant::now() + drain;
+        while tracker.outstanding() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(100)).await;
+        }
+        report.end_to_end = Some(tracker.end_to_end());
+    }
+    report
+}
+
+type WriteOutcome = (Duration, Result<String, String>);
+
+fn record(
+    report: &mut LoadReport,
+    write_latencies: &mut Vec<Duration>,
+    done: Result<WriteOutcome, tokio::task::JoinError>,
+) {
+    match done {
+        Ok((latency, Ok(_))) => {
+            report.written += 1;
+            write_latencies.push(latency);
+        }
+        Ok((_, Err(reason))) => *report.failures.entry(reason).or_default() += 1,
+        Err(e) => *report.failures.entry(e.to_string()).or_default() += 1,
+    }
+}
+
+#[derive(Default)]
+struct Tracking {
+    /// When each write not yet consumed started, by outbox id.
+    written: HashMap<String, Instant>,
+    /// Messages consumed before their write was acknowledged.
+    consumed_early: HashMap<String, Instant>,
+    latencies: Vec<Duration>,
+}
+
+/// Consumes the topic the relay publishes to and matches messages to writes by outbox id.
+/// Clones share their measurements, so one can be handed to the consumer.
+#[derive(Clone, Default)]
+pub struct LatencyTracker {
+    tracking: Arc<Mutex<Tracking>>,
+    assigned: Arc<AtomicBool>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the consumer got partitions yet. Writes before that may be missed.
+    pub fn is_assigned(&self) -> bool {
+        self.assigned.load(Ordering::SeqCst)
+    }
+
+    fn written(&self, outbox_id: &str, began: Instant) {
+        let mut tracking = self.tracking.lock().unwrap();
+        match tracking.consumed_early.remove(outbox_id) {
+            Some(consumed) => tracking.latencies.push(consumed - began),
+            None => {
+                tracking.written.insert(outbox_id.to_string(), began);
+            }
+        }
+    }
+
+    fn outstanding(&self) -> usize {
+        self.tracking.lock().unwrap().written.len()
+    }
+
+    fn end_to_end(&self) -> EndToEnd {
+        let tracking = self.tracking.lock().unwrap();
+        EndToEnd {
+            latency: Latencies::from(tracking.latencies.clone()),
+            missing: tracking.written.len(),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageHandler for LatencyTracker {
+    async fn handle(&self, _key: &[u8], _payload: &[u8]) -> KafkaResult<()> {
+        Ok(())
+    }
+
+    async fn handle_message(&self, message: &ConsumedMessage) -> KafkaResult<()> {
+        let Some(outbox_id) = message.header(OUTBOX_ID_HEADER) else {
+            return Ok(());
+        };
+        let outbox_id = String::from_utf8_lossy(outbox_id).into_owned();
+        let consumed = Instant::now();
+
+        let mut tracking = self.tracking.lock().unwrap();
+        match tracking.written.remove(&outbox_id) {
+            Some(began) => tracking.latencies.push(consumed - began),
+            None => {
+                tracking.consumed_early.insert(outbox_id, consumed);
+            }
+        }
+        Ok(())
+    }
+
+    fn post_rebalance(&self, event: &RebalanceEvent) {
+        if matches!(event, RebalanceEvent::Assign(partitions) if !partitions.is_empty()) {
+            self.assigned.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every write offset of a run, in milliseconds.
+    fn offsets(pattern: Pattern, rate: f64, duration: f64, seed: u64) -> Vec<u64> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut schedule = Schedule {
+            pattern,
+            rate,
+            duration,
+            written: 0,
+            last: 0.0,
+        };
+        std::iter::from_fn(|| schedule.next(&mut rng))
+            .map(|at| at.as_millis() as u64)
+            .collect()
+    }
+
+    fn millis(samples: impl IntoIterator<Item = u64>) -> Vec<Duration> {
+        samples.into_iter().map(Duration::from_millis).collect()
+    }
+
+    #[test]
+    fn steady_writes_are_evenly_spaced() {
+        assert_eq!(
+            offsets(Pattern::Steady, 4.0, 2.0, 1),
+            [0, 250, 500, 750, 1000, 1250, 1500, 1750]
+        );
+    }
+
+    #[test]
+    fn bursts_keep_the_average_rate() {
+        assert_eq!(
+            offsets(Pattern::Burst { size: 3 }, 6.0, 1.0, 1),
+            [0, 0, 0, 500, 500, 500]
+        );
+        // A burst of nothing is a burst of one.
+        assert_eq!(
+            offsets(Pattern::Burst { size: 0 }, 4.0, 1.0, 1),
+            offsets(Pattern::Steady, 4.0, 1.0, 1)
+        );
+    }
+
+    #[test]
+    fn ramps_average_half_the_rate_and_speed_up() {
+        let offsets = offsets(Pattern::Ramp, 10.0, 2.0, 1);
+
+        assert_eq!(offsets.len(), 10);
+        assert_eq!(offsets[..3], [0, 632, 894]);
+        let gaps: Vec<u64> = offsets.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(gaps.windows(2).all(|pair| pair[1] <= pair[0]), "{:?}", gaps);
+    }
+
+    #[test]
+    fn poisson_arrivals_are_reproducible_and_average_the_rate() {
+        let arrivals = offsets(Pattern::Poisson, 100.0, 10.0, 7);
+
+        assert_eq!(arrivals, offsets(Pattern::Poisson, 100.0, 10.0, 7));
+        assert_ne!(arrivals, offsets(Pattern::Poisson, 100.0, 10.0, 8));
+        // 1000 expected, give or take three standard deviations.
+        assert!((905..=1095).contains(&arrivals.len()), "{}", arrivals.len());
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(arrivals.iter().all(|at| *at < 10_000));
+    }
+
+    #[test]
+    fn percentiles_are_the_nearest_rank() {
+        assert_eq!(
+            Latencies::from(millis((1..=100).rev())),
+            Latencies {
+                count: 100,
+                p50: Duration::from_millis(50),
+                p95: Duration::from_millis(95),
+                p99: Duration::from_millis(99),
+                max: Duration::from_millis(100),
+            }
+        );
+        assert_eq!(
+            Latencies::from(millis([7, 3, 9, 1, 5, 10, 2, 8, 4, 6])),
+            Latencies {
+                count: 10,
+                p50: Duration::from_millis(5),
+                p95: Duration::from_millis(10),
+                p99: Duration::from_millis(10),
+                max: Duration::from_millis(10),
+            }
+        );
+    }
+
+    #[test]
+    fn percentiles_of_a_few_samples() {
+        assert_eq!(Latencies::from(Vec::new()), Latencies::default());
+        assert_eq!(
+            Latencies::from(millis([40])),
+            Latencies {
+                count: 1,
+                p50: Duration::from_millis(40),
+                p95: Duration::from_millis(40),
+                p99: Duration::from_millis(40),
+                max: Duration::from_millis(40),
+            }
+        );
+        assert_eq!(
+            Latencies::from(millis([30, 10])),
+            Latencies {
+                count: 2,
+                p50: Duration::from_millis(10),
+                p95: Duration::from_millis(30),
+                p99: Duration::from_millis(30),
+                max: Duration::from_millis(30),
+            }
+        );
+    }
+}
@@ -1,61 +1,111 @@
-use csv::ReaderBuilder;
-use inbound_outbox::Outbox;
-use model::{CsvRow, RowWithUser};
-use rand::seq::SliceRandom;
-use rand::Rng;
+use clap::{Parser, Subcommand};
+use common_error::error::{WarehouseError, WarehouseResult};
+use inbound_service::api::router;
+use inbound_service::file_drop::{FileDrop, FileDropConfig};
+use inbound_service::import::{import_csv, ImportConfig};
+use inbound_service::store::ReceivingStore;
+use std::path::PathBuf;
 use std::time::Duration;
-use tokio::time::sleep;
-pub mod model;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let outbox = Outbox::new("mongodb://localhost:27017", "warehouse", "inbound_outbox").await?;
-    let pending_entries = outbox.fetch_pending_entries().await?;
-
-    for entry in pending_entries {
-        println!("Pending Entry: {:?}", entry);
-    }
-
-    let user_ids = vec![
-        16349, 17899, 17455, 20778, 13447, 20865, 17745, 18752, 16334, 16577, 17889, 13887, 12227,
-    ];
+const DB_NAME: &str = "warehouse";
+const OUTBOX_COLLECTION: &str = "inbound_outbox";
 
-    let mut reader = ReaderBuilder::new()
-        .has_headers(true)
-        .from_path("inbound-stock.csv")?;
-
-    let csv_rows: Vec<CsvRow> = reader.deserialize().collect::<Result<_, _>>()?;
+#[derive(Parser)]
+#[command(about = "Books inbound stock in through the outbox")]
+struct Cli {
+    #[arg(long, env = "MONGO_URI", default_value = "mongodb://localhost:27017")]
+    mongo_uri: String,
+    #[command(subcommand)]
+    command: Command,
+}
 
-    if csv_rows.is_empty() {
-        println!("No data found in the CSV file");
-        return Ok(());
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Serves the scan-ingest API.
+    Serve {
+        #[arg(long, env = "BIND_ADDR", default_value = "0.0.0.0:8080")]
+        bind: String,
+    },
+    /// Ingests X12 856 ship notices dropped into a directory as ASNs.
+    WatchEdi {
+        #[arg(long, env = "EDI_DROP_DIR", default_value = "edi-drop")]
+        dir: PathBuf,
+        #[arg(long, default_value_t = 10)]
+        poll_secs: u64,
+    },
+    /// Imports a supplier manifest CSV, reporting the rows that were rejected.
+    Import {
+        file: PathBuf,
+        /// Who the rows are booked in for.
+        #[arg(long)]
+        operator_id: u64,
+        /// Where the rejected rows are written, `<file>.errors.csv` by default.
+        #[arg(long)]
+        report: Option<PathBuf>,
+        #[arg(long, default_value_t = 500)]
+        batch_size: usize,
+        /// Validates the file and writes the report without importing anything.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
 
-    for user_id in user_ids.iter() {
-        println!("Processing for userId: {}", user_id);
+#[tokio::main]
+async fn main() -> WarehouseResult<()> {
+    tracing_subscriber::fmt::init();
 
-        let mut rng = rand::thread_rng();
-        let row_count: usize = rng.gen_range(5..=10);
-        let selected_rows = csv_rows.choose_multiple(&mut rng, row_count);
+    let cli = Cli::parse();
+    let store = ReceivingStore::connect(&cli.mongo_uri, DB_NAME, OUTBOX_COLLECTION).await?;
 
-        for row in selected_rows {
-            let row_with_user = RowWithUser {
-                user_id: *user_id,
-                shipment_id: row.shipment_id,
-                product_id: row.product_id,
-                quantity: row.quantity,
+    match cli.command {
+        Command::Serve { bind } => serve(store, &bind).await,
+        Command::WatchEdi { dir, poll_secs } => {
+            let config = FileDropConfig {
+                dir,
+                poll_interval: Duration::from_secs(poll_secs),
             };
-
-            let payload = serde_json::to_string(&row_with_user)?;
-            let outbox_id = outbox.add_entry(*user_id, payload.clone()).await?;
-            println!("Outbox entry created with ID: {}", outbox_id);
-
-            let delay = rng.gen_range(3000..=9000);
-            sleep(Duration::from_millis(delay)).await;
+            FileDrop::new(store, config).run().await
+        }
+        Command::Import {
+            file,
+            operator_id,
+            report,
+            batch_size,
+            dry_run,
+        } => {
+            let report = report.unwrap_or_else(|| {
+                let mut name = file.clone().into_os_string();
+                name.push(".errors.csv");
+                PathBuf::from(name)
+            });
+            let config = ImportConfig {
+                operator_id,
+                batch_size,
+                dry_run,
+            };
+            let summary = import_csv(&store, &file, &report, &config).await?;
+            if summary.rejected > 0 {
+                tracing::warn!(
+                    "{} rows were rejected, see {}",
+                    summary.rejected,
+                    report.display()
+                );
+            }
+            Ok(())
         }
-
-        println!("Finished processing for userId: {}\n", user_id);
     }
+}
+
+async fn serve(store: ReceivingStore, bind: &str) -> WarehouseResult<()> {
+    let listener = tokio::net::TcpListener::bind(bind)
+        .await
+        .map_err(|e| WarehouseError::Configuration(format!("cannot listen on {}: {}", bind, e)))?;
+    tracing::info!("Scan-ingest API listening on {}", bind);
 
-    Ok(())
+    axum::serve(listener, router(store))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .map_err(|e| WarehouseError::Configuration(format!("API server failed: {}", e)))
 }
@@ -1,16 +1,567 @@
+use crate::uom::{PackHierarchy, Uom};
+use chrono::{DateTime, NaiveDate, Utc};
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
+/// A row of a supplier manifest. The unit, lot, expiry and serial columns are optional.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CsvRow {
     pub shipment_id: u64,
     pub product_id: u64,
+    /// In `uom`, eaches if left empty.
     pub quantity: u32,
+    #[serde(default)]
+    pub uom: Option<Uom>,
+    /// Good if left empty.
+    #[serde(default)]
+    pub condition: Option<Condition>,
+    #[serde(default)]
+    pub lot: Option<String>,
+    #[serde(default)]
+    pub expiry: Option<NaiveDate>,
+    #[serde(default)]
+    pub serial: Option<String>,
 }
 
+/// The payload of a `stock_received` or `stock_quarantined` event.
 #[derive(Serialize, Debug)]
 pub struct RowWithUser {
     pub user_id: u64,
     pub shipment_id: u64,
     pub product_id: u64,
+    /// In eaches.
     pub quantity: u32,
+    /// What was scanned, e.g. 2 cases for a `quantity` of 24.
+    pub received_uom: Uom,
+    pub received_quantity: u32,
+    pub condition: Condition,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+}
+
+/// A product in the catalog, only products listed in it can be imported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Product {
+    #[serde(rename = "_id")]
+    pub product_id: u64,
+    pub name: String,
+    /// Every receipt must name the lot (batch) it is from.
+    #[serde(default)]
+    pub lot_controlled: bool,
+    /// Every unit is received on its own, with its serial number.
+    #[serde(default)]
+    pub serial_controlled: bool,
+    /// How many days before its expiry date stock must arrive at the latest. Receipts of the
+    /// product must carry an expiry date.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_shelf_life_days: Option<u32>,
+    /// The packs the product is delivered in, only eaches without one.
+    #[serde(default)]
+    pub pack: PackHierarchy,
+}
+
+impl Product {
+    /// Checks a receipt of `quantity` eaches of the product on `received_on` against its tracking
+    /// rules.
+    pub fn check_receipt(
+        &self,
+        quantity: u32,
+        lot: Option<&str>,
+        expiry: Option<NaiveDate>,
+        serial: Option<&str>,
+        received_on: NaiveDate,
+    ) -> Result<(), String> {
+        if self.lot_controlled && lot.is_none() {
+            return Err(format!("product {} needs a lot", self.product_id));
+        }
+        if self.serial_controlled {
+            if serial.is_none() {
+                return Err(format!("product {} needs a serial number", self.product_id));
+            }
+            if quantity != 1 {
+                return Err(format!(
+                    "product {} is serial-controlled, each unit is received on its own",
+                    self.product_id
+                ));
+            }
+        }
+        if let Some(min_days) = self.min_shelf_life_days {
+            let Some(expiry) = expiry else {
+                return Err(format!("product {} needs an expiry date", self.product_id));
+            };
+            let left = (expiry - received_on).num_days();
+            if left < i64::from(min_days) {
+                return Err(format!(
+                    "product {} expires {}, {} days of shelf life left, at least {} required",
+                    self.product_id,
+                    expiry,
+                    left.max(0),
+                    min_days
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A product scanned in at the dock by an operator's handheld.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scan {
+    pub shipment_id: u64,
+    pub product_id: u64,
+    /// In `uom`.
+    pub quantity: u32,
+    #[serde(default)]
+    pub uom: Uom,
+    pub operator_id: u64,
+    #[serde(default)]
+    pub condition: Condition,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// Makes resubmitting the scan, e.g. after a timeout, record it only once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<IdempotencyKey>,
+}
+
+/// The state stock arrived in. Anything but good stock is quarantined, kept apart from sellable
+/// stock until it is released or scrapped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    #[default]
+    Good,
+    Damaged,
+    /// Held for a reason other than damage, e.g. a recall or pest control.
+    Quarantine,
+    /// Held until quality assurance has inspected it.
+    QaHold,
+}
+
+impl Condition {
+    pub fn is_sellable(self) -> bool {
+        self == Condition::Good
+    }
+}
+
+/// Identifies a scan by the handheld that made it and the handheld's own count of its scans.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IdempotencyKey {
+    pub device_id: String,
+    pub sequence: u64,
+}
+
+impl std::fmt::Display for IdempotencyKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.device_id, self.sequence)
+    }
+}
+
+/// A line received against a shipment, stored along with the outbox entry announcing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceivedLine {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub shipment_id: u64,
+    pub product_id: u64,
+    /// In eaches.
+    pub quantity: u32,
+    /// The unit scanned, eaches on lines from before units of measure.
+    #[serde(default)]
+    pub received_uom: Uom,
+    /// How many of `received_uom` were scanned, `quantity` when left out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_quantity: Option<u32>,
+    pub operator_id: u64,
+    /// Lines in any other condition than good are quarantined, announced by their outbox entry as
+    /// `stock_quarantined` rather than `stock_received`.
+    #[serde(default)]
+    pub condition: Condition,
+    pub outbox_id: ObjectId,
+    pub received_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// The key of the scan the line was recorded from, unique among lines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<IdempotencyKey>,
+}
+
+impl ReceivedLine {
+    /// Whether the line is what recording `scan` would store, apart from ids and time.
+    pub fn matches(&self, scan: &Scan) -> bool {
+        self.shipment_id == scan.shipment_id
+            && self.product_id == scan.product_id
+            && self.received_uom == scan.uom
+            && self.received_quantity.unwrap_or(self.quantity) == scan.quantity
+            && self.operator_id == scan.operator_id
+            && self.condition == scan.condition
+            && self.lot == scan.lot
+            && self.expiry == scan.expiry
+            && self.serial == scan.serial
+    }
+}
+
+/// Which kind of code a label was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelFormat {
+    /// GS1 application identifiers, from a GS1-128 barcode or a GS1 DataMatrix or QR code.
+    Gs1,
+    /// Our own JSON QR codes.
+    Json,
+}
+
+/// What a scanned label says, decoded and validated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScannedLabel {
+    pub format: LabelFormat,
+    /// Serial shipping container code of the pallet or case the label is on.
+    pub sscc: Option<String>,
+    /// GTIN-14 of the product, or of the products a pallet or case contains.
+    pub gtin: Option<String>,
+    /// Our own product id, only our JSON codes carry it.
+    pub product_id: Option<u64>,
+    pub shipment_id: Option<u64>,
+    pub quantity: Option<u32>,
+    pub lot: Option<String>,
+    pub expiry: Option<NaiveDate>,
+    pub serial: Option<String>,
+    /// GS1 elements without a field of their own, such as the net weight, as (AI, value).
+    pub other: Vec<(String, String)>,
+}
+
+/// Event type of the outbox entries announcing receiving discrepancies.
+pub const DISCREPANCY_EVENT: &str = "receiving_discrepancy";
+
+/// An Advance Shipping Notice: what a supplier says a shipment holds and when it arrives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Asn {
+    #[serde(rename = "_id")]
+    pub shipment_id: u64,
+    pub supplier: String,
+    pub expected_arrival: DateTime<Utc>,
+    pub lines: Vec<AsnLine>,
+    pub status: AsnStatus,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_received_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+impl Asn {
+    /// Whether both announce the same shipment from the same supplier, e.g. because a ship notice
+    /// was submitted twice. Arrival times are compared to the millisecond MongoDB stores.
+    pub fn announces_same(&self, other: &Asn) -> bool {
+        self.shipment_id == other.shipment_id
+            && self.supplier == other.supplier
+            && self.expected_arrival.timestamp_millis() == other.expected_arrival.timestamp_millis()
+            && self.lines == other.lines
+    }
+}
+
+/// An ASN as it is submitted, through the API or an EDI ship notice.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewAsn {
+    pub shipment_id: u64,
+    pub supplier: String,
+    pub expected_arrival: DateTime<Utc>,
+    pub lines: Vec<AsnLine>,
+}
+
+impl NewAsn {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.supplier.trim().is_empty() {
+            return Err("supplier must not be empty".into());
+        }
+        if self.lines.is_empty() {
+            return Err("ASN holds no lines".into());
+        }
+
+        let mut products = HashSet::new();
+        for line in &self.lines {
+            if line.quantity == 0 {
+                return Err(format!(
+                    "quantity of product {} must be at least 1",
+                    line.product_id
+                ));
+            }
+            if !products.insert((line.product_id, line.uom)) {
+                return Err(format!(
+                    "product {} is listed in {}s more than once",
+                    line.product_id, line.uom
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AsnLine {
+    pub product_id: u64,
+    /// In `uom`. Stored ASNs are converted to eaches.
+    pub quantity: u32,
+    #[serde(default)]
+    pub uom: Uom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AsnStatus {
+    /// Scans are received against it.
+    Open,
+    /// Receiving is done, its discrepancies were announced and it takes no more scans.
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscrepancyKind {
+    /// More was received than the ASN announced.
+    Over,
+    /// Less was received than the ASN announced, possibly nothing.
+    Short,
+    /// A product the ASN did not announce at all.
+    Unexpected,
+}
+
+/// A product whose received quantity differs from what the ASN announced, published when the
+/// shipment is closed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Discrepancy {
+    pub shipment_id: u64,
+    pub supplier: String,
+    pub product_id: u64,
+    pub kind: DiscrepancyKind,
+    pub expected: u32,
+    pub received: u32,
+}
+
+/// Event type of the outbox entries announcing stock received in any other condition than good.
+/// Unlike `stock_received` stock, it must not be allocated.
+pub const STOCK_QUARANTINED: &str = "stock_quarantined";
+/// Event type of the outbox entries announcing quarantined stock that was scrapped. Released stock
+/// is announced as `stock_received`, as it can be allocated from then on.
+pub const STOCK_SCRAPPED: &str = "stock_scrapped";
+
+/// Stock received in any other condition than good, held apart from sellable stock until all of
+/// it was released or scrapped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedStock {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// The line it was received on.
+    pub line_id: ObjectId,
+    pub shipment_id: u64,
+    pub product_id: u64,
+    pub condition: Condition,
+    /// In eaches, as received.
+    pub quantity: u32,
+    /// In eaches, what was neither released nor scrapped yet.
+    pub held: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lot: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    pub quarantined_at: DateTime<Utc>,
+    /// What was released or scrapped of it so far, oldest first.
+    #[serde(default)]
+    pub dispositions: Vec<Disposition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DispositionAction {
+    /// Made sellable after all.
+    Release,
+    /// Written off.
+    Scrap,
+}
+
+/// Part or all of a quarantined stock released or scrapped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Disposition {
+    pub action: DispositionAction,
+    /// In eaches.
+    pub quantity: u32,
+    /// Why, from the reason codes inventory control uses, e.g. `QA_PASSED` or `CRUSHED`.
+    pub reason_code: String,
+    pub operator_id: u64,
+    pub at: DateTime<Utc>,
+}
+
+/// The payload of a `stock_scrapped` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrappedStock {
+    pub quarantine_id: String,
+    pub shipment_id: u64,
+    pub product_id: u64,
+    pub condition: Condition,
+    /// In eaches.
+    pub quantity: u32,
+    pub reason_code: String,
+    pub user_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn asn(lines: &[(u64, u32)]) -> Asn {
+        Asn {
+            shipment_id: 12345,
+            supplier: "Acme Supply".into(),
+            expected_arrival: Utc.with_ymd_and_hms(2026, 1, 5, 8, 0, 0).unwrap(),
+            lines: lines
+                .iter()
+                .map(|&(product_id, quantity)| AsnLine {
+                    product_id,
+                    quantity,
+                    uom: Uom::Each,
+                })
+                .collect(),
+            status: AsnStatus::Open,
+            created_at: Utc::now(),
+            last_received_at: None,
+            closed_at: None,
+        }
+    }
+
+    fn product() -> Product {
+        Product {
+            product_id: 1001,
+            name: "Yoghurt".into(),
+            lot_controlled: false,
+            serial_controlled: false,
+            min_shelf_life_days: None,
+            pack: PackHierarchy::default(),
+        }
+    }
+
+    fn received_on() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 1, 5).unwrap()
+    }
+
+    fn expiring_in(days: i64) -> Option<NaiveDate> {
+        Some(received_on() + Duration::days(days))
+    }
+
+    #[test]
+    fn untracked_products_take_any_receipt() {
+        let product = product();
+        assert_eq!(
+            product.check_receipt(12, None, None, None, received_on()),
+            Ok(())
+        );
+        assert_eq!(
+            product.check_receipt(12, Some("L1"), expiring_in(-3), Some("S1"), received_on()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn lot_controlled_products_need_a_lot() {
+        let product = Product {
+            lot_controlled: true,
+            ..product()
+        };
+        assert_eq!(
+            product.check_receipt(12, None, None, None, received_on()),
+            Err("product 1001 needs a lot".into())
+        );
+        assert_eq!(
+            product.check_receipt(12, Some("L1"), None, None, received_on()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn serial_controlled_products_are_received_one_serial_at_a_time() {
+        let product = Product {
+            serial_controlled: true,
+            ..product()
+        };
+        assert_eq!(
+            product.check_receipt(1, None, None, None, received_on()),
+            Err("product 1001 needs a serial number".into())
+        );
+        assert_eq!(
+            product.check_receipt(2, None, None, Some("S1"), received_on()),
+            Err("product 1001 is serial-controlled, each unit is received on its own".into())
+        );
+        assert_eq!(
+            product.check_receipt(1, None, None, Some("S1"), received_on()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn products_with_a_minimum_shelf_life_need_enough_of_it_left() {
+        let product = Product {
+            min_shelf_life_days: Some(10),
+            ..product()
+        };
+        assert_eq!(
+            product.check_receipt(12, None, None, None, received_on()),
+            Err("product 1001 needs an expiry date".into())
+        );
+        assert_eq!(
+            product.check_receipt(12, None, expiring_in(9), None, received_on()),
+            Err(
+                "product 1001 expires 2026-01-14, 9 days of shelf life left, at least 10 required"
+                    .into()
+            )
+        );
+        assert_eq!(
+            product.check_receipt(12, None, expiring_in(-2), None, received_on()),
+            Err(
+                "product 1001 expires 2026-01-03, 0 days of shelf life left, at least 10 required"
+                    .into()
+            )
+        );
+        assert_eq!(
+            product.check_receipt(12, None, expiring_in(10), None, received_on()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn asns_announce_the_same_regardless_of_their_state() {
+        let stored = Asn {
+            status: AsnStatus::Closed,
+            created_at: Utc::now() - Duration::days(1),
+            ..asn(&[(1001, 10), (1002, 4)])
+        };
+        let mut resubmitted = asn(&[(1001, 10), (1002, 4)]);
+        // Lost when MongoDB stores it.
+        resubmitted.expected_arrival += Duration::microseconds(250);
+
+        assert!(stored.announces_same(&resubmitted));
+        assert!(!stored.announces_same(&asn(&[(1001, 10), (1002, 5)])));
+        assert!(!stored.announces_same(&asn(&[(1001, 10)])));
+        assert!(!stored.announces_same(&Asn {
+            supplier: "Other Supply".into(),
+            ..asn(&[(1001, 10), (1002, 4)])
+        }));
+    }
 }
@@ -0,0 +1,608 @@
+//! Decodes the payloads handheld scanners read off labels into a `ScannedLabel`.
+//!
+//! Two formats are understood:
+//!
+//! - GS1 element strings, as carried by GS1-128 barcodes and GS1 DataMatrix and QR codes. Both
+//!   the raw form, with FNC1 sent as the ASCII group separator, and the human readable form with
+//!   the application identifiers in brackets, e.g. `(01)09506000134352(17)270131(10)LOT42`.
+//!   Values in the human readable form cannot contain brackets.
+//! - Our own JSON QR codes, e.g. `{"shipment_id": 1001, "product_id": 42, "quantity": 12}`, which
+//!   may also carry `gtin`, `sscc`, `lot`, `expiry` (as `YYYY-MM-DD`) and `serial`.
+//!
+//! Not wired into any endpoint yet: `POST /scans` still takes the fields of a `Scan`, since GS1
+//! labels identify products by GTIN and the catalog does not map GTINs to product ids.
+
+use crate::model::{LabelFormat, ScannedLabel};
+use chrono::{Datelike, NaiveDate, Utc};
+use common_error::error::WarehouseError;
+use serde::Deserialize;
+use std::collections::HashSet;
+use thiserror::Error;
+
+/// Stands in for FNC1 in raw GS1 element strings, ending variable length values.
+const GROUP_SEPARATOR: char = '\u{1d}';
+
+/// Prefixes scanners put in front of the data to say which kind of code they read.
+const SYMBOLOGY_IDENTIFIERS: [&str; 4] = ["]C1", "]e0", "]d2", "]Q3"];
+
+/// Why a scanned payload could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum LabelError {
+    #[error("Payload is empty")]
+    Empty,
+    #[error("Payload is not a GS1 element string or a JSON label: {0}")]
+    Malformed(String),
+    #[error("Unknown application identifier at \"{0}\"")]
+    UnknownAi(String),
+    #[error("Application identifier ({0}) appears more than once")]
+    DuplicateAi(&'static str),
+    #[error("Invalid {field}: {reason}")]
+    InvalidField { field: &'static str, reason: String },
+    #[error("Label identifies neither a product nor a pallet")]
+    Unidentified,
+}
+
+impl LabelError {
+    fn invalid(field: &'static str, reason: impl Into<String>) -> Self {
+        LabelError::InvalidField {
+            field,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl From<LabelError> for WarehouseError {
+    fn from(e: LabelError) -> Self {
+        WarehouseError::Validation(e.to_string())
+    }
+}
+
+/// Decodes a scanned payload, telling the format apart by its first character.
+pub fn parse_label(payload: &str) -> Result<ScannedLabel, LabelError> {
+    // Scanners often end what they read with a line break.
+    let payload = payload.trim_matches(|c: char| c == '\r' || c == '\n' || c == ' ');
+    if payload.is_empty() {
+        return Err(LabelError::Empty);
+    }
+
+    if payload.starts_with('{') {
+        parse_json(payload)
+    } else {
+        parse_gs1(payload)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Length {
+    Fixed(usize),
+    Variable(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Content {
+    Numeric,
+    Alphanumeric,
+}
+
+/// The GS1 application identifiers we understand.
+struct Ai {
+    code: &'static str,
+    name: &'static str,
+    length: Length,
+    content: Content,
+}
+
+const fn ai(code: &'static str, name: &'static str, length: Length, content: Content) -> Ai {
+    Ai {
+        code,
+        name,
+        length,
+        content,
+    }
+}
+
+// No code is a prefix of another, so raw element strings split unambiguously.
+const AIS: &[Ai] = &[
+    ai("00", "SSCC", Length::Fixed(18), Content::Numeric),
+    ai("01", "GTIN", Length::Fixed(14), Content::Numeric),
+    ai("02", "content GTIN", Length::Fixed(14), Content::Numeric),
+    ai(
+        "10",
+        "batch/lot",
+        Length::Variable(20),
+        Content::Alphanumeric,
+    ),
+    ai("11", "production date", Length::Fixed(6), Content::Numeric),
+    ai("15", "best before date", Length::Fixed(6), Content::Numeric),
+    ai("17", "expiry date", Length::Fixed(6), Content::Numeric),
+    ai(
+        "21",
+        "serial number",
+        Length::Variable(20),
+        Content::Alphanumeric,
+    ),
+    ai(
+        "30",
+        "variable count",
+        Length::Variable(8),
+        Content::Numeric,
+    ),
+    ai("37", "count", Length::Variable(8), Content::Numeric),
+    ai(
+        "3100",
+        "net weight (kg)",
+        Length::Fixed(6),
+        Content::Numeric,
+    ),
+    ai(
+        "3101",
+        "net weight (kg)",
+        Length::Fixed(6),
+        Content::Numeric,
+    ),
+    ai(
+        "3102",
+        "net weight (kg)",
+        Length::Fixed(6),
+        Content::Numeric,
+    ),
+    ai(
+        "3103",
+        "net weight (kg)",
+        Length::Fixed(6),
+        Content::Numeric,
+    ),
+    ai(
+        "400",
+        "order number",
+        Length::Variable(30),
+        Content::Alphanumeric,
+    ),
+];
+
+fn parse_gs1(payload: &str) -> Result<ScannedLabel, LabelError> {
+    let data = SYMBOLOGY_IDENTIFIERS
+        .iter()
+        .find_map(|prefix| payload.strip_prefix(prefix))
+        .unwrap_or(payload)
+        .trim_start_matches(GROUP_SEPARATOR);
+    if data.is_empty() {
+        return Err(LabelError::Empty);
+    }
+    if !data.is_ascii() {
+        return Err(LabelError::Malformed(
+            "GS1 element strings only hold ASCII characters".into(),
+        ));
+    }
+
+    let elements = if data.starts_with('(') {
+        split_bracketed(data)?
+    } else {
+        split_raw(data)?
+    };
+
+    let mut label = ScannedLabel {
+        format: LabelFormat::Gs1,
+        sscc: None,
+        gtin: None,
+        product_id: None,
+        shipment_id: None,
+        quantity: None,
+        lot: None,
+        expiry: None,
+        serial: None,
+        other: Vec::new(),
+    };
+    let mut seen = HashSet::new();
+    for (ai, value) in elements {
+        if !seen.insert(ai.code) {
+            return Err(LabelError::DuplicateAi(ai.code));
+        }
+        check_value(ai, value)?;
+
+        match ai.code {
+            "00" => label.sscc = Some(check_digit_valid(ai.name, value)?),
+            "01" | "02" => {
+                if label.gtin.is_some() {
+                    return Err(LabelError::Malformed(
+                        "label carries both a GTIN (01) and a content GTIN (02)".into(),
+                    ));
+                }
+                label.gtin = Some(check_digit_valid(ai.name, value)?);
+            }
+            "10" => label.lot = Some(value.to_string()),
+            "17" => label.expiry = Some(gs1_date(ai.name, value)?),
+            "21" => label.serial = Some(value.to_string()),
+            "30" | "37" => {
+                if label.quantity.is_some() {
+                    return Err(LabelError::Malformed(
+                        "label carries both a variable count (30) and a count (37)".into(),
+                    ));
+                }
+                label.quantity = Some(quantity(ai.name, value)?);
+            }
+            "11" | "15" => {
+                gs1_date(ai.name, value)?;
+                label.other.push((ai.code.to_string(), value.to_string()));
+            }
+            _ => label.other.push((ai.code.to_string(), value.to_string())),
+        }
+    }
+
+    if label.gtin.is_none() && label.sscc.is_none() {
+        return Err(LabelError::Unidentified);
+    }
+    Ok(label)
+}
+
+/// Splits raw element strings, where variable length values end with a group separator unless
+/// they are last.
+fn split_raw(mut data: &str) -> Result<Vec<(&'static Ai, &str)>, LabelError> {
+    let mut elements = Vec::new();
+    while !data.is_empty() {
+        let ai = AIS
+            .iter()
+            .find(|ai| data.starts_with(ai.code))
+            .ok_or_else(|| LabelError::UnknownAi(data.chars().take(4).collect()))?;
+        let rest = &data[ai.code.len()..];
+
+        let (value, rest) = match ai.length {
+            Length::Fixed(length) => {
+                if rest.len() < length || rest[..length].contains(GROUP_SEPARATOR) {
+                    return Err(LabelError::invalid(
+                        ai.name,
+                        format!("expected {} characters", length),
+                    ));
+                }
+                rest.split_at(length)
+            }
+            Length::Variable(_) => rest.split_at(rest.find(GROUP_SEPARATOR).unwrap_or(rest.len())),
+        };
+        elements.push((ai, value));
+        // Some encoders also end fixed length values with a separator.
+        data = rest.strip_prefix(GROUP_SEPARATOR).unwrap_or(rest);
+    }
+    Ok(elements)
+}
+
+/// Splits `(01)09506000134352(10)LOT42` style strings.
+fn split_bracketed(mut data: &str) -> Result<Vec<(&'static Ai, &str)>, LabelError> {
+    let mut elements = Vec::new();
+    while let Some(rest) = data.strip_prefix('(') {
+        let (code, rest) = rest.split_once(')').ok_or_else(|| {
+            LabelError::Malformed("application identifier without a closing bracket".into())
+        })?;
+        let ai = AIS
+            .iter()
+            .find(|ai| ai.code == code)
+            .ok_or_else(|| LabelError::UnknownAi(code.to_string()))?;
+
+        let end = rest.find('(').unwrap_or(rest.len());
+        elements.push((ai, &rest[..end]));
+        data = &rest[end..];
+    }
+
+    if !data.is_empty() {
+        return Err(LabelError::Malformed(format!(
+            "unexpected \"{}\" outside of an element",
+            data
+        )));
+    }
+    Ok(elements)
+}
+
+fn check_value(ai: &Ai, value: &str) -> Result<(), LabelError> {
+    match ai.length {
+        Length::Fixed(length) if value.len() != length => {
+            return Err(LabelError::invalid(
+                ai.name,
+                format!("expected {} characters, got {}", length, value.len()),
+            ));
+        }
+        Length::Variable(max) if value.is_empty() || value.len() > max => {
+            return Err(LabelError::invalid(
+                ai.name,
+                format!("expected 1 to {} characters, got {}", max, value.len()),
+            ));
+        }
+        _ => {}
+    }
+
+    match ai.content {
+        Content::Numeric if !value.bytes().all(|b| b.is_ascii_digit()) => {
+            Err(LabelError::invalid(ai.name, "expected digits only"))
+        }
+        Content::Alphanumeric => check_charset(ai.name, value),
+        _ => Ok(()),
+    }
+}
+
+/// GS1 alphanumeric values are limited to the printable characters of "character set 82".
+fn check_charset(field: &'static str, value: &str) -> Result<(), LabelError> {
+    match value
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "!\"%&'()*+,-./:;<=>?_".contains(*c)))
+    {
+        Some(c) => Err(LabelError::invalid(
+            field,
+            format!("character {:?} is not allowed", c),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Returns the digits if their last one is the GS1 check digit of the others.
+fn check_digit_valid(field: &'static str, digits: &str) -> Result<String, LabelError> {
+    let (body, check) = digits.split_at(digits.len() - 1);
+    let sum: u32 = body
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(position, b)| {
+            let digit = u32::from(b - b'0');
+            if position % 2 == 0 {
+                digit * 3
+            } else {
+                digit
+            }
+        })
+        .sum();
+    let expected = (10 - sum % 10) % 10;
+
+    if u32::from(check.as_bytes()[0] - b'0') != expected {
+        return Err(LabelError::invalid(
+            field,
+            format!("check digit should be {}", expected),
+        ));
+    }
+    Ok(digits.to_string())
+}
+
+/// A `YYMMDD` date. A day of `00` means the last day of the month, and the century is the one
+/// that puts the date closest to today, as GS1 specifies.
+fn gs1_date(field: &'static str, value: &str) -> Result<NaiveDate, LabelError> {
+    let number = |range: std::ops::Range<usize>| value[range].parse::<i32>().unwrap_or(-1);
+    let (yy, month, day) = (number(0..2), number(2..4) as u32, number(4..6) as u32);
+
+    let this_year = Utc::now().year();
+    let century = this_year - this_year % 100;
+    let year = match yy - this_year % 100 {
+        diff if diff >= 51 => century - 100 + yy,
+        diff if diff <= -50 => century + 100 + yy,
+        _ => century + yy,
+    };
+
+    let date = if day == 0 {
+        NaiveDate::from_ymd_opt(year, month, 1)
+            .and_then(|first| first.checked_add_months(chrono::Months::new(1)))
+            .and_then(|next| next.pred_opt())
+    } else {
+        NaiveDate::from_ymd_opt(year, month, day)
+    };
+    date.ok_or_else(|| LabelError::invalid(field, format!("{} is not a date", value)))
+}
+
+fn quantity(field: &'static str, value: &str) -> Result<u32, LabelError> {
+    match value.parse::<u32>() {
+        Ok(0) => Err(LabelError::invalid(field, "must be at least 1")),
+        Ok(quantity) => Ok(quantity),
+        Err(_) => Err(LabelError::invalid(field, "too large")),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonLabel {
+    shipment_id: Option<u64>,
+    product_id: Option<u64>,
+    gtin: Option<String>,
+    sscc: Option<String>,
+    quantity: Option<u32>,
+    lot: Option<String>,
+    expiry: Option<NaiveDate>,
+    serial: Option<String>,
+}
+
+fn parse_json(payload: &str) -> Result<ScannedLabel, LabelError> {
+    let json: JsonLabel =
+        serde_json::from_str(payload).map_err(|e| LabelError::Malformed(e.to_string()))?;
+
+    let gtin = json.gtin.map(|gtin| json_gtin(&gtin)).transpose()?;
+    let sscc = json
+        .sscc
+        .map(|sscc| {
+            if sscc.len() != 18 || !sscc.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(LabelError::invalid("SSCC", "expected 18 digits"));
+            }
+            check_digit_valid("SSCC", &sscc)
+        })
+        .transpose()?;
+    if json.quantity == Some(0) {
+        return Err(LabelError::invalid("quantity", "must be at least 1"));
+    }
+    for (field, value) in [("lot", &json.lot), ("serial", &json.serial)] {
+        if let Some(value) = value {
+            if value.is_empty() || value.len() > 20 {
+                return Err(LabelError::invalid(field, "expected 1 to 20 characters"));
+            }
+            check_charset(field, value)?;
+        }
+    }
+
+    if json.product_id.is_none() && gtin.is_none() && sscc.is_none() {
+        return Err(LabelError::Unidentified);
+    }
+    Ok(ScannedLabel {
+        format: LabelFormat::Json,
+        sscc,
+        gtin,
+        product_id: json.product_id,
+        shipment_id: json.shipment_id,
+        quantity: json.quantity,
+        lot: json.lot,
+        expiry: json.expiry,
+        serial: json.serial,
+        other: Vec::new(),
+    })
+}
+
+/// Accepts GTIN-8, -12, -13 and -14 and returns them as GTIN-14.
+fn json_gtin(gtin: &str) -> Result<String, LabelError> {
+    if !matches!(gtin.len(), 8 | 12 | 13 | 14) || !gtin.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(LabelError::invalid(
+            "GTIN",
+            "expected 8, 12, 13 or 14 digits",
+        ));
+    }
+    check_digit_valid("GTIN", &format!("{:0>14}", gtin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GTIN: &str = "09506000134352";
+    const SSCC: &str = "106141411234567897";
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn invalid_field(result: Result<ScannedLabel, LabelError>) -> &'static str {
+        match result {
+            Err(LabelError::InvalidField { field, .. }) => field,
+            other => panic!("expected an invalid field, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_bracketed_element_strings() {
+        let label = parse_label(&format!("(01){}(17)270131(10)LOT42(21)SN-7", GTIN)).unwrap();
+
+        assert_eq!(label.format, LabelFormat::Gs1);
+        assert_eq!(label.gtin.as_deref(), Some(GTIN));
+        assert_eq!(label.expiry, Some(date(2027, 1, 31)));
+        assert_eq!(label.lot.as_deref(), Some("LOT42"));
+        assert_eq!(label.serial.as_deref(), Some("SN-7"));
+    }
+
+    #[test]
+    fn parses_raw_element_strings() {
+        let payload = format!("]C101{}1727013110LOT42\u{1d}3712\r\n", GTIN);
+        let label = parse_label(&payload).unwrap();
+
+        assert_eq!(label.gtin.as_deref(), Some(GTIN));
+        assert_eq!(label.expiry, Some(date(2027, 1, 31)));
+        assert_eq!(label.lot.as_deref(), Some("LOT42"));
+        assert_eq!(label.quantity, Some(12));
+    }
+
+    #[test]
+    fn parses_pallet_labels() {
+        let label = parse_label(&format!("00{}\u{1d}02{}3740", SSCC, GTIN)).unwrap();
+
+        assert_eq!(label.sscc.as_deref(), Some(SSCC));
+        assert_eq!(label.gtin.as_deref(), Some(GTIN));
+        assert_eq!(label.quantity, Some(40));
+    }
+
+    #[test]
+    fn keeps_elements_without_a_field() {
+        let label = parse_label(&format!("(01){}(3102)001250(11)260115", GTIN)).unwrap();
+
+        assert_eq!(
+            label.other,
+            [
+                ("3102".to_string(), "001250".to_string()),
+                ("11".to_string(), "260115".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_a_bad_check_digit() {
+        assert_eq!(invalid_field(parse_label("(01)09506000134353")), "GTIN");
+        assert_eq!(invalid_field(parse_label("00106141411234567890")), "SSCC");
+    }
+
+    #[test]
+    fn rejects_a_duplicate_ai() {
+        let result = parse_label(&format!("(01){}(10)A(10)B", GTIN));
+        assert_eq!(result.unwrap_err(), LabelError::DuplicateAi("10"));
+    }
+
+    #[test]
+    fn rejects_gtin_together_with_content_gtin() {
+        let result = parse_label(&format!("(01){}(02){}", GTIN, GTIN));
+        assert!(matches!(result, Err(LabelError::Malformed(_))));
+    }
+
+    #[test]
+    fn rejects_an_unknown_ai() {
+        let bracketed = parse_label(&format!("(01){}(99)X", GTIN));
+        assert_eq!(bracketed.unwrap_err(), LabelError::UnknownAi("99".into()));
+
+        let raw = parse_label(&format!("01{}9912", GTIN));
+        assert_eq!(raw.unwrap_err(), LabelError::UnknownAi("9912".into()));
+    }
+
+    #[test]
+    fn rejects_a_truncated_fixed_length_ai() {
+        assert_eq!(invalid_field(parse_label("010950600013435")), "GTIN");
+        assert_eq!(invalid_field(parse_label("(01)0950600013435")), "GTIN");
+        // A separator inside a fixed length value cuts it short too.
+        let payload = format!("01{}17270\u{1d}10LOT", GTIN);
+        assert_eq!(invalid_field(parse_label(&payload)), "expiry date");
+    }
+
+    #[test]
+    fn day_zero_is_the_last_day_of_the_month() {
+        let label = parse_label(&format!("(01){}(17)280200", GTIN)).unwrap();
+        assert_eq!(label.expiry, Some(date(2028, 2, 29)));
+
+        let result = parse_label(&format!("(01){}(17)271300", GTIN));
+        assert_eq!(invalid_field(result), "expiry date");
+    }
+
+    #[test]
+    fn requires_a_product_or_pallet() {
+        assert_eq!(
+            parse_label("(10)LOT42").unwrap_err(),
+            LabelError::Unidentified
+        );
+        assert_eq!(parse_label(" \r\n").unwrap_err(), LabelError::Empty);
+    }
+
+    #[test]
+    fn parses_json_labels() {
+        let label = parse_label(
+            r#"{"shipment_id": 1001, "product_id": 42, "quantity": 12, "gtin": "4006381333931", "expiry": "2027-01-31"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(label.format, LabelFormat::Json);
+        assert_eq!(label.shipment_id, Some(1001));
+        assert_eq!(label.product_id, Some(42));
+        assert_eq!(label.quantity, Some(12));
+        assert_eq!(label.gtin.as_deref(), Some("04006381333931"));
+        assert_eq!(label.expiry, Some(date(2027, 1, 31)));
+    }
+
+    #[test]
+    fn rejects_json_with_unknown_fields() {
+        let result = parse_label(r#"{"product_id": 42, "colour": "red"}"#);
+        assert!(matches!(result, Err(LabelError::Malformed(_))));
+    }
+
+    #[test]
+    fn rejects_invalid_json_values() {
+        let zero = parse_label(r#"{"product_id": 42, "quantity": 0}"#);
+        assert_eq!(invalid_field(zero), "quantity");
+
+        let lot = parse_label(r#"{"product_id": 42, "lot": "L{1}"}"#);
+        assert_eq!(invalid_field(lot), "lot");
+
+        let gtin = parse_label(r#"{"gtin": "4006381333932"}"#);
+        assert_eq!(invalid_field(gtin), "GTIN");
+    }
+}
@@ -0,0 +1,228 @@
+use crate::model::{Asn, Discrepancy, DiscrepancyKind, ReceivedLine};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// What the ASN announced of a product and what was received of it so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProductTally {
+    pub product_id: u64,
+    pub expected: u32,
+    pub received: u32,
+    /// Whether the ASN announced the product at all.
+    pub announced: bool,
+}
+
+impl ProductTally {
+    pub fn discrepancy(&self) -> Option<DiscrepancyKind> {
+        if !self.announced {
+            Some(DiscrepancyKind::Unexpected)
+        } else if self.received > self.expected {
+            Some(DiscrepancyKind::Over)
+        } else if self.received < self.expected {
+            Some(DiscrepancyKind::Short)
+        } else {
+            None
+        }
+    }
+}
+
+/// Matches the lines received against a shipment with its ASN, one tally per product announced
+/// or received, ordered by product id.
+pub fn tally(asn: &Asn, lines: &[ReceivedLine]) -> Vec<ProductTally> {
+    let mut products: BTreeMap<u64, ProductTally> = BTreeMap::new();
+    for line in &asn.lines {
+        let tally = products
+            .entry(line.product_id)
+            .or_insert_with(|| ProductTally {
+                product_id: line.product_id,
+                expected: 0,
+                received: 0,
+                announced: true,
+            });
+        tally.expected = tally.expected.saturating_add(line.quantity);
+    }
+    for line in lines {
+        let tally = products
+            .entry(line.product_id)
+            .or_insert_with(|| ProductTally {
+                product_id: line.product_id,
+                expected: 0,
+                received: 0,
+                announced: false,
+            });
+        tally.received = tally.received.saturating_add(line.quantity);
+    }
+
+    products.into_values().collect()
+}
+
+/// Every product received over or short of the ASN, or not announced by it.
+pub fn discrepancies(asn: &Asn, lines: &[ReceivedLine]) -> Vec<Discrepancy> {
+    tally(asn, lines)
+        .into_iter()
+        .filter_map(|tally| {
+            Some(Discrepancy {
+                shipment_id: asn.shipment_id,
+                supplier: asn.supplier.clone(),
+                product_id: tally.product_id,
+                kind: tally.discrepancy()?,
+                expected: tally.expected,
+                received: tally.received,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{AsnLine, AsnStatus, Condition, Product};
+    use crate::uom::{to_eaches, PackHierarchy, Uom};
+    use chrono::Utc;
+    use mongodb::bson::oid::ObjectId;
+
+    /// A stored ASN for shipment 7, its lines in eaches.
+    fn asn(lines: &[(u64, u32)]) -> Asn {
+        Asn {
+            shipment_id: 7,
+            supplier: "Acme Supply".into(),
+            expected_arrival: Utc::now(),
+            lines: lines
+                .iter()
+                .map(|&(product_id, quantity)| AsnLine {
+                    product_id,
+                    quantity,
+                    uom: Uom::Each,
+                })
+                .collect(),
+            status: AsnStatus::Open,
+            created_at: Utc::now(),
+            last_received_at: None,
+            closed_at: None,
+        }
+    }
+
+    /// A line of `received_quantity` x `uom` holding `eaches` eaches.
+    fn line(product_id: u64, eaches: u32, uom: Uom, received_quantity: u32) -> ReceivedLine {
+        ReceivedLine {
+            id: ObjectId::new(),
+            shipment_id: 7,
+            product_id,
+            quantity: eaches,
+            received_uom: uom,
+            received_quantity: Some(received_quantity),
+            operator_id: 1,
+            condition: Condition::Good,
+            outbox_id: ObjectId::new(),
+            received_at: Utc::now(),
+            lot: None,
+            expiry: None,
+            serial: None,
+            idempotency_key: None,
+        }
+    }
+
+    fn each(product_id: u64, quantity: u32) -> ReceivedLine {
+        line(product_id, quantity, Uom::Each, quantity)
+    }
+
+    fn kinds(asn: &Asn, lines: &[ReceivedLine]) -> Vec<(u64, DiscrepancyKind, u32, u32)> {
+        discrepancies(asn, lines)
+            .into_iter()
+            .map(|d| (d.product_id, d.kind, d.expected, d.received))
+            .collect()
+    }
+
+    #[test]
+    fn tallies_add_up_lines_per_product_in_product_order() {
+        let asn = asn(&[(2, 10), (1, 4), (2, 5)]);
+        let lines = [each(1, 3), each(2, 15), each(1, 1), each(3, 2)];
+
+        assert_eq!(
+            tally(&asn, &lines),
+            [
+                ProductTally {
+                    product_id: 1,
+                    expected: 4,
+                    received: 4,
+                    announced: true,
+                },
+                ProductTally {
+                    product_id: 2,
+                    expected: 15,
+                    received: 15,
+                    announced: true,
+                },
+                ProductTally {
+                    product_id: 3,
+                    expected: 0,
+                    received: 2,
+                    announced: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn matching_receipts_have_no_discrepancies() {
+        let asn = asn(&[(1, 4), (2, 6)]);
+        assert!(discrepancies(&asn, &[each(1, 4), each(2, 2), each(2, 4)]).is_empty());
+    }
+
+    #[test]
+    fn each_kind_of_discrepancy_is_reported() {
+        let asn = asn(&[(1, 4), (2, 6), (3, 5)]);
+        let lines = [each(1, 5), each(2, 2), each(4, 1)];
+
+        assert_eq!(
+            kinds(&asn, &lines),
+            [
+                (1, DiscrepancyKind::Over, 4, 5),
+                (2, DiscrepancyKind::Short, 6, 2),
+                (3, DiscrepancyKind::Short, 5, 0),
+                (4, DiscrepancyKind::Unexpected, 0, 1),
+            ]
+        );
+        let discrepancy = &discrepancies(&asn, &lines)[0];
+        assert_eq!(discrepancy.shipment_id, 7);
+        assert_eq!(discrepancy.supplier, "Acme Supply");
+    }
+
+    #[test]
+    fn lines_in_other_units_are_compared_in_eaches() {
+        let product = Product {
+            product_id: 1,
+            name: "Tea".into(),
+            lot_controlled: false,
+            serial_controlled: false,
+            min_shelf_life_days: None,
+            pack: PackHierarchy {
+                inner: Some(6),
+                case: Some(12),
+                pallet: Some(120),
+            },
+        };
+        // Converted the way receiving and storing an ASN convert them.
+        let scanned = |quantity, uom| {
+            let eaches = to_eaches(Some(&product), 1, quantity, uom).unwrap();
+            line(1, eaches, uom, quantity)
+        };
+        let asn = asn(&[(1, to_eaches(Some(&product), 1, 3, Uom::Case).unwrap())]);
+
+        let lines = [
+            scanned(2, Uom::Case),
+            scanned(1, Uom::Inner),
+            scanned(6, Uom::Each),
+        ];
+        assert!(discrepancies(&asn, &lines).is_empty());
+
+        assert_eq!(
+            kinds(&asn, &[scanned(1, Uom::Pallet)]),
+            [(1, DiscrepancyKind::Over, 36, 120)]
+        );
+        assert_eq!(
+            kinds(&asn, &[scanned(3, Uom::Inner)]),
+            [(1, DiscrepancyKind::Short, 36, 18)]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use crate::model::{Condition, CsvRow, RowWithUser};
+use crate::uom::Uom;
+use common_error::error::{WarehouseError, WarehouseResult};
+use csv::ReaderBuilder;
+use inbound_outbox::Outbox;
+use mongodb::bson::oid::ObjectId;
+use std::path::Path;
+
+pub fn read_rows(path: impl AsRef<Path>) -> WarehouseResult<Vec<CsvRow>> {
+    let mut reader = ReaderBuilder::new().has_headers(true).from_path(path)?;
+    Ok(reader.deserialize().collect::<Result<_, _>>()?)
+}
+
+/// Writes a row to the outbox on behalf of a user. Without the catalog at hand, only rows in
+/// eaches can be written, and without a quarantine only rows of good stock.
+pub async fn add_row(outbox: &Outbox, user_id: u64, row: &CsvRow) -> WarehouseResult<ObjectId> {
+    if let Some(uom) = row.uom.filter(|uom| *uom != Uom::Each) {
+        return Err(WarehouseError::Validation(format!(
+            "row of product {} is in {}s, only eaches can be written straight to the outbox",
+            row.product_id, uom
+        )));
+    }
+    if let Some(condition) = row.condition.filter(|condition| !condition.is_sellable()) {
+        return Err(WarehouseError::Validation(format!(
+            "row of product {} is {:?}, only good stock can be written straight to the outbox",
+            row.product_id, condition
+        )));
+    }
+    let row_with_user = RowWithUser {
+        user_id,
+        shipment_id: row.shipment_id,
+        product_id: row.product_id,
+        quantity: row.quantity,
+        received_uom: Uom::Each,
+        received_quantity: row.quantity,
+        condition: Condition::Good,
+        lot: row.lot.clone(),
+        expiry: row.expiry,
+        serial: row.serial.clone(),
+    };
+
+    let payload = serde_json::to_string(&row_with_user)?;
+    let outbox_id = outbox.add_entry(user_id, payload).await?;
+    tracing::debug!("Outbox entry created with ID: {}", outbox_id);
+    Ok(outbox_id)
+}
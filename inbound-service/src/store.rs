@@ -0,0 +1,748 @@
+use crate::model::{
+    Asn, AsnLine, AsnStatus, Condition, Discrepancy, Disposition, DispositionAction,
+    IdempotencyKey, NewAsn, Product, QuarantinedStock, ReceivedLine, RowWithUser, Scan,
+    ScrappedStock, DISCREPANCY_EVENT, STOCK_QUARANTINED, STOCK_SCRAPPED,
+};
+use crate::receiving::discrepancies;
+use crate::uom::{to_eaches, Uom};
+use chrono::{DateTime, Utc};
+use common_error::error::{WarehouseError, WarehouseResult};
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, TryStreamExt};
+use inbound_outbox::{Outbox, STOCK_RECEIVED};
+use mongodb::bson::{self, doc, oid::ObjectId, Document};
+use mongodb::error::{
+    ErrorKind, WriteFailure, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT,
+};
+use mongodb::options::{ClientOptions, IndexOptions, ReturnDocument};
+use mongodb::{Client, ClientSession, Collection, IndexModel};
+use std::collections::{HashMap, HashSet};
+
+/// Where received lines are stored, in the same database as the outbox.
+pub const LINES_COLLECTION: &str = "inbound_lines";
+/// Where ASNs are stored, keyed by shipment id.
+pub const ASNS_COLLECTION: &str = "inbound_asns";
+/// The product catalog, keyed by product id.
+pub const PRODUCTS_COLLECTION: &str = "products";
+/// Where stock received in any other condition than good is held.
+pub const QUARANTINE_COLLECTION: &str = "inbound_quarantine";
+
+const DUPLICATE_KEY: i32 = 11000;
+/// How often a transaction is run, and its commit tried, before a transient failure is given up on.
+const TRANSACTION_ATTEMPTS: u32 = 5;
+
+/// Records received lines together with the outbox entries that announce them.
+///
+/// Every line and its entry are written in one transaction, so a line is never announced without
+/// being stored and never stored without being announced. Transactions need MongoDB to run as a
+/// replica set, which the outbox relay's change stream needs as well.
+#[derive(Clone)]
+pub struct ReceivingStore {
+    client: Client,
+    lines: Collection<ReceivedLine>,
+    asns: Collection<Asn>,
+    products: Collection<Product>,
+    quarantine: Collection<QuarantinedStock>,
+    outbox: Outbox,
+}
+
+impl ReceivingStore {
+    pub async fn connect(
+        uri: &str,
+        db_name: &str,
+        outbox_collection: &str,
+    ) -> WarehouseResult<Self> {
+        let options = ClientOptions::parse(uri).await?;
+        let store = Self::from_client(Client::with_options(options)?, db_name, outbox_collection);
+        store
+            .lines
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "shipment_id": 1 })
+                    .build(),
+            )
+            .await?;
+        // Two lines recorded from the same scan would count its stock twice.
+        store
+            .lines
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "idempotency_key.device_id": 1, "idempotency_key.sequence": 1 })
+                    .options(
+                        IndexOptions::builder()
+                            .unique(true)
+                            .partial_filter_expression(
+                                doc! { "idempotency_key": { "$exists": true } },
+                            )
+                            .build(),
+                    )
+                    .build(),
+            )
+            .await?;
+        store
+            .quarantine
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "shipment_id": 1 })
+                    .build(),
+            )
+            .await?;
+        Ok(store)
+    }
+
+    /// A store on an existing client, without creating its indexes. The client connects lazily,
+    /// so nothing is sent to the server until the store is used.
+    pub(crate) fn from_client(client: Client, db_name: &str, outbox_collection: &str) -> Self {
+        let database = client.database(db_name);
+        let outbox = Outbox::from_client(&client, db_name, outbox_collection);
+        Self {
+            lines: database.collection(LINES_COLLECTION),
+            asns: database.collection(ASNS_COLLECTION),
+            products: database.collection(PRODUCTS_COLLECTION),
+            quarantine: database.collection(QUARANTINE_COLLECTION),
+            outbox,
+            client,
+        }
+    }
+
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    pub async fn record_scan(&self, scan: &Scan) -> WarehouseResult<ReceivedLine> {
+        let mut lines = self.record_scans(std::slice::from_ref(scan)).await?;
+        Ok(lines.remove(0))
+    }
+
+    /// Records a scan like `record_scan`, returning its line with whether this call recorded it,
+    /// `false` when its idempotency key was recorded before.
+    pub async fn record_or_replay_scan(
+        &self,
+        scan: &Scan,
+    ) -> WarehouseResult<(ReceivedLine, bool)> {
+        let mut lines = self
+            .record_or_replay_scans(std::slice::from_ref(scan))
+            .await?;
+        Ok(lines.remove(0))
+    }
+
+    /// Records every scan or, if any of them fails, none of them, converted to eaches. Scans for a
+    /// shipment whose ASN was closed are refused, as are scans in a pack their product does not
+    /// come in or breaking the lot, serial or shelf life rules of their product in the catalog.
+    /// Products that are not in the catalog have no rules to break, they are recorded as scanned
+    /// but only in eaches.
+    ///
+    /// Stock scanned in any other condition than good is quarantined and announced as
+    /// `stock_quarantined` instead of `stock_received`.
+    ///
+    /// A scan whose idempotency key was recorded before is not recorded again, the line it was
+    /// recorded as is returned instead. Reusing a key for a different scan is a conflict.
+    pub async fn record_scans(&self, scans: &[Scan]) -> WarehouseResult<Vec<ReceivedLine>> {
+        let lines = self.record_or_replay_scans(scans).await?;
+        Ok(lines.into_iter().map(|(line, _)| line).collect())
+    }
+
+    /// Records scans like `record_scans`, returning each line with whether this call recorded it,
+    /// `false` when its idempotency key was recorded before.
+    pub async fn record_or_replay_scans(
+        &self,
+        scans: &[Scan],
+    ) -> WarehouseResult<Vec<(ReceivedLine, bool)>> {
+        let mut keys = HashSet::new();
+        for key in scans
+            .iter()
+            .filter_map(|scan| scan.idempotency_key.as_ref())
+        {
+            if !keys.insert(key) {
+                return Err(WarehouseError::Validation(format!(
+                    "idempotency key {} is used more than once",
+                    key
+                )));
+            }
+        }
+
+        match self.record_scans_once(scans).await {
+            // A resubmission was committed in the meantime. Its lines are found this time.
+            Err(WarehouseError::Storage(e)) if is_duplicate_key(&e) => {
+                self.record_scans_once(scans).await
+            }
+            result => result,
+        }
+    }
+
+    async fn record_scans_once(
+        &self,
+        scans: &[Scan],
+    ) -> WarehouseResult<Vec<(ReceivedLine, bool)>> {
+        let product_ids: Vec<u64> = scans
+            .iter()
+            .map(|scan| scan.product_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let products = self.products(&product_ids).await?;
+
+        let (lines, inserted) = self
+            .in_transaction(&(scans, &products), |store, session, (scans, products)| {
+                store.record_scans_in(session, scans, products).boxed()
+            })
+            .await?;
+
+        for (line, _) in lines
+            .iter()
+            .zip(&inserted)
+            .filter(|(_, inserted)| **inserted)
+        {
+            tracing::info!(
+                "Received {} x product {} on shipment {}, outbox entry {}",
+                line.quantity,
+                line.product_id,
+                line.shipment_id,
+                line.outbox_id
+            );
+        }
+        Ok(lines.into_iter().zip(inserted).collect())
+    }
+
+    async fn insert_line(
+        &self,
+        session: &mut ClientSession,
+        scan: &Scan,
+        eaches: u32,
+    ) -> WarehouseResult<ReceivedLine> {
+        let received_at = Utc::now();
+        self.touch_asn(session, scan.shipment_id, received_at)
+            .await?;
+
+        let payload = serde_json::to_string(&RowWithUser {
+            user_id: scan.operator_id,
+            shipment_id: scan.shipment_id,
+            product_id: scan.product_id,
+            quantity: eaches,
+            received_uom: scan.uom,
+            received_quantity: scan.quantity,
+            condition: scan.condition,
+            lot: scan.lot.clone(),
+            expiry: scan.expiry,
+            serial: scan.serial.clone(),
+        })?;
+        let event_type = if scan.condition.is_sellable() {
+            STOCK_RECEIVED
+        } else {
+            STOCK_QUARANTINED
+        };
+        let outbox_id = self
+            .outbox
+            .add_entry_with_session(session, scan.operator_id, event_type, payload)
+            .await?;
+
+        let line = ReceivedLine {
+            id: ObjectId::new(),
+            shipment_id: scan.shipment_id,
+            product_id: scan.product_id,
+            quantity: eaches,
+            received_uom: scan.uom,
+            received_quantity: Some(scan.quantity),
+            operator_id: scan.operator_id,
+            condition: scan.condition,
+            outbox_id,
+            received_at,
+            lot: scan.lot.clone(),
+            expiry: scan.expiry,
+            serial: scan.serial.clone(),
+            idempotency_key: scan.idempotency_key.clone(),
+        };
+        self.lines.insert_one(&line).session(&mut *session).await?;
+
+        if !scan.condition.is_sellable() {
+            let stock = QuarantinedStock {
+                id: ObjectId::new(),
+                line_id: line.id,
+                shipment_id: line.shipment_id,
+                product_id: line.product_id,
+                condition: line.condition,
+                quantity: eaches,
+                held: eaches,
+                lot: line.lot.clone(),
+                expiry: line.expiry,
+                serial: line.serial.clone(),
+                quarantined_at: received_at,
+                dispositions: Vec::new(),
+            };
+            self.quarantine.insert_one(&stock).session(session).await?;
+        }
+        Ok(line)
+    }
+
+    /// The lines already recorded under the idempotency keys of `scans`, by key.
+    async fn recorded_lines(
+        &self,
+        session: &mut ClientSession,
+        scans: &[Scan],
+    ) -> WarehouseResult<HashMap<IdempotencyKey, ReceivedLine>> {
+        let keys: Vec<Document> = scans
+            .iter()
+            .filter_map(|scan| scan.idempotency_key.as_ref())
+            .map(|key| {
+                doc! {
+                    "idempotency_key.device_id": &key.device_id,
+                    "idempotency_key.sequence": key.sequence as i64,
+                }
+            })
+            .collect();
+        if keys.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let mut cursor = self
+            .lines
+            .find(doc! { "$or": keys })
+            .session(&mut *session)
+            .await?;
+        let lines: Vec<ReceivedLine> = cursor.stream(&mut *session).try_collect().await?;
+        Ok(lines
+            .into_iter()
+            .filter_map(|line| Some((line.idempotency_key.clone()?, line)))
+            .collect())
+    }
+
+    /// Notes the receipt on the shipment's ASN, if it has one, refusing it if the ASN is closed.
+    ///
+    /// Writing to the ASN also makes receiving and closing the shipment conflict, so a scan cannot
+    /// slip in while the shipment is being closed.
+    async fn touch_asn(
+        &self,
+        session: &mut ClientSession,
+        shipment_id: u64,
+        received_at: DateTime<Utc>,
+    ) -> WarehouseResult<()> {
+        let result = self
+            .asns
+            .update_one(
+                doc! { "_id": shipment_id as i64, "status": "open" },
+                doc! { "$set": { "last_received_at": bson::to_bson(&received_at)? } },
+            )
+            .session(&mut *session)
+            .await?;
+        if result.matched_count > 0 {
+            return Ok(());
+        }
+
+        let asn = self
+            .asns
+            .find_one(doc! { "_id": shipment_id as i64 })
+            .session(&mut *session)
+            .await?;
+        match asn {
+            Some(_) => Err(WarehouseError::Conflict(format!(
+                "shipment {} is closed",
+                shipment_id
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The lines received against a shipment, oldest first.
+    pub async fn lines_for_shipment(&self, shipment_id: u64) -> WarehouseResult<Vec<ReceivedLine>> {
+        let cursor = self
+            .lines
+            .find(doc! { "shipment_id": shipment_id as i64 })
+            .sort(doc! { "_id": 1 })
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Stores the ASN of a shipment, with its quantities converted to eaches, and returns it with
+    /// whether it was created. Every shipment has at most one: submitting the same ASN again
+    /// returns the stored one, a different one is a conflict.
+    pub async fn create_asn(&self, new: NewAsn) -> WarehouseResult<(Asn, bool)> {
+        let product_ids: Vec<u64> = new.lines.iter().map(|line| line.product_id).collect();
+        let products = self.products(&product_ids).await?;
+        let mut lines = Vec::with_capacity(new.lines.len());
+        for line in new.lines {
+            let product = products.get(&line.product_id);
+            lines.push(AsnLine {
+                product_id: line.product_id,
+                quantity: to_eaches(product, line.product_id, line.quantity, line.uom)?,
+                uom: Uom::Each,
+            });
+        }
+
+        let shipment_id = new.shipment_id;
+        let asn = Asn {
+            shipment_id,
+            supplier: new.supplier,
+            expected_arrival: new.expected_arrival,
+            lines,
+            status: AsnStatus::Open,
+            created_at: Utc::now(),
+            last_received_at: None,
+            closed_at: None,
+        };
+
+        match self.asns.insert_one(&asn).await {
+            Ok(_) => Ok((asn, true)),
+            Err(e) if is_duplicate_key(&e) => {
+                let stored = self.asn(shipment_id).await?;
+                if stored.announces_same(&asn) {
+                    Ok((stored, false))
+                } else {
+                    Err(WarehouseError::Conflict(format!(
+                        "shipment {} already has a different ASN",
+                        shipment_id
+                    )))
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The ones of `product_ids` that are in the catalog, by id.
+    pub async fn products(&self, product_ids: &[u64]) -> WarehouseResult<HashMap<u64, Product>> {
+        let ids: Vec<i64> = product_ids.iter().map(|id| *id as i64).collect();
+        let cursor = self.products.find(doc! { "_id": { "$in": ids } }).await?;
+        let products: Vec<Product> = cursor.try_collect().await?;
+
+        Ok(products
+            .into_iter()
+            .map(|product| (product.product_id, product))
+            .collect())
+    }
+
+    pub async fn asn(&self, shipment_id: u64) -> WarehouseResult<Asn> {
+        self.asns
+            .find_one(doc! { "_id": shipment_id as i64 })
+            .await?
+            .ok_or_else(|| WarehouseError::not_found("ASN for shipment", shipment_id))
+    }
+
+    /// The stock quarantined on receipt against a shipment, oldest first, including stock that was
+    /// released or scrapped since.
+    pub async fn quarantined_for_shipment(
+        &self,
+        shipment_id: u64,
+    ) -> WarehouseResult<Vec<QuarantinedStock>> {
+        let cursor = self
+            .quarantine
+            .find(doc! { "shipment_id": shipment_id as i64 })
+            .sort(doc! { "_id": 1 })
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Releases or scraps `quantity` eaches of quarantined stock, or all that is still held if
+    /// `None`. Released stock is announced as `stock_received`, since it can be allocated from
+    /// then on, and scrapped stock as `stock_scrapped`, on behalf of the operator.
+    pub async fn dispose_quarantined(
+        &self,
+        quarantine_id: ObjectId,
+        action: DispositionAction,
+        quantity: Option<u32>,
+        reason_code: &str,
+        operator_id: u64,
+    ) -> WarehouseResult<QuarantinedStock> {
+        let context = (quarantine_id, action, quantity, reason_code, operator_id);
+        let (disposed, quantity) = self
+            .in_transaction(&context, |store, session, context| {
+                let (quarantine_id, action, quantity, reason_code, operator_id) = *context;
+                store
+                    .dispose_quarantined_in(
+                        session,
+                        quarantine_id,
+                        action,
+                        quantity,
+                        reason_code,
+                        operator_id,
+                    )
+                    .boxed()
+            })
+            .await?;
+
+        tracing::info!(
+            "{:?} {} x product {} of quarantined stock {}, reason {}",
+            action,
+            quantity,
+            disposed.product_id,
+            quarantine_id,
+            reason_code
+        );
+        Ok(disposed)
+    }
+
+    /// Closes a shipment's ASN and announces every discrepancy between it and what was received,
+    /// one outbox entry each, on behalf of the operator closing it.
+    pub async fn close_shipment(
+        &self,
+        shipment_id: u64,
+        operator_id: u64,
+    ) -> WarehouseResult<(Asn, Vec<Discrepancy>)> {
+        let (asn, found) = self
+            .in_transaction(&(shipment_id, operator_id), |store, session, context| {
+                let (shipment_id, operator_id) = *context;
+                store
+                    .close_shipment_in(session, shipment_id, operator_id)
+                    .boxed()
+            })
+            .await?;
+
+        tracing::info!(
+            "Closed shipment {} with {} discrepancies",
+            shipment_id,
+            found.len()
+        );
+        Ok((asn, found))
+    }
+
+    async fn record_scans_in(
+        &self,
+        session: &mut ClientSession,
+        scans: &[Scan],
+        products: &HashMap<u64, Product>,
+    ) -> WarehouseResult<(Vec<ReceivedLine>, Vec<bool>)> {
+        let mut recorded = self.recorded_lines(session, scans).await?;
+        let mut lines = Vec::with_capacity(scans.len());
+        let mut inserted = Vec::with_capacity(scans.len());
+        for scan in scans {
+            let original = match &scan.idempotency_key {
+                Some(key) => recorded.remove(key).map(|line| (key, line)),
+                None => None,
+            };
+            match original {
+                Some((key, line)) if line.matches(scan) => {
+                    tracing::info!("Scan {} was already recorded as line {}", key, line.id);
+                    lines.push(line);
+                    inserted.push(false);
+                }
+                Some((key, line)) => {
+                    return Err(WarehouseError::Conflict(format!(
+                        "idempotency key {} was already used for a different scan, line {}",
+                        key, line.id
+                    )))
+                }
+                None => {
+                    let product = products.get(&scan.product_id);
+                    let eaches = to_eaches(product, scan.product_id, scan.quantity, scan.uom)?;
+                    if let Some(product) = product {
+                        product
+                            .check_receipt(
+                                eaches,
+                                scan.lot.as_deref(),
+                                scan.expiry,
+                                scan.serial.as_deref(),
+                                Utc::now().date_naive(),
+                            )
+                            .map_err(WarehouseError::Validation)?;
+                    }
+                    lines.push(self.insert_line(session, scan, eaches).await?);
+                    inserted.push(true);
+                }
+            }
+        }
+        Ok((lines, inserted))
+    }
+
+    async fn dispose_quarantined_in(
+        &self,
+        session: &mut ClientSession,
+        quarantine_id: ObjectId,
+        action: DispositionAction,
+        quantity: Option<u32>,
+        reason_code: &str,
+        operator_id: u64,
+    ) -> WarehouseResult<(QuarantinedStock, u32)> {
+        let stock = self
+            .quarantine
+            .find_one(doc! { "_id": quarantine_id })
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| WarehouseError::not_found("quarantined stock", quarantine_id))?;
+        let quantity = quantity.unwrap_or(stock.held);
+        if stock.held == 0 {
+            return Err(WarehouseError::Conflict(format!(
+                "quarantined stock {} was all released or scrapped already",
+                quarantine_id
+            )));
+        }
+        if quantity > stock.held {
+            return Err(WarehouseError::Validation(format!(
+                "only {} of quarantined stock {} is still held",
+                stock.held, quarantine_id
+            )));
+        }
+
+        let disposition = Disposition {
+            action,
+            quantity,
+            reason_code: reason_code.to_string(),
+            operator_id,
+            at: Utc::now(),
+        };
+        // Matching what was held keeps two dispositions from both taking the last of it.
+        let disposed = self
+            .quarantine
+            .find_one_and_update(
+                doc! { "_id": quarantine_id, "held": i64::from(stock.held) },
+                doc! {
+                    "$inc": { "held": -i64::from(quantity) },
+                    "$push": { "dispositions": bson::to_bson(&disposition)? },
+                },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| {
+                WarehouseError::Conflict(format!(
+                    "quarantined stock {} was changed at the same time",
+                    quarantine_id
+                ))
+            })?;
+
+        let (event_type, payload) = match action {
+            DispositionAction::Release => (
+                STOCK_RECEIVED,
+                serde_json::to_string(&RowWithUser {
+                    user_id: operator_id,
+                    shipment_id: stock.shipment_id,
+                    product_id: stock.product_id,
+                    quantity,
+                    received_uom: Uom::Each,
+                    received_quantity: quantity,
+                    condition: Condition::Good,
+                    lot: stock.lot,
+                    expiry: stock.expiry,
+                    serial: stock.serial,
+                })?,
+            ),
+            DispositionAction::Scrap => (
+                STOCK_SCRAPPED,
+                serde_json::to_string(&ScrappedStock {
+                    quarantine_id: quarantine_id.to_hex(),
+                    shipment_id: stock.shipment_id,
+                    product_id: stock.product_id,
+                    condition: stock.condition,
+                    quantity,
+                    reason_code: reason_code.to_string(),
+                    user_id: operator_id,
+                    lot: stock.lot,
+                    serial: stock.serial,
+                })?,
+            ),
+        };
+        self.outbox
+            .add_entry_with_session(session, operator_id, event_type, payload)
+            .await?;
+        Ok((disposed, quantity))
+    }
+
+    async fn close_shipment_in(
+        &self,
+        session: &mut ClientSession,
+        shipment_id: u64,
+        operator_id: u64,
+    ) -> WarehouseResult<(Asn, Vec<Discrepancy>)> {
+        let closed = self
+            .asns
+            .find_one_and_update(
+                doc! { "_id": shipment_id as i64, "status": "open" },
+                doc! { "$set": {
+                    "status": "closed",
+                    "closed_at": bson::to_bson(&Utc::now())?,
+                }},
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?;
+        let Some(asn) = closed else {
+            let exists = self
+                .asns
+                .find_one(doc! { "_id": shipment_id as i64 })
+                .session(&mut *session)
+                .await?
+                .is_some();
+            return Err(if exists {
+                WarehouseError::Conflict(format!("shipment {} is already closed", shipment_id))
+            } else {
+                WarehouseError::not_found("ASN for shipment", shipment_id)
+            });
+        };
+
+        let mut cursor = self
+            .lines
+            .find(doc! { "shipment_id": shipment_id as i64 })
+            .session(&mut *session)
+            .await?;
+        let lines: Vec<ReceivedLine> = cursor.stream(&mut *session).try_collect().await?;
+
+        let found = discrepancies(&asn, &lines);
+        for discrepancy in &found {
+            let payload = serde_json::to_string(discrepancy)?;
+            self.outbox
+                .add_entry_with_session(session, operator_id, DISCREPANCY_EVENT, payload)
+                .await?;
+        }
+        Ok((asn, found))
+    }
+
+    /// Runs `body` in a transaction and commits it, running it again from the start while MongoDB
+    /// labels the failure a transient transaction error, such as a write conflict between two
+    /// scans touching the same ASN. Commits whose outcome is unknown are retried on their own.
+    async fn in_transaction<C: Sync, T>(
+        &self,
+        context: &C,
+        mut body: impl for<'s> FnMut(
+            &'s Self,
+            &'s mut ClientSession,
+            &'s C,
+        ) -> BoxFuture<'s, WarehouseResult<T>>,
+    ) -> WarehouseResult<T> {
+        let mut attempt = 1;
+        loop {
+            let mut session = self.client.start_session().await?;
+            session.start_transaction().await?;
+            // Dropping the session on an error aborts the transaction.
+            let result = match body(self, &mut session, context).await {
+                Ok(value) => commit(&mut session).await.map(|()| value),
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(WarehouseError::Storage(e))
+                    if e.contains_label(TRANSIENT_TRANSACTION_ERROR)
+                        && attempt < TRANSACTION_ATTEMPTS =>
+                {
+                    tracing::warn!("Transaction attempt {} failed, retrying: {}", attempt, e);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+async fn commit(session: &mut ClientSession) -> WarehouseResult<()> {
+    let mut attempt = 1;
+    loop {
+        match session.commit_transaction().await {
+            Err(e)
+                if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                    && attempt < TRANSACTION_ATTEMPTS =>
+            {
+                tracing::warn!(
+                    "Commit attempt {} has an unknown outcome, retrying: {}",
+                    attempt,
+                    e
+                );
+                attempt += 1;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == DUPLICATE_KEY
+    )
+}
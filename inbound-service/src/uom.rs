@@ -0,0 +1,314 @@
+//! Units of measure and the pack hierarchies products are delivered in.
+//!
+//! Stock is stored and announced in eaches, the base unit. A product's pack hierarchy says how
+//! many eaches its inner packs, cases and pallets hold, and quantities received in those units are
+//! converted to eaches when they are recorded.
+
+use crate::model::Product;
+use common_error::error::WarehouseError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Uom {
+    #[default]
+    Each,
+    Inner,
+    Case,
+    Pallet,
+}
+
+impl Uom {
+    /// The unit of an X12 unit of measurement code, for the codes suppliers ship in.
+    ///
+    /// No code is read as an inner pack: suppliers name inners with different codes, `PK`, `BX`
+    /// and others that mean an outer pack to the next supplier, so notices have to announce them
+    /// in eaches or cases rather than have the unit guessed.
+    pub fn from_x12(code: &str) -> Option<Self> {
+        match code {
+            "EA" => Some(Uom::Each),
+            "CA" => Some(Uom::Case),
+            "PL" => Some(Uom::Pallet),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Uom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Uom::Each => "each",
+            Uom::Inner => "inner",
+            Uom::Case => "case",
+            Uom::Pallet => "pallet",
+        })
+    }
+}
+
+/// How many eaches each pack of a product holds. Packs the product does not come in are left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackHierarchy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inner: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub case: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pallet: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum UomError {
+    #[error("Product {product_id} is not in the catalog, it can only be received in eaches")]
+    UnknownProduct { product_id: u64 },
+    #[error("Product {product_id} does not come in {uom} packs")]
+    NoPack { product_id: u64, uom: Uom },
+    #[error("Pack hierarchy of product {product_id} is invalid: {reason}")]
+    InvalidHierarchy { product_id: u64, reason: String },
+    #[error("{quantity} x {uom} of product {product_id} is too many eaches to count")]
+    Overflow {
+        product_id: u64,
+        quantity: u32,
+        uom: Uom,
+    },
+}
+
+impl From<UomError> for WarehouseError {
+    fn from(e: UomError) -> Self {
+        WarehouseError::Validation(e.to_string())
+    }
+}
+
+impl PackHierarchy {
+    /// How many eaches a pack of `uom` holds, `None` if the product does not come in it.
+    pub fn eaches_per(&self, uom: Uom) -> Option<u32> {
+        match uom {
+            Uom::Each => Some(1),
+            Uom::Inner => self.inner,
+            Uom::Case => self.case,
+            Uom::Pallet => self.pallet,
+        }
+    }
+
+    /// Checks that every pack holds more eaches than, and a whole number of, the packs below it.
+    /// A case of 12 made of inners of 5 is a miscount waiting to happen.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut below = (Uom::Each, 1);
+        for uom in [Uom::Inner, Uom::Case, Uom::Pallet] {
+            let Some(eaches) = self.eaches_per(uom) else {
+                continue;
+            };
+            if eaches <= below.1 || eaches % below.1 != 0 {
+                return Err(format!(
+                    "a {} of {} eaches is not a whole number of {}s of {}",
+                    uom, eaches, below.0, below.1
+                ));
+            }
+            below = (uom, eaches);
+        }
+        Ok(())
+    }
+}
+
+/// Converts `quantity` packs of `uom` of a product to eaches. Without a catalog entry only eaches
+/// can be received.
+pub fn to_eaches(
+    product: Option<&Product>,
+    product_id: u64,
+    quantity: u32,
+    uom: Uom,
+) -> Result<u32, UomError> {
+    if uom == Uom::Each {
+        return Ok(quantity);
+    }
+    let product = product.ok_or(UomError::UnknownProduct { product_id })?;
+    product
+        .pack
+        .validate()
+        .map_err(|reason| UomError::InvalidHierarchy { product_id, reason })?;
+    let eaches = product
+        .pack
+        .eaches_per(uom)
+        .ok_or(UomError::NoPack { product_id, uom })?;
+    quantity.checked_mul(eaches).ok_or(UomError::Overflow {
+        product_id,
+        quantity,
+        uom,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(pack: PackHierarchy) -> Product {
+        Product {
+            product_id: 1001,
+            name: "Tea".into(),
+            lot_controlled: false,
+            serial_controlled: false,
+            min_shelf_life_days: None,
+            pack,
+        }
+    }
+
+    fn tea() -> Product {
+        product(PackHierarchy {
+            inner: Some(6),
+            case: Some(24),
+            pallet: Some(960),
+        })
+    }
+
+    #[test]
+    fn x12_codes_suppliers_ship_in() {
+        assert_eq!(Uom::from_x12("EA"), Some(Uom::Each));
+        assert_eq!(Uom::from_x12("CA"), Some(Uom::Case));
+        assert_eq!(Uom::from_x12("PL"), Some(Uom::Pallet));
+        for code in ["PK", "BX", "ea", ""] {
+            assert_eq!(Uom::from_x12(code), None, "{}", code);
+        }
+    }
+
+    #[test]
+    fn packs_are_converted_to_eaches() {
+        let tea = tea();
+        assert_eq!(to_eaches(Some(&tea), 1001, 5, Uom::Each), Ok(5));
+        assert_eq!(to_eaches(Some(&tea), 1001, 5, Uom::Inner), Ok(30));
+        assert_eq!(to_eaches(Some(&tea), 1001, 5, Uom::Case), Ok(120));
+        assert_eq!(to_eaches(Some(&tea), 1001, 2, Uom::Pallet), Ok(1920));
+    }
+
+    #[test]
+    fn products_not_in_the_catalog_come_in_eaches_only() {
+        assert_eq!(to_eaches(None, 1001, 5, Uom::Each), Ok(5));
+        for uom in [Uom::Inner, Uom::Case, Uom::Pallet] {
+            assert_eq!(
+                to_eaches(None, 1001, 5, uom),
+                Err(UomError::UnknownProduct { product_id: 1001 })
+            );
+        }
+    }
+
+    #[test]
+    fn packs_a_product_does_not_come_in_are_refused() {
+        let product = product(PackHierarchy {
+            inner: None,
+            case: Some(12),
+            pallet: None,
+        });
+        assert_eq!(to_eaches(Some(&product), 1001, 2, Uom::Case), Ok(24));
+        assert_eq!(
+            to_eaches(Some(&product), 1001, 2, Uom::Pallet),
+            Err(UomError::NoPack {
+                product_id: 1001,
+                uom: Uom::Pallet
+            })
+        );
+        assert_eq!(
+            to_eaches(Some(&product), 1001, 2, Uom::Inner)
+                .unwrap_err()
+                .to_string(),
+            "Product 1001 does not come in inner packs"
+        );
+    }
+
+    #[test]
+    fn hierarchies_must_nest_whole_packs() {
+        let valid = [
+            PackHierarchy::default(),
+            tea().pack,
+            // Levels may be skipped.
+            PackHierarchy {
+                inner: None,
+                case: Some(12),
+                pallet: Some(600),
+            },
+        ];
+        for pack in valid {
+            assert_eq!(pack.validate(), Ok(()), "{:?}", pack);
+        }
+
+        let invalid = [
+            (
+                PackHierarchy {
+                    inner: Some(5),
+                    case: Some(12),
+                    pallet: None,
+                },
+                "a case of 12 eaches is not a whole number of inners of 5",
+            ),
+            (
+                PackHierarchy {
+                    inner: None,
+                    case: Some(12),
+                    pallet: Some(100),
+                },
+                "a pallet of 100 eaches is not a whole number of cases of 12",
+            ),
+            (
+                PackHierarchy {
+                    inner: Some(6),
+                    case: Some(6),
+                    pallet: None,
+                },
+                "a case of 6 eaches is not a whole number of inners of 6",
+            ),
+        ];
+        for (pack, reason) in invalid {
+            assert_eq!(pack.validate(), Err(reason.to_string()), "{:?}", pack);
+        }
+        // An inner of one is an each.
+        let single = PackHierarchy {
+            inner: Some(1),
+            case: None,
+            pallet: None,
+        };
+        assert!(single.validate().is_err());
+    }
+
+    #[test]
+    fn invalid_hierarchies_are_not_converted() {
+        let product = product(PackHierarchy {
+            inner: Some(5),
+            case: Some(12),
+            pallet: None,
+        });
+        assert_eq!(
+            to_eaches(Some(&product), 1001, 1, Uom::Case),
+            Err(UomError::InvalidHierarchy {
+                product_id: 1001,
+                reason: "a case of 12 eaches is not a whole number of inners of 5".into(),
+            })
+        );
+        // Eaches need no hierarchy.
+        assert_eq!(to_eaches(Some(&product), 1001, 7, Uom::Each), Ok(7));
+    }
+
+    #[test]
+    fn quantities_too_large_to_count_overflow() {
+        let tea = tea();
+        assert_eq!(
+            to_eaches(Some(&tea), 1001, u32::MAX / 960 + 1, Uom::Pallet),
+            Err(UomError::Overflow {
+                product_id: 1001,
+                quantity: u32::MAX / 960 + 1,
+                uom: Uom::Pallet,
+            })
+        );
+        assert_eq!(
+            to_eaches(Some(&tea), 1001, u32::MAX / 960, Uom::Pallet),
+            Ok(u32::MAX / 960 * 960)
+        );
+    }
+
+    #[test]
+    fn unit_errors_are_validation_failures() {
+        let error: WarehouseError = UomError::UnknownProduct { product_id: 1001 }.into();
+        assert_eq!(error.code(), "VALIDATION_FAILED");
+        assert_eq!(error.http_status(), 422);
+    }
+}
@@ -0,0 +1,57 @@
+//! MongoDB for the integration tests, a single node replica set started with testcontainers.
+
+use mongodb::bson::doc;
+use mongodb::Client;
+use std::time::Duration;
+use testcontainers::core::{IntoContainerPort, WaitFor};
+use testcontainers::runners::AsyncRunner;
+use testcontainers::{ContainerAsync, GenericImage, ImageExt};
+
+/// Starts MongoDB and returns the container, stopped when dropped, and its connection string.
+pub async fn start_mongo() -> (ContainerAsync<GenericImage>, String) {
+    let mongo = GenericImage::new("mongo", "7.0")
+        .with_exposed_port(27017.tcp())
+        .with_wait_for(WaitFor::message_on_stdout("Waiting for connections"))
+        .with_cmd(["--replSet", "rs0", "--bind_ip_all"])
+        .start()
+        .await
+        .expect("failed to start MongoDB");
+    let port = mongo.get_host_port_ipv4(27017).await.unwrap();
+    let uri = format!("mongodb://127.0.0.1:{}/?directConnection=true", port);
+
+    // Change streams and transactions need a replica set, even a single node one.
+    let client = Client::with_uri_str(&uri).await.unwrap();
+    let admin = client.database("admin");
+    admin
+        .run_command(doc! {
+            "replSetInitiate": {
+                "_id": "rs0",
+                "members": [{ "_id": 0, "host": "127.0.0.1:27017" }],
+            }
+        })
+        .await
+        .unwrap();
+    wait_for("replica set primary", || async {
+        admin
+            .run_command(doc! { "hello": 1 })
+            .await
+            .is_ok_and(|hello| hello.get_bool("isWritablePrimary").unwrap_or(false))
+    })
+    .await;
+
+    (mongo, uri)
+}
+
+pub async fn wait_for<F, Fut>(what: &str, mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    for _ in 0..300 {
+        if condition().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("timed out waiting for {}", what);
+}
@@ -0,0 +1,256 @@
+//! End-to-end tests of the inbound pipeline: rows are written to the outbox the way the simulator
+//! does it and relayed to an in-memory stand-in for Kafka, against a single node MongoDB replica set
+//! started with testcontainers.
+//!
+//! They need a Docker daemon, run them with `cargo test -p inbound-service -- --ignored`.
+
+mod common;
+
+use async_trait::async_trait;
+use common::{start_mongo, wait_for};
+use common_error::error::KafkaResult;
+use common_kafka::config::InboundConfig;
+use common_kafka::memory::{InMemoryBroker, InMemoryProducer};
+use common_kafka::{DeliveryLookup, DeliveryReport, MessageProducer, RetryPolicy};
+use inbound_outbox::relay::{OutboxRelay, RelayConfig, OUTBOX_ID_HEADER};
+use inbound_outbox::Outbox;
+use inbound_service::model::CsvRow;
+use inbound_service::simulator::{add_row, read_rows};
+use mongodb::bson::oid::ObjectId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use testcontainers::{ContainerAsync, GenericImage};
+
+const TOPIC: &str = "INBOUND";
+const USER_ID: u64 = 16349;
+
+struct Pipeline {
+    _mongo: ContainerAsync<GenericImage>,
+    outbox: Outbox,
+    broker: InMemoryBroker,
+}
+
+impl Pipeline {
+    async fn start() -> Self {
+        let (mongo, uri) = start_mongo().await;
+
+        let outbox = Outbox::new(&uri, "warehouse", "inbound_outbox")
+            .await
+            .unwrap();
+
+        Self {
+            _mongo: mongo,
+            outbox,
+            broker: InMemoryBroker::new().with_default_partitions(3),
+        }
+    }
+
+    fn producer(&self) -> Arc<InMemoryProducer> {
+        Arc::new(InMemoryProducer::new(&self.broker, InboundConfig::new()))
+    }
+
+    fn relay<P: MessageProducer + DeliveryLookup>(
+        &self,
+        producer: Arc<P>,
+        claim_timeout: Duration,
+    ) -> OutboxRelay<P> {
+        let config = RelayConfig {
+            send_policy: RetryPolicy::fixed(Duration::from_millis(10)).with_max_attempts(3),
+            sweep_policy: RetryPolicy::fixed(Duration::from_millis(100)),
+            claim_timeout,
+        };
+        OutboxRelay::new(self.outbox.clone(), producer, config)
+    }
+
+    async fn add_rows(&self, rows: &[CsvRow]) -> Vec<ObjectId> {
+        let mut ids = Vec::new();
+        for row in rows {
+            ids.push(add_row(&self.outbox, USER_ID, row).await.unwrap());
+        }
+        ids
+    }
+
+    async fn wait_until_sent(&self, ids: &[ObjectId]) {
+        wait_for("entries to be marked sent", || async {
+            for id in ids {
+                if self.outbox.get(*id).await.unwrap().status != "sent" {
+                    return false;
+                }
+            }
+            true
+        })
+        .await;
+    }
+
+    /// Every entry is on the topic exactly once and marked sent with the offset it was stored at.
+    async fn assert_published_once(&self, ids: &[ObjectId]) {
+        let messages = self.broker.messages(TOPIC);
+        assert_eq!(messages.len(), ids.len(), "unexpected number of messages");
+
+        let mut by_entry: HashMap<Vec<u8>, Vec<_>> = HashMap::new();
+        for message in &messages {
+            let id = message
+                .header(OUTBOX_ID_HEADER)
+                .expect("message without outbox id");
+            by_entry.entry(id.to_vec()).or_default().push(message);
+        }
+
+        for id in ids {
+            let published = &by_entry[id.to_hex().as_bytes()];
+            assert_eq!(published.len(), 1, "entry {} published more than once", id);
+
+            let entry = self.outbox.get(*id).await.unwrap();
+            assert_eq!(entry.status, "sent");
+            assert_eq!(entry.kafka_partition, Some(published[0].partition));
+            assert_eq!(entry.kafka_offset, Some(published[0].offset));
+        }
+    }
+}
+
+fn csv_rows() -> Vec<CsvRow> {
+    let rows = read_rows(concat!(env!("CARGO_MANIFEST_DIR"), "/inbound-stock.csv")).unwrap();
+    assert!(!rows.is_empty());
+    rows
+}
+
+/// Publishes like the wrapped producer, but stops responding after `sends_before_crash` messages
+/// were handed to the broker, like a relay that dies before it can mark the entry sent.
+struct CrashingProducer {
+    inner: InMemoryProducer,
+    sends_before_crash: AtomicUsize,
+}
+
+#[async_trait]
+impl MessageProducer for CrashingProducer {
+    fn topic(&self) -> &str {
+        self.inner.topic()
+    }
+
+    async fn send_to(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(&str, &[u8])],
+    ) -> KafkaResult<DeliveryReport> {
+        let delivery = self.inner.send_to(topic, key, payload, headers).await?;
+        if self.sends_before_crash.fetch_sub(1, Ordering::SeqCst) <= 1 {
+            std::future::pending::<()>().await;
+        }
+        Ok(delivery)
+    }
+}
+
+#[async_trait]
+impl DeliveryLookup for CrashingProducer {
+    async fn find_published(
+        &self,
+        topic: &str,
+        since: i64,
+        name: &str,
+        value: &[u8],
+    ) -> KafkaResult<Option<DeliveryReport>> {
+        self.inner.find_published(topic, since, name, value).await
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn every_csv_row_is_published_once_and_marked_sent() {
+    let pipeline = Pipeline::start().await;
+    let rows = csv_rows();
+    let (before, after) = rows.split_at(rows.len() / 2);
+
+    // Rows written before the relay starts are picked up by its first sweep, the rest through the
+    // change stream.
+    let mut ids = pipeline.add_rows(before).await;
+    let relay = Arc::new(pipeline.relay(pipeline.producer(), Duration::from_secs(300)));
+    let running = tokio::spawn({
+        let relay = relay.clone();
+        async move { relay.run().await }
+    });
+    ids.extend(pipeline.add_rows(after).await);
+
+    pipeline.wait_until_sent(&ids).await;
+    running.abort();
+
+    pipeline.assert_published_once(&ids).await;
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn claims_left_by_a_crashed_relay_are_recovered_without_duplicates() {
+    let pipeline = Pipeline::start().await;
+    let ids = pipeline.add_rows(&csv_rows()).await;
+    let crashed = pipeline.relay(pipeline.producer(), Duration::ZERO);
+
+    // Killed between send and mark.
+    for id in &ids[..3] {
+        let entry = pipeline.outbox.claim(*id).await.unwrap().unwrap();
+        crashed.publish(&entry).await.unwrap();
+    }
+    // Killed between claim and send.
+    for id in &ids[3..5] {
+        pipeline.outbox.claim(*id).await.unwrap().unwrap();
+    }
+
+    let restarted = pipeline.relay(pipeline.producer(), Duration::ZERO);
+    let summary = restarted.sweep().await.unwrap();
+
+    assert_eq!(summary.recovered, 5);
+    assert_eq!(summary.failed, 0);
+    pipeline.assert_published_once(&ids).await;
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn relay_killed_mid_run_is_recovered_by_its_replacement() {
+    let pipeline = Pipeline::start().await;
+    let ids = pipeline.add_rows(&csv_rows()).await;
+
+    let crashing = Arc::new(CrashingProducer {
+        inner: InMemoryProducer::new(&pipeline.broker, InboundConfig::new()),
+        sends_before_crash: AtomicUsize::new(ids.len() / 2),
+    });
+    let crashing_relay = Arc::new(pipeline.relay(crashing, Duration::from_secs(300)));
+    let running = tokio::spawn({
+        let relay = crashing_relay.clone();
+        async move { relay.run().await }
+    });
+
+    wait_for("the relay to hang after a send", || async {
+        pipeline.broker.messages(TOPIC).len() == ids.len() / 2
+    })
+    .await;
+    running.abort();
+    let _ = running.await;
+
+    let replacement = pipeline.relay(pipeline.producer(), Duration::ZERO);
+    let summary = replacement.sweep().await.unwrap();
+
+    assert_eq!(summary.recovered, 1);
+    pipeline.assert_published_once(&ids).await;
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn entries_stay_pending_while_kafka_is_down() {
+    let pipeline = Pipeline::start().await;
+    let ids = pipeline.add_rows(&csv_rows()).await;
+    let relay = pipeline.relay(pipeline.producer(), Duration::from_secs(300));
+
+    pipeline.broker.set_unavailable(true);
+    let summary = relay.sweep().await.unwrap();
+    assert_eq!(summary.failed, ids.len());
+    assert_eq!(
+        pipeline.outbox.fetch_pending_entries().await.unwrap().len(),
+        ids.len()
+    );
+
+    pipeline.broker.set_unavailable(false);
+    let summary = relay.sweep().await.unwrap();
+    assert_eq!(summary.sent, ids.len());
+    pipeline.assert_published_once(&ids).await;
+}
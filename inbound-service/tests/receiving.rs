@@ -0,0 +1,404 @@
+//! Tests of receiving against MongoDB, a single node replica set started with testcontainers so
+//! that transactions work.
+//!
+//! They need a Docker daemon, run them with `cargo test -p inbound-service -- --ignored`.
+
+mod common;
+
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use common::start_mongo;
+use inbound_outbox::{OutboxEntry, STOCK_RECEIVED};
+use inbound_service::api::router;
+use inbound_service::edi::parse_856;
+use inbound_service::file_drop::{FileDrop, FileDropConfig};
+use inbound_service::model::{Product, STOCK_SCRAPPED};
+use inbound_service::store::{ReceivingStore, PRODUCTS_COLLECTION};
+use inbound_service::uom::PackHierarchy;
+use mongodb::bson::oid::ObjectId;
+use mongodb::Client;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::Duration;
+use testcontainers::{ContainerAsync, GenericImage};
+use tower::ServiceExt;
+
+const ISA: &str = "ISA*00*          *00*          *ZZ*SENDERID       *ZZ*RECEIVERID     \
+                   *260101*1200*U*00401*000000001*0*P*>";
+
+struct Receiving {
+    _mongo: ContainerAsync<GenericImage>,
+    uri: String,
+    store: ReceivingStore,
+}
+
+impl Receiving {
+    async fn start() -> Self {
+        let (mongo, uri) = start_mongo().await;
+        let store = ReceivingStore::connect(&uri, "warehouse", "inbound_outbox")
+            .await
+            .unwrap();
+        Self {
+            _mongo: mongo,
+            uri,
+            store,
+        }
+    }
+
+    async fn add_products(&self, products: &[Product]) {
+        let client = Client::with_uri_str(&self.uri).await.unwrap();
+        client
+            .database("warehouse")
+            .collection::<Product>(PRODUCTS_COLLECTION)
+            .insert_many(products)
+            .await
+            .unwrap();
+    }
+
+    async fn request(&self, request: Request<Body>) -> (StatusCode, Value) {
+        let response = router(self.store.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn post(&self, uri: &str, body: Value) -> (StatusCode, Value) {
+        let request = Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        self.request(request).await
+    }
+
+    async fn get(&self, uri: &str) -> (StatusCode, Value) {
+        self.request(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+    }
+
+    /// The outbox entries of `event_type` not relayed yet, as their payloads.
+    async fn pending(&self, event_type: &str) -> Vec<Value> {
+        let entries: Vec<OutboxEntry> = self.store.outbox().fetch_pending_entries().await.unwrap();
+        entries
+            .into_iter()
+            .filter(|entry| entry.event_type == event_type)
+            .map(|entry| serde_json::from_str(&entry.payload).unwrap())
+            .collect()
+    }
+
+    /// Receives `quantity` damaged eaches of product 1001 on shipment 500 and returns the id of
+    /// the stock quarantined.
+    async fn quarantine(&self, quantity: u32) -> String {
+        let scan = json!({
+            "shipment_id": 500,
+            "product_id": 1001,
+            "quantity": quantity,
+            "operator_id": 16349,
+            "condition": "damaged",
+        });
+        let (status, _) = self.post("/scans", scan).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, stock) = self.get("/shipments/500/quarantine").await;
+        stock[0]["id"].as_str().unwrap().to_string()
+    }
+
+    /// A drop directory of its own holding `files`, as (name, content).
+    async fn file_drop(&self, files: &[(&str, &str)]) -> (FileDrop, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("file-drop-{}", ObjectId::new()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        for (name, content) in files {
+            tokio::fs::write(dir.join(name), content).await.unwrap();
+        }
+        let config = FileDropConfig {
+            dir: dir.clone(),
+            poll_interval: Duration::from_secs(1),
+        };
+        (FileDrop::new(self.store.clone(), config), dir)
+    }
+}
+
+/// A scan of `quantity` eaches of product 1001 on shipment 500, from scanner `device` as its
+/// `sequence`th scan.
+fn scan(device: &str, sequence: u64, quantity: u32) -> Value {
+    json!({
+        "shipment_id": 500,
+        "product_id": 1001,
+        "quantity": quantity,
+        "operator_id": 16349,
+        "idempotency_key": { "device_id": device, "sequence": sequence },
+    })
+}
+
+/// An interchange with a ship notice per shipment, each announcing 10 eaches of product 1001 and
+/// `quantity` eaches of product 1002.
+fn interchange(shipments: &[(u64, u32)]) -> String {
+    let mut segments = vec![
+        ISA.to_string(),
+        "GS*SH*SENDER*RECEIVER*20260101*1200*1*X*004010".to_string(),
+    ];
+    for (index, (shipment_id, quantity)) in shipments.iter().enumerate() {
+        let control_number = format!("{:04}", index + 1);
+        let set = [
+            format!("ST*856*{}", control_number),
+            format!("BSN*00*{}*20260101*1200", shipment_id),
+            "HL*1**S".to_string(),
+            "DTM*017*20260105*0800".to_string(),
+            "N1*SF*Acme Supply".to_string(),
+            "HL*2*1*I".to_string(),
+            "LIN**BP*1001".to_string(),
+            "SN1**10*EA".to_string(),
+            "HL*3*1*I".to_string(),
+            "LIN**BP*1002".to_string(),
+            format!("SN1**{}*EA", quantity),
+        ];
+        let trailer = format!("SE*{}*{}", set.len() + 1, control_number);
+        segments.extend(set);
+        segments.push(trailer);
+    }
+    segments.push(format!("GE*{}*1", shipments.len()));
+    segments.push("IEA*1*000000001".to_string());
+    segments
+        .into_iter()
+        .map(|segment| format!("{}~\n", segment))
+        .collect()
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn ship_notices_stored_before_a_file_failed_partway_count_as_created() {
+    let receiving = Receiving::start().await;
+    let file = interchange(&[(12345, 4), (12346, 6)]);
+
+    // The poll that failed had stored the first notice already.
+    let first = parse_856(&file).unwrap().notices.remove(0);
+    receiving.store.create_asn(first.asn).await.unwrap();
+
+    let (file_drop, dir) = receiving.file_drop(&[("notices.edi", &file)]).await;
+    let reports = file_drop.ingest_dropped().await.unwrap();
+
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].created, [12345, 12346]);
+    assert!(reports[0].failed.is_empty(), "{:?}", reports[0].failed);
+    assert!(dir.join("processed").join("notices.edi").exists());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn a_different_ship_notice_for_a_shipment_with_an_asn_fails() {
+    let receiving = Receiving::start().await;
+    let stored = parse_856(&interchange(&[(12345, 4)]))
+        .unwrap()
+        .notices
+        .remove(0);
+    receiving.store.create_asn(stored.asn).await.unwrap();
+
+    let file = interchange(&[(12345, 5)]);
+    let (file_drop, _dir) = receiving.file_drop(&[("notices.edi", &file)]).await;
+    let reports = file_drop.ingest_dropped().await.unwrap();
+
+    assert!(reports[0].created.is_empty());
+    assert_eq!(reports[0].failed.len(), 1);
+    assert_eq!(reports[0].failed[0].shipment_id, 12345);
+    assert_eq!(
+        reports[0].failed[0].reason,
+        "Conflict: shipment 12345 already has a different ASN"
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn resubmitted_scans_return_the_line_they_were_recorded_as() {
+    let receiving = Receiving::start().await;
+
+    let (status, recorded) = receiving.post("/scans", scan("hh-7", 1, 3)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, replayed) = receiving.post("/scans", scan("hh-7", 1, 3)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(replayed, recorded);
+
+    let (_, lines) = receiving.get("/shipments/500/lines").await;
+    assert_eq!(lines.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn reusing_an_idempotency_key_for_another_scan_is_a_conflict() {
+    let receiving = Receiving::start().await;
+    let (_, recorded) = receiving.post("/scans", scan("hh-7", 1, 3)).await;
+
+    let (status, problem) = receiving.post("/scans", scan("hh-7", 1, 4)).await;
+
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(problem["code"], "CONFLICT");
+    assert_eq!(
+        problem["detail"],
+        format!(
+            "Conflict: idempotency key hh-7:1 was already used for a different scan, line {}",
+            recorded["id"].as_str().unwrap()
+        )
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn batches_replay_recorded_scans_and_record_the_rest() {
+    let receiving = Receiving::start().await;
+    let (_, first) = receiving.post("/scans", scan("hh-7", 1, 3)).await;
+
+    let batch = json!({ "scans": [scan("hh-7", 1, 3), scan("hh-7", 2, 5)] });
+    let (status, lines) = receiving.post("/scans/batch", batch.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(lines[0], first);
+    assert_eq!(lines[1]["quantity"], 5);
+
+    let (status, replayed) = receiving.post("/scans/batch", batch).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(replayed, lines);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn batches_using_a_key_twice_are_refused_whole() {
+    let receiving = Receiving::start().await;
+
+    let batch = json!({ "scans": [scan("hh-7", 2, 3), scan("hh-8", 1, 1), scan("hh-7", 2, 3)] });
+    let (status, problem) = receiving.post("/scans/batch", batch).await;
+
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        problem["detail"],
+        "Validation failed: idempotency key hh-7:2 is used more than once"
+    );
+    let (_, lines) = receiving.get("/shipments/500/lines").await;
+    assert_eq!(lines, json!([]));
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn products_not_in_the_catalog_are_received_as_scanned_in_eaches() {
+    let receiving = Receiving::start().await;
+    let scan = json!({ "shipment_id": 500, "product_id": 2002, "quantity": 4, "operator_id": 1 });
+
+    let (status, line) = receiving.post("/scans", scan.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(line["quantity"], 4);
+
+    let mut in_cases = scan;
+    in_cases["uom"] = json!("case");
+    let (status, problem) = receiving.post("/scans", in_cases).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        problem["detail"],
+        "Validation failed: Product 2002 is not in the catalog, it can only be received in eaches"
+    );
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn catalogued_products_are_held_to_their_tracking_rules() {
+    let receiving = Receiving::start().await;
+    receiving
+        .add_products(&[Product {
+            product_id: 1001,
+            name: "Yoghurt".into(),
+            lot_controlled: true,
+            serial_controlled: false,
+            min_shelf_life_days: None,
+            pack: PackHierarchy::default(),
+        }])
+        .await;
+    let scan = json!({ "shipment_id": 500, "product_id": 1001, "quantity": 4, "operator_id": 1 });
+
+    let (status, problem) = receiving.post("/scans", scan.clone()).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        problem["detail"],
+        "Validation failed: product 1001 needs a lot"
+    );
+
+    let mut with_lot = scan;
+    with_lot["lot"] = json!("L2601");
+    let (status, _) = receiving.post("/scans", with_lot).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn quarantined_stock_is_released_and_scrapped_until_none_is_held() {
+    let receiving = Receiving::start().await;
+    let id = receiving.quarantine(10).await;
+
+    let release = json!({ "quantity": 4, "reason_code": "INSPECTED_OK", "operator_id": 17899 });
+    let (status, stock) = receiving
+        .post(&format!("/quarantine/{}/release", id), release)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stock["held"], 6);
+    assert_eq!(stock["dispositions"][0]["quantity"], 4);
+    let released = receiving.pending(STOCK_RECEIVED).await;
+    assert_eq!(released.len(), 1);
+    assert_eq!(released[0]["quantity"], 4);
+    assert_eq!(released[0]["condition"], "good");
+
+    // Without a quantity, all that is still held.
+    let scrap = json!({ "reason_code": "CRUSHED", "operator_id": 17899 });
+    let (status, stock) = receiving
+        .post(&format!("/quarantine/{}/scrap", id), scrap)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stock["held"], 0);
+    assert_eq!(stock["dispositions"].as_array().unwrap().len(), 2);
+    let scrapped = receiving.pending(STOCK_SCRAPPED).await;
+    assert_eq!(scrapped.len(), 1);
+    assert_eq!(scrapped[0]["quantity"], 6);
+    assert_eq!(scrapped[0]["quarantine_id"], id.as_str());
+    assert_eq!(scrapped[0]["reason_code"], "CRUSHED");
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn more_than_is_held_cannot_be_disposed() {
+    let receiving = Receiving::start().await;
+    let id = receiving.quarantine(10).await;
+
+    let scrap = json!({ "quantity": 11, "reason_code": "CRUSHED", "operator_id": 17899 });
+    let (status, problem) = receiving
+        .post(&format!("/quarantine/{}/scrap", id), scrap)
+        .await;
+
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        problem["detail"],
+        format!(
+            "Validation failed: only 10 of quarantined stock {} is still held",
+            id
+        )
+    );
+    let (_, stock) = receiving.get("/shipments/500/quarantine").await;
+    assert_eq!(stock[0]["held"], 10);
+    assert!(receiving.pending(STOCK_SCRAPPED).await.is_empty());
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn stock_disposed_of_entirely_cannot_be_disposed_again() {
+    let receiving = Receiving::start().await;
+    let id = receiving.quarantine(10).await;
+    let release = json!({ "reason_code": "INSPECTED_OK", "operator_id": 17899 });
+    receiving
+        .post(&format!("/quarantine/{}/release", id), release.clone())
+        .await;
+
+    let (status, problem) = receiving
+        .post(&format!("/quarantine/{}/release", id), release)
+        .await;
+
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(
+        problem["detail"],
+        format!(
+            "Conflict: quarantined stock {} was all released or scrapped already",
+            id
+        )
+    );
+    assert_eq!(receiving.pending(STOCK_RECEIVED).await.len(), 1);
+}